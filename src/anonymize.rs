@@ -0,0 +1,323 @@
+use anyhow::{bail, Result};
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, Write},
+};
+
+use crate::{
+    event_codec::{LogFormat, LogHeader},
+    event_log::{EventLogReader, LogRecord, LoggedEvent},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Events are scrubbed as JSON values by the names of their fields, like in
+// `migrations`. Only the strings of the fields below are kept or replaced by
+// pseudonyms; all other strings are blanked, so that a new field with text in
+// it is scrubbed until it is listed here.
+
+/// Fields whose strings are kept: enum variants and ids that do not tell
+/// anything about users.
+const KEPT_FIELDS: &[&str] = &["anon_mode", "kind", "language", "poll_id"];
+
+/// Fields whose strings are replaced by pseudonyms with the given prefix,
+/// because other events refer to them. Logins are in `login` and `*_login`
+/// fields as well.
+const PSEUDONYMIZED_FIELDS: &[(&str, &str)] =
+    &[("room", "room"), ("label", "label"), ("tags", "tag")];
+
+/// Rewrites an event log with logins, room names, labels and interests
+/// replaced by pseudonyms and all other text blanked, so that it can be shared
+/// for debugging. Pseudonyms are derived with HMAC keyed by `salt`, the same
+/// name always getting the same one, and thread ids are kept, so the scrubbed
+/// log replays into the same state with other names. Returns the number of
+/// records written.
+pub fn anonymize_log<R: BufRead, W: Write>(reader: R, writer: W, salt: &[u8]) -> Result<u64> {
+    let mut reader = EventLogReader::new(reader)?;
+    let format = reader.header().format;
+    anonymize_records(reader.iter_records(), format, writer, salt)
+}
+
+/// Writes anonymized `records` as a new event log in `format`, see
+/// `anonymize_log`. The records may come from several segments of a log.
+pub fn anonymize_records<W: Write>(
+    records: impl Iterator<Item = Result<LogRecord>>,
+    format: LogFormat,
+    mut writer: W,
+    salt: &[u8],
+) -> Result<u64> {
+    let codec = format.codec();
+    writer.write_all(&LogHeader::current(format).to_bytes())?;
+    let mut anonymizer = Anonymizer::new(salt);
+    let mut count = 0;
+    let mut buf = vec![];
+    for record in records {
+        let LoggedEvent { ts, event } = match record? {
+            LogRecord::Event(logged) => logged,
+            // Its logins are in keys of maps as well, which cannot be told
+            // apart by name.
+            LogRecord::Snapshot(_) => bail!(
+                "snapshots cannot be anonymized, anonymize the log it has been taken from instead"
+            ),
+        };
+        let mut value = serde_json::to_value(event)?;
+        anonymizer.scrub(None, &mut value)?;
+        let event = serde_json::from_value(value)?;
+        buf.clear();
+        codec.encode(&LogRecord::Event(LoggedEvent { ts, event }), &mut buf)?;
+        writer.write_all(&buf)?;
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+struct Anonymizer {
+    mac: Hmac<Sha256>,
+    pseudonyms: HashMap<(&'static str, String), String>,
+    /// The name each pseudonym has been given to, to catch collisions.
+    names: HashMap<String, String>,
+}
+
+impl Anonymizer {
+    fn new(salt: &[u8]) -> Self {
+        Self {
+            mac: Hmac::new_from_slice(salt).expect("HMAC accepts keys of any length"),
+            pseudonyms: HashMap::new(),
+            names: HashMap::new(),
+        }
+    }
+
+    /// Returns a pseudonym like `user_ab12cd` for a name of some kind, which
+    /// is also the prefix of the pseudonym.
+    fn pseudonym(&mut self, kind: &'static str, name: &str) -> Result<String> {
+        let key = (kind, name.to_owned());
+        if let Some(pseudonym) = self.pseudonyms.get(&key) {
+            return Ok(pseudonym.clone());
+        }
+        let mut mac = self.mac.clone();
+        // Equal names of different kinds should not be told apart as such.
+        mac.update(kind.as_bytes());
+        mac.update(b"\0");
+        mac.update(name.as_bytes());
+        let digest = mac.finalize().into_bytes();
+        let pseudonym = format!(
+            "{}_{:02x}{:02x}{:02x}",
+            kind, digest[0], digest[1], digest[2]
+        );
+        if let Some(other) = self.names.get(&pseudonym) {
+            bail!(
+                "{} {} and {} got the same pseudonym, try another salt",
+                kind,
+                other,
+                name
+            );
+        }
+        self.names.insert(pseudonym.clone(), name.to_owned());
+        self.pseudonyms.insert(key, pseudonym.clone());
+        Ok(pseudonym)
+    }
+
+    /// Scrubs a value found in the field named `key`.
+    fn scrub(&mut self, key: Option<&str>, value: &mut Value) -> Result<()> {
+        let key = key.unwrap_or_default();
+        match value {
+            Value::String(login) if key == "login" || key.ends_with("_login") => {
+                *login = self.pseudonym("user", login)?;
+            }
+            // `@login` thread ids name the other user.
+            Value::String(thread_id) if key == "thread_id" || key.ends_with("_thread_id") => {
+                if let Some(login) = thread_id.strip_prefix('@') {
+                    *thread_id = format!("@{}", self.pseudonym("user", login)?);
+                }
+            }
+            Value::String(name) => {
+                let kind = PSEUDONYMIZED_FIELDS
+                    .iter()
+                    .find(|(field, _)| *field == key)
+                    .map(|(_, kind)| *kind);
+                if let Some(kind) = kind {
+                    *name = self.pseudonym(kind, name)?;
+                } else if !KEPT_FIELDS.contains(&key) {
+                    name.clear();
+                }
+            }
+            Value::Object(fields) => {
+                for (key, value) in fields {
+                    self.scrub(Some(key), value)?;
+                }
+            }
+            Value::Array(values) => {
+                for value in values {
+                    self.scrub(Some(key), value)?;
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::command_dispatcher::CommandDispatcherBuilder;
+
+    const SALT: &[u8] = b"salt";
+
+    /// A log without a header, one bare event per line.
+    const LOG: &str = r##"{"UserConnected":{"user":{"login":"alice","first_name":"Alice","last_name":"Liddell"},"chat_id":1,"connected_at":5}}
+{"UserConnected":{"user":{"login":"bob","first_name":"Bob","last_name":null},"chat_id":2,"connected_at":6}}
+{"UserConnected":{"user":{"login":"carol","first_name":"Carol","last_name":null},"chat_id":3,"connected_at":6}}
+{"ThreadStarted":{"login":"alice","other_login":"bob","my_thread_id":"@bob","other_thread_id":"#x","anon_mode":"Me","is_initiator":true,"created_at":7}}
+{"ThreadStarted":{"login":"bob","other_login":"alice","my_thread_id":"#x","other_thread_id":"@bob","anon_mode":"Them","is_initiator":false,"created_at":7}}
+{"OutgoingMessage":{"login":"alice","thread_id":"@bob","message_id":10,"delete_at":null,"source_message_id":3,"text":{"Full":"hello bob, it's alice"}}}
+{"ThreadMessageReceived":{"login":"bob","message_id":11,"thread_id":"#x","kind":"Received"}}
+{"ThreadLabeled":{"login":"alice","thread_id":"@bob","label":"wonderland"}}
+{"ThreadLabeled":{"login":"alice","thread_id":"@bob","label":"rabbit"}}
+{"ThreadUnlabeled":{"login":"alice","thread_id":"@bob","label":"rabbit"}}
+{"ThreadNoteChanged":{"login":"alice","thread_id":"@bob","text":"met at the tea party"}}
+{"PollReceived":{"login":"bob","thread_id":"#x","poll_id":"42","question":"tea or coffee?","options":["tea","coffee"]}}
+{"UserInterestsChanged":{"login":"carol","tags":["chess","rabbit"]}}
+{"TopicChanged":{"login":"carol","topic":"chess openings"}}
+{"GreetingChanged":{"login":"bob","text":"hi, I'm Bob"}}
+{"UserSettingsChanged":{"login":"alice","settings":{"language":"Ru","notifications_sound_prefix":"Alice's phone"}}}
+{"RoomCreated":{"login":"carol","room":"chess club"}}
+{"RoomJoined":{"login":"carol","room":"chess club","alias":1}}
+{"RoomJoined":{"login":"alice","room":"chess club","alias":2}}
+{"ThreadStarted":{"login":"carol","other_login":"alice","my_thread_id":"#y","other_thread_id":"@carol","anon_mode":"Both","is_initiator":true,"created_at":8}}
+{"ThreadStarted":{"login":"alice","other_login":"carol","my_thread_id":"@carol","other_thread_id":"#y","anon_mode":"Both","is_initiator":false,"created_at":8}}
+{"UserBanned":{"login":"carol","banned_login":"alice","banned_thread_id":"#y","reason":"spam from alice","banned_at":9,"expires_at":null}}
+"##;
+
+    /// Words of the log that tell something about its users.
+    const PERSONAL_WORDS: &[&str] = &[
+        "alice",
+        "Alice",
+        "bob",
+        "Bob",
+        "carol",
+        "Carol",
+        "Liddell",
+        "wonderland",
+        "rabbit",
+        "tea",
+        "chess",
+        "phone",
+        "spam",
+    ];
+
+    fn anonymize(log: &str) -> String {
+        let mut output = vec![];
+        anonymize_log(log.as_bytes(), &mut output, SALT).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    fn replay(log: &str) -> Value {
+        let builder = CommandDispatcherBuilder::from_event_log(log.as_bytes(), false).unwrap();
+        serde_json::to_value(builder.snapshot()).unwrap()
+    }
+
+    /// Blanks all strings, keys of maps included, and sorts arrays, so that
+    /// states that only differ in names compare equal.
+    fn shape(value: &Value) -> Value {
+        match value {
+            Value::String(_) => Value::String(String::new()),
+            Value::Array(values) => {
+                let mut values: Vec<_> = values.iter().map(shape).collect();
+                values.sort_by_key(|value| value.to_string());
+                Value::Array(values)
+            }
+            Value::Object(fields) => {
+                let mut values: Vec<_> = fields.values().map(shape).collect();
+                values.sort_by_key(|value| value.to_string());
+                Value::Array(values)
+            }
+            _ => value.clone(),
+        }
+    }
+
+    fn logins(state: &Value) -> Vec<String> {
+        let mut logins: Vec<_> = state["users"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|user| user["user"]["login"].as_str().unwrap().to_owned())
+            .collect();
+        logins.sort();
+        logins
+    }
+
+    #[test]
+    fn anonymized_log_replays_into_same_state() {
+        let anonymized = anonymize(LOG);
+        let original_state = replay(LOG);
+        let anonymized_state = replay(&anonymized);
+        assert_eq!(shape(&original_state), shape(&anonymized_state));
+
+        let mut anonymizer = Anonymizer::new(SALT);
+        let mut expected: Vec<_> = logins(&original_state)
+            .iter()
+            .map(|login| anonymizer.pseudonym("user", login).unwrap())
+            .collect();
+        expected.sort();
+        assert_eq!(logins(&anonymized_state), expected);
+    }
+
+    #[test]
+    fn anonymized_log_has_no_personal_data() {
+        let anonymized = anonymize(LOG);
+        for word in PERSONAL_WORDS {
+            assert!(!anonymized.contains(word), "{:?} is left in the log", word);
+        }
+        // Structure is kept.
+        assert!(anonymized.contains("\"poll_id\":\"42\""));
+        assert!(anonymized.contains("\"anon_mode\":\"Both\""));
+        assert!(anonymized.contains("\"language\":\"Ru\""));
+        assert!(anonymized.contains("\"#x\""));
+    }
+
+    #[test]
+    fn pseudonyms_are_stable_and_keyed_by_salt() {
+        assert_eq!(anonymize(LOG), anonymize(LOG));
+        let mut first = Anonymizer::new(b"one");
+        let mut second = Anonymizer::new(b"two");
+        let pseudonym = first.pseudonym("user", "alice").unwrap();
+        assert!(pseudonym.starts_with("user_"));
+        assert_eq!(pseudonym.len(), "user_".len() + 6);
+        assert_eq!(first.pseudonym("user", "alice").unwrap(), pseudonym);
+        assert_ne!(second.pseudonym("user", "alice").unwrap(), pseudonym);
+        assert_ne!(
+            first.pseudonym("label", "alice").unwrap()["label_".len()..],
+            pseudonym["user_".len()..]
+        );
+    }
+
+    #[test]
+    fn thread_ids_naming_users_get_their_pseudonyms() {
+        let anonymized = anonymize(LOG);
+        let bob = Anonymizer::new(SALT).pseudonym("user", "bob").unwrap();
+        assert!(anonymized.contains(&format!("\"my_thread_id\":\"@{}\"", bob)));
+    }
+
+    #[test]
+    fn snapshots_are_refused() {
+        let builder = CommandDispatcherBuilder::from_event_log(LOG.as_bytes(), false).unwrap();
+        let mut log = vec![];
+        crate::event_log::write_snapshot(
+            &mut log,
+            crate::event_codec::LogFormat::Json,
+            builder.snapshot(),
+        )
+        .unwrap();
+        let err = anonymize_log(log.as_slice(), vec![], SALT).unwrap_err();
+        assert!(err.to_string().contains("snapshots cannot be anonymized"));
+    }
+}
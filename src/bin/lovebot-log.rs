@@ -0,0 +1,319 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    io::{self, BufRead, BufReader, BufWriter},
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use lovebot::{
+    anonymize_records, export_records, live_segments, CommandDispatcherBuilder, EventLogReader,
+    ExportFormat, LogFormat, LogHeader, LogRecord,
+};
+
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Inspects an event log of lovebot without starting the bot.
+#[derive(Parser, Debug)]
+#[clap(author, version, long_about = None)]
+struct Args {
+    /// The event log file.
+    event_log: PathBuf,
+
+    #[clap(subcommand)]
+    command: LogCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum LogCommand {
+    /// Count events per type and per user, and show the time they span.
+    Stats,
+
+    /// Print the last events of the log as JSON.
+    Tail {
+        /// How many events to print.
+        #[clap(short, default_value = "10")]
+        n: usize,
+    },
+
+    /// Print the events of a user or of a type as JSON.
+    Grep {
+        /// Only events of this user.
+        #[clap(long)]
+        login: Option<String>,
+
+        /// Only events of this type, e.g. `ThreadStarted`.
+        #[clap(long = "type")]
+        event_type: Option<String>,
+    },
+
+    /// Write all events of the log to the standard output, for jq or a
+    /// spreadsheet.
+    Export {
+        /// `json` for an indented array of events or `csv` for a row per
+        /// event.
+        #[clap(long, default_value = "json")]
+        format: ExportFormat,
+    },
+
+    /// Write a copy of the log with logins, rooms, labels and interests
+    /// replaced by pseudonyms and other text blanked, to be shared for
+    /// debugging.
+    Anonymize {
+        /// The new log file.
+        output: PathBuf,
+
+        /// Keys the pseudonyms, which stay the same for the same salt. Keep it
+        /// secret, or the logins can be guessed back.
+        #[clap(long)]
+        salt: String,
+    },
+
+    /// Replay the log the way the bot does on start and report the first
+    /// record that cannot be applied.
+    Validate,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// A closed segment of a rotated event log or the log itself.
+struct LogPart {
+    path: PathBuf,
+    reader: EventLogReader<Box<dyn BufRead + Send>>,
+}
+
+/// Opens the closed segments of the event log, oldest first, and then the log
+/// itself: the ones the bot replays on start.
+fn open_event_log(path: &Path) -> Result<Vec<LogPart>> {
+    let mut parts = vec![];
+    for segment in live_segments(path)? {
+        parts.push(LogPart {
+            path: segment.path().to_owned(),
+            reader: EventLogReader::new(segment.open()?)?,
+        });
+    }
+    let file = fs::File::open(path).context("failed to open event log file")?;
+    let file: Box<dyn BufRead + Send> = Box::new(BufReader::new(file));
+    parts.push(LogPart {
+        path: path.to_owned(),
+        reader: EventLogReader::new(file)?,
+    });
+    Ok(parts)
+}
+
+/// Reads the records of all parts of the event log in order.
+fn records(parts: &mut [LogPart]) -> impl Iterator<Item = Result<LogRecord>> + '_ {
+    parts.iter_mut().flat_map(|part| &mut part.reader)
+}
+
+/// The header of the log being appended to.
+fn current_header(parts: &[LogPart]) -> LogHeader {
+    parts.last().expect("the log itself").reader.header()
+}
+
+fn format_ts(ts: i64) -> String {
+    let time = UNIX_EPOCH + Duration::from_secs(ts.max(0) as u64);
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
+/// Sorts counts from the largest one, and by name among equal ones.
+fn sorted_counts(counts: HashMap<String, usize>) -> Vec<(String, usize)> {
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by(|(a_name, a), (b_name, b)| b.cmp(a).then_with(|| a_name.cmp(b_name)));
+    counts
+}
+
+fn stats(path: &Path) -> Result<()> {
+    let mut parts = open_event_log(path)?;
+    let header = current_header(&parts);
+    let segment_count = parts.len() - 1;
+    let mut event_count = 0;
+    let mut snapshot_users = None;
+    let mut by_type = HashMap::<String, usize>::new();
+    let mut by_user = HashMap::<String, usize>::new();
+    let mut time_range: Option<(i64, i64)> = None;
+    let mut untimed = 0;
+    for record in records(&mut parts) {
+        let logged = match record? {
+            LogRecord::Event(logged) => logged,
+            LogRecord::Snapshot(snapshot) => {
+                snapshot_users = Some(snapshot.user_count());
+                continue;
+            }
+        };
+        event_count += 1;
+        *by_type.entry(logged.event.name().into()).or_default() += 1;
+        *by_user.entry(logged.event.login().into()).or_default() += 1;
+        match logged.ts {
+            Some(ts) => {
+                let (first, last) = time_range.get_or_insert((ts, ts));
+                *first = ts.min(*first);
+                *last = ts.max(*last);
+            }
+            None => untimed += 1,
+        }
+    }
+
+    println!("Format: {}, version {}", header.format, header.version);
+    if segment_count > 0 {
+        println!("Closed segments: {}", segment_count);
+    }
+    if let Some(users) = snapshot_users {
+        println!("Starts with a snapshot of {} users", users);
+    }
+    println!("Events: {}", event_count);
+    match time_range {
+        Some((first, last)) => println!("Time range: {} to {}", format_ts(first), format_ts(last)),
+        None => println!("Time range: unknown"),
+    }
+    if untimed > 0 {
+        println!("Events without a timestamp: {}", untimed);
+    }
+    println!();
+    println!("Events by type:");
+    for (name, count) in sorted_counts(by_type) {
+        println!("  {:<32} {}", name, count);
+    }
+    println!();
+    println!("Events by user:");
+    for (login, count) in sorted_counts(by_user) {
+        println!("  @{:<31} {}", login, count);
+    }
+    Ok(())
+}
+
+fn tail(path: &Path, n: usize) -> Result<()> {
+    let mut last = VecDeque::new();
+    for record in records(&mut open_event_log(path)?) {
+        if let LogRecord::Event(logged) = record? {
+            last.push_back(logged);
+            if last.len() > n {
+                last.pop_front();
+            }
+        }
+    }
+    for logged in last {
+        println!("{}", serde_json::to_string(&logged)?);
+    }
+    Ok(())
+}
+
+fn grep(path: &Path, login: Option<&str>, event_type: Option<&str>) -> Result<()> {
+    for record in records(&mut open_event_log(path)?) {
+        let logged = match record? {
+            LogRecord::Event(logged) => logged,
+            LogRecord::Snapshot(_) => continue,
+        };
+        if login.is_some_and(|login| logged.event.login() != login)
+            || event_type.is_some_and(|name| logged.event.name() != name)
+        {
+            continue;
+        }
+        println!("{}", serde_json::to_string(&logged)?);
+    }
+    Ok(())
+}
+
+fn export(path: &Path, format: ExportFormat) -> Result<()> {
+    let stdout = io::stdout();
+    let mut parts = open_event_log(path)?;
+    export_records(records(&mut parts), format, BufWriter::new(stdout.lock()))?;
+    Ok(())
+}
+
+/// Writes the whole history of the log, closed segments included, into a
+/// single anonymized log.
+fn anonymize(path: &Path, output: &Path, salt: &str) -> Result<()> {
+    let mut parts = open_event_log(path)?;
+    let format = current_header(&parts).format;
+    let output_file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(output)
+        .context("failed to create anonymized event log file")?;
+    let result = anonymize_records(
+        records(&mut parts),
+        format,
+        BufWriter::new(output_file),
+        salt.as_bytes(),
+    );
+    // A partial log would be mistaken for a complete one.
+    let count = result.inspect_err(|_| {
+        let _ = fs::remove_file(output);
+    })?;
+    println!("Wrote {} anonymized events to {}", count, output.display());
+    Ok(())
+}
+
+/// Names the record with `index` among the ones after the header: the line of
+/// a JSON log, which follows the header if there is one, or the number of a
+/// bincode record.
+fn describe_position(header: LogHeader, index: usize) -> String {
+    match header.format {
+        LogFormat::Json if header.version > 0 => format!("line {}", index + 2),
+        LogFormat::Json => format!("line {}", index + 1),
+        LogFormat::Bincode => format!("record {}", index + 1),
+    }
+}
+
+/// Replays the closed segments and then the log itself, like
+/// `CommandDispatcherBuilder::from_rotated_event_log` does on start, but
+/// record by record, so that a failure is reported with its position.
+fn validate(path: &Path) -> Result<()> {
+    let mut parts = open_event_log(path)?;
+    let last = parts.len() - 1;
+    let mut builder = CommandDispatcherBuilder::default();
+    let mut record_count = 0;
+    for (number, part) in parts.iter_mut().enumerate() {
+        let header = part.reader.header();
+        for (index, record) in part.reader.by_ref().enumerate() {
+            let result = record.and_then(|record| builder.replay_record(record));
+            if let Err(err) = result {
+                bail!(
+                    "{}: {}: {}",
+                    part.path.display(),
+                    describe_position(header, index),
+                    err.root_cause()
+                );
+            }
+            record_count += 1;
+        }
+        match part.reader.torn_tail() {
+            // Nothing is appended to a closed segment, so it is not repaired.
+            Some(offset) if number < last => bail!(
+                "{}: the last record, at byte {}, has been cut short",
+                part.path.display(),
+                offset
+            ),
+            Some(offset) => println!(
+                "The last record, at byte {}, has been cut short and will be dropped on start",
+                offset
+            ),
+            None => {}
+        }
+    }
+    println!("All {} records are consistent", record_count);
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    pretty_env_logger::init();
+
+    match args.command {
+        LogCommand::Stats => stats(&args.event_log),
+        LogCommand::Tail { n } => tail(&args.event_log, n),
+        LogCommand::Grep { login, event_type } => {
+            grep(&args.event_log, login.as_deref(), event_type.as_deref())
+        }
+        LogCommand::Export { format } => export(&args.event_log, format),
+        LogCommand::Anonymize { output, salt } => anonymize(&args.event_log, &output, &salt),
+        LogCommand::Validate => validate(&args.event_log),
+    }
+}
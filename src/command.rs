@@ -1,107 +1,3588 @@
-use anyhow::{bail, ensure, Context};
-use teloxide::types::Message;
+use anyhow::{anyhow, bail, ensure, Context};
+use teloxide::types::{BotCommand, DiceEmoji, Message, MessageEntity, MessageEntityKind};
+use thiserror::Error;
 
-use crate::data::ThreadId;
+use std::{collections::HashSet, fmt, str::FromStr, time::Duration};
+
+use crate::{
+    data::{format_flag, normalize_login, normalize_thread_id, DndWindow, MediaKind, ThreadId},
+    messages::{self, Key, Language},
+    util::{slice_entities, utf16_len},
+};
 
 ////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug, PartialEq, Eq)]
+const MAX_POLL_OPTIONS: usize = 10;
+const MAX_SEND_RECEIVERS: usize = 10;
+const START_THREAD_CALLBACK_PREFIX: &str = "start:";
+const CONFIRM_CALLBACK_PREFIX: &str = "confirm:";
+const CANCEL_CALLBACK_PREFIX: &str = "cancel:";
+
+const DICE_EMOJIS: &[(&str, DiceEmoji)] = &[
+    ("🎲", DiceEmoji::Dice),
+    ("🎯", DiceEmoji::Darts),
+    ("🏀", DiceEmoji::Basketball),
+    ("⚽", DiceEmoji::Football),
+    ("🎳", DiceEmoji::Bowling),
+    ("🎰", DiceEmoji::SlotMachine),
+];
+
+#[derive(Debug, PartialEq)]
 pub enum Command {
-    Start,
-    Help,
+    /// The payload comes from a `t.me/<bot>?start=<payload>` link, see
+    /// `StartPayload`.
+    Start {
+        payload: Option<String>,
+    },
+    Help {
+        topic: Option<&'static str>,
+    },
     Users,
-    Threads,
+    Pick,
+    Online,
+    Find {
+        query: String,
+    },
+    Threads {
+        archived: bool,
+        label: Option<String>,
+    },
+    Label {
+        thread_id: ThreadId,
+        label: String,
+    },
+    Unlabel {
+        thread_id: ThreadId,
+        label: String,
+    },
+    Labels,
     Random {
+        message_id: i32,
+        tag: Option<String>,
+        text: String,
+        entities: Vec<MessageEntity>,
+    },
+    Skip {
+        thread_id: ThreadId,
         message_id: i32,
         text: String,
     },
+    Cancel,
+    Interests {
+        tags: Option<Vec<String>>,
+    },
+    Topic {
+        topic: Option<String>,
+    },
     Send {
         thread_id: ThreadId,
         message_id: i32,
         text: String,
+        entities: Vec<MessageEntity>,
+    },
+    SendMany {
+        thread_ids: Vec<ThreadId>,
+        message_id: i32,
+        text: String,
+    },
+    Schedule {
+        delay: Duration,
+        thread_id: ThreadId,
+        message_id: i32,
+        text: String,
+    },
+    Scheduled,
+    Unschedule {
+        index: usize,
+    },
+    Remind {
+        delay: Duration,
+        text: String,
+    },
+    Reminders,
+    CancelReminder {
+        index: usize,
+    },
+    Create {
+        room: String,
+    },
+    Join {
+        room: String,
+    },
+    Leave {
+        room: String,
+    },
+    Poll {
+        thread_id: ThreadId,
+        question: String,
+        options: Vec<String>,
+    },
+    Dice {
+        thread_id: ThreadId,
+        emoji: DiceEmoji,
+    },
+    PollAnswer {
+        poll_id: String,
+        option_ids: Vec<i32>,
+    },
+    /// An emoji reaction put on a message, `None` if it has been removed.
+    Reaction {
+        message_id: i32,
+        emoji: Option<String>,
+    },
+    StartThreadWith {
+        login: String,
+    },
+    Confirm {
+        nonce: u64,
+        confirmed: bool,
+    },
+    SendDefault {
+        message_id: i32,
+        text: String,
+        entities: Vec<MessageEntity>,
+        forwarded: bool,
+    },
+    SendMedia {
+        message_id: i32,
+        thread_id: Option<ThreadId>,
+        reply_message_id: Option<i32>,
+        kind: MediaKind,
+        file_id: String,
+        file_size: Option<u64>,
+        caption: Option<String>,
+        media_group_id: Option<String>,
+        forwarded: bool,
+    },
+    Default {
+        thread_id: Option<ThreadId>,
     },
     Reply {
         message_id: i32,
         reply_message_id: i32,
+        reply_text: Option<String>,
         text: String,
+        entities: Vec<MessageEntity>,
+    },
+    Edit {
+        thread_id: ThreadId,
+        new_text: String,
+    },
+    Edited {
+        message_id: i32,
+        new_text: String,
+    },
+    Delete {
+        thread_id: ThreadId,
     },
     Close {
+        thread_id: Option<ThreadId>,
+    },
+    CloseAll,
+    Undo,
+    Accept {
+        thread_id: ThreadId,
+    },
+    Decline {
         thread_id: ThreadId,
     },
     Ban {
+        thread_id: Option<ThreadId>,
+        duration: Option<Duration>,
+        reason: Option<String>,
+    },
+    BanInfo {
         thread_id: ThreadId,
     },
     Unban {
         thread_id: ThreadId,
     },
+    Pin {
+        thread_id: ThreadId,
+    },
+    Unpin {
+        thread_id: ThreadId,
+    },
+    Priority {
+        thread_id: ThreadId,
+    },
+    Pause {
+        thread_id: ThreadId,
+    },
+    Resume {
+        thread_id: ThreadId,
+    },
+    Expire {
+        thread_id: ThreadId,
+        duration: Option<Duration>,
+    },
+    ThreadInfo {
+        thread_id: ThreadId,
+    },
+    Note {
+        thread_id: ThreadId,
+        text: Option<String>,
+    },
+    Secret {
+        thread_id: ThreadId,
+        seconds: u64,
+    },
+    Receipts {
+        thread_id: ThreadId,
+        enabled: bool,
+    },
+    Previews {
+        thread_id: ThreadId,
+        enabled: bool,
+    },
+    Slow {
+        thread_id: ThreadId,
+        seconds: u64,
+    },
+    Archive {
+        thread_id: ThreadId,
+    },
+    Unarchive {
+        thread_id: ThreadId,
+    },
     Banlist,
+    Block {
+        login: String,
+    },
+    Unblock {
+        login: String,
+    },
     Stop,
     Broadcast {
+        audience: BroadcastAudience,
         text: String,
     },
+    ShowSettings,
+    Settings {
+        key: String,
+        value: String,
+    },
+    ShowPolicy,
+    Policy {
+        key: String,
+        value: String,
+    },
+    Language {
+        code: String,
+    },
+    Greeting {
+        text: Option<String>,
+    },
+    Dnd {
+        window: Option<DndWindow>,
+    },
+    Limit {
+        max_incoming: Option<usize>,
+    },
+    Status,
+    Export,
+    Forget {
+        confirmed: bool,
+    },
+    Whoami {
+        refresh: bool,
+        first_name: String,
+        last_name: Option<String>,
+    },
+    Stats,
+    About,
 }
 
-impl TryFrom<&Message> for Command {
-    type Error = anyhow::Error;
+////////////////////////////////////////////////////////////////////////////////
 
-    fn try_from(message: &Message) -> anyhow::Result<Self> {
-        let text = message
-            .text()
-            .context("non-text messages are not supported")?;
-        if let Some(reply_to) = message.reply_to_message() {
-            return Ok(Command::Reply {
-                message_id: message.id,
-                reply_message_id: reply_to.id,
-                text: text.to_string(),
-            });
-        }
+/// Who receives a `/broadcast`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BroadcastAudience {
+    All,
+    /// Users who have not stopped the bot.
+    Active,
+    Users(Vec<String>),
+}
 
-        let mut iter = text.trim_start().split(" ");
-        let head = iter.next().context("empty message")?;
-        let command = match head {
-            "/start" => Command::Start,
-            "/help" => Command::Help,
-            "/users" => Command::Users,
-            "/threads" => Command::Threads,
-            "/random" => {
-                let text = iter.collect::<Vec<_>>().join(" ");
-                ensure!(!text.is_empty(), "empty message");
-                Command::Random {
-                    message_id: message.id,
-                    text,
+impl BroadcastAudience {
+    /// Parses a selector like `all`, `active` or `users:@a,@b`. Returns `None`
+    /// if the word is not a selector at all.
+    fn parse(word: &str) -> anyhow::Result<Option<Self>> {
+        let audience = match word {
+            "all" => BroadcastAudience::All,
+            "active" => BroadcastAudience::Active,
+            _ => match word.strip_prefix("users:") {
+                Some(list) => {
+                    let mut logins = vec![];
+                    for login in list.split(',').map(normalize_login) {
+                        ensure!(
+                            !login.is_empty()
+                                && login.chars().all(|c| c.is_alphanumeric() || c == '_'),
+                            "invalid user list: {} (expected something like `users:@alice,@bob`)",
+                            list
+                        );
+                        if !logins.contains(&login) {
+                            logins.push(login);
+                        }
+                    }
+                    BroadcastAudience::Users(logins)
                 }
+                None => return Ok(None),
+            },
+        };
+        Ok(Some(audience))
+    }
+}
+
+impl fmt::Display for BroadcastAudience {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BroadcastAudience::All => write!(f, "all"),
+            BroadcastAudience::Active => write!(f, "active"),
+            BroadcastAudience::Users(logins) => {
+                let logins = logins
+                    .iter()
+                    .map(|login| format!("@{}", login))
+                    .collect::<Vec<_>>();
+                write!(f, "users:{}", logins.join(","))
             }
-            "/send" => {
-                let receiver = iter.next().context("no receiver specified")?.to_string();
-                let text = iter.collect::<Vec<_>>().join(" ");
-                ensure!(!text.is_empty(), "empty message");
-                Command::Send {
-                    message_id: message.id,
-                    thread_id: receiver,
-                    text,
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Deep-link payloads that `/start` understands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StartPayload {
+    /// `dm_<login>`: asks for the first message to `@login`, for links like
+    /// "message me anonymously".
+    DirectMessage { login: String },
+}
+
+impl StartPayload {
+    /// Returns `None` for payloads that are not known, they are ignored.
+    pub fn parse(payload: &str) -> Option<Self> {
+        let login = normalize_login(payload.strip_prefix("dm_")?);
+        if login.is_empty() || !login.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return None;
+        }
+        Some(StartPayload::DirectMessage { login })
+    }
+}
+
+impl fmt::Display for StartPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartPayload::DirectMessage { login } => write!(f, "dm_{}", login),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Tells whether the message comes from a private chat with the bot. Group
+/// chats and channels are not supported.
+pub fn is_private_chat(message: &Message) -> bool {
+    message.chat.is_private()
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Describes a command for `/help` and the Telegram command menu. A command
+/// may have several entries, one per form of its arguments.
+pub struct CommandInfo {
+    pub name: &'static str,
+    pub args: &'static str,
+    pub description: &'static str,
+    pub ru_args: &'static str,
+    pub ru_description: &'static str,
+    pub example: &'static str,
+    /// Common mistakes as pairs of English and Russian explanations.
+    pub errors: &'static [(&'static str, &'static str)],
+}
+
+pub const COMMANDS: &[CommandInfo] = &[
+    CommandInfo {
+        name: "send",
+        args: "[receiver] [message]",
+        description: "send a message. Receiver can either be a @username or a #thread. Separate several receivers with commas, e.g. `@alice,#thread`.",
+        ru_args: "[получатель] [сообщение]",
+        ru_description: "отправить сообщение. Получатель - это @username или #тред. Несколько получателей разделяются запятыми, например `@alice,#тред`.",
+        example: "/send @sergio_4min Hello!",
+        errors: &[
+            ("`unknown thread` - the thread has been closed or never existed; check `/threads`.", "`unknown thread` - тред закрыт или не существовал; проверьте `/threads`."),
+            ("`empty message` - the text after the receiver is missing.", "`empty message` - после получателя нет текста."),
+            ("`cannot send a message to self` - you have used your own username.", "`cannot send a message to self` - вы указали свой собственный логин."),
+        ],
+    },
+    CommandInfo {
+        name: "random",
+        args: "[message]",
+        description: "send a message to a random user.",
+        ru_args: "[сообщение]",
+        ru_description: "отправить сообщение случайному пользователю.",
+        example: "/random Hello!",
+        errors: &[
+            ("`empty message` - `/random` needs some text to send.", "`empty message` - `/random` нужен текст для отправки."),
+        ],
+    },
+    CommandInfo {
+        name: "random",
+        args: "#[tag] [message]",
+        description: "send a message to a random user who shares this interest.",
+        ru_args: "#[тег] [сообщение]",
+        ru_description: "отправить сообщение случайному пользователю с таким интересом.",
+        example: "/random #music Any good albums lately?",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "random",
+        args: "[topic] [message]",
+        description: "send a message to a random user whose current topic is this, e.g. `/random books Hello!`.",
+        ru_args: "[тема] [сообщение]",
+        ru_description: "отправить сообщение случайному пользователю с такой текущей темой, например `/random books Привет!`.",
+        example: "/random books Hello!",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "topic",
+        args: "[topic]",
+        description: "set your current topic for `/random`. Use `off` to clear it.",
+        ru_args: "[тема]",
+        ru_description: "указать вашу текущую тему для `/random`. `off` очищает её.",
+        example: "/topic books",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "skip",
+        args: "[thread] [message]",
+        description: "close a random thread and send a message to another random user.",
+        ru_args: "[тред] [сообщение]",
+        ru_description: "закрыть случайный тред и написать другому случайному пользователю.",
+        example: "/skip #brave_owl Hi there!",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "cancel",
+        args: "",
+        description: "leave the `/random` waiting queue.",
+        ru_args: "",
+        ru_description: "покинуть очередь ожидания `/random`.",
+        example: "/cancel",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "interests",
+        args: "[tags]",
+        description: "set up to five interests, e.g. `/interests music books`. Use `/interests clear` to reset.",
+        ru_args: "[теги]",
+        ru_description: "указать до пяти интересов, например `/interests music books`. `/interests clear` очищает список.",
+        example: "/interests music books",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "users",
+        args: "",
+        description: "list available users.",
+        ru_args: "",
+        ru_description: "список доступных пользователей.",
+        example: "/users",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "pick",
+        args: "",
+        description: "pick a user to write to from a list of buttons.",
+        ru_args: "",
+        ru_description: "выбрать собеседника из списка кнопок.",
+        example: "/pick",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "find",
+        args: "[query]",
+        description: "search users by name or username.",
+        ru_args: "[запрос]",
+        ru_description: "найти пользователей по имени или логину.",
+        example: "/find sergio",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "online",
+        args: "",
+        description: "list users active in the last 24 hours.",
+        ru_args: "",
+        ru_description: "список пользователей, активных за последние 24 часа.",
+        example: "/online",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "threads",
+        args: "",
+        description: "list active anonymous threads.",
+        ru_args: "",
+        ru_description: "список активных анонимных тредов.",
+        example: "/threads",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "threads",
+        args: "archived",
+        description: "list archived threads.",
+        ru_args: "archived",
+        ru_description: "список архивных тредов.",
+        example: "/threads archived",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "threads",
+        args: "[label]",
+        description: "list threads with this label.",
+        ru_args: "[метка]",
+        ru_description: "список тредов с этой меткой.",
+        example: "/threads work",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "label",
+        args: "[thread] [label]",
+        description: "add a label to a thread, e.g. `/label #thread work`.",
+        ru_args: "[тред] [метка]",
+        ru_description: "добавить треду метку, например `/label #тред work`.",
+        example: "/label #brave_owl work",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "unlabel",
+        args: "[thread] [label]",
+        description: "remove a label from a thread.",
+        ru_args: "[тред] [метка]",
+        ru_description: "убрать метку с треда.",
+        example: "/unlabel #brave_owl work",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "labels",
+        args: "",
+        description: "list all your labels.",
+        ru_args: "",
+        ru_description: "список всех ваших меток.",
+        example: "/labels",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "threadinfo",
+        args: "[thread]",
+        description: "show thread details.",
+        ru_args: "[тред]",
+        ru_description: "показать подробности о треде.",
+        example: "/threadinfo #brave_owl",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "note",
+        args: "[thread] [text]",
+        description: "attach a private note to a thread. Without text, shows the note; `clear` removes it.",
+        ru_args: "[тред] [текст]",
+        ru_description: "прикрепить к треду личную заметку. Без текста показывает заметку, `clear` удаляет её.",
+        example: "/note #brave_owl met at the conference",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "create",
+        args: "#[room]",
+        description: "create a group room and join it.",
+        ru_args: "#[комната]",
+        ru_description: "создать групповую комнату и войти в неё.",
+        example: "/create #books",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "join",
+        args: "#[room]",
+        description: "join a group room. Use `/send #[room] [message]` to write to everybody in it anonymously.",
+        ru_args: "#[комната]",
+        ru_description: "войти в групповую комнату. `/send #[комната] [сообщение]` анонимно пишет всем её участникам.",
+        example: "/join #books",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "leave",
+        args: "#[room]",
+        description: "leave a group room.",
+        ru_args: "#[комната]",
+        ru_description: "выйти из групповой комнаты.",
+        example: "/leave #books",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "poll",
+        args: "[thread] [question] | [option] | [option]",
+        description: "send a poll to a thread, with up to 10 options.",
+        ru_args: "[тред] [вопрос] | [вариант] | [вариант]",
+        ru_description: "отправить опрос в тред, до 10 вариантов.",
+        example: "/poll #brave_owl Pizza or sushi? | Pizza | Sushi",
+        errors: &[
+            ("`a poll needs from 2 to 10 options` - separate the question and the options with `|`.", "`a poll needs from 2 to 10 options` - разделяйте вопрос и варианты символом `|`."),
+        ],
+    },
+    CommandInfo {
+        name: "dice",
+        args: "[thread] [emoji]",
+        description: "roll an animated dice in a thread: 🎲 🎯 🏀 ⚽ 🎳 or 🎰. Also `/roll`.",
+        ru_args: "[тред] [эмодзи]",
+        ru_description: "бросить анимированный кубик в тред: 🎲 🎯 🏀 ⚽ 🎳 или 🎰. Также `/roll`.",
+        example: "/dice #brave_owl 🎯",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "schedule",
+        args: "[delay] [thread] [message]",
+        description: "send a message later, e.g. `/schedule 2h #thread Good morning!`.",
+        ru_args: "[задержка] [тред] [сообщение]",
+        ru_description: "отправить сообщение позже, например `/schedule 2h #тред Доброе утро!`.",
+        example: "/schedule 2h #brave_owl Good morning!",
+        errors: &[
+            ("`invalid delay` - the delay comes first and looks like `2h` or `30m`.", "`invalid delay` - задержка указывается первой, например `2h` или `30m`."),
+        ],
+    },
+    CommandInfo {
+        name: "scheduled",
+        args: "",
+        description: "list your scheduled messages.",
+        ru_args: "",
+        ru_description: "список запланированных сообщений.",
+        example: "/scheduled",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "unschedule",
+        args: "[number]",
+        description: "cancel a scheduled message.",
+        ru_args: "[номер]",
+        ru_description: "отменить запланированное сообщение.",
+        example: "/unschedule 1",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "remind",
+        args: "[delay] [text]",
+        description: "get a reminder from the bot, e.g. `/remind 45m reply to #thread`.",
+        ru_args: "[задержка] [текст]",
+        ru_description: "получить напоминание от бота, например `/remind 45m ответить в #тред`.",
+        example: "/remind 45m reply to #brave_owl",
+        errors: &[
+            ("`invalid delay` - the delay comes first and looks like `45m`.", "`invalid delay` - задержка указывается первой, например `45m`."),
+        ],
+    },
+    CommandInfo {
+        name: "reminders",
+        args: "",
+        description: "list your reminders.",
+        ru_args: "",
+        ru_description: "список ваших напоминаний.",
+        example: "/reminders",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "cancelreminder",
+        args: "[number]",
+        description: "cancel a reminder.",
+        ru_args: "[номер]",
+        ru_description: "отменить напоминание.",
+        example: "/cancelreminder 1",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "default",
+        args: "[thread]",
+        description: "send messages without a command to this thread. Use `off` to go back to the last used thread.",
+        ru_args: "[тред]",
+        ru_description: "отправлять сообщения без команды в этот тред. `off` возвращает последний использованный тред.",
+        example: "/default #brave_owl",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "edit",
+        args: "[thread] [message]",
+        description: "replace your last message in a thread.",
+        ru_args: "[тред] [сообщение]",
+        ru_description: "заменить ваше последнее сообщение в треде.",
+        example: "/edit #brave_owl Sorry, I meant tomorrow",
+        errors: &[
+            ("`there are no messages to edit` - you have not written to this thread yet.", "`there are no messages to edit` - вы ещё не писали в этот тред."),
+        ],
+    },
+    CommandInfo {
+        name: "delete",
+        args: "[thread]",
+        description: "delete your last message in a thread.",
+        ru_args: "[тред]",
+        ru_description: "удалить ваше последнее сообщение в треде.",
+        example: "/delete #brave_owl",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "close",
+        args: "[thread]",
+        description: "close a thread. Without a thread, closes your only one.",
+        ru_args: "[тред]",
+        ru_description: "закрыть тред. Без треда закрывает ваш единственный тред.",
+        example: "/close #brave_owl",
+        errors: &[
+            ("`thread does not exist` - the thread is already closed; check `/threads`.", "`thread does not exist` - тред уже закрыт; проверьте `/threads`."),
+        ],
+    },
+    CommandInfo {
+        name: "closeall",
+        args: "",
+        description: "close all your active threads.",
+        ru_args: "",
+        ru_description: "закрыть все ваши активные треды.",
+        example: "/closeall",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "undo",
+        args: "",
+        description: "undo the last `/close`, `/ban` or `/archive` within 5 minutes.",
+        ru_args: "",
+        ru_description: "отменить последний `/close`, `/ban` или `/archive` в течение 5 минут.",
+        example: "/undo",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "accept",
+        args: "[thread]",
+        description: "accept a thread request (see the `approve_threads` setting).",
+        ru_args: "[тред]",
+        ru_description: "принять запрос на тред (см. настройку `approve_threads`).",
+        example: "/accept #brave_owl",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "decline",
+        args: "[thread]",
+        description: "decline a thread request.",
+        ru_args: "[тред]",
+        ru_description: "отклонить запрос на тред.",
+        example: "/decline #brave_owl",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "pin",
+        args: "[thread]",
+        description: "pin a thread to the top of `/threads`.",
+        ru_args: "[тред]",
+        ru_description: "закрепить тред в начале списка `/threads`.",
+        example: "/pin #brave_owl",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "unpin",
+        args: "[thread]",
+        description: "unpin a thread.",
+        ru_args: "[тред]",
+        ru_description: "открепить тред.",
+        example: "/unpin #brave_owl",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "priority",
+        args: "[thread]",
+        description: "toggle priority: messages from a priority thread are delivered even during do-not-disturb.",
+        ru_args: "[тред]",
+        ru_description: "включить или выключить приоритет: сообщения из приоритетного треда доставляются даже в режиме «не беспокоить».",
+        example: "/priority #brave_owl",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "pause",
+        args: "[thread]",
+        description: "stop messages in a thread in both directions until you `/resume` it.",
+        ru_args: "[тред]",
+        ru_description: "остановить сообщения в треде в обе стороны, пока вы не сделаете `/resume`.",
+        example: "/pause #brave_owl",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "resume",
+        args: "[thread]",
+        description: "resume a paused thread.",
+        ru_args: "[тред]",
+        ru_description: "возобновить приостановленный тред.",
+        example: "/resume #brave_owl",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "expire",
+        args: "[thread] [duration]",
+        description: "close a thread automatically after a while, e.g. `/expire #thread 24h`. Use `off` to cancel.",
+        ru_args: "[тред] [время]",
+        ru_description: "автоматически закрыть тред через заданное время, например `/expire #тред 24h`. `off` отменяет.",
+        example: "/expire #brave_owl 24h",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "secret",
+        args: "[thread] [seconds]",
+        description: "delete your messages in a thread after a delay. Use `off` to disable.",
+        ru_args: "[тред] [секунды]",
+        ru_description: "удалять ваши сообщения в треде через заданное время. `off` отключает.",
+        example: "/secret #brave_owl 30",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "receipts",
+        args: "[thread] on|off",
+        description: "get a notice when the other side replies to your message. Works if both sides turn it on.",
+        ru_args: "[тред] on|off",
+        ru_description: "получать уведомление, когда собеседник отвечает на ваше сообщение. Работает, если включено у обеих сторон.",
+        example: "/receipts #brave_owl on",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "previews",
+        args: "[thread] on|off",
+        description: "show or hide link previews in messages from the thread. Overrides the `link_previews` setting.",
+        ru_args: "[тред] on|off",
+        ru_description: "показывать или скрывать превью ссылок в сообщениях из треда. Важнее настройки `link_previews`.",
+        example: "/previews #brave_owl off",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "slow",
+        args: "[thread] [seconds]",
+        description: "limit how often you can write to a thread. Use 0 to disable.",
+        ru_args: "[тред] [секунды]",
+        ru_description: "ограничить, как часто вы можете писать в тред. 0 отключает ограничение.",
+        example: "/slow #brave_owl 60",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "archive",
+        args: "[thread]",
+        description: "hide a thread without closing it.",
+        ru_args: "[тред]",
+        ru_description: "убрать тред в архив, не закрывая его.",
+        example: "/archive #brave_owl",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "unarchive",
+        args: "[thread]",
+        description: "restore an archived thread.",
+        ru_args: "[тред]",
+        ru_description: "вернуть тред из архива.",
+        example: "/unarchive #brave_owl",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "ban",
+        args: "[thread] [duration] [reason]",
+        description: "close a thread and ban the user that started it. The thread may be omitted if it is your only anonymous one. With a duration like `7d` the ban is lifted automatically. The reason is optional and only visible to you.",
+        ru_args: "[тред] [время] [причина]",
+        ru_description: "закрыть тред и забанить пользователя, который его начал. Тред можно не указывать, если он у вас единственный анонимный. Если указать время, например `7d`, бан снимется автоматически. Причина необязательна и видна только вам.",
+        example: "/ban #brave_owl 7d spam",
+        errors: &[
+            ("`cannot ban random or non-anonimous chat` - only threads started by anonymous users can be banned; use `/close` instead.", "`cannot ban random or non-anonimous chat` - забанить можно только тред, начатый анонимным пользователем; используйте `/close`."),
+            ("`expected a thread id, got: ...` - a reason needs the thread before it, starting with `#` or `@`.", "`expected a thread id, got: ...` - перед причиной нужно указать тред, начиная с `#` или `@`."),
+        ],
+    },
+    CommandInfo {
+        name: "baninfo",
+        args: "[thread]",
+        description: "show when and why a thread was banned.",
+        ru_args: "[тред]",
+        ru_description: "показать, когда и почему тред был забанен.",
+        example: "/baninfo #brave_owl",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "unban",
+        args: "[thread]",
+        description: "unban a user that started this thread.",
+        ru_args: "[тред]",
+        ru_description: "разбанить пользователя, который начал этот тред.",
+        example: "/unban #brave_owl",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "banlist",
+        args: "",
+        description: "show all the banned threads and blocked users.",
+        ru_args: "",
+        ru_description: "показать все забаненные треды и заблокированных пользователей.",
+        example: "/banlist",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "block",
+        args: "[@username]",
+        description: "block a user, even if they have not written to you yet.",
+        ru_args: "[@username]",
+        ru_description: "заблокировать пользователя, даже если он вам ещё не писал.",
+        example: "/block @spammer",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "unblock",
+        args: "[@username]",
+        description: "unblock a user.",
+        ru_args: "[@username]",
+        ru_description: "разблокировать пользователя.",
+        example: "/unblock @spammer",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "settings",
+        args: "",
+        description: "show your settings.",
+        ru_args: "",
+        ru_description: "показать ваши настройки.",
+        example: "/settings",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "settings",
+        args: "[key] [value]",
+        description: "change a setting, e.g. `/settings receive_random off`.",
+        ru_args: "[ключ] [значение]",
+        ru_description: "изменить настройку, например `/settings receive_random off`.",
+        example: "/settings receive_random off",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "language",
+        args: "[code]",
+        description: "change the bot language (`en` or `ru`).",
+        ru_args: "[код]",
+        ru_description: "сменить язык бота (`en` или `ru`).",
+        example: "/language ru",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "greeting",
+        args: "[message]",
+        description: "automatically reply with this message to new threads. Use `off` to disable.",
+        ru_args: "[сообщение]",
+        ru_description: "автоматически отвечать этим сообщением на новые треды. `off` отключает.",
+        example: "/greeting Hi! I will answer in the evening.",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "dnd",
+        args: "[from-to]",
+        description: "hold back incoming messages every day during this time (UTC), e.g. `/dnd 23:00-08:00`. Use `off` to disable.",
+        ru_args: "[с-до]",
+        ru_description: "каждый день придерживать входящие сообщения в это время (UTC), например `/dnd 23:00-08:00`. `off` отключает.",
+        example: "/dnd 23:00-08:00",
+        errors: &[
+            ("`expected time like 23:00` - use 24-hour UTC time like `23:00-08:00`.", "`expected time like 23:00` - укажите время UTC в 24-часовом формате, например `23:00-08:00`."),
+        ],
+    },
+    CommandInfo {
+        name: "limit",
+        args: "[number]",
+        description: "limit how many anonymous threads others can open to you. Use `off` to remove the limit.",
+        ru_args: "[число]",
+        ru_description: "ограничить число анонимных тредов, которые могут открыть вам другие. `off` снимает ограничение.",
+        example: "/limit 5",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "status",
+        args: "",
+        description: "show your limits, do-not-disturb and default thread.",
+        ru_args: "",
+        ru_description: "показать ваши ограничения, режим «не беспокоить» и тред по умолчанию.",
+        example: "/status",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "stats",
+        args: "",
+        description: "show your usage statistics.",
+        ru_args: "",
+        ru_description: "показать вашу статистику.",
+        example: "/stats",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "export",
+        args: "",
+        description: "download everything the bot stores about you.",
+        ru_args: "",
+        ru_description: "скачать всё, что бот хранит о вас.",
+        example: "/export",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "forget",
+        args: "",
+        description: "close all your threads and remove you from the bot.",
+        ru_args: "",
+        ru_description: "закрыть все ваши треды и удалить вас из бота.",
+        example: "/forget",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "whoami",
+        args: "",
+        description: "show the profile data the bot has stored about you.",
+        ru_args: "",
+        ru_description: "показать данные профиля, которые хранит бот.",
+        example: "/whoami",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "about",
+        args: "",
+        description: "show bot version and uptime.",
+        ru_args: "",
+        ru_description: "показать версию бота и время работы.",
+        example: "/about",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "stop",
+        args: "",
+        description: "stop this bot.",
+        ru_args: "",
+        ru_description: "остановить бота.",
+        example: "/stop",
+        errors: &[],
+    },
+    CommandInfo {
+        name: "help",
+        args: "[command]",
+        description: "show this message, or details about one command, e.g. `/help send`.",
+        ru_args: "[команда]",
+        ru_description: "показать это сообщение или подробности об одной команде, например `/help send`.",
+        example: "/help send",
+        errors: &[],
+    },
+];
+
+/// Short forms of frequent commands. An alias is only used when there is no
+/// command with the same name.
+pub const ALIASES: &[(&str, &str)] = &[
+    ("s", "send"),
+    ("r", "random"),
+    ("t", "threads"),
+    ("c", "close"),
+];
+
+/// Command verbs in other languages, mapped onto the English command names.
+pub const LOCALIZED_VERBS: &[(Language, &str, &str)] = &[
+    (Language::Ru, "старт", "start"),
+    (Language::Ru, "стоп", "stop"),
+    (Language::Ru, "помощь", "help"),
+    (Language::Ru, "отправить", "send"),
+    (Language::Ru, "случайный", "random"),
+    (Language::Ru, "пропустить", "skip"),
+    (Language::Ru, "отмена", "cancel"),
+    (Language::Ru, "пользователи", "users"),
+    (Language::Ru, "найти", "find"),
+    (Language::Ru, "онлайн", "online"),
+    (Language::Ru, "треды", "threads"),
+    (Language::Ru, "закрыть", "close"),
+    (Language::Ru, "закрытьвсе", "closeall"),
+    (Language::Ru, "отменить", "undo"),
+    (Language::Ru, "принять", "accept"),
+    (Language::Ru, "отклонить", "decline"),
+    (Language::Ru, "изменить", "edit"),
+    (Language::Ru, "удалить", "delete"),
+    (Language::Ru, "забанить", "ban"),
+    (Language::Ru, "разбанить", "unban"),
+    (Language::Ru, "банлист", "banlist"),
+    (Language::Ru, "заблокировать", "block"),
+    (Language::Ru, "разблокировать", "unblock"),
+    (Language::Ru, "архив", "archive"),
+    (Language::Ru, "разархивировать", "unarchive"),
+    (Language::Ru, "настройки", "settings"),
+    (Language::Ru, "язык", "language"),
+    (Language::Ru, "статус", "status"),
+    (Language::Ru, "статистика", "stats"),
+];
+
+fn localized_usage_error(language: Language, verb: &str, command: &str) -> anyhow::Error {
+    let args = COMMANDS
+        .iter()
+        .find(|info| info.name == command)
+        .map(|info| match language {
+            Language::En => info.args,
+            Language::Ru => info.ru_args,
+        })
+        .unwrap_or_default();
+    let usage = if args.is_empty() {
+        format!("`/{}`", verb)
+    } else {
+        format!("`/{} {}`", verb, args)
+    };
+    anyhow!(
+        "{} {}",
+        messages::get(language, Key::MalformedCommand),
+        usage
+    )
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// An error in the arguments of a known command. It is shown to the user along
+/// with the expected syntax instead of as a bare error.
+#[derive(Error, Debug)]
+#[error("{problem}.\nUsage: {}\nExample: `{example}`", usages.join(", "))]
+pub struct CommandParseError {
+    pub command: &'static str,
+    pub usages: Vec<String>,
+    pub example: &'static str,
+    pub problem: String,
+}
+
+impl CommandParseError {
+    /// Attaches the syntax of `name` to the error, if `name` is in `COMMANDS`.
+    fn wrap(name: &str, err: anyhow::Error) -> anyhow::Error {
+        let mut infos = COMMANDS.iter().filter(|info| info.name == name).peekable();
+        let (command, example) = match infos.peek() {
+            Some(info) => (info.name, info.example),
+            None => return err,
+        };
+        let usages = infos
+            .map(|info| {
+                if info.args.is_empty() {
+                    format!("`/{}`", info.name)
+                } else {
+                    format!("`/{} {}`", info.name, info.args)
                 }
+            })
+            .collect();
+        CommandParseError {
+            command,
+            usages,
+            example,
+            problem: capitalize(&format!("{:#}", err)),
+        }
+        .into()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Splits command arguments on any whitespace, but keeps the text that follows
+/// them intact, so that messages retain their newlines and indentation.
+#[derive(Clone)]
+struct Args<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Args<'a> {
+    fn new(text: &'a str) -> Self {
+        Self { rest: text }
+    }
+
+    /// Returns the remaining text verbatim. Only the spaces separating it from
+    /// the last argument, and a single line break after them, are dropped.
+    fn rest(self) -> String {
+        if self.rest.trim().is_empty() {
+            return String::new();
+        }
+        let rest = self.rest.trim_start_matches([' ', '\t']);
+        let rest = rest
+            .strip_prefix("\r\n")
+            .or_else(|| rest.strip_prefix('\n'))
+            .unwrap_or(rest);
+        rest.to_string()
+    }
+}
+
+impl<'a> Iterator for Args<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        let text = self.rest.trim_start();
+        if text.is_empty() {
+            self.rest = text;
+            return None;
+        }
+        let end = text.find(char::is_whitespace).unwrap_or(text.len());
+        let (arg, rest) = text.split_at(end);
+        self.rest = rest;
+        Some(arg)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+impl Command {
+    /// Returns the thread the command is applied to, if it takes one.
+    pub fn thread_id_mut(&mut self) -> Option<&mut ThreadId> {
+        match self {
+            Command::Label { thread_id, .. }
+            | Command::Unlabel { thread_id, .. }
+            | Command::Skip { thread_id, .. }
+            | Command::Send { thread_id, .. }
+            | Command::Schedule { thread_id, .. }
+            | Command::Poll { thread_id, .. }
+            | Command::Dice { thread_id, .. }
+            | Command::Edit { thread_id, .. }
+            | Command::Delete { thread_id }
+            | Command::Accept { thread_id }
+            | Command::Decline { thread_id }
+            | Command::BanInfo { thread_id }
+            | Command::Unban { thread_id }
+            | Command::Pin { thread_id }
+            | Command::Unpin { thread_id }
+            | Command::Priority { thread_id }
+            | Command::Pause { thread_id }
+            | Command::Resume { thread_id }
+            | Command::Expire { thread_id, .. }
+            | Command::ThreadInfo { thread_id }
+            | Command::Note { thread_id, .. }
+            | Command::Secret { thread_id, .. }
+            | Command::Receipts { thread_id, .. }
+            | Command::Previews { thread_id, .. }
+            | Command::Slow { thread_id, .. }
+            | Command::Archive { thread_id }
+            | Command::Unarchive { thread_id } => Some(thread_id),
+            Command::Default { thread_id }
+            | Command::Close { thread_id }
+            | Command::Ban { thread_id, .. }
+            | Command::SendMedia { thread_id, .. } => thread_id.as_mut(),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the command as typed by the user, without the slash.
+    /// Commands that do not come from a typed command yield `None`.
+    pub fn name(&self) -> Option<&'static str> {
+        let name = match self {
+            Command::Start { .. } => "start",
+            Command::Help { .. } => "help",
+            Command::Users => "users",
+            Command::Pick => "pick",
+            Command::Online => "online",
+            Command::Find { .. } => "find",
+            Command::Threads { .. } => "threads",
+            Command::Label { .. } => "label",
+            Command::Unlabel { .. } => "unlabel",
+            Command::Labels => "labels",
+            Command::Random { .. } => "random",
+            Command::Skip { .. } => "skip",
+            Command::Cancel => "cancel",
+            Command::Interests { .. } => "interests",
+            Command::Topic { .. } => "topic",
+            Command::Send { .. } => "send",
+            Command::SendMany { .. } => "send",
+            Command::Schedule { .. } => "schedule",
+            Command::Scheduled => "scheduled",
+            Command::Unschedule { .. } => "unschedule",
+            Command::Remind { .. } => "remind",
+            Command::Reminders => "reminders",
+            Command::CancelReminder { .. } => "cancelreminder",
+            Command::Create { .. } => "create",
+            Command::Join { .. } => "join",
+            Command::Leave { .. } => "leave",
+            Command::Poll { .. } => "poll",
+            Command::Dice { .. } => "dice",
+            Command::Default { .. } => "default",
+            Command::Edit { .. } => "edit",
+            Command::Delete { .. } => "delete",
+            Command::Close { .. } => "close",
+            Command::CloseAll => "closeall",
+            Command::Undo => "undo",
+            Command::Accept { .. } => "accept",
+            Command::Decline { .. } => "decline",
+            Command::Ban { .. } => "ban",
+            Command::BanInfo { .. } => "baninfo",
+            Command::Unban { .. } => "unban",
+            Command::Pin { .. } => "pin",
+            Command::Unpin { .. } => "unpin",
+            Command::Priority { .. } => "priority",
+            Command::Pause { .. } => "pause",
+            Command::Resume { .. } => "resume",
+            Command::Expire { .. } => "expire",
+            Command::ThreadInfo { .. } => "threadinfo",
+            Command::Note { .. } => "note",
+            Command::Secret { .. } => "secret",
+            Command::Receipts { .. } => "receipts",
+            Command::Previews { .. } => "previews",
+            Command::Slow { .. } => "slow",
+            Command::Archive { .. } => "archive",
+            Command::Unarchive { .. } => "unarchive",
+            Command::Banlist => "banlist",
+            Command::Block { .. } => "block",
+            Command::Unblock { .. } => "unblock",
+            Command::Stop => "stop",
+            Command::Broadcast { .. } => "broadcast",
+            Command::ShowSettings => "settings",
+            Command::Settings { .. } => "settings",
+            Command::ShowPolicy | Command::Policy { .. } => "policy",
+            Command::Language { .. } => "language",
+            Command::Greeting { .. } => "greeting",
+            Command::Dnd { .. } => "dnd",
+            Command::Limit { .. } => "limit",
+            Command::Status => "status",
+            Command::Export => "export",
+            Command::Forget { .. } => "forget",
+            Command::Whoami { .. } => "whoami",
+            Command::Stats => "stats",
+            Command::About => "about",
+            Command::PollAnswer { .. }
+            | Command::Reaction { .. }
+            | Command::StartThreadWith { .. }
+            | Command::Confirm { .. }
+            | Command::SendDefault { .. }
+            | Command::SendMedia { .. }
+            | Command::Reply { .. }
+            | Command::Edited { .. } => return None,
+        };
+        Some(name)
+    }
+
+    /// Returns the id of the message the command came with, if it keeps one.
+    fn message_id_mut(&mut self) -> Option<&mut i32> {
+        match self {
+            Command::Random { message_id, .. }
+            | Command::Skip { message_id, .. }
+            | Command::Send { message_id, .. }
+            | Command::SendMany { message_id, .. }
+            | Command::Schedule { message_id, .. }
+            | Command::SendDefault { message_id, .. }
+            | Command::SendMedia { message_id, .. }
+            | Command::Reply { message_id, .. } => Some(message_id),
+            _ => None,
+        }
+    }
+
+    /// Lists commands for the Telegram command menu.
+    pub fn bot_commands() -> Vec<BotCommand> {
+        let mut seen = HashSet::new();
+        COMMANDS
+            .iter()
+            .filter(|info| seen.insert(info.name))
+            .map(|info| BotCommand::new(info.name, info.description.replace('`', "")))
+            .collect()
+    }
+
+    /// Encodes the command as callback data of an inline keyboard button.
+    pub fn callback_data(&self) -> Option<String> {
+        match self {
+            Command::StartThreadWith { login } => {
+                Some(format!("{}{}", START_THREAD_CALLBACK_PREFIX, login))
             }
-            "/close" => {
-                let thread_id = iter.next().context("no thread id specified")?.to_string();
-                Command::Close { thread_id }
+            Command::Confirm { nonce, confirmed } => {
+                let prefix = if *confirmed {
+                    CONFIRM_CALLBACK_PREFIX
+                } else {
+                    CANCEL_CALLBACK_PREFIX
+                };
+                Some(format!("{}{}", prefix, nonce))
+            }
+            _ => None,
+        }
+    }
+
+    /// Decodes callback data produced by `callback_data`.
+    pub fn parse_callback_data(data: &str) -> anyhow::Result<Self> {
+        if let Some(login) = data
+            .strip_prefix(START_THREAD_CALLBACK_PREFIX)
+            .filter(|login| !login.is_empty())
+        {
+            return Ok(Command::StartThreadWith {
+                login: normalize_login(login),
+            });
+        }
+        let (nonce, confirmed) = match data.strip_prefix(CONFIRM_CALLBACK_PREFIX) {
+            Some(nonce) => (nonce, true),
+            None => (
+                data.strip_prefix(CANCEL_CALLBACK_PREFIX)
+                    .with_context(|| format!("unknown callback data: {}", data))?,
+                false,
+            ),
+        };
+        let nonce = nonce
+            .parse()
+            .with_context(|| format!("malformed callback data: {}", data))?;
+        Ok(Command::Confirm { nonce, confirmed })
+    }
+
+    /// Parses an edited message. Only edits of messages that are sent into a
+    /// thread yield a command.
+    pub fn parse_edited(message: &Message, bot_username: &str) -> Option<Self> {
+        let new_text = match Self::parse(message, bot_username).ok()?? {
+            Command::Send { text, .. }
+            | Command::SendDefault { text, .. }
+            | Command::Reply { text, .. } => text,
+            _ => return None,
+        };
+        Some(Command::Edited {
+            message_id: message.id,
+            new_text,
+        })
+    }
+
+    /// Parses a message sent to the bot with the given username. Commands
+    /// addressed to other bots, like `/help@other_bot`, yield `None`.
+    pub fn parse(message: &Message, bot_username: &str) -> anyhow::Result<Option<Self>> {
+        if let Some(photo) = message
+            .photo()
+            .and_then(|sizes| sizes.iter().max_by_key(|size| size.width * size.height))
+        {
+            let file_size = photo.file_size.map(u64::from);
+            let kind = MediaKind::Photo {
+                spoiler: false,
+                scrub: false,
+            };
+            return Self::parse_media(message, kind, photo.file_id.clone(), file_size).map(Some);
+        }
+        if let Some(sticker) = message.sticker() {
+            let file_size = sticker.file_size.map(u64::from);
+            return Self::parse_media(
+                message,
+                MediaKind::Sticker,
+                sticker.file_id.clone(),
+                file_size,
+            )
+            .map(Some);
+        }
+        if let Some(animation) = message.animation() {
+            let file_size = animation.file_size.map(u64::from);
+            return Self::parse_media(
+                message,
+                MediaKind::Animation,
+                animation.file_id.clone(),
+                file_size,
+            )
+            .map(Some);
+        }
+        if let Some(video) = message.video() {
+            let kind = MediaKind::Video {
+                duration: video.duration,
+                spoiler: false,
+            };
+            let file_size = video.file_size.map(u64::from);
+            return Self::parse_media(message, kind, video.file_id.clone(), file_size).map(Some);
+        }
+        if let Some(video_note) = message.video_note() {
+            let kind = MediaKind::VideoNote {
+                duration: video_note.duration,
+            };
+            let file_size = video_note.file_size.map(u64::from);
+            return Self::parse_media(message, kind, video_note.file_id.clone(), file_size)
+                .map(Some);
+        }
+        if let Some(audio) = message.audio() {
+            let kind = MediaKind::Audio {
+                duration: audio.duration,
+                mime_type: audio.mime_type.as_ref().map(|mime| mime.to_string()),
+            };
+            let file_size = audio.file_size.map(u64::from);
+            return Self::parse_media(message, kind, audio.file_id.clone(), file_size).map(Some);
+        }
+        if let Some(document) = message.document() {
+            let kind = MediaKind::Document {
+                file_name: document.file_name.clone(),
+                mime_type: document.mime_type.as_ref().map(|mime| mime.to_string()),
+            };
+            let file_size = document.file_size.map(u64::from);
+            return Self::parse_media(message, kind, document.file_id.clone(), file_size).map(Some);
+        }
+        if let Some(voice) = message.voice() {
+            let kind = MediaKind::Voice {
+                duration: voice.duration,
+            };
+            return Self::parse_media(message, kind, voice.file_id.clone(), voice.file_size)
+                .map(Some);
+        }
+        if let Some(location) = message.location() {
+            ensure!(
+                location.live_period.is_none(),
+                "live locations are not relayed; share a static location instead"
+            );
+            let kind = MediaKind::Location {
+                latitude: location.latitude,
+                longitude: location.longitude,
+                title: None,
+                address: None,
+            };
+            return Self::parse_media(message, kind, String::new(), None).map(Some);
+        }
+        if let Some(venue) = message.venue() {
+            let kind = MediaKind::Location {
+                latitude: venue.location.latitude,
+                longitude: venue.location.longitude,
+                title: Some(venue.title.clone()),
+                address: Some(venue.address.clone()),
+            };
+            return Self::parse_media(message, kind, String::new(), None).map(Some);
+        }
+        if let Some(contact) = message.contact() {
+            let kind = MediaKind::Contact {
+                phone_number: contact.phone_number.clone(),
+                first_name: contact.first_name.clone(),
+                last_name: contact.last_name.clone(),
+            };
+            return Self::parse_media(message, kind, String::new(), None).map(Some);
+        }
+        let text = message
+            .text()
+            .context("non-text messages are not supported")?;
+        // Forwarded text is never a command, it goes to the default thread.
+        if is_forwarded(message) {
+            return Ok(Some(Command::SendDefault {
+                message_id: message.id,
+                text: text.to_string(),
+                entities: slice_entities(
+                    message.entities().unwrap_or_default(),
+                    0,
+                    utf16_len(text),
+                ),
+                forwarded: true,
+            }));
+        }
+        if let Some(reply_to) = message.reply_to_message() {
+            return Ok(Some(Command::Reply {
+                message_id: message.id,
+                reply_message_id: reply_to.id,
+                reply_text: reply_to.text().map(|text| text.to_string()),
+                text: text.to_string(),
+                entities: slice_entities(
+                    message.entities().unwrap_or_default(),
+                    0,
+                    utf16_len(text),
+                ),
+            }));
+        }
+
+        let resolved;
+        let text = match message.entities() {
+            Some(entities) => {
+                resolved = resolve_entities(text, entities)?;
+                resolved.as_str()
+            }
+            None => text,
+        };
+        let mut command = match Self::parse_text(text, bot_username)? {
+            Some(command) => command,
+            None => return Ok(None),
+        };
+        if let Some(message_id) = command.message_id_mut() {
+            *message_id = message.id;
+        }
+        if let Some(entities) = message.entities() {
+            command.attach_entities(message.text().unwrap_or_default(), entities);
+        }
+        if let Command::Whoami {
+            first_name,
+            last_name,
+            ..
+        } = &mut command
+        {
+            let from = message.from().context("message has no sender")?;
+            *first_name = from.first_name.clone();
+            *last_name = from.last_name.clone();
+        }
+        Ok(Some(command))
+    }
+
+    /// Keeps the formatting of the message text that is relayed by the command.
+    /// The relayed text is the tail of the message, e.g. `/send @login [text]`;
+    /// if it is not (text mentions have been resolved), formatting is dropped.
+    fn attach_entities(&mut self, message_text: &str, message_entities: &[MessageEntity]) {
+        let (text, entities) = match self {
+            Command::Random { text, entities, .. }
+            | Command::Send { text, entities, .. }
+            | Command::SendDefault { text, entities, .. } => (text, entities),
+            _ => return,
+        };
+        if let Some(prefix) = message_text.strip_suffix(text.as_str()) {
+            *entities = slice_entities(message_entities, utf16_len(prefix), utf16_len(text));
+        }
+    }
+
+    /// Parses media: a photo, a sticker, an animation, a voice message, a video,
+    /// a video note, an audio file, a document, a contact or a location. It goes
+    /// to the thread of the replied message, to the receiver of a
+    /// `/send [receiver]` caption, or to the default thread. A `/spoiler`
+    /// caption, alone or next to `/send`, hides a photo or a video under a spoiler.
+    fn parse_media(
+        message: &Message,
+        mut kind: MediaKind,
+        file_id: String,
+        file_size: Option<u64>,
+    ) -> anyhow::Result<Self> {
+        let (thread_id, spoiler, caption) =
+            parse_media_caption(message.caption().unwrap_or_default())?;
+        if spoiler {
+            match &mut kind {
+                MediaKind::Photo { spoiler: flag, .. } | MediaKind::Video { spoiler: flag, .. } => {
+                    *flag = true
+                }
+                _ => bail!("only photos and videos can be sent under a spoiler"),
+            }
+            ensure!(
+                message.media_group_id().is_none(),
+                "albums cannot be sent under a spoiler"
+            );
+        }
+        Ok(Command::SendMedia {
+            message_id: message.id,
+            thread_id,
+            reply_message_id: message.reply_to_message().map(|reply_to| reply_to.id),
+            kind,
+            file_id,
+            file_size,
+            caption: if caption.is_empty() {
+                None
+            } else {
+                Some(caption)
+            },
+            media_group_id: message.media_group_id().map(|id| id.to_string()),
+            forwarded: is_forwarded(message),
+        })
+    }
+
+    /// Parses the text of a message. Message ids are left zero and the sender's
+    /// name in `/whoami` is left empty, see `parse` for the complete command.
+    fn parse_text(text: &str, bot_username: &str) -> anyhow::Result<Option<Self>> {
+        if !text.trim_start().starts_with('/') {
+            return Ok(Some(Command::SendDefault {
+                message_id: 0,
+                text: text.to_string(),
+                entities: vec![],
+                forwarded: false,
+            }));
+        }
+
+        let mut iter = Args::new(text);
+        let head = iter.next().context("empty message")?;
+        let head = match head.split_once('@') {
+            Some((head, username)) if username.eq_ignore_ascii_case(bot_username) => head,
+            Some(_) => return Ok(None),
+            None => head,
+        };
+        let name = head.trim_start_matches('/');
+        if !COMMANDS.iter().any(|info| info.name == name) {
+            if let Some(&(_, command)) = ALIASES.iter().find(|&&(alias, _)| alias == name) {
+                return Self::parse_args(&format!("/{}", command), iter)
+                    .map(Some)
+                    .map_err(|err| CommandParseError::wrap(command, err));
+            }
+        }
+        let lowercase_name = name.to_lowercase();
+        if let Some(&(language, verb, command)) = LOCALIZED_VERBS
+            .iter()
+            .find(|&&(_, verb, _)| verb == lowercase_name)
+        {
+            return Self::parse_args(&format!("/{}", command), iter)
+                .map(Some)
+                .map_err(|_| localized_usage_error(language, verb, command));
+        }
+        Self::parse_args(head, iter)
+            .map(Some)
+            .map_err(|err| CommandParseError::wrap(name, err))
+    }
+
+    fn parse_args(head: &str, mut iter: Args) -> anyhow::Result<Self> {
+        let command = match head {
+            "/start" => Command::Start {
+                payload: iter.next().map(str::to_string),
+            },
+            "/help" => match iter.next() {
+                None => Command::Help { topic: None },
+                Some(topic) => {
+                    let name = resolve_command_name(topic).with_context(|| {
+                        let mut topics = COMMANDS.iter().map(|info| info.name).collect::<Vec<_>>();
+                        topics.dedup();
+                        format!(
+                            "unknown help topic: {}; available topics: {}",
+                            topic,
+                            topics.join(", ")
+                        )
+                    })?;
+                    Command::Help { topic: Some(name) }
+                }
+            },
+            "/users" => Command::Users,
+            "/pick" => Command::Pick,
+            "/online" => Command::Online,
+            "/find" => {
+                let query = iter.rest();
+                ensure!(!query.is_empty(), "empty search query");
+                Command::Find { query }
+            }
+            "/threads" => match iter.next() {
+                None => Command::Threads {
+                    archived: false,
+                    label: None,
+                },
+                Some("archived") => Command::Threads {
+                    archived: true,
+                    label: None,
+                },
+                Some(label) => Command::Threads {
+                    archived: false,
+                    label: Some(normalize_tag(label)),
+                },
+            },
+            "/label" | "/unlabel" => {
+                let thread_id = normalize_thread_id(iter.next().context("no thread id specified")?);
+                let label = normalize_tag(iter.next().context("no label specified")?);
+                ensure!(!label.is_empty(), "empty label");
+                if head == "/label" {
+                    Command::Label { thread_id, label }
+                } else {
+                    Command::Unlabel { thread_id, label }
+                }
+            }
+            "/labels" => Command::Labels,
+            "/random" => {
+                let mut lookahead = iter.clone();
+                let tag = match lookahead.next() {
+                    Some(word) if word.starts_with('#') && lookahead.clone().next().is_some() => {
+                        iter = lookahead;
+                        Some(normalize_tag(word))
+                    }
+                    _ => None,
+                };
+                let text = iter.rest();
+                ensure!(!text.is_empty(), "empty message");
+                Command::Random {
+                    message_id: 0,
+                    tag,
+                    text,
+                    entities: vec![],
+                }
+            }
+            "/skip" => {
+                let thread_id = normalize_thread_id(iter.next().context("no thread id specified")?);
+                let text = iter.rest();
+                ensure!(!text.is_empty(), "empty message");
+                Command::Skip {
+                    thread_id,
+                    message_id: 0,
+                    text,
+                }
+            }
+            "/cancel" => Command::Cancel,
+            "/interests" => {
+                let words = iter.filter(|w| !w.is_empty()).collect::<Vec<_>>();
+                let tags = match words.as_slice() {
+                    [] => None,
+                    ["clear"] => Some(vec![]),
+                    _ => {
+                        let mut tags = words.into_iter().map(normalize_tag).collect::<Vec<_>>();
+                        tags.dedup();
+                        Some(tags)
+                    }
+                };
+                Command::Interests { tags }
+            }
+            "/topic" => {
+                let topic = match iter.next() {
+                    None => None,
+                    Some("off") => Some(String::new()),
+                    Some(topic) => Some(normalize_tag(topic)),
+                };
+                Command::Topic { topic }
+            }
+            "/send" => {
+                let mut receivers = vec![];
+                for receiver in iter.next().context("no receiver specified")?.split(',') {
+                    let receiver = parse_receiver(receiver)?;
+                    if !receivers.contains(&receiver) {
+                        receivers.push(receiver);
+                    }
+                }
+                ensure!(
+                    receivers.len() <= MAX_SEND_RECEIVERS,
+                    "too many receivers, the limit is {}",
+                    MAX_SEND_RECEIVERS
+                );
+                let text = iter.rest();
+                ensure!(!text.is_empty(), "empty message");
+                if receivers.len() == 1 {
+                    Command::Send {
+                        message_id: 0,
+                        thread_id: receivers.remove(0),
+                        text,
+                        entities: vec![],
+                    }
+                } else {
+                    Command::SendMany {
+                        message_id: 0,
+                        thread_ids: receivers,
+                        text,
+                    }
+                }
+            }
+            "/schedule" => {
+                let delay = humantime::parse_duration(iter.next().context("no delay specified")?)
+                    .context("invalid delay, expected something like `2h`")?;
+                let thread_id = normalize_thread_id(iter.next().context("no thread id specified")?);
+                let text = iter.rest();
+                ensure!(!text.is_empty(), "empty message");
+                Command::Schedule {
+                    delay,
+                    thread_id,
+                    message_id: 0,
+                    text,
+                }
+            }
+            "/scheduled" => Command::Scheduled,
+            "/unschedule" => {
+                let index = iter
+                    .next()
+                    .context("no number specified")?
+                    .parse()
+                    .context("expected the number of a scheduled message from `/scheduled`")?;
+                Command::Unschedule { index }
+            }
+            "/remind" => {
+                let delay = humantime::parse_duration(iter.next().context("no delay specified")?)
+                    .context("invalid delay, expected something like `45m`")?;
+                let text = iter.rest();
+                ensure!(!text.is_empty(), "empty reminder");
+                Command::Remind { delay, text }
+            }
+            "/reminders" => Command::Reminders,
+            "/cancelreminder" => {
+                let index = iter
+                    .next()
+                    .context("no number specified")?
+                    .parse()
+                    .context("expected the number of a reminder from `/reminders`")?;
+                Command::CancelReminder { index }
+            }
+            "/create" | "/join" | "/leave" => {
+                let room = parse_room_name(iter.next().context("no room specified")?)?;
+                match head {
+                    "/create" => Command::Create { room },
+                    "/join" => Command::Join { room },
+                    _ => Command::Leave { room },
+                }
+            }
+            "/poll" => {
+                let thread_id = normalize_thread_id(iter.next().context("no thread id specified")?);
+                let text = iter.rest();
+                let mut parts = text
+                    .split('|')
+                    .map(|part| part.trim().to_string())
+                    .collect::<Vec<_>>();
+                let question = parts.remove(0);
+                ensure!(!question.is_empty(), "empty poll question");
+                ensure!(
+                    (2..=MAX_POLL_OPTIONS).contains(&parts.len()),
+                    "a poll needs from 2 to {} options, e.g. `/poll #thread Tea or coffee? | Tea | Coffee`",
+                    MAX_POLL_OPTIONS
+                );
+                ensure!(
+                    parts.iter().all(|option| !option.is_empty()),
+                    "empty poll option"
+                );
+                Command::Poll {
+                    thread_id,
+                    question,
+                    options: parts,
+                }
+            }
+            "/dice" | "/roll" => {
+                let thread_id = normalize_thread_id(iter.next().context("no thread id specified")?);
+                let emoji = match iter.next() {
+                    None => DiceEmoji::Dice,
+                    Some(word) => DICE_EMOJIS
+                        .iter()
+                        .find(|&&(symbol, _)| symbol == word)
+                        .map(|&(_, emoji)| emoji)
+                        .with_context(|| {
+                            let symbols = DICE_EMOJIS
+                                .iter()
+                                .map(|&(symbol, _)| symbol)
+                                .collect::<Vec<_>>();
+                            format!(
+                                "unsupported dice: {} (available: {})",
+                                word,
+                                symbols.join(" ")
+                            )
+                        })?,
+                };
+                Command::Dice { thread_id, emoji }
+            }
+            "/default" => {
+                let thread_id = match iter.next().context("no thread id specified")? {
+                    "off" => None,
+                    thread_id => Some(normalize_thread_id(thread_id)),
+                };
+                Command::Default { thread_id }
+            }
+            "/edit" => {
+                let thread_id = normalize_thread_id(iter.next().context("no thread id specified")?);
+                let new_text = iter.rest();
+                ensure!(!new_text.is_empty(), "empty message");
+                Command::Edit {
+                    thread_id,
+                    new_text,
+                }
+            }
+            "/delete" => {
+                let thread_id = normalize_thread_id(iter.next().context("no thread id specified")?);
+                Command::Delete { thread_id }
+            }
+            "/close" => Command::Close {
+                thread_id: iter.next().map(normalize_thread_id),
+            },
+            "/closeall" => Command::CloseAll,
+            "/undo" => Command::Undo,
+            "/accept" => {
+                let thread_id = normalize_thread_id(iter.next().context("no thread id specified")?);
+                Command::Accept { thread_id }
+            }
+            "/decline" => {
+                let thread_id = normalize_thread_id(iter.next().context("no thread id specified")?);
+                Command::Decline { thread_id }
             }
             "/ban" => {
-                let thread_id = iter.next().context("no thread id specified")?.to_string();
-                Command::Ban { thread_id }
+                let mut lookahead = iter.clone();
+                let thread_id = match lookahead.next() {
+                    Some(token) if token.starts_with('#') || token.starts_with('@') => {
+                        iter = lookahead;
+                        Some(normalize_thread_id(token))
+                    }
+                    _ => None,
+                };
+                let mut lookahead = iter.clone();
+                let duration = lookahead
+                    .next()
+                    .and_then(|token| humantime::parse_duration(token).ok());
+                if duration.is_some() {
+                    iter = lookahead;
+                }
+                // A reason alone would ban the only thread, while the user
+                // most likely meant a thread and missed its `#`.
+                if let (None, None, Some(token)) = (&thread_id, duration, iter.clone().next()) {
+                    bail!("expected a thread id, got: {}", token);
+                }
+                let reason = iter.rest();
+                let reason = if reason.is_empty() {
+                    None
+                } else {
+                    Some(reason)
+                };
+                Command::Ban {
+                    thread_id,
+                    duration,
+                    reason,
+                }
+            }
+            "/baninfo" => {
+                let thread_id = normalize_thread_id(iter.next().context("no thread id specified")?);
+                Command::BanInfo { thread_id }
             }
             "/unban" => {
-                let thread_id = iter.next().context("no thread id specified")?.to_string();
+                let thread_id = normalize_thread_id(iter.next().context("no thread id specified")?);
                 Command::Unban { thread_id }
             }
+            "/pin" => {
+                let thread_id = normalize_thread_id(iter.next().context("no thread id specified")?);
+                Command::Pin { thread_id }
+            }
+            "/unpin" => {
+                let thread_id = normalize_thread_id(iter.next().context("no thread id specified")?);
+                Command::Unpin { thread_id }
+            }
+            "/priority" => {
+                let thread_id = normalize_thread_id(iter.next().context("no thread id specified")?);
+                Command::Priority { thread_id }
+            }
+            "/pause" => {
+                let thread_id = normalize_thread_id(iter.next().context("no thread id specified")?);
+                Command::Pause { thread_id }
+            }
+            "/resume" => {
+                let thread_id = normalize_thread_id(iter.next().context("no thread id specified")?);
+                Command::Resume { thread_id }
+            }
+            "/expire" => {
+                let thread_id = normalize_thread_id(iter.next().context("no thread id specified")?);
+                let duration = match iter.next().context("no duration specified")? {
+                    "off" => None,
+                    word => Some(
+                        humantime::parse_duration(word)
+                            .context("invalid duration, expected something like `24h`")?,
+                    ),
+                };
+                Command::Expire {
+                    thread_id,
+                    duration,
+                }
+            }
+            "/threadinfo" => {
+                let thread_id = normalize_thread_id(iter.next().context("no thread id specified")?);
+                Command::ThreadInfo { thread_id }
+            }
+            "/note" => {
+                let thread_id = normalize_thread_id(iter.next().context("no thread id specified")?);
+                let text = iter.rest();
+                let text = match text.as_str() {
+                    "" => None,
+                    "clear" => Some(String::new()),
+                    _ => Some(text),
+                };
+                Command::Note { thread_id, text }
+            }
+            "/secret" => {
+                let thread_id = normalize_thread_id(iter.next().context("no thread id specified")?);
+                let seconds = match iter.next().context("no delay specified")? {
+                    "off" => 0,
+                    word => word
+                        .parse()
+                        .context("delay must be a number of seconds or `off`")?,
+                };
+                Command::Secret { thread_id, seconds }
+            }
+            "/receipts" => {
+                let thread_id = normalize_thread_id(iter.next().context("no thread id specified")?);
+                let enabled = match iter.next().context("expected `on` or `off`")? {
+                    "on" => true,
+                    "off" => false,
+                    word => bail!("expected `on` or `off`, got: {}", word),
+                };
+                Command::Receipts { thread_id, enabled }
+            }
+            "/previews" => {
+                let thread_id = normalize_thread_id(iter.next().context("no thread id specified")?);
+                let enabled = match iter.next().context("expected `on` or `off`")? {
+                    "on" => true,
+                    "off" => false,
+                    word => bail!("expected `on` or `off`, got: {}", word),
+                };
+                Command::Previews { thread_id, enabled }
+            }
+            "/slow" => {
+                let thread_id = normalize_thread_id(iter.next().context("no thread id specified")?);
+                let seconds = iter
+                    .next()
+                    .context("no interval specified")?
+                    .parse()
+                    .context("interval must be a number of seconds")?;
+                Command::Slow { thread_id, seconds }
+            }
+            "/archive" => {
+                let thread_id = normalize_thread_id(iter.next().context("no thread id specified")?);
+                Command::Archive { thread_id }
+            }
+            "/unarchive" => {
+                let thread_id = normalize_thread_id(iter.next().context("no thread id specified")?);
+                Command::Unarchive { thread_id }
+            }
             "/banlist" => Command::Banlist,
+            "/block" => {
+                let login = iter.next().context("no user specified")?;
+                Command::Block {
+                    login: normalize_login(login),
+                }
+            }
+            "/unblock" => {
+                let login = iter.next().context("no user specified")?;
+                Command::Unblock {
+                    login: normalize_login(login),
+                }
+            }
             "/stop" => Command::Stop,
             "/broadcast" => {
-                let text = iter.collect::<Vec<_>>().join(" ");
+                let mut lookahead = iter.clone();
+                let audience = match lookahead.next().map(BroadcastAudience::parse) {
+                    Some(audience) => audience?,
+                    None => None,
+                };
+                let audience = match audience {
+                    Some(audience) => {
+                        iter = lookahead;
+                        audience
+                    }
+                    None => BroadcastAudience::All,
+                };
+                let text = iter.rest();
                 ensure!(!text.is_empty(), "empty message");
-                Command::Broadcast { text }
+                Command::Broadcast { audience, text }
+            }
+            "/settings" => match iter.next() {
+                None => Command::ShowSettings,
+                Some(key) => {
+                    let value = iter.rest();
+                    ensure!(!value.is_empty(), "no value specified");
+                    Command::Settings {
+                        key: key.to_string(),
+                        value,
+                    }
+                }
+            },
+            "/policy" => match iter.next() {
+                None => Command::ShowPolicy,
+                Some("set") => {
+                    let key = iter.next().context("no media category specified")?;
+                    let value = iter.next().context("no value specified")?;
+                    Command::Policy {
+                        key: key.to_string(),
+                        value: value.to_string(),
+                    }
+                }
+                Some(arg) => bail!("unknown argument: {}; expected `set`", arg),
+            },
+            "/language" => {
+                let code = iter
+                    .next()
+                    .context("no language code specified")?
+                    .to_string();
+                Command::Language { code }
+            }
+            "/greeting" => {
+                let text = iter.rest();
+                ensure!(!text.is_empty(), "empty greeting");
+                let text = if text == "off" { None } else { Some(text) };
+                Command::Greeting { text }
+            }
+            "/dnd" => {
+                let window = match iter.next().context("no time window specified")? {
+                    "off" => None,
+                    value => Some(DndWindow::parse(value)?),
+                };
+                Command::Dnd { window }
+            }
+            "/limit" => {
+                let max_incoming = match iter.next().context("no limit specified")? {
+                    "off" => None,
+                    value => Some(
+                        value
+                            .parse()
+                            .context("limit must be a number of threads or `off`")?,
+                    ),
+                };
+                Command::Limit { max_incoming }
+            }
+            "/status" => Command::Status,
+            "/export" => Command::Export,
+            "/forget" => match iter.next() {
+                None => Command::Forget { confirmed: false },
+                Some("confirm") => Command::Forget { confirmed: true },
+                Some(word) => bail!("expected `/forget confirm`, got: {}", word),
+            },
+            "/whoami" => {
+                let refresh = match iter.next() {
+                    None => false,
+                    Some("refresh") => true,
+                    Some(word) => bail!("expected `/whoami refresh`, got: {}", word),
+                };
+                Command::Whoami {
+                    refresh,
+                    first_name: String::new(),
+                    last_name: None,
+                }
+            }
+            "/stats" => Command::Stats,
+            "/about" => Command::About,
+            _ => {
+                let similar = similar_commands(head);
+                if similar.is_empty() {
+                    bail!("unknown command {}; use `/help` to see all commands", head);
+                }
+                bail!(
+                    "unknown command {} — did you mean {}?",
+                    head,
+                    similar.join(" or ")
+                );
             }
-            _ => bail!("unknown command: {}", head),
         };
         Ok(command)
     }
 }
+
+impl FromStr for Command {
+    type Err = anyhow::Error;
+
+    /// Parses the text of a command. Unlike `Command::parse`, message ids are
+    /// zero and `/whoami` has no sender name.
+    fn from_str(text: &str) -> anyhow::Result<Self> {
+        Self::parse_text(text, "")?.context("command is addressed to another bot")
+    }
+}
+
+impl fmt::Display for Command {
+    /// Formats the command as canonical text that parses back into it. Commands
+    /// that do not come from text, like poll answers and button presses, are
+    /// shown in angle brackets and do not parse back.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Start { payload: None } => write!(f, "/start"),
+            Command::Start {
+                payload: Some(payload),
+            } => write!(f, "/start {}", payload),
+            Command::Help { topic: None } => write!(f, "/help"),
+            Command::Help { topic: Some(topic) } => write!(f, "/help {}", topic),
+            Command::Users => write!(f, "/users"),
+            Command::Pick => write!(f, "/pick"),
+            Command::Online => write!(f, "/online"),
+            Command::Find { query } => write!(f, "/find {}", query),
+            Command::Threads { archived: true, .. } => write!(f, "/threads archived"),
+            Command::Threads {
+                label: Some(label), ..
+            } => write!(f, "/threads {}", label),
+            Command::Threads { .. } => write!(f, "/threads"),
+            Command::Label { thread_id, label } => write!(f, "/label {} {}", thread_id, label),
+            Command::Unlabel { thread_id, label } => {
+                write!(f, "/unlabel {} {}", thread_id, label)
+            }
+            Command::Labels => write!(f, "/labels"),
+            Command::Random {
+                tag: Some(tag),
+                text,
+                ..
+            } => write!(f, "/random #{} {}", tag, text),
+            Command::Random { text, .. } => write!(f, "/random {}", text),
+            Command::Skip {
+                thread_id, text, ..
+            } => write!(f, "/skip {} {}", thread_id, text),
+            Command::Cancel => write!(f, "/cancel"),
+            Command::Interests { tags: None } => write!(f, "/interests"),
+            Command::Interests { tags: Some(tags) } if tags.is_empty() => {
+                write!(f, "/interests clear")
+            }
+            Command::Interests { tags: Some(tags) } => write!(f, "/interests {}", tags.join(" ")),
+            Command::Topic { topic: None } => write!(f, "/topic"),
+            Command::Topic { topic: Some(topic) } if topic.is_empty() => write!(f, "/topic off"),
+            Command::Topic { topic: Some(topic) } => write!(f, "/topic {}", topic),
+            Command::Send {
+                thread_id, text, ..
+            } => write!(f, "/send {} {}", thread_id, text),
+            Command::SendMany {
+                thread_ids, text, ..
+            } => write!(f, "/send {} {}", thread_ids.join(","), text),
+            Command::Schedule {
+                delay,
+                thread_id,
+                text,
+                ..
+            } => write!(
+                f,
+                "/schedule {} {} {}",
+                format_duration_arg(*delay),
+                thread_id,
+                text
+            ),
+            Command::Scheduled => write!(f, "/scheduled"),
+            Command::Unschedule { index } => write!(f, "/unschedule {}", index),
+            Command::Remind { delay, text } => {
+                write!(f, "/remind {} {}", format_duration_arg(*delay), text)
+            }
+            Command::Reminders => write!(f, "/reminders"),
+            Command::CancelReminder { index } => write!(f, "/cancelreminder {}", index),
+            Command::Create { room } => write!(f, "/create {}", room),
+            Command::Join { room } => write!(f, "/join {}", room),
+            Command::Leave { room } => write!(f, "/leave {}", room),
+            Command::Poll {
+                thread_id,
+                question,
+                options,
+            } => write!(
+                f,
+                "/poll {} {} | {}",
+                thread_id,
+                question,
+                options.join(" | ")
+            ),
+            Command::Dice { thread_id, emoji } => {
+                let symbol = DICE_EMOJIS
+                    .iter()
+                    .find(|&&(_, known)| known == *emoji)
+                    .map_or("🎲", |&(symbol, _)| symbol);
+                write!(f, "/dice {} {}", thread_id, symbol)
+            }
+            Command::PollAnswer {
+                poll_id,
+                option_ids,
+            } => write!(f, "<poll answer {} {:?}>", poll_id, option_ids),
+            Command::Reaction { message_id, emoji } => write!(
+                f,
+                "<reaction {} {}>",
+                message_id,
+                emoji.as_deref().unwrap_or("none")
+            ),
+            Command::StartThreadWith { .. } | Command::Confirm { .. } => {
+                write!(f, "<button {}>", self.callback_data().unwrap_or_default())
+            }
+            Command::SendDefault { text, .. } => write!(f, "{}", text),
+            Command::SendMedia {
+                kind,
+                file_id,
+                caption,
+                ..
+            } => {
+                let kind = match kind {
+                    MediaKind::Photo { .. } => "photo",
+                    MediaKind::Sticker => "sticker",
+                    MediaKind::Animation => "animation",
+                    MediaKind::Voice { .. } => "voice",
+                    MediaKind::Video { .. } => "video",
+                    MediaKind::VideoNote { .. } => "video note",
+                    MediaKind::Audio { .. } => "audio",
+                    MediaKind::Contact { .. } => "contact",
+                    MediaKind::Location { .. } => "location",
+                    MediaKind::Document { .. } => "document",
+                };
+                write!(
+                    f,
+                    "<{} {}> {}",
+                    kind,
+                    file_id,
+                    caption.as_deref().unwrap_or_default()
+                )
+            }
+            Command::Default { thread_id: None } => write!(f, "/default off"),
+            Command::Default {
+                thread_id: Some(thread_id),
+            } => write!(f, "/default {}", thread_id),
+            Command::Reply {
+                reply_message_id,
+                text,
+                ..
+            } => write!(f, "<reply to {}> {}", reply_message_id, text),
+            Command::Edit {
+                thread_id,
+                new_text,
+            } => write!(f, "/edit {} {}", thread_id, new_text),
+            Command::Edited {
+                message_id,
+                new_text,
+            } => write!(f, "<edit of {}> {}", message_id, new_text),
+            Command::Delete { thread_id } => write!(f, "/delete {}", thread_id),
+            Command::Close { thread_id: None } => write!(f, "/close"),
+            Command::Close {
+                thread_id: Some(thread_id),
+            } => write!(f, "/close {}", thread_id),
+            Command::CloseAll => write!(f, "/closeall"),
+            Command::Undo => write!(f, "/undo"),
+            Command::Accept { thread_id } => write!(f, "/accept {}", thread_id),
+            Command::Decline { thread_id } => write!(f, "/decline {}", thread_id),
+            Command::Ban {
+                thread_id,
+                duration,
+                reason,
+            } => {
+                write!(f, "/ban")?;
+                if let Some(thread_id) = thread_id {
+                    write!(f, " {}", thread_id)?;
+                }
+                if let Some(duration) = duration {
+                    write!(f, " {}", format_duration_arg(*duration))?;
+                }
+                if let Some(reason) = reason {
+                    write!(f, " {}", reason)?;
+                }
+                Ok(())
+            }
+            Command::BanInfo { thread_id } => write!(f, "/baninfo {}", thread_id),
+            Command::Unban { thread_id } => write!(f, "/unban {}", thread_id),
+            Command::Pin { thread_id } => write!(f, "/pin {}", thread_id),
+            Command::Unpin { thread_id } => write!(f, "/unpin {}", thread_id),
+            Command::Priority { thread_id } => write!(f, "/priority {}", thread_id),
+            Command::Pause { thread_id } => write!(f, "/pause {}", thread_id),
+            Command::Resume { thread_id } => write!(f, "/resume {}", thread_id),
+            Command::Expire {
+                thread_id,
+                duration: None,
+            } => write!(f, "/expire {} off", thread_id),
+            Command::Expire {
+                thread_id,
+                duration: Some(duration),
+            } => write!(
+                f,
+                "/expire {} {}",
+                thread_id,
+                format_duration_arg(*duration)
+            ),
+            Command::ThreadInfo { thread_id } => write!(f, "/threadinfo {}", thread_id),
+            Command::Note {
+                thread_id,
+                text: None,
+            } => write!(f, "/note {}", thread_id),
+            Command::Note {
+                thread_id,
+                text: Some(text),
+            } if text.is_empty() => write!(f, "/note {} clear", thread_id),
+            Command::Note {
+                thread_id,
+                text: Some(text),
+            } => write!(f, "/note {} {}", thread_id, text),
+            Command::Secret {
+                thread_id,
+                seconds: 0,
+            } => write!(f, "/secret {} off", thread_id),
+            Command::Secret { thread_id, seconds } => {
+                write!(f, "/secret {} {}", thread_id, seconds)
+            }
+            Command::Receipts { thread_id, enabled } => {
+                write!(f, "/receipts {} {}", thread_id, format_flag(*enabled))
+            }
+            Command::Previews { thread_id, enabled } => {
+                write!(f, "/previews {} {}", thread_id, format_flag(*enabled))
+            }
+            Command::Slow { thread_id, seconds } => write!(f, "/slow {} {}", thread_id, seconds),
+            Command::Archive { thread_id } => write!(f, "/archive {}", thread_id),
+            Command::Unarchive { thread_id } => write!(f, "/unarchive {}", thread_id),
+            Command::Banlist => write!(f, "/banlist"),
+            Command::Block { login } => write!(f, "/block @{}", login),
+            Command::Unblock { login } => write!(f, "/unblock @{}", login),
+            Command::Stop => write!(f, "/stop"),
+            Command::Broadcast { audience, text } => {
+                write!(f, "/broadcast {} {}", audience, text)
+            }
+            Command::ShowSettings => write!(f, "/settings"),
+            Command::Settings { key, value } => write!(f, "/settings {} {}", key, value),
+            Command::ShowPolicy => write!(f, "/policy"),
+            Command::Policy { key, value } => write!(f, "/policy set {} {}", key, value),
+            Command::Language { code } => write!(f, "/language {}", code),
+            Command::Greeting { text: None } => write!(f, "/greeting off"),
+            Command::Greeting { text: Some(text) } => write!(f, "/greeting {}", text),
+            Command::Dnd { window: None } => write!(f, "/dnd off"),
+            Command::Dnd {
+                window: Some(window),
+            } => write!(f, "/dnd {}", window),
+            Command::Limit { max_incoming: None } => write!(f, "/limit off"),
+            Command::Limit {
+                max_incoming: Some(max_incoming),
+            } => write!(f, "/limit {}", max_incoming),
+            Command::Status => write!(f, "/status"),
+            Command::Export => write!(f, "/export"),
+            Command::Forget { confirmed: false } => write!(f, "/forget"),
+            Command::Forget { confirmed: true } => write!(f, "/forget confirm"),
+            Command::Whoami { refresh: false, .. } => write!(f, "/whoami"),
+            Command::Whoami { refresh: true, .. } => write!(f, "/whoami refresh"),
+            Command::Stats => write!(f, "/stats"),
+            Command::About => write!(f, "/about"),
+        }
+    }
+}
+
+/// Formats a duration as a single argument, e.g. `1h30m` rather than `1h 30m`.
+fn format_duration_arg(duration: Duration) -> String {
+    humantime::format_duration(duration)
+        .to_string()
+        .replace(' ', "")
+}
+
+/// Rewrites the text of a command using the entities Telegram has marked in
+/// it: mentions of users picked by name (text mentions) become `@login`, so
+/// that they work as receivers. Text that does not start with a bot command
+/// entity is returned as is.
+fn resolve_entities(text: &str, entities: &[MessageEntity]) -> anyhow::Result<String> {
+    let command_end = match entities.iter().find(|entity| entity.offset == 0) {
+        Some(MessageEntity {
+            kind: MessageEntityKind::BotCommand,
+            length,
+            ..
+        }) => utf16_to_byte_offset(text, *length).context("malformed bot command entity")?,
+        _ => return Ok(text.to_string()),
+    };
+    let first_arg_start =
+        command_end + (text[command_end..].len() - text[command_end..].trim_start().len());
+
+    let mut mentions = entities
+        .iter()
+        .filter_map(|entity| match &entity.kind {
+            MessageEntityKind::TextMention { user } => Some((entity, user)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    mentions.sort_by_key(|(entity, _)| entity.offset);
+
+    let mut resolved = String::with_capacity(text.len());
+    let mut position = 0;
+    for (entity, user) in mentions {
+        let start =
+            utf16_to_byte_offset(text, entity.offset).context("malformed mention entity")?;
+        let end = utf16_to_byte_offset(text, entity.offset + entity.length)
+            .context("malformed mention entity")?;
+        if start < position {
+            continue;
+        }
+        match &user.username {
+            Some(username) => {
+                resolved.push_str(&text[position..start]);
+                resolved.push('@');
+                resolved.push_str(&normalize_login(username));
+                position = end;
+            }
+            None if start == first_arg_start => bail!(
+                "{} has no username, so the bot cannot reach them",
+                &text[start..end]
+            ),
+            None => {}
+        }
+    }
+    resolved.push_str(&text[position..]);
+    Ok(resolved)
+}
+
+/// Splits a media caption into the `/send [receiver]` thread, the `/spoiler`
+/// flag and the caption itself. Both prefixes are optional and go in any order.
+fn parse_media_caption(caption: &str) -> anyhow::Result<(Option<ThreadId>, bool, String)> {
+    let mut iter = Args::new(caption);
+    let mut thread_id = None;
+    let mut spoiler = false;
+    loop {
+        let mut lookahead = iter.clone();
+        match lookahead.next() {
+            Some("/send") if thread_id.is_none() => {
+                let receiver = lookahead.next().context("no receiver specified")?;
+                thread_id = Some(
+                    parse_receiver(receiver).map_err(|err| CommandParseError::wrap("send", err))?,
+                );
+            }
+            Some("/spoiler") if !spoiler => spoiler = true,
+            _ => break,
+        }
+        iter = lookahead;
+    }
+    Ok((thread_id, spoiler, iter.rest()))
+}
+
+/// Tells if a message has been forwarded with its author shown, be it a user,
+/// a hidden user's name or a channel.
+fn is_forwarded(message: &Message) -> bool {
+    message.forward_from().is_some() || message.forward_from_chat().is_some()
+}
+
+/// Converts an offset in UTF-16 code units, which Telegram uses for entities,
+/// to a byte offset in the text.
+fn utf16_to_byte_offset(text: &str, offset: usize) -> Option<usize> {
+    let mut utf16_offset = 0;
+    for (byte_offset, c) in text.char_indices() {
+        if utf16_offset == offset {
+            return Some(byte_offset);
+        }
+        utf16_offset += c.len_utf16();
+    }
+    (utf16_offset == offset).then_some(text.len())
+}
+
+/// Parses the receiver of `/send`. `#` means a thread or a room, anything else
+/// is a user login with an optional `@`, possibly pasted as a `t.me` link.
+fn parse_receiver(receiver: &str) -> anyhow::Result<ThreadId> {
+    if receiver.starts_with('#') {
+        return Ok(receiver.to_string());
+    }
+    let link = receiver
+        .strip_prefix("https://")
+        .or_else(|| receiver.strip_prefix("http://"))
+        .unwrap_or(receiver);
+    let login = match link
+        .strip_prefix("t.me/")
+        .or_else(|| link.strip_prefix("telegram.me/"))
+    {
+        Some(path) => path
+            .split(['?', '#'])
+            .next()
+            .unwrap_or_default()
+            .trim_end_matches('/'),
+        None => receiver,
+    };
+    let login = normalize_login(login);
+    ensure!(
+        !login.is_empty() && login.chars().all(|c| c.is_alphanumeric() || c == '_'),
+        "invalid receiver: {} (expected @username, #thread or a t.me link)",
+        receiver
+    );
+    Ok(format!("@{}", login))
+}
+
+fn capitalize(text: &str) -> String {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Maps a command name, alias or localized verb, with or without the leading
+/// slash, to the name of the command in `COMMANDS`.
+fn resolve_command_name(name: &str) -> Option<&'static str> {
+    let name = name.trim_start_matches('/').to_lowercase();
+    COMMANDS
+        .iter()
+        .map(|info| info.name)
+        .find(|&command| command == name)
+        .or_else(|| {
+            ALIASES
+                .iter()
+                .find(|&&(alias, _)| alias == name)
+                .map(|&(_, command)| command)
+        })
+        .or_else(|| {
+            LOCALIZED_VERBS
+                .iter()
+                .find(|&&(_, verb, _)| verb == name)
+                .map(|&(_, _, command)| command)
+        })
+}
+
+/// Finds up to three commands that look like a mistyped `head`, closest first.
+/// Aliases and localized verbs are suggested as well.
+fn similar_commands(head: &str) -> Vec<String> {
+    let head = head.trim_start_matches('/').to_lowercase();
+    let len = head.chars().count();
+    // NB: short heads are compared strictly, so that garbage like `/x` does
+    // not match every alias and three-letter command.
+    let max_distance = match len {
+        0..=2 => 0,
+        3..=4 => 1,
+        _ => 2,
+    };
+
+    let names = COMMANDS
+        .iter()
+        .map(|info| info.name)
+        .chain(ALIASES.iter().map(|&(alias, _)| alias))
+        .chain(LOCALIZED_VERBS.iter().map(|&(_, verb, _)| verb));
+    let mut candidates = vec![];
+    for name in names {
+        let distance = if len >= 3 && name.starts_with(head.as_str()) {
+            0
+        } else {
+            edit_distance(&head, name)
+        };
+        if distance <= max_distance && !candidates.iter().any(|&(_, n)| n == name) {
+            candidates.push((distance, name));
+        }
+    }
+    candidates.sort_by_key(|&(distance, _)| distance);
+    candidates
+        .into_iter()
+        .take(3)
+        .map(|(_, name)| format!("`/{}`", name))
+        .collect()
+}
+
+/// Levenshtein distance that also counts a swap of adjacent letters as a
+/// single edit, since that is the most common typo.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a = a.chars().collect::<Vec<_>>();
+    let b = b.chars().collect::<Vec<_>>();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    d[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+fn parse_room_name(name: &str) -> anyhow::Result<String> {
+    let name = name.to_lowercase();
+    ensure!(
+        name.len() > 1
+            && name.starts_with('#')
+            && name[1..]
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '-'),
+        "invalid room name: {} (expected something like `#books`)",
+        name
+    );
+    Ok(name)
+}
+
+fn normalize_tag(tag: &str) -> String {
+    tag.trim_start_matches('#').to_lowercase()
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    const BOT: &str = "lovebot_bot";
+
+    /// A text message from alice, in a private chat unless `chat` says
+    /// otherwise. `extra` fields are merged into the message.
+    fn message(text: &str, extra: serde_json::Value) -> Message {
+        let mut message = json!({
+            "message_id": 7,
+            "date": 0,
+            "chat": {"id": 1, "type": "private", "username": "alice", "first_name": "Alice"},
+            "from": {"id": 1, "is_bot": false, "username": "alice", "first_name": "Alice"},
+            "text": text,
+        });
+        for (key, value) in extra.as_object().unwrap() {
+            message[key] = value.clone();
+        }
+        serde_json::from_value(message).unwrap()
+    }
+
+    fn parse(text: &str) -> Command {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn message_text_keeps_its_whitespace() {
+        let text = "  first line\n\tindented  with  double spaces\n\nlast line ";
+        assert_eq!(
+            parse(&format!("/send @bob\n{}", text)),
+            Command::Send {
+                thread_id: "@bob".into(),
+                message_id: 0,
+                text: text.into(),
+                entities: vec![],
+            }
+        );
+        assert_eq!(
+            parse(&format!("/random\n{}", text)),
+            Command::Random {
+                message_id: 0,
+                tag: None,
+                text: text.into(),
+                entities: vec![],
+            }
+        );
+        assert_eq!(
+            parse(&format!("/broadcast active \n{}", text)),
+            Command::Broadcast {
+                audience: BroadcastAudience::Active,
+                text: text.into(),
+            }
+        );
+    }
+
+    #[test]
+    fn one_line_break_after_the_arguments_is_dropped() {
+        let command = parse("/send   @bob\n\nhello\n  world");
+        assert!(matches!(command, Command::Send { text, .. } if text == "\nhello\n  world"));
+
+        let command = parse("/send @bob\r\nhello");
+        assert!(matches!(command, Command::Send { text, .. } if text == "hello"));
+    }
+
+    #[test]
+    fn replies_keep_the_whole_text() {
+        let text = "/send @bob  not a command\n\tbut a reply ";
+        let reply_to = json!({
+            "message_id": 5,
+            "date": 0,
+            "chat": {"id": 1, "type": "private", "username": "alice", "first_name": "Alice"},
+            "text": "hi",
+        });
+        let command = Command::parse(&message(text, json!({ "reply_to_message": reply_to })), BOT)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            command,
+            Command::Reply {
+                message_id: 7,
+                reply_message_id: 5,
+                reply_text: Some("hi".into()),
+                text: text.into(),
+                entities: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn own_bot_suffix_is_stripped() {
+        let group = json!({ "chat": {"id": -5, "type": "group", "title": "Friends"} });
+        for extra in [json!({}), group] {
+            let command = Command::parse(&message("/help@lovebot_bot", extra.clone()), BOT);
+            assert_eq!(command.unwrap(), Some(Command::Help { topic: None }));
+
+            let command = Command::parse(&message("/send@LoveBot_Bot @bob hi", extra), BOT);
+            assert_eq!(
+                command.unwrap(),
+                Some(Command::Send {
+                    thread_id: "@bob".into(),
+                    message_id: 7,
+                    text: "hi".into(),
+                    entities: vec![],
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn commands_for_other_bots_are_ignored() {
+        let group = json!({ "chat": {"id": -5, "type": "group", "title": "Friends"} });
+        for extra in [json!({}), group] {
+            let command = Command::parse(&message("/help@other_bot", extra.clone()), BOT);
+            assert_eq!(command.unwrap(), None);
+
+            // Even unknown commands, which would be an error for this bot.
+            let command = Command::parse(&message("/sedn@other_bot", extra), BOT);
+            assert_eq!(command.unwrap(), None);
+        }
+        assert!("/help@other_bot".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn near_misses_are_suggested() {
+        assert_eq!(similar_commands("/sedn")[0], "`/send`");
+        assert_eq!(similar_commands("/thraeds")[0], "`/threads`");
+        assert_eq!(similar_commands("/HLEP")[0], "`/help`");
+        assert!(similar_commands("/unbna").contains(&"`/unban`".to_string()));
+        assert!(similar_commands("/sched").contains(&"`/schedule`".to_string()));
+        assert!(similar_commands("/сатрт").contains(&"`/старт`".to_string()));
+        assert!(similar_commands("/se").len() <= 3);
+    }
+
+    #[test]
+    fn aliases_are_commands_not_suggestions() {
+        assert!(matches!(parse("/s @bob hi"), Command::Send { .. }));
+        assert!(matches!(parse("/r hi"), Command::Random { .. }));
+        assert_eq!(parse("/отправить @bob hi"), parse("/send @bob hi"));
+        assert_eq!(parse("/c"), Command::Close { thread_id: None });
+    }
+
+    #[test]
+    fn garbage_is_not_suggested_anything() {
+        for head in ["/x", "/zz", "/qwertyuiop", "/12345", "/"] {
+            assert_eq!(similar_commands(head), Vec::<String>::new(), "{}", head);
+        }
+        let err = "/qwertyuiop".parse::<Command>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown command /qwertyuiop; use `/help` to see all commands"
+        );
+    }
+
+    #[test]
+    fn unknown_command_error_names_suggestions() {
+        let err = "/sedn @bob hi".parse::<Command>().unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("unknown command /sedn — did you mean `/send`"),
+            "{}",
+            err
+        );
+        assert!(err.downcast_ref::<CommandParseError>().is_none());
+    }
+
+    #[test]
+    fn edit_distance_counts_swaps_as_one_edit() {
+        assert_eq!(edit_distance("send", "send"), 0);
+        assert_eq!(edit_distance("sedn", "send"), 1);
+        assert_eq!(edit_distance("snd", "send"), 1);
+        assert_eq!(edit_distance("sendd", "send"), 1);
+        assert_eq!(edit_distance("", "send"), 4);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn receivers_may_be_links() {
+        for receiver in [
+            "sergio_4min",
+            "@Sergio_4min",
+            "t.me/sergio_4min",
+            "t.me/sergio_4min/",
+            "https://t.me/Sergio_4min",
+            "http://t.me/sergio_4min/",
+            "https://telegram.me/sergio_4min",
+            "https://t.me/sergio_4min?start=dm_bob",
+            "https://t.me/sergio_4min/?utm_source=profile",
+            "t.me/sergio_4min#about",
+        ] {
+            assert_eq!(
+                parse_receiver(receiver).unwrap(),
+                "@sergio_4min",
+                "{}",
+                receiver
+            );
+        }
+        let command = parse("/send https://t.me/sergio_4min?start=x hi");
+        assert!(matches!(command, Command::Send { thread_id, .. } if thread_id == "@sergio_4min"));
+    }
+
+    #[test]
+    fn thread_receivers_are_kept_as_is() {
+        assert_eq!(parse_receiver("#Quick_Fox").unwrap(), "#Quick_Fox");
+        assert_eq!(parse_receiver("#books").unwrap(), "#books");
+        let command = parse("/send #x,@bob,t.me/bob hi");
+        assert!(matches!(
+            command,
+            Command::SendMany { thread_ids, .. } if thread_ids == ["#x", "@bob"]
+        ));
+    }
+
+    #[test]
+    fn invalid_receivers_are_rejected() {
+        for receiver in [
+            "t.me/",
+            "https://t.me/?start=x",
+            "@",
+            "bob!",
+            "https://example.com/bob",
+        ] {
+            let err = parse_receiver(receiver).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "invalid receiver: {} (expected @username, #thread or a t.me link)",
+                    receiver
+                )
+            );
+        }
+        let err = "/send t.me/ hi".parse::<Command>().unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CommandParseError>().unwrap().command,
+            "send"
+        );
+    }
+
+    #[test]
+    fn ban_needs_a_thread_before_a_reason() {
+        let ban =
+            |thread_id: Option<&str>, duration: Option<u64>, reason: Option<&str>| Command::Ban {
+                thread_id: thread_id.map(|id| id.into()),
+                duration: duration.map(Duration::from_secs),
+                reason: reason.map(|reason| reason.into()),
+            };
+        assert_eq!(parse("/ban"), ban(None, None, None));
+        assert_eq!(parse("/ban 1h spam"), ban(None, Some(3600), Some("spam")));
+        assert_eq!(
+            parse("/ban #brave_lion spam"),
+            ban(Some("#brave_lion"), None, Some("spam"))
+        );
+        for text in ["/ban brave_lion", "/ban brave_lion spam"] {
+            let err = text.parse::<Command>().unwrap_err();
+            assert!(
+                err.to_string()
+                    .starts_with("Expected a thread id, got: brave_lion.\n"),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn only_private_chats_are_supported() {
+        assert!(is_private_chat(&message("/start", json!({}))));
+        for chat in [
+            json!({"id": -5, "type": "group", "title": "Friends"}),
+            json!({"id": -1005, "type": "supergroup", "title": "Friends", "username": "friends"}),
+            json!({"id": -1006, "type": "channel", "title": "News", "username": "news"}),
+        ] {
+            let message = message("/start", json!({ "chat": chat }));
+            assert!(!is_private_chat(&message), "{:?}", message.chat.kind);
+        }
+    }
+
+    /// Commands that come from text, one or more of each variant.
+    fn text_commands() -> Vec<Command> {
+        let thread_id = || "#brave_lion".to_string();
+        vec![
+            Command::Start { payload: None },
+            Command::Start {
+                payload: Some("dm_alice".into()),
+            },
+            Command::Help { topic: None },
+            Command::Help {
+                topic: Some("send"),
+            },
+            Command::Users,
+            Command::Pick,
+            Command::Online,
+            Command::Find {
+                query: "chess  club".into(),
+            },
+            Command::Threads {
+                archived: false,
+                label: None,
+            },
+            Command::Threads {
+                archived: true,
+                label: None,
+            },
+            Command::Threads {
+                archived: false,
+                label: Some("work".into()),
+            },
+            Command::Label {
+                thread_id: thread_id(),
+                label: "work".into(),
+            },
+            Command::Unlabel {
+                thread_id: thread_id(),
+                label: "work".into(),
+            },
+            Command::Labels,
+            Command::Random {
+                message_id: 0,
+                tag: None,
+                text: "hi\nthere".into(),
+                entities: vec![],
+            },
+            Command::Random {
+                message_id: 0,
+                tag: Some("chess".into()),
+                text: "e4?".into(),
+                entities: vec![],
+            },
+            Command::Skip {
+                thread_id: thread_id(),
+                message_id: 0,
+                text: "not my type".into(),
+            },
+            Command::Cancel,
+            Command::Interests { tags: None },
+            Command::Interests { tags: Some(vec![]) },
+            Command::Interests {
+                tags: Some(vec!["chess".into(), "tea".into()]),
+            },
+            Command::Topic { topic: None },
+            Command::Topic {
+                topic: Some(String::new()),
+            },
+            Command::Topic {
+                topic: Some("books".into()),
+            },
+            Command::Send {
+                thread_id: "@bob".into(),
+                message_id: 0,
+                text: "hello,\n  bob".into(),
+                entities: vec![],
+            },
+            Command::SendMany {
+                thread_ids: vec!["@bob".into(), thread_id()],
+                message_id: 0,
+                text: "hello all".into(),
+            },
+            Command::Schedule {
+                delay: Duration::from_secs(5400),
+                thread_id: thread_id(),
+                message_id: 0,
+                text: "good morning".into(),
+            },
+            Command::Scheduled,
+            Command::Unschedule { index: 2 },
+            Command::Remind {
+                delay: Duration::from_secs(2700),
+                text: "call bob".into(),
+            },
+            Command::Reminders,
+            Command::CancelReminder { index: 1 },
+            Command::Create {
+                room: "#books".into(),
+            },
+            Command::Join {
+                room: "#books".into(),
+            },
+            Command::Leave {
+                room: "#books".into(),
+            },
+            Command::Poll {
+                thread_id: thread_id(),
+                question: "Tea or coffee?".into(),
+                options: vec!["Tea".into(), "Coffee".into()],
+            },
+            Command::Dice {
+                thread_id: thread_id(),
+                emoji: DiceEmoji::Darts,
+            },
+            Command::SendDefault {
+                message_id: 0,
+                text: "just text".into(),
+                entities: vec![],
+                forwarded: false,
+            },
+            Command::Default { thread_id: None },
+            Command::Default {
+                thread_id: Some(thread_id()),
+            },
+            Command::Edit {
+                thread_id: thread_id(),
+                new_text: "fixed".into(),
+            },
+            Command::Delete {
+                thread_id: thread_id(),
+            },
+            Command::Close { thread_id: None },
+            Command::Close {
+                thread_id: Some(thread_id()),
+            },
+            Command::CloseAll,
+            Command::Undo,
+            Command::Accept {
+                thread_id: thread_id(),
+            },
+            Command::Decline {
+                thread_id: thread_id(),
+            },
+            Command::Ban {
+                thread_id: None,
+                duration: None,
+                reason: None,
+            },
+            Command::Ban {
+                thread_id: Some(thread_id()),
+                duration: Some(Duration::from_secs(86400)),
+                reason: Some("rude".into()),
+            },
+            Command::BanInfo {
+                thread_id: thread_id(),
+            },
+            Command::Unban {
+                thread_id: thread_id(),
+            },
+            Command::Pin {
+                thread_id: thread_id(),
+            },
+            Command::Unpin {
+                thread_id: thread_id(),
+            },
+            Command::Priority {
+                thread_id: thread_id(),
+            },
+            Command::Pause {
+                thread_id: thread_id(),
+            },
+            Command::Resume {
+                thread_id: thread_id(),
+            },
+            Command::Expire {
+                thread_id: thread_id(),
+                duration: None,
+            },
+            Command::Expire {
+                thread_id: thread_id(),
+                duration: Some(Duration::from_secs(86400)),
+            },
+            Command::ThreadInfo {
+                thread_id: thread_id(),
+            },
+            Command::Note {
+                thread_id: thread_id(),
+                text: None,
+            },
+            Command::Note {
+                thread_id: thread_id(),
+                text: Some(String::new()),
+            },
+            Command::Note {
+                thread_id: thread_id(),
+                text: Some("likes tea".into()),
+            },
+            Command::Secret {
+                thread_id: thread_id(),
+                seconds: 0,
+            },
+            Command::Secret {
+                thread_id: thread_id(),
+                seconds: 30,
+            },
+            Command::Receipts {
+                thread_id: thread_id(),
+                enabled: true,
+            },
+            Command::Previews {
+                thread_id: thread_id(),
+                enabled: false,
+            },
+            Command::Slow {
+                thread_id: thread_id(),
+                seconds: 60,
+            },
+            Command::Archive {
+                thread_id: thread_id(),
+            },
+            Command::Unarchive {
+                thread_id: thread_id(),
+            },
+            Command::Banlist,
+            Command::Block {
+                login: "bob".into(),
+            },
+            Command::Unblock {
+                login: "bob".into(),
+            },
+            Command::Stop,
+            Command::Broadcast {
+                audience: BroadcastAudience::All,
+                text: "maintenance at noon".into(),
+            },
+            Command::Broadcast {
+                audience: BroadcastAudience::Users(vec!["alice".into(), "bob".into()]),
+                text: "hi".into(),
+            },
+            Command::ShowSettings,
+            Command::Settings {
+                key: "sound".into(),
+                value: "off".into(),
+            },
+            Command::ShowPolicy,
+            Command::Policy {
+                key: "photo".into(),
+                value: "block".into(),
+            },
+            Command::Language { code: "ru".into() },
+            Command::Greeting { text: None },
+            Command::Greeting {
+                text: Some("hi, I'm Alice".into()),
+            },
+            Command::Dnd { window: None },
+            Command::Dnd {
+                window: Some(DndWindow::parse("23:00-08:00").unwrap()),
+            },
+            Command::Limit { max_incoming: None },
+            Command::Limit {
+                max_incoming: Some(5),
+            },
+            Command::Status,
+            Command::Export,
+            Command::Forget { confirmed: false },
+            Command::Forget { confirmed: true },
+            Command::Whoami {
+                refresh: false,
+                first_name: String::new(),
+                last_name: None,
+            },
+            Command::Whoami {
+                refresh: true,
+                first_name: String::new(),
+                last_name: None,
+            },
+            Command::Stats,
+            Command::About,
+        ]
+    }
+
+    #[test]
+    fn text_commands_round_trip() {
+        for command in text_commands() {
+            let text = command.to_string();
+            assert_eq!(text.parse::<Command>().unwrap(), command, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn commands_parse_to_canonical_text() {
+        for (text, canonical) in [
+            ("/s @Bob hi", "/send @bob hi"),
+            ("/send t.me/bob,#x hi", "/send @bob,#x hi"),
+            ("/random #Chess e4", "/random #chess e4"),
+            ("/expire #x 1day", "/expire #x 1day"),
+            ("/schedule 90m #x hi", "/schedule 1h30m #x hi"),
+            ("/broadcast hi", "/broadcast all hi"),
+            ("/broadcast users:bob,@Bob hi", "/broadcast users:@bob hi"),
+            ("/dice #x", "/dice #x 🎲"),
+            ("/block Bob", "/block @bob"),
+        ] {
+            assert_eq!(parse(text).to_string(), canonical);
+        }
+    }
+
+    #[test]
+    fn other_commands_do_not_parse_back() {
+        for command in [
+            Command::PollAnswer {
+                poll_id: "42".into(),
+                option_ids: vec![1],
+            },
+            Command::Reaction {
+                message_id: 7,
+                emoji: Some("❤".into()),
+            },
+            Command::StartThreadWith {
+                login: "bob".into(),
+            },
+            Command::Confirm {
+                nonce: 3,
+                confirmed: true,
+            },
+            Command::Reply {
+                message_id: 7,
+                reply_message_id: 5,
+                reply_text: None,
+                text: "hi".into(),
+                entities: vec![],
+            },
+            Command::Edited {
+                message_id: 7,
+                new_text: "hi".into(),
+            },
+        ] {
+            let text = command.to_string();
+            assert!(text.starts_with('<'), "{}", text);
+            assert!(matches!(
+                text.parse::<Command>().unwrap(),
+                Command::SendDefault { .. }
+            ));
+        }
+    }
+
+    #[test]
+    fn broadcast_audience_selectors() {
+        assert_eq!(
+            BroadcastAudience::parse("all").unwrap(),
+            Some(BroadcastAudience::All)
+        );
+        assert_eq!(
+            BroadcastAudience::parse("active").unwrap(),
+            Some(BroadcastAudience::Active)
+        );
+        assert_eq!(
+            BroadcastAudience::parse("users:@Alice,bob,@alice").unwrap(),
+            Some(BroadcastAudience::Users(vec!["alice".into(), "bob".into()]))
+        );
+        assert_eq!(BroadcastAudience::parse("hello").unwrap(), None);
+        assert_eq!(BroadcastAudience::parse("All").unwrap(), None);
+    }
+
+    #[test]
+    fn invalid_user_lists_are_rejected() {
+        for list in ["", "@alice,", ",bob", "@", "alice;bob", "@al ice"] {
+            let err = BroadcastAudience::parse(&format!("users:{}", list)).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "invalid user list: {} (expected something like `users:@alice,@bob`)",
+                    list
+                )
+            );
+        }
+        assert!("/broadcast users:@alice, hi".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn broadcast_without_a_selector_goes_to_all() {
+        assert_eq!(
+            parse("/broadcast hello everyone"),
+            Command::Broadcast {
+                audience: BroadcastAudience::All,
+                text: "hello everyone".into(),
+            }
+        );
+        assert_eq!(
+            parse("/broadcast users:@bob active users"),
+            Command::Broadcast {
+                audience: BroadcastAudience::Users(vec!["bob".into()]),
+                text: "active users".into(),
+            }
+        );
+        let err = "/broadcast active".parse::<Command>().unwrap_err();
+        assert_eq!(err.to_string(), "empty message");
+    }
+
+    /// A photo with the caption, and with `extra` fields merged in.
+    fn photo(caption: &str, extra: serde_json::Value) -> Message {
+        let mut fields = json!({
+            "text": null,
+            "photo": [
+                {"file_id": "small", "file_unique_id": "s", "width": 90, "height": 90},
+                {"file_id": "large", "file_unique_id": "l", "width": 800, "height": 800},
+            ],
+            "caption": caption,
+        });
+        for (key, value) in extra.as_object().unwrap() {
+            fields[key] = value.clone();
+        }
+        message("", fields)
+    }
+
+    #[test]
+    fn spoiler_caption_prefix_hides_media() {
+        for caption in [
+            "/spoiler look",
+            "/send @bob /spoiler look",
+            "/spoiler /send @bob look",
+        ] {
+            let command = Command::parse(&photo(caption, json!({})), BOT)
+                .unwrap()
+                .unwrap();
+            let Command::SendMedia {
+                kind,
+                file_id,
+                caption,
+                ..
+            } = command
+            else {
+                panic!("expected media: {:?}", command);
+            };
+            assert_eq!(
+                kind,
+                MediaKind::Photo {
+                    spoiler: true,
+                    scrub: false
+                }
+            );
+            assert_eq!(file_id, "large");
+            assert_eq!(caption.as_deref(), Some("look"));
+        }
+        let (thread_id, spoiler, caption) = parse_media_caption("/send #x /spoiler").unwrap();
+        assert_eq!(
+            (thread_id.as_deref(), spoiler, caption.as_str()),
+            (Some("#x"), true, "")
+        );
+    }
+
+    #[test]
+    fn media_without_the_prefix_is_not_hidden() {
+        for caption in ["", "look /spoiler", "/send @bob look"] {
+            let command = Command::parse(&photo(caption, json!({})), BOT)
+                .unwrap()
+                .unwrap();
+            assert!(matches!(
+                command,
+                Command::SendMedia {
+                    kind: MediaKind::Photo { spoiler: false, .. },
+                    ..
+                }
+            ));
+        }
+    }
+
+    #[test]
+    fn only_single_photos_and_videos_can_be_hidden() {
+        let animation = message(
+            "",
+            json!({
+                "text": null,
+                "animation": {
+                    "file_id": "gif", "file_unique_id": "g", "width": 320, "height": 240,
+                    "duration": 3, "mime_type": "video/mp4",
+                },
+                "caption": "/spoiler",
+            }),
+        );
+        let err = Command::parse(&animation, BOT).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "only photos and videos can be sent under a spoiler"
+        );
+
+        let album = photo("/spoiler", json!({"media_group_id": "album"}));
+        let err = Command::parse(&album, BOT).unwrap_err();
+        assert_eq!(err.to_string(), "albums cannot be sent under a spoiler");
+    }
+
+    #[test]
+    fn animations_are_relayed_as_media() {
+        let animation = json!({
+            "file_id": "gif", "file_unique_id": "g", "width": 320, "height": 240,
+            "duration": 3, "mime_type": "video/mp4", "file_size": 1000,
+        });
+        let reply_to = json!({
+            "message_id": 5,
+            "date": 0,
+            "chat": {"id": 1, "type": "private", "username": "alice", "first_name": "Alice"},
+            "text": "hi",
+        });
+        let message = message(
+            "",
+            json!({
+                "text": null,
+                "animation": animation,
+                "caption": "/send @Bob  so\nfunny",
+                "reply_to_message": reply_to,
+            }),
+        );
+        assert_eq!(
+            Command::parse(&message, BOT).unwrap(),
+            Some(Command::SendMedia {
+                message_id: 7,
+                thread_id: Some("@bob".into()),
+                reply_message_id: Some(5),
+                kind: MediaKind::Animation,
+                file_id: "gif".into(),
+                file_size: Some(1000),
+                caption: Some("so\nfunny".into()),
+                media_group_id: None,
+                forwarded: false,
+            })
+        );
+    }
+
+    #[test]
+    fn relayed_entities_are_relative_to_the_text() {
+        // "/send @bob " is 11 code units, the emoji before "bold" are 2 each.
+        let message = message(
+            "/send @bob 🙂🙂 bold link",
+            json!({
+                "entities": [
+                    {"type": "bot_command", "offset": 0, "length": 5},
+                    {"type": "mention", "offset": 6, "length": 4},
+                    {"type": "bold", "offset": 16, "length": 4},
+                    {"type": "text_link", "offset": 21, "length": 4, "url": "https://example.com/"},
+                ],
+            }),
+        );
+        let command = Command::parse(&message, BOT).unwrap().unwrap();
+        let Command::Send { text, entities, .. } = command else {
+            panic!("expected /send: {:?}", command);
+        };
+        assert_eq!(text, "🙂🙂 bold link");
+        assert_eq!(entities.len(), 2);
+        assert_eq!((entities[0].offset, entities[0].length), (5, 4));
+        assert_eq!(entities[0].kind, MessageEntityKind::Bold);
+        assert_eq!((entities[1].offset, entities[1].length), (10, 4));
+    }
+
+    #[test]
+    fn direct_message_payloads() {
+        for payload in ["dm_alice", "dm_Alice", "dm_@alice"] {
+            assert_eq!(
+                StartPayload::parse(payload),
+                Some(StartPayload::DirectMessage {
+                    login: "alice".into()
+                }),
+                "{}",
+                payload
+            );
+        }
+        let payload = StartPayload::DirectMessage {
+            login: "sergio_4min".into(),
+        };
+        assert_eq!(StartPayload::parse(&payload.to_string()), Some(payload));
+    }
+
+    #[test]
+    fn unknown_payloads_are_ignored() {
+        for payload in ["", "dm_", "dm_a-b", "dm_a b", "ref_42", "DM_alice", "alice"] {
+            assert_eq!(StartPayload::parse(payload), None, "{:?}", payload);
+        }
+        // The command itself keeps any payload, `/start` falls back to the
+        // plain greeting for unknown ones.
+        assert_eq!(
+            parse("/start ref_42"),
+            Command::Start {
+                payload: Some("ref_42".into())
+            }
+        );
+        assert_eq!(
+            Command::parse(&message("/start dm_bob", json!({})), BOT).unwrap(),
+            Some(Command::Start {
+                payload: Some("dm_bob".into())
+            })
+        );
+    }
+
+    #[test]
+    fn empty_text_is_an_error() {
+        for text in ["/send @bob", "/send @bob \n ", "/random"] {
+            let err = text.parse::<Command>().unwrap_err();
+            let err = err.downcast_ref::<CommandParseError>().unwrap();
+            assert_eq!(err.problem, "Empty message", "{:?}", text);
+        }
+        // Admin commands are not in `COMMANDS` and have no usage to show.
+        let err = "/broadcast all  ".parse::<Command>().unwrap_err();
+        assert_eq!(err.to_string(), "empty message");
+    }
+}
@@ -1,20 +1,32 @@
 use crate::{
-    data::User,
-    event_log::{Event, EventLogReader, EventTracker, UserConnectedEvent},
-    handler::{Action, ActionRequest, CommandRequest, Handler, HandlerBuilder},
-    util::Writer,
+    data::{MediaLimits, MediaPolicy, User, UserSettings},
+    event_codec::{LogFormat, LogHeader},
+    event_log::{
+        write_snapshot, Event, EventLogReader, EventTracker, LogRecord, LoggedEvent,
+        UserConnectedEvent, UserForgottenEvent, UserProfileUpdatedEvent,
+    },
+    event_store::{EventStore, Segment, Storage},
+    handler::{Action, ActionRequest, CommandRequest, HandlerBuilder, HandlerSnapshot},
+    util::{now_timestamp, Writer},
     Command, EventServiceHandle,
 };
 
-use anyhow::{Context, Result};
-use log::info;
+use anyhow::{anyhow, ensure, Context, Result};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
 use teloxide::{adaptors::AutoSend, Bot};
 use tokio::sync::{mpsc, oneshot};
 
 use std::{
-    collections::HashMap,
-    io::BufRead,
-    sync::{atomic::AtomicBool, Arc, Mutex},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs,
+    io::{BufRead, BufWriter},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
 };
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -22,104 +34,798 @@ use std::{
 #[derive(Clone)]
 pub struct UserHandle {
     pub user: Arc<User>,
+    pub chat_id: i64,
+    pub connected_at: Option<i64>,
     pub channel: mpsc::Sender<ActionRequest>,
     pub is_stopped: Arc<AtomicBool>,
+    pub settings: Arc<RwLock<UserSettings>>,
+    pub last_activity: Arc<AtomicI64>,
+    pub interests: Arc<RwLock<Vec<String>>>,
+    pub topic: Arc<RwLock<Option<String>>>,
 }
 
 impl UserHandle {
+    pub fn is_listed(&self) -> bool {
+        !self.is_stopped.load(Ordering::Relaxed)
+            && self
+                .settings
+                .read()
+                .expect("UserHandle settings.read() failed")
+                .show_in_users_list
+    }
+
+    pub fn is_active_within(&self, seconds: i64) -> bool {
+        now_timestamp() - self.last_activity.load(Ordering::Relaxed) <= seconds
+    }
+
+    pub fn display_line(&self) -> String {
+        match self.user.last_name.as_ref() {
+            Some(last_name) => format!(
+                "{} {} @{}",
+                self.user.first_name, last_name, self.user.login
+            ),
+            None => format!("{} @{}", self.user.first_name, self.user.login),
+        }
+    }
+
     pub async fn send_action(&self, action: Action) -> Result<()> {
         let (result_sender, result_receiver) = oneshot::channel();
         let action_request = ActionRequest {
             action,
             result_sender,
         };
+        // The handler of a forgotten user shuts down while threads of others
+        // may still point to it.
         self.channel
             .send(action_request)
             .await
-            .unwrap_or_else(|err| panic!("failed to send action request: {}", err));
+            .map_err(|_| anyhow!("the other user has left"))?;
         result_receiver
             .await
-            .unwrap_or_else(|err| panic!("failed to get action result: {}", err))
+            .map_err(|_| anyhow!("the other user has left"))?
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct QueuedRandomRequest {
+    pub login: String,
+    pub text: String,
+    pub expires_at: i64,
+}
+
+pub struct RandomQueue {
+    entries: Vec<QueuedRandomRequest>,
+    timeout: Duration,
+}
+
+impl RandomQueue {
+    fn new() -> Self {
+        Self {
+            entries: vec![],
+            timeout: Duration::from_secs(60 * 60),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn push(&mut self, request: QueuedRandomRequest) {
+        self.remove(&request.login);
+        self.entries.push(request);
+    }
+
+    pub fn remove(&mut self, login: &str) -> Option<QueuedRandomRequest> {
+        let index = self.entries.iter().position(|r| r.login == login)?;
+        Some(self.entries.remove(index))
+    }
+
+    pub fn get(&self, login: &str) -> Option<&QueuedRandomRequest> {
+        self.entries.iter().find(|r| r.login == login)
+    }
+
+    pub fn pop_match(&mut self, is_eligible: impl Fn(&str) -> bool) -> Option<QueuedRandomRequest> {
+        let now = now_timestamp();
+        let index = self
+            .entries
+            .iter()
+            .position(|r| r.expires_at > now && is_eligible(&r.login))?;
+        Some(self.entries.remove(index))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// A group room. Members talk to each other under stable per-member aliases.
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct Room {
+    members: BTreeMap<String, u32>,
+    last_alias: u32,
+}
+
+impl Room {
+    pub fn alias(&self, login: &str) -> Option<String> {
+        self.members
+            .get(login)
+            .map(|number| format!("participant-{}", number))
+    }
+
+    pub fn members(&self) -> impl Iterator<Item = &String> {
+        self.members.keys()
+    }
+
+    pub fn is_member(&self, login: &str) -> bool {
+        self.members.contains_key(login)
+    }
+
+    pub fn new_alias(&self) -> u32 {
+        self.last_alias + 1
+    }
+}
+
+#[derive(Default)]
+pub struct Rooms {
+    rooms: HashMap<String, Room>,
+}
+
+impl Rooms {
+    pub fn get(&self, name: &str) -> Option<&Room> {
+        self.rooms.get(name)
+    }
+
+    pub fn create(&mut self, name: String) {
+        self.rooms.entry(name).or_default();
+    }
+
+    pub fn join(&mut self, name: &str, login: String, alias: u32) {
+        if let Some(room) = self.rooms.get_mut(name) {
+            room.members.insert(login, alias);
+            room.last_alias = room.last_alias.max(alias);
+        }
+    }
+
+    /// Removes a member and drops the room once nobody is left in it.
+    pub fn leave(&mut self, name: &str, login: &str) {
+        if let Some(room) = self.rooms.get_mut(name) {
+            room.members.remove(login);
+            if room.members.is_empty() {
+                self.rooms.remove(name);
+            }
+        }
+    }
+
+    pub fn rooms_of(&self, login: &str) -> Vec<String> {
+        let mut names = self
+            .rooms
+            .iter()
+            .filter(|(_, room)| room.members.contains_key(login))
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
     }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
+/// The state restored from the event log, stored as the first record of a new
+/// log so that the events before it need not be replayed. Time-based state,
+/// like slow mode and activity, starts anew.
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    users: Vec<UserSnapshot>,
+    forgotten: BTreeSet<String>,
+    random_queue: Vec<QueuedRandomRequest>,
+    rooms: BTreeMap<String, Room>,
+    /// Only set if the admin has changed the policy with `/policy set`.
+    media_policy: Option<MediaPolicy>,
+}
+
+impl Snapshot {
+    pub fn user_count(&self) -> usize {
+        self.users.len()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct UserSnapshot {
+    user: User,
+    chat_id: i64,
+    connected_at: Option<i64>,
+    is_stopped: bool,
+    settings: UserSettings,
+    interests: Vec<String>,
+    topic: Option<String>,
+    handler: HandlerSnapshot,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
 pub struct CommandDispatcherBuilder {
     command_channels: HashMap<String, mpsc::Sender<CommandRequest>>,
     user_handles: Writer<HashMap<String, UserHandle>>,
     builders: HashMap<String, HandlerBuilder>,
+    random_queue: Arc<Mutex<RandomQueue>>,
+    rooms: Arc<Mutex<Rooms>>,
+    storage: Option<Storage>,
+    media_limits: MediaLimits,
+    media_policy: Arc<RwLock<MediaPolicy>>,
+    media_policy_changed: bool,
+    forgotten: HashSet<String>,
+    torn_tail: Option<u64>,
+    event_log_header: LogHeader,
+    replayed_records: usize,
 }
 
-impl CommandDispatcherBuilder {
-    pub fn from_event_log<R: BufRead>(reader: R) -> Result<Self> {
-        let mut builder = Self {
+/// An empty state to replay records into, see `replay_record`.
+impl Default for CommandDispatcherBuilder {
+    fn default() -> Self {
+        Self {
             command_channels: Default::default(),
             user_handles: Default::default(),
             builders: Default::default(),
-        };
+            random_queue: Arc::new(Mutex::new(RandomQueue::new())),
+            rooms: Default::default(),
+            storage: None,
+            media_limits: MediaLimits::default(),
+            media_policy: Default::default(),
+            media_policy_changed: false,
+            forgotten: Default::default(),
+            torn_tail: None,
+            event_log_header: LogHeader::legacy(),
+            replayed_records: 0,
+        }
+    }
+}
+
+impl CommandDispatcherBuilder {
+    /// Restores the state from the event log. With `skip_corrupt`, lines that
+    /// fail their checksum or do not parse are skipped instead of failing.
+    pub fn from_event_log<R: BufRead>(reader: R, skip_corrupt: bool) -> Result<Self> {
+        Self::from_rotated_event_log(&[], reader, skip_corrupt)
+    }
+
+    /// Restores the state from the closed segments of a rotated event log,
+    /// oldest first, and then from the log itself, see `from_event_log`.
+    pub fn from_rotated_event_log<R: BufRead>(
+        segments: &[Segment],
+        reader: R,
+        skip_corrupt: bool,
+    ) -> Result<Self> {
+        let mut builder = Self::default();
+        let mut skipped = 0;
+        for segment in segments {
+            let mut reader = EventLogReader::new(segment.open()?)?.skip_corrupt(skip_corrupt);
+            builder
+                .replay(reader.iter_records())
+                .with_context(|| format!("failed to replay {}", segment.path().display()))?;
+            ensure!(
+                reader.torn_tail().is_none(),
+                "event log segment {} has been cut short",
+                segment.path().display()
+            );
+            skipped += reader.skipped();
+        }
+        let mut reader = EventLogReader::new(reader)?.skip_corrupt(skip_corrupt);
+        builder.replay(reader.iter_records())?;
+        skipped += reader.skipped();
+        if skipped > 0 {
+            warn!("Skipped {} corrupt lines of event log", skipped);
+        }
+        builder.torn_tail = reader.torn_tail();
+        builder.event_log_header = reader.header();
+        Ok(builder)
+    }
+
+    /// Restores the state from an event store other than the event log.
+    pub fn from_event_store(store: &mut dyn EventStore) -> Result<Self> {
+        let mut builder = Self::default();
+        builder.replay(store.iter())?;
+        Ok(builder)
+    }
 
-        let mut reader = EventLogReader::new(reader);
+    fn replay(&mut self, records: impl Iterator<Item = Result<LogRecord>>) -> Result<()> {
         let mut count = 0;
-        for mb_event in reader.iter_events() {
-            let event = mb_event.context("failed to read event")?;
-            match event {
-                Event::UserConnected(ev) => builder.handle_user_connected(ev),
-                Event::ThreadStarted(ev) => builder
-                    .builders
-                    .get_mut(&ev.login)
-                    .with_context(|| format!("user not found: @{}", ev.login))?
-                    .handle_thread_started(ev)?,
-                Event::ThreadMessageReceived(ev) => {
-                    builder
-                        .builders
-                        .get_mut(&ev.login)
-                        .with_context(|| format!("user not found: @{}", ev.login))?
-                        .handle_thread_message_received(ev);
-                }
-                Event::ThreadTerminated(ev) => {
-                    builder
-                        .builders
-                        .get_mut(&ev.login)
-                        .with_context(|| format!("user not found: @{}", ev.login))?
-                        .terminate_thread(&ev.my_thread_id)?;
-                    builder
-                        .builders
-                        .get_mut(&ev.other_login)
-                        .with_context(|| format!("user not found: @{}", ev.other_login))?
-                        .terminate_thread(&ev.other_thread_id)?;
-                }
-                Event::UserBanned(ev) => builder
-                    .builders
+        for mb_record in records {
+            let record = mb_record.context("failed to read event")?;
+            if matches!(record, LogRecord::Event(_)) {
+                count += 1;
+            }
+            self.replay_record(record)?;
+        }
+
+        info!("Read {} events from event log", count);
+        Ok(())
+    }
+
+    /// Applies the next record of the log to the state, the same way a whole
+    /// log is replayed on start.
+    pub fn replay_record(&mut self, record: LogRecord) -> Result<()> {
+        let is_first = self.replayed_records == 0;
+        self.replayed_records += 1;
+        let LoggedEvent { ts, event } = match record {
+            LogRecord::Event(logged) => logged,
+            LogRecord::Snapshot(snapshot) => {
+                ensure!(
+                    is_first,
+                    "snapshot is not the first record of the event log"
+                );
+                return self.restore_snapshot(*snapshot);
+            }
+        };
+        if self.forgotten.contains(event.login())
+            && !matches!(
+                event,
+                Event::UserConnected(_) | Event::MediaPolicyChanged(_)
+            )
+        {
+            debug!("skipping event of forgotten user @{}", event.login());
+            return Ok(());
+        }
+        match event {
+            Event::UserConnected(ev) => self.handle_user_connected(ev, ts),
+            Event::UserForgotten(ev) => self.handle_user_forgotten(ev),
+            Event::UserProfileUpdated(ev) => self.handle_user_profile_updated(ev)?,
+            Event::ThreadStarted(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_thread_started(ev, ts)?,
+            Event::ThreadSlowModeChanged(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_thread_slow_mode_changed(ev)?,
+            Event::ThreadExpiryChanged(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_thread_expiry_changed(ev)?,
+            Event::ThreadLabeled(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_thread_labeled(ev)?,
+            Event::ThreadUnlabeled(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_thread_unlabeled(ev)?,
+            Event::ThreadReceiptsChanged(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_thread_receipts_changed(ev)?,
+            Event::ThreadPreviewsChanged(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_thread_previews_changed(ev)?,
+            Event::ThreadPriorityChanged(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_thread_priority_changed(ev)?,
+            Event::ReceiptSent(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_receipt_sent(ev),
+            Event::PollReceived(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_poll_received(ev),
+            Event::ReminderSet(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_reminder_set(ev),
+            Event::ReminderRemoved(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_reminder_removed(ev),
+            Event::MessageScheduled(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_message_scheduled(ev),
+            Event::ScheduledMessageRemoved(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_scheduled_message_removed(ev),
+            Event::ThreadNoteChanged(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_thread_note_changed(ev)?,
+            Event::ThreadSecretChanged(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_thread_secret_changed(ev)?,
+            Event::IncomingLimitChanged(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_incoming_limit_changed(ev),
+            Event::DndChanged(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_dnd_changed(ev),
+            Event::GreetingChanged(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_greeting_changed(ev),
+            Event::DefaultThreadChanged(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_default_thread_changed(ev),
+            Event::ThreadRequested(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_thread_requested(ev)?,
+            Event::ThreadRequestMessage(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_thread_request_message(ev)?,
+            Event::ThreadMessageReceived(ev) => {
+                self.builders
                     .get_mut(&ev.login)
                     .with_context(|| format!("user not found: @{}", ev.login))?
-                    .handle_user_banned(ev)?,
-                Event::UserUnbanned(ev) => builder
-                    .builders
+                    .handle_thread_message_received(ev);
+            }
+            Event::ThreadMediaReceived(ev) => {
+                self.builders
                     .get_mut(&ev.login)
                     .with_context(|| format!("user not found: @{}", ev.login))?
-                    .handle_user_unbanned(ev)?,
-                Event::UserStopped(ev) => builder
-                    .builders
+                    .handle_thread_media_received(ev);
+            }
+            Event::ThreadTerminated(ev) => {
+                self.builders
                     .get_mut(&ev.login)
                     .with_context(|| format!("user not found: @{}", ev.login))?
-                    .handle_user_stopped(),
-                Event::UserStarted(ev) => builder
-                    .builders
+                    .terminate_thread(&ev.my_thread_id)?;
+                if !self.forgotten.contains(&ev.other_login) {
+                    self.builders
+                        .get_mut(&ev.other_login)
+                        .with_context(|| format!("user not found: @{}", ev.other_login))?
+                        .terminate_thread(&ev.other_thread_id)?;
+                }
+            }
+            Event::ThreadPauseChanged(ev) => {
+                self.builders
                     .get_mut(&ev.login)
                     .with_context(|| format!("user not found: @{}", ev.login))?
-                    .handle_user_started(),
+                    .set_thread_paused(&ev.my_thread_id, true, ev.paused)?;
+                if !self.forgotten.contains(&ev.other_login) {
+                    self.builders
+                        .get_mut(&ev.other_login)
+                        .with_context(|| format!("user not found: @{}", ev.other_login))?
+                        .set_thread_paused(&ev.other_thread_id, false, ev.paused)?;
+                }
+            }
+            Event::UserBanned(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_user_banned(ev, ts)?,
+            Event::UserUnbanned(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_user_unbanned(ev)?,
+            Event::UserStopped(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_user_stopped(),
+            Event::UserStarted(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_user_started(),
+            Event::UserSettingsChanged(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_user_settings_changed(ev),
+            Event::UserBlocked(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_user_blocked(ev),
+            Event::UserUnblocked(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_user_unblocked(ev)?,
+            Event::ThreadArchived(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_thread_archived(ev)?,
+            Event::ThreadUnarchived(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_thread_unarchived(ev)?,
+            Event::ThreadPinned(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_thread_pinned(ev)?,
+            Event::ThreadUnpinned(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_thread_unpinned(ev)?,
+            Event::OutgoingMessage(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_outgoing_message(ev)?,
+            Event::OutgoingMessageDeleted(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_outgoing_message_deleted(ev),
+            Event::TopicChanged(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_topic_changed(ev),
+            Event::UserInterestsChanged(ev) => self
+                .builders
+                .get_mut(&ev.login)
+                .with_context(|| format!("user not found: @{}", ev.login))?
+                .handle_user_interests_changed(ev),
+            Event::RandomQueued(ev) => self
+                .random_queue
+                .lock()
+                .expect("builder random_queue.lock() failed")
+                .push(QueuedRandomRequest {
+                    login: ev.login,
+                    text: ev.text,
+                    expires_at: ev.expires_at,
+                }),
+            Event::RoomCreated(ev) => self
+                .rooms
+                .lock()
+                .expect("builder rooms.lock() failed")
+                .create(ev.room),
+            Event::RoomJoined(ev) => self
+                .rooms
+                .lock()
+                .expect("builder rooms.lock() failed")
+                .join(&ev.room, ev.login, ev.alias),
+            Event::RoomLeft(ev) => self
+                .rooms
+                .lock()
+                .expect("builder rooms.lock() failed")
+                .leave(&ev.room, &ev.login),
+            Event::RoomMessage(_) => (),
+            Event::MediaPolicyChanged(ev) => {
+                *self
+                    .media_policy
+                    .write()
+                    .expect("builder media_policy.write() failed") = ev.policy;
+                self.media_policy_changed = true;
+            }
+            Event::RandomDequeued(ev) => {
+                self.random_queue
+                    .lock()
+                    .expect("builder random_queue.lock() failed")
+                    .remove(&ev.login);
             }
-            count += 1;
         }
+        Ok(())
+    }
 
-        info!("Read {} events from event log", count);
+    pub fn snapshot(&self) -> Snapshot {
+        let reader = self.user_handles.reader();
+        let user_handles = reader.read().expect("builder user_handles.read() failed");
+        let mut users = self
+            .builders
+            .iter()
+            .filter_map(|(login, builder)| {
+                let handle = user_handles.get(login)?;
+                Some(UserSnapshot {
+                    user: (*handle.user).clone(),
+                    chat_id: handle.chat_id,
+                    connected_at: handle.connected_at,
+                    is_stopped: handle.is_stopped.load(Ordering::Relaxed),
+                    settings: handle
+                        .settings
+                        .read()
+                        .expect("builder settings.read() failed")
+                        .clone(),
+                    interests: handle
+                        .interests
+                        .read()
+                        .expect("builder interests.read() failed")
+                        .clone(),
+                    topic: handle
+                        .topic
+                        .read()
+                        .expect("builder topic.read() failed")
+                        .clone(),
+                    handler: builder.snapshot(),
+                })
+            })
+            .collect::<Vec<_>>();
+        users.sort_by(|a, b| a.user.login.cmp(&b.user.login));
 
-        Ok(builder)
+        let media_policy = self
+            .media_policy
+            .read()
+            .expect("builder media_policy.read() failed")
+            .clone();
+        Snapshot {
+            users,
+            forgotten: self.forgotten.iter().cloned().collect(),
+            random_queue: self
+                .random_queue
+                .lock()
+                .expect("builder random_queue.lock() failed")
+                .entries
+                .clone(),
+            rooms: self
+                .rooms
+                .lock()
+                .expect("builder rooms.lock() failed")
+                .rooms
+                .iter()
+                .map(|(name, room)| (name.clone(), room.clone()))
+                .collect(),
+            media_policy: self.media_policy_changed.then_some(media_policy),
+        }
+    }
+
+    /// Writes a snapshot to a new file at `path`, to be used as the event log
+    /// from then on.
+    pub fn snapshot_to(&self, path: &Path, format: LogFormat) -> Result<()> {
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .context("failed to create snapshot file")?;
+        write_snapshot(BufWriter::new(file), format, self.snapshot())
+            .context("failed to write snapshot")
+    }
+
+    fn restore_snapshot(&mut self, snapshot: Snapshot) -> Result<()> {
+        for user in &snapshot.users {
+            self.handle_user_connected(
+                UserConnectedEvent {
+                    user: user.user.clone(),
+                    chat_id: user.chat_id,
+                    connected_at: user.connected_at,
+                },
+                None,
+            );
+            let user_handles = self
+                .user_handles
+                .write()
+                .expect("builder user_handles.write() failed");
+            let handle = &user_handles[&user.user.login];
+            handle.is_stopped.store(user.is_stopped, Ordering::Relaxed);
+            *handle
+                .settings
+                .write()
+                .expect("builder settings.write() failed") = user.settings.clone();
+            *handle
+                .interests
+                .write()
+                .expect("builder interests.write() failed") = user.interests.clone();
+            *handle.topic.write().expect("builder topic.write() failed") = user.topic.clone();
+        }
+        // Threads refer to other users, so they are restored once all users are.
+        let user_count = snapshot.users.len();
+        for user in snapshot.users {
+            self.builders
+                .get_mut(&user.user.login)
+                .with_context(|| format!("user not found: @{}", user.user.login))?
+                .restore_snapshot(user.handler)?;
+        }
+
+        self.forgotten = snapshot.forgotten.into_iter().collect();
+        self.random_queue
+            .lock()
+            .expect("builder random_queue.lock() failed")
+            .entries = snapshot.random_queue;
+        self.rooms
+            .lock()
+            .expect("builder rooms.lock() failed")
+            .rooms = snapshot.rooms.into_iter().collect();
+        if let Some(policy) = snapshot.media_policy {
+            *self
+                .media_policy
+                .write()
+                .expect("builder media_policy.write() failed") = policy;
+            self.media_policy_changed = true;
+        }
+        info!("Restored snapshot of {} users", user_count);
+        Ok(())
+    }
+
+    /// The offset to truncate the event log at before appending to it, if it
+    /// ends with a record torn by a crash.
+    pub fn torn_tail(&self) -> Option<u64> {
+        self.torn_tail
     }
 
-    fn handle_user_connected(&mut self, event: UserConnectedEvent) {
+    /// The format and version of the event log the state has been restored
+    /// from.
+    pub fn event_log_header(&self) -> LogHeader {
+        self.event_log_header
+    }
+
+    /// Lets handlers read the events back, e.g. for `/export`.
+    pub fn set_storage(&mut self, storage: Storage) {
+        self.storage = Some(storage);
+    }
+
+    /// Caps the duration (in seconds) and file size (in bytes) of voice messages.
+    pub fn set_voice_limits(&mut self, max_duration: u32, max_file_size: u64) {
+        self.media_limits.max_voice_duration = max_duration;
+        self.media_limits.max_voice_size = max_file_size;
+    }
+
+    /// Caps the duration (in seconds) and file size (in bytes) of videos and
+    /// video notes.
+    pub fn set_video_limits(&mut self, max_duration: u32, max_file_size: u64) {
+        self.media_limits.max_video_duration = max_duration;
+        self.media_limits.max_video_size = max_file_size;
+    }
+
+    /// Caps the size (in bytes) of audio files.
+    pub fn set_max_audio_size(&mut self, max_file_size: u64) {
+        self.media_limits.max_audio_size = max_file_size;
+    }
+
+    /// Caps the size (in bytes) of documents.
+    pub fn set_max_document_size(&mut self, max_file_size: u64) {
+        self.media_limits.max_document_size = max_file_size;
+    }
+
+    /// Caps the size (in bytes) of photos that are downloaded to be scrubbed,
+    /// see the `scrub_media` setting.
+    pub fn set_max_scrubbed_photo_size(&mut self, max_file_size: u64) {
+        self.media_limits.max_scrubbed_photo_size = max_file_size;
+    }
+
+    /// Sets the media policy, unless the admin has changed it with `/policy set`.
+    pub fn set_media_policy(&mut self, policy: MediaPolicy) {
+        if !self.media_policy_changed {
+            *self
+                .media_policy
+                .write()
+                .expect("builder media_policy.write() failed") = policy;
+        }
+    }
+
+    pub fn set_random_queue_timeout(&mut self, timeout: Duration) {
+        self.random_queue
+            .lock()
+            .expect("builder random_queue.lock() failed")
+            .timeout = timeout;
+    }
+
+    /// `ts` is the time the event has been written at, for old events that do
+    /// not carry the time of connection.
+    fn handle_user_connected(&mut self, event: UserConnectedEvent, ts: Option<i64>) {
         let login = event.user.login.clone();
+        self.forgotten.remove(&login);
 
         let (command_sender, command_receiver) = mpsc::channel(100);
         self.command_channels.insert(login.clone(), command_sender);
@@ -127,8 +833,14 @@ impl CommandDispatcherBuilder {
         let (action_sender, action_receiver) = mpsc::channel(100);
         let user_handle = UserHandle {
             user: Arc::new(event.user),
+            chat_id: event.chat_id,
+            connected_at: event.connected_at.or(ts),
             channel: action_sender,
             is_stopped: Arc::new(AtomicBool::new(false)),
+            settings: Arc::new(RwLock::new(UserSettings::default())),
+            last_activity: Arc::new(AtomicI64::new(0)),
+            interests: Arc::new(RwLock::new(vec![])),
+            topic: Arc::new(RwLock::new(None)),
         };
         self.user_handles
             .write()
@@ -141,15 +853,55 @@ impl CommandDispatcherBuilder {
                 user_handle,
                 event.chat_id,
                 self.user_handles.reader(),
+                self.random_queue.clone(),
+                self.rooms.clone(),
                 command_receiver,
                 action_receiver,
             ),
         );
     }
 
+    fn handle_user_profile_updated(&mut self, event: UserProfileUpdatedEvent) -> Result<()> {
+        let login = event.login.clone();
+        let user = self
+            .builders
+            .get_mut(&login)
+            .with_context(|| format!("user not found: @{}", login))?
+            .handle_user_profile_updated(event);
+        if let Some(handle) = self.user_handles.write().unwrap().get_mut(&login) {
+            handle.user = user;
+        }
+        Ok(())
+    }
+
+    /// Drops everything known about a user, so that their next message starts from scratch.
+    fn handle_user_forgotten(&mut self, event: UserForgottenEvent) {
+        self.command_channels.remove(&event.login);
+        self.builders.remove(&event.login);
+        self.user_handles.write().unwrap().remove(&event.login);
+        self.random_queue
+            .lock()
+            .expect("builder random_queue.lock() failed")
+            .remove(&event.login);
+        let mut rooms = self.rooms.lock().expect("builder rooms.lock() failed");
+        for room in rooms.rooms_of(&event.login) {
+            rooms.leave(&room, &event.login);
+        }
+        drop(rooms);
+        self.forgotten.insert(event.login);
+    }
+
     pub fn build(self, bot: AutoSend<Bot>, event_service: EventServiceHandle) -> CommandDispatcher {
+        let started_at = Instant::now();
         for builder in self.builders.into_values() {
-            let mut handler = builder.build(bot.clone(), event_service.clone());
+            let mut handler = builder.build(
+                bot.clone(),
+                event_service.clone(),
+                started_at,
+                self.storage.clone(),
+                self.media_limits,
+                self.media_policy.clone(),
+            );
             tokio::spawn(async move {
                 handler.run().await;
             });
@@ -160,6 +912,12 @@ impl CommandDispatcherBuilder {
             user_handles: self.user_handles,
             command_channels: Mutex::new(self.command_channels),
             event_service,
+            started_at,
+            random_queue: self.random_queue,
+            rooms: self.rooms,
+            storage: self.storage,
+            media_limits: self.media_limits,
+            media_policy: self.media_policy,
         }
     }
 }
@@ -171,6 +929,12 @@ pub struct CommandDispatcher {
     command_channels: Mutex<HashMap<String, mpsc::Sender<CommandRequest>>>,
     user_handles: Writer<HashMap<String, UserHandle>>,
     event_service: EventServiceHandle,
+    started_at: Instant,
+    random_queue: Arc<Mutex<RandomQueue>>,
+    rooms: Arc<Mutex<Rooms>>,
+    storage: Option<Storage>,
+    media_limits: MediaLimits,
+    media_policy: Arc<RwLock<MediaPolicy>>,
 }
 
 impl CommandDispatcher {
@@ -180,6 +944,8 @@ impl CommandDispatcher {
         chat_id: i64,
         command: Command,
     ) -> Result<()> {
+        let is_forget = command == Command::Forget { confirmed: true };
+        let is_refresh = matches!(command, Command::Whoami { refresh: true, .. });
         let (command_sender, mb_event_tracker) = self.get_command_sender(&user, chat_id);
 
         let (result_sender, result_receiver) = oneshot::channel();
@@ -188,20 +954,102 @@ impl CommandDispatcher {
             result_sender,
         };
 
-        command_sender.send(request).await.unwrap_or_else(|err| {
-            panic!("failed to send request to @{} handler: {}", user.login, err)
-        });
+        command_sender
+            .send(request)
+            .await
+            .map_err(|_| anyhow!("handler of @{} has shut down", user.login))?;
         if let Some(event_tracker) = mb_event_tracker {
             // NB: make sure that UserConnected event has been written to disk
             // before replying.
             event_tracker.wait_written().await?;
         }
-        result_receiver.await.unwrap_or_else(|err| {
-            panic!(
-                "failed to get command request result of @{} handler: {}",
-                user.login, err
-            )
-        })
+        let result = result_receiver
+            .await
+            .map_err(|_| anyhow!("handler of @{} has shut down", user.login))?;
+        if is_forget && result.is_ok() {
+            self.forget_user(&user.login);
+        }
+        if is_refresh && result.is_ok() {
+            self.update_user_profile(user);
+        }
+        result
+    }
+
+    fn update_user_profile(&self, user: Arc<User>) {
+        if let Some(handle) = self
+            .user_handles
+            .write()
+            .expect("dispatcher user_handles.write() failed")
+            .get_mut(&user.login)
+        {
+            handle.user = user;
+        }
+    }
+
+    /// Routes a vote in a relayed poll to the handler of the user who voted.
+    pub async fn handle_poll_answer(
+        &self,
+        login: &str,
+        poll_id: String,
+        option_ids: Vec<i32>,
+    ) -> Result<()> {
+        let command = Command::PollAnswer {
+            poll_id,
+            option_ids,
+        };
+        self.send_user_command(login, command, "poll answer").await
+    }
+
+    pub async fn handle_reaction(
+        &self,
+        login: &str,
+        message_id: i32,
+        emoji: Option<String>,
+    ) -> Result<()> {
+        let command = Command::Reaction { message_id, emoji };
+        self.send_user_command(login, command, "reaction").await
+    }
+
+    /// Passes a command that does not come from a message to the handler of
+    /// an existing user. Unknown users are ignored.
+    async fn send_user_command(&self, login: &str, command: Command, what: &str) -> Result<()> {
+        let command_sender = match self
+            .command_channels
+            .lock()
+            .expect("failed to lock command channels")
+            .get(login)
+        {
+            Some(channel) => channel.clone(),
+            None => {
+                debug!("ignoring {} of unknown user @{}", what, login);
+                return Ok(());
+            }
+        };
+
+        let (result_sender, result_receiver) = oneshot::channel();
+        let request = CommandRequest {
+            command,
+            result_sender,
+        };
+        command_sender
+            .send(request)
+            .await
+            .map_err(|_| anyhow!("handler of @{} has terminated", login))?;
+        result_receiver
+            .await
+            .with_context(|| format!("failed to get {} result of @{}", what, login))?
+    }
+
+    /// Unregisters a forgotten user. Their handler has already stopped.
+    fn forget_user(&self, login: &str) {
+        self.command_channels
+            .lock()
+            .expect("failed to lock command channels")
+            .remove(login);
+        self.user_handles
+            .write()
+            .expect("dispatcher user_handles.write() failed")
+            .remove(login);
     }
 
     fn get_command_sender(
@@ -217,36 +1065,57 @@ impl CommandDispatcher {
         match command_channels.get(&user.login) {
             Some(channel) => (channel.clone(), None),
             None => {
+                let connected_at = now_timestamp();
                 let event_tracker =
                     self.event_service
                         .write(Event::UserConnected(UserConnectedEvent {
-                            user: User::clone(&user),
+                            user: User::clone(user),
                             chat_id,
+                            connected_at: Some(connected_at),
                         }));
-                let channel = self.spawn_handler(user, chat_id);
+                let channel = self.spawn_handler(user, chat_id, connected_at);
                 command_channels.insert(user.login.clone(), channel.clone());
                 (channel, Some(event_tracker))
             }
         }
     }
 
-    fn spawn_handler(&self, user: &Arc<User>, chat_id: i64) -> mpsc::Sender<CommandRequest> {
+    fn spawn_handler(
+        &self,
+        user: &Arc<User>,
+        chat_id: i64,
+        connected_at: i64,
+    ) -> mpsc::Sender<CommandRequest> {
         let (command_sender, command_receiver) = mpsc::channel(100);
         let (action_sender, action_receiver) = mpsc::channel(100);
 
         let user_handle = UserHandle {
             user: user.clone(),
+            chat_id,
+            connected_at: Some(connected_at),
             channel: action_sender,
             is_stopped: Arc::new(AtomicBool::new(false)),
+            settings: Arc::new(RwLock::new(UserSettings::default())),
+            last_activity: Arc::new(AtomicI64::new(0)),
+            interests: Arc::new(RwLock::new(vec![])),
+            topic: Arc::new(RwLock::new(None)),
         };
-        let mut handler = Handler::new(
-            self.bot.clone(),
-            self.event_service.clone(),
-            chat_id,
+        let mut handler = HandlerBuilder::new(
             user_handle.clone(),
+            chat_id,
             self.user_handles.reader(),
+            self.random_queue.clone(),
+            self.rooms.clone(),
             command_receiver,
             action_receiver,
+        )
+        .build(
+            self.bot.clone(),
+            self.event_service.clone(),
+            self.started_at,
+            self.storage.clone(),
+            self.media_limits,
+            self.media_policy.clone(),
         );
         tokio::spawn(async move {
             handler.run().await;
@@ -259,3 +1128,178 @@ impl CommandDispatcher {
         command_sender
     }
 }
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_handle(login: &str) -> (UserHandle, mpsc::Receiver<ActionRequest>) {
+        let (channel, receiver) = mpsc::channel(1);
+        let handle = UserHandle {
+            user: Arc::new(User {
+                login: login.into(),
+                first_name: login.into(),
+                last_name: None,
+            }),
+            chat_id: 1,
+            connected_at: None,
+            channel,
+            is_stopped: Arc::new(AtomicBool::new(false)),
+            settings: Default::default(),
+            last_activity: Arc::new(AtomicI64::new(0)),
+            interests: Default::default(),
+            topic: Default::default(),
+        };
+        (handle, receiver)
+    }
+
+    /// Alice has a thread with Bob, who sees her as `#x`.
+    const THREAD_LOG: &str = r##"{"UserConnected":{"user":{"login":"alice","first_name":"Alice","last_name":null},"chat_id":1,"connected_at":5}}
+{"UserConnected":{"user":{"login":"bob","first_name":"Bob","last_name":null},"chat_id":2,"connected_at":6}}
+{"ThreadStarted":{"login":"alice","other_login":"bob","my_thread_id":"@bob","other_thread_id":"#x","anon_mode":"Me","is_initiator":true,"created_at":7}}
+{"ThreadStarted":{"login":"bob","other_login":"alice","my_thread_id":"#x","other_thread_id":"@bob","anon_mode":"Me","is_initiator":false,"created_at":7}}
+"##;
+
+    fn replay(log: &str) -> CommandDispatcherBuilder {
+        CommandDispatcherBuilder::from_event_log(log.as_bytes(), false).unwrap()
+    }
+
+    /// The state of a user as it would be written into a snapshot.
+    fn user_state(builder: &CommandDispatcherBuilder, login: &str) -> serde_json::Value {
+        let snapshot = serde_json::to_value(builder.snapshot()).unwrap();
+        snapshot["users"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|user| user["user"]["login"] == login)
+            .cloned()
+            .unwrap()
+    }
+
+    #[test]
+    fn relayed_media_can_be_replied_to() {
+        let log = format!(
+            "{}{}",
+            THREAD_LOG,
+            r##"{"ThreadMediaReceived":{"login":"bob","message_id":11,"thread_id":"#x","kind":"Received"}}"##
+        );
+        let builder = replay(&log);
+        let handler = &user_state(&builder, "bob")["handler"];
+        assert_eq!(
+            handler["message_id_to_thread_id"],
+            serde_json::json!([[11, "#x"]])
+        );
+        assert_eq!(handler["threads"][0]["messages_received"], 1);
+    }
+
+    #[test]
+    fn timestamps_fill_in_missing_times() {
+        // A bare event has no time, and a `created_at` saved with the event
+        // wins over the time of the event.
+        let log = r##"{"UserConnected":{"user":{"login":"alice","first_name":"Alice","last_name":null},"chat_id":1}}
+{"ts":6,"event":{"UserConnected":{"user":{"login":"bob","first_name":"Bob","last_name":null},"chat_id":2}}}
+{"ts":7,"event":{"ThreadStarted":{"login":"alice","other_login":"bob","my_thread_id":"@bob","other_thread_id":"#x","anon_mode":"Me"}}}
+{"ts":8,"event":{"ThreadStarted":{"login":"bob","other_login":"alice","my_thread_id":"#x","other_thread_id":"@bob","anon_mode":"Me","created_at":3}}}
+"##;
+        let builder = replay(log);
+        let alice = user_state(&builder, "alice");
+        let bob = user_state(&builder, "bob");
+        assert_eq!(alice["connected_at"], serde_json::Value::Null);
+        assert_eq!(bob["connected_at"], 6);
+        assert_eq!(alice["handler"]["threads"][0]["created_at"], 7);
+        assert_eq!(bob["handler"]["threads"][0]["created_at"], 3);
+    }
+
+    #[test]
+    fn stop_and_start_toggle_the_user() {
+        let stopped = format!("{}{}\n", THREAD_LOG, r#"{"UserStopped":{"login":"bob"}}"#);
+        let builder = replay(&stopped);
+        assert_eq!(user_state(&builder, "bob")["is_stopped"], true);
+        assert_eq!(user_state(&builder, "alice")["is_stopped"], false);
+
+        let restarted = format!("{}{}\n", stopped, r#"{"UserStarted":{"login":"bob"}}"#);
+        let builder = replay(&restarted);
+        let bob = user_state(&builder, "bob");
+        assert_eq!(bob["is_stopped"], false);
+        // Starting again, e.g. from a "message me" link, keeps the threads.
+        assert_eq!(bob["handler"]["threads"][0]["id"], "#x");
+    }
+
+    #[test]
+    fn replay_from_snapshot_matches_full_replay() {
+        let log = format!(
+            "{}{}\n{}\n{}\n",
+            THREAD_LOG,
+            r##"{"ThreadMediaReceived":{"login":"bob","message_id":11,"thread_id":"#x","kind":"Received"}}"##,
+            r#"{"UserStopped":{"login":"bob"}}"#,
+            r#"{"UserStarted":{"login":"bob"}}"#,
+        );
+        let expected = serde_json::to_value(replay(&log).snapshot()).unwrap();
+
+        for format in [LogFormat::Json, LogFormat::Bincode] {
+            for split in 0..=log.lines().count() {
+                let mut records = EventLogReader::new(log.as_bytes()).unwrap();
+                let mut builder = CommandDispatcherBuilder::default();
+                for record in records.by_ref().take(split) {
+                    builder.replay_record(record.unwrap()).unwrap();
+                }
+                let mut compacted = vec![];
+                write_snapshot(&mut compacted, format, builder.snapshot()).unwrap();
+                for record in records {
+                    format
+                        .codec()
+                        .encode(&record.unwrap(), &mut compacted)
+                        .unwrap();
+                }
+
+                let builder =
+                    CommandDispatcherBuilder::from_event_log(compacted.as_slice(), false).unwrap();
+                assert_eq!(
+                    serde_json::to_value(builder.snapshot()).unwrap(),
+                    expected,
+                    "{:?} snapshot after {} records",
+                    format,
+                    split
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn send_action_fails_once_handler_has_shut_down() {
+        let (handle, receiver) = user_handle("alice");
+        drop(receiver);
+        let err = handle
+            .send_action(Action::TerminateThread("#x".into()))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "the other user has left");
+    }
+
+    #[tokio::test]
+    async fn send_action_fails_if_request_is_dropped_unanswered() {
+        let (handle, mut receiver) = user_handle("alice");
+        tokio::spawn(async move {
+            drop(receiver.recv().await);
+        });
+        let result = handle
+            .send_action(Action::TerminateThread("#x".into()))
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn send_action_returns_handler_result() {
+        let (handle, mut receiver) = user_handle("alice");
+        tokio::spawn(async move {
+            let request = receiver.recv().await.unwrap();
+            request.result_sender.send(Ok(())).ok();
+        });
+        handle
+            .send_action(Action::TerminateThread("#x".into()))
+            .await
+            .unwrap();
+    }
+}
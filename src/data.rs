@@ -1,10 +1,15 @@
-use anyhow::{Context, Result};
-use serde::{Deserialize, Serialize};
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{messages::Language, util::escape_markup};
+
+use std::fmt;
 
 ////////////////////////////////////////////////////////////////////////////////
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
+    #[serde(deserialize_with = "deserialize_login")]
     pub login: String,
     pub first_name: String,
     pub last_name: Option<String>,
@@ -18,14 +23,46 @@ impl TryFrom<&teloxide::types::User> for User {
             login: tg_user
                 .username
                 .as_ref()
-                .context("user has no username")?
-                .clone(),
+                .context("user has no username")
+                .map(|login| normalize_login(login))?,
             first_name: tg_user.first_name.clone(),
             last_name: tg_user.last_name.clone(),
         })
     }
 }
 
+/// Telegram usernames are case-insensitive, so logins are always lowercased
+/// before being used as keys.
+pub fn normalize_login(login: &str) -> String {
+    login.trim_start_matches('@').to_lowercase()
+}
+
+/// Normalizes the login part of a `@login` thread id. Other ids are kept as is.
+pub fn normalize_thread_id(thread_id: &str) -> ThreadId {
+    if thread_id.starts_with('@') {
+        format!("@{}", normalize_login(thread_id))
+    } else {
+        thread_id.to_string()
+    }
+}
+
+pub fn deserialize_login<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    String::deserialize(deserializer).map(|login| normalize_login(&login))
+}
+
+pub fn deserialize_thread_id<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<ThreadId, D::Error> {
+    ThreadId::deserialize(deserializer).map(|thread_id| normalize_thread_id(&thread_id))
+}
+
+pub fn deserialize_optional_thread_id<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<ThreadId>, D::Error> {
+    Option::<ThreadId>::deserialize(deserializer)
+        .map(|thread_id| thread_id.map(|thread_id| normalize_thread_id(&thread_id)))
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 
 pub type ThreadId = String;
@@ -36,3 +73,397 @@ pub enum ThreadAnonimityMode {
     Them,
     Both,
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MessageKind {
+    Sent,
+    Received,
+    Service,
+}
+
+/// Media that can be relayed through threads besides text.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MediaKind {
+    /// Photos and videos under a spoiler are blurred until tapped. Scrubbed
+    /// photos are re-encoded without their metadata before being relayed.
+    Photo {
+        spoiler: bool,
+        scrub: bool,
+    },
+    Sticker,
+    Animation,
+    Voice {
+        duration: u32,
+    },
+    Video {
+        duration: u32,
+        spoiler: bool,
+    },
+    VideoNote {
+        duration: u32,
+    },
+    Audio {
+        duration: u32,
+        mime_type: Option<String>,
+    },
+    Document {
+        file_name: Option<String>,
+        mime_type: Option<String>,
+    },
+    /// Contacts carry no file, their file id is empty.
+    Contact {
+        phone_number: String,
+        first_name: String,
+        last_name: Option<String>,
+    },
+    /// A venue has a title and an address, a plain location has neither.
+    /// Like contacts, locations carry no file.
+    Location {
+        latitude: f64,
+        longitude: f64,
+        title: Option<String>,
+        address: Option<String>,
+    },
+}
+
+/// Caps on media sent through threads. Sizes are in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MediaLimits {
+    pub max_voice_duration: u32,
+    pub max_voice_size: u64,
+    pub max_video_duration: u32,
+    pub max_video_size: u64,
+    pub max_audio_size: u64,
+    pub max_document_size: u64,
+    pub max_scrubbed_photo_size: u64,
+}
+
+impl Default for MediaLimits {
+    fn default() -> Self {
+        Self {
+            max_voice_duration: 120,
+            max_voice_size: 1024 * 1024,
+            max_video_duration: 300,
+            max_video_size: 20 * 1024 * 1024,
+            max_audio_size: 20 * 1024 * 1024,
+            max_document_size: 20 * 1024 * 1024,
+            max_scrubbed_photo_size: 10 * 1024 * 1024,
+        }
+    }
+}
+
+/// Categories of media that may be relayed through threads at all. The
+/// operator sets it with `--disabled-media`, the admin changes it with
+/// `/policy set`. Video notes fall under `video`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MediaPolicy {
+    pub photo: bool,
+    pub sticker: bool,
+    pub animation: bool,
+    pub voice: bool,
+    pub video: bool,
+    pub audio: bool,
+    pub document: bool,
+    pub contact: bool,
+    pub location: bool,
+}
+
+impl Default for MediaPolicy {
+    fn default() -> Self {
+        Self {
+            photo: true,
+            sticker: true,
+            animation: true,
+            voice: true,
+            video: true,
+            audio: true,
+            document: true,
+            contact: true,
+            location: true,
+        }
+    }
+}
+
+impl MediaPolicy {
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let flag = parse_flag(value)?;
+        match key {
+            "photo" => self.photo = flag,
+            "sticker" => self.sticker = flag,
+            "animation" => self.animation = flag,
+            "voice" => self.voice = flag,
+            "video" => self.video = flag,
+            "audio" => self.audio = flag,
+            "document" => self.document = flag,
+            "contact" => self.contact = flag,
+            "location" => self.location = flag,
+            _ => bail!(
+                "unknown media category: {}; available categories: photo, sticker, animation, voice, video, audio, document, contact, location",
+                key
+            ),
+        }
+        Ok(())
+    }
+
+    /// Fails with the rule that forbids relaying the media, if any.
+    pub fn check(&self, kind: &MediaKind) -> Result<()> {
+        let (allowed, key, name) = match kind {
+            MediaKind::Photo { .. } => (self.photo, "photo", "photos"),
+            MediaKind::Sticker => (self.sticker, "sticker", "stickers"),
+            MediaKind::Animation => (self.animation, "animation", "animations"),
+            MediaKind::Voice { .. } => (self.voice, "voice", "voice messages"),
+            MediaKind::Video { .. } => (self.video, "video", "videos"),
+            MediaKind::VideoNote { .. } => (self.video, "video", "video notes"),
+            MediaKind::Audio { .. } => (self.audio, "audio", "audio files"),
+            MediaKind::Document { .. } => (self.document, "document", "files"),
+            MediaKind::Contact { .. } => (self.contact, "contact", "contacts"),
+            MediaKind::Location { .. } => (self.location, "location", "locations"),
+        };
+        ensure!(
+            allowed,
+            "{} are not allowed by the media policy (`{} off`)",
+            name,
+            key
+        );
+        Ok(())
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "* photo: {}\n* sticker: {}\n* animation: {}\n* voice: {}\n* video: {}\n* audio: {}\n* document: {}\n* contact: {}\n* location: {}",
+            format_flag(self.photo),
+            format_flag(self.sticker),
+            format_flag(self.animation),
+            format_flag(self.voice),
+            format_flag(self.video),
+            format_flag(self.audio),
+            format_flag(self.document),
+            format_flag(self.contact),
+            format_flag(self.location),
+        )
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UserSettings {
+    pub receive_random: bool,
+    pub show_in_users_list: bool,
+    pub notifications_sound_prefix: Option<String>,
+    pub language: Language,
+    pub quote_replies: bool,
+    pub random_queue: bool,
+    pub approve_threads: bool,
+    pub confirm_actions: bool,
+    pub voice_confirmed: bool,
+    pub hide_file_names: bool,
+    pub hide_audio_metadata: bool,
+    pub allow_revealing_content: bool,
+    pub link_previews: bool,
+    pub scrub_media: bool,
+    pub keep_sent_texts: bool,
+}
+
+impl Default for UserSettings {
+    fn default() -> Self {
+        Self {
+            receive_random: true,
+            show_in_users_list: true,
+            notifications_sound_prefix: None,
+            language: Language::default(),
+            quote_replies: false,
+            random_queue: false,
+            approve_threads: false,
+            confirm_actions: true,
+            voice_confirmed: false,
+            hide_file_names: false,
+            hide_audio_metadata: false,
+            allow_revealing_content: false,
+            link_previews: true,
+            scrub_media: false,
+            keep_sent_texts: false,
+        }
+    }
+}
+
+impl UserSettings {
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "receive_random" => self.receive_random = parse_flag(value)?,
+            "show_in_users_list" => self.show_in_users_list = parse_flag(value)?,
+            "notifications_sound_prefix" => {
+                self.notifications_sound_prefix = match value {
+                    "off" | "none" => None,
+                    _ => Some(value.to_string()),
+                }
+            }
+            "language" => self.language = Language::from_code(value)?,
+            "quote_replies" => self.quote_replies = parse_flag(value)?,
+            "random_queue" => self.random_queue = parse_flag(value)?,
+            "approve_threads" => self.approve_threads = parse_flag(value)?,
+            "confirm_actions" => self.confirm_actions = parse_flag(value)?,
+            "voice_confirmed" => self.voice_confirmed = parse_flag(value)?,
+            "hide_file_names" => self.hide_file_names = parse_flag(value)?,
+            "hide_audio_metadata" => self.hide_audio_metadata = parse_flag(value)?,
+            "allow_revealing_content" => self.allow_revealing_content = parse_flag(value)?,
+            "link_previews" => self.link_previews = parse_flag(value)?,
+            "scrub_media" => self.scrub_media = parse_flag(value)?,
+            "keep_sent_texts" => self.keep_sent_texts = parse_flag(value)?,
+            _ => bail!("unknown setting: {}", key),
+        }
+        Ok(())
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "* receive_random: {}\n* show_in_users_list: {}\n* notifications_sound_prefix: {}\n* language: {}\n* quote_replies: {}\n* random_queue: {}\n* approve_threads: {}\n* confirm_actions: {}\n* voice_confirmed: {}\n* hide_file_names: {}\n* hide_audio_metadata: {}\n* allow_revealing_content: {}\n* link_previews: {}\n* scrub_media: {}\n* keep_sent_texts: {}",
+            format_flag(self.receive_random),
+            format_flag(self.show_in_users_list),
+            self.notifications_sound_prefix.as_deref().unwrap_or("none"),
+            self.language.code(),
+            format_flag(self.quote_replies),
+            format_flag(self.random_queue),
+            format_flag(self.approve_threads),
+            format_flag(self.confirm_actions),
+            format_flag(self.voice_confirmed),
+            format_flag(self.hide_file_names),
+            format_flag(self.hide_audio_metadata),
+            format_flag(self.allow_revealing_content),
+            format_flag(self.link_previews),
+            format_flag(self.scrub_media),
+            format_flag(self.keep_sent_texts),
+        )
+    }
+}
+
+fn parse_flag(value: &str) -> Result<bool> {
+    match value {
+        "on" | "yes" | "true" => Ok(true),
+        "off" | "no" | "false" => Ok(false),
+        _ => bail!("expected `on` or `off`, got: {}", value),
+    }
+}
+
+/// What is kept of a text relayed into a thread: the text itself, or only its
+/// length and checksum unless the sender has turned `keep_sent_texts` on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SentText {
+    Full(String),
+    Digest { length: usize, crc32: u32 },
+}
+
+impl SentText {
+    pub fn new(text: &str, keep: bool) -> Self {
+        if keep {
+            SentText::Full(text.to_string())
+        } else {
+            SentText::Digest {
+                length: text.chars().count(),
+                crc32: crc32fast::hash(text.as_bytes()),
+            }
+        }
+    }
+
+    /// A short reminder of the text for the sender, in markup.
+    pub fn describe(&self) -> String {
+        const MAX_PREVIEW_LENGTH: usize = 30;
+        match self {
+            SentText::Full(text) if text.chars().count() > MAX_PREVIEW_LENGTH => {
+                let preview: String = text.chars().take(MAX_PREVIEW_LENGTH).collect();
+                format!("\"{}…\"", escape_markup(&preview))
+            }
+            SentText::Full(text) => format!("\"{}\"", escape_markup(text)),
+            SentText::Digest { length, .. } => format!(
+                "{} character{}",
+                length,
+                if *length == 1 { "" } else { "s" }
+            ),
+        }
+    }
+}
+
+pub fn format_flag(flag: bool) -> &'static str {
+    if flag {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// A daily do-not-disturb window in UTC, stored as minutes since midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DndWindow {
+    pub start_minute: u32,
+    pub end_minute: u32,
+}
+
+impl DndWindow {
+    /// Parses a window like `23:00-08:00`.
+    pub fn parse(value: &str) -> Result<Self> {
+        let (start, end) = value
+            .split_once('-')
+            .context("expected a window like `23:00-08:00`")?;
+        let window = Self {
+            start_minute: parse_time_of_day(start)?,
+            end_minute: parse_time_of_day(end)?,
+        };
+        ensure!(
+            window.start_minute != window.end_minute,
+            "do-not-disturb window is empty"
+        );
+        Ok(window)
+    }
+
+    pub fn contains(&self, timestamp: i64) -> bool {
+        let minute = (timestamp.rem_euclid(86400) / 60) as u32;
+        if self.start_minute < self.end_minute {
+            self.start_minute <= minute && minute < self.end_minute
+        } else {
+            minute >= self.start_minute || minute < self.end_minute
+        }
+    }
+
+    /// Returns the timestamp of the next end of the window after `timestamp`.
+    pub fn next_end(&self, timestamp: i64) -> i64 {
+        let end = self.end_minute as i64 * 60;
+        let delta = (end - timestamp.rem_euclid(86400)).rem_euclid(86400);
+        timestamp + if delta == 0 { 86400 } else { delta }
+    }
+
+    pub fn describe(&self) -> String {
+        format!("{} UTC", self)
+    }
+}
+
+impl fmt::Display for DndWindow {
+    /// Formats the window the way `parse` expects it, e.g. `23:00-08:00`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start_minute / 60,
+            self.start_minute % 60,
+            self.end_minute / 60,
+            self.end_minute % 60
+        )
+    }
+}
+
+fn parse_time_of_day(value: &str) -> Result<u32> {
+    let (hours, minutes) = value
+        .split_once(':')
+        .with_context(|| format!("expected time like `23:00`, got: {}", value))?;
+    let hours: u32 = hours
+        .parse()
+        .with_context(|| format!("invalid hours: {}", hours))?;
+    let minutes: u32 = minutes
+        .parse()
+        .with_context(|| format!("invalid minutes: {}", minutes))?;
+    ensure!(hours < 24 && minutes < 60, "invalid time: {}", value);
+    Ok(hours * 60 + minutes)
+}
@@ -0,0 +1,576 @@
+use anyhow::{anyhow, bail, ensure, Context, Error, Result};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    fmt::{self, Display},
+    io::{self, BufRead, Read},
+    str::FromStr,
+};
+
+use crate::{
+    command_dispatcher::Snapshot,
+    event_log::{LogRecord, LoggedEvent},
+    migrations::{self, CURRENT_VERSION},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Starts the header line that names the format and version of an event log.
+/// Logs written before formats were introduced have no header, are JSON lines
+/// and have version 0, see `migrations`.
+const HEADER_MAGIC: &[u8] = b"lovebot-event-log ";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// A line of JSON per record, see `JsonCodec`.
+    Json,
+    /// Length-prefixed bincode records, see `BincodeCodec`.
+    Bincode,
+}
+
+/// The format and version of an event log, as stated by its header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogHeader {
+    pub format: LogFormat,
+    pub version: u32,
+}
+
+impl LogHeader {
+    /// The header of a new log, which is written at the current version.
+    pub fn current(format: LogFormat) -> Self {
+        Self {
+            format,
+            version: CURRENT_VERSION,
+        }
+    }
+
+    /// The header of a log written before headers were introduced.
+    pub fn legacy() -> Self {
+        Self {
+            format: LogFormat::Json,
+            version: 0,
+        }
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        format!(
+            "{}{} {}\n",
+            String::from_utf8_lossy(HEADER_MAGIC),
+            self.format,
+            self.version
+        )
+        .into_bytes()
+    }
+
+    /// Reads the header of a log, if it has one, and returns it along with its
+    /// length. Returns `None` if the header has been cut short by a crash while
+    /// the log was being created.
+    pub fn read<R: BufRead>(reader: &mut R) -> Result<Option<(Self, u64)>> {
+        if !reader.fill_buf()?.starts_with(HEADER_MAGIC) {
+            return Ok(Some((Self::legacy(), 0)));
+        }
+        let mut line = vec![];
+        reader.read_until(b'\n', &mut line)?;
+        if !line.ends_with(b"\n") {
+            return Ok(None);
+        }
+        let text = std::str::from_utf8(&line[HEADER_MAGIC.len()..line.len() - 1])
+            .context("malformed event log header")?;
+        let mut parts = text.split(' ');
+        let format = parts.next().unwrap_or_default().parse()?;
+        // The first headers named the format only.
+        let version = match parts.next() {
+            Some(version) => version
+                .parse()
+                .with_context(|| format!("malformed event log version: {}", version))?,
+            None => 1,
+        };
+        Ok(Some((Self { format, version }, line.len() as u64)))
+    }
+
+    /// The codec that reads records of this format and version.
+    pub fn codec(self) -> Result<Box<dyn EventCodec>> {
+        ensure!(
+            self.version <= CURRENT_VERSION,
+            "event log version {} is newer than supported version {}",
+            self.version,
+            CURRENT_VERSION
+        );
+        Ok(match self.format {
+            LogFormat::Json => Box::new(JsonCodec {
+                version: self.version,
+            }),
+            LogFormat::Bincode => {
+                ensure!(
+                    self.version == CURRENT_VERSION,
+                    "bincode event logs of version {} cannot be migrated",
+                    self.version
+                );
+                Box::new(BincodeCodec)
+            }
+        })
+    }
+}
+
+impl LogFormat {
+    /// The codec that writes records of the current version.
+    pub fn codec(self) -> Box<dyn EventCodec> {
+        LogHeader::current(self)
+            .codec()
+            .expect("current version is always supported")
+    }
+}
+
+impl Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFormat::Json => write!(f, "json"),
+            LogFormat::Bincode => write!(f, "bincode"),
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(LogFormat::Json),
+            "bincode" => Ok(LogFormat::Bincode),
+            _ => bail!("unknown event log format: {}", s),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// A record read from the log, or the reason it could not be read.
+pub enum Decoded {
+    Record(LogRecord),
+    /// The record is damaged, but the ones after it can still be read.
+    Corrupt(Error),
+    /// The log ends in the middle of the record, as if a write was cut short
+    /// by a crash.
+    Torn(Error),
+}
+
+/// Turns the records of an event log into bytes and back.
+pub trait EventCodec: Send {
+    fn encode(&self, record: &LogRecord, buf: &mut Vec<u8>) -> Result<()>;
+
+    /// Reads the next record along with the number of bytes it took up.
+    /// Returns `None` at the end of the log.
+    fn decode(&self, reader: &mut dyn BufRead) -> io::Result<Option<(Decoded, u64)>>;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Writes a record as a line of JSON followed by its CRC32, so that torn or
+/// corrupted lines can be told apart on reading. Records of older versions are
+/// upgraded on reading, see `migrations`.
+pub struct JsonCodec {
+    version: u32,
+}
+
+/// Separates the JSON of a record from its checksum. Compact JSON never
+/// contains a raw tab.
+const CHECKSUM_SEPARATOR: char = '\t';
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum JsonLineRef<'a> {
+    Logged(&'a LoggedEvent),
+    Snapshot { snapshot: &'a Snapshot },
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonLine {
+    Logged(LoggedEvent),
+    Snapshot { snapshot: Box<Snapshot> },
+}
+
+impl From<JsonLine> for LogRecord {
+    fn from(line: JsonLine) -> Self {
+        match line {
+            JsonLine::Logged(logged) => LogRecord::Event(logged),
+            JsonLine::Snapshot { snapshot } => LogRecord::Snapshot(snapshot),
+        }
+    }
+}
+
+impl JsonCodec {
+    fn parse_line(&self, json: &[u8]) -> Result<LogRecord> {
+        let line = if self.version == CURRENT_VERSION {
+            serde_json::from_slice::<JsonLine>(json)?
+        } else {
+            let record = migrations::migrate(self.version, serde_json::from_slice(json)?);
+            serde_json::from_value::<JsonLine>(record)?
+        };
+        Ok(line.into())
+    }
+
+    /// Checks the checksum of a line, if it has one, and returns its JSON.
+    /// Lines written before checksums were introduced have none.
+    fn verify_line(line: &[u8]) -> Result<&[u8]> {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let separator = CHECKSUM_SEPARATOR as u8;
+        let (json, checksum) = match line.iter().rposition(|&c| c == separator) {
+            Some(index) => (&line[..index], &line[index + 1..]),
+            None => return Ok(line),
+        };
+        let expected = std::str::from_utf8(checksum)
+            .ok()
+            .and_then(|checksum| u32::from_str_radix(checksum, 16).ok())
+            .context("malformed checksum")?;
+        let actual = crc32fast::hash(json);
+        ensure!(
+            actual == expected,
+            "checksum mismatch: expected {:08x}, got {:08x}",
+            expected,
+            actual
+        );
+        Ok(json)
+    }
+}
+
+impl EventCodec for JsonCodec {
+    fn encode(&self, record: &LogRecord, buf: &mut Vec<u8>) -> Result<()> {
+        let line = match record {
+            LogRecord::Event(logged) => JsonLineRef::Logged(logged),
+            LogRecord::Snapshot(snapshot) => JsonLineRef::Snapshot { snapshot },
+        };
+        let json = serde_json::to_vec(&line)?;
+        buf.extend_from_slice(&json);
+        buf.extend_from_slice(
+            format!("{}{:08x}\n", CHECKSUM_SEPARATOR, crc32fast::hash(&json)).as_bytes(),
+        );
+        Ok(())
+    }
+
+    fn decode(&self, reader: &mut dyn BufRead) -> io::Result<Option<(Decoded, u64)>> {
+        let mut data = vec![];
+        reader.read_until(b'\n', &mut data)?;
+        if data.is_empty() {
+            return Ok(None);
+        }
+        let result = Self::verify_line(&data).and_then(|json| self.parse_line(json));
+        let decoded = match result {
+            Ok(record) => Decoded::Record(record),
+            // Only the last line may lack a newline.
+            Err(err) if !data.ends_with(b"\n") => Decoded::Torn(err),
+            Err(err) => Decoded::Corrupt(err),
+        };
+        Ok(Some((decoded, data.len() as u64)))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Writes a record as bincode prefixed by a frame header: the length of the
+/// record, the CRC32 of the length and the CRC32 of the record, all of them
+/// little-endian `u32`. Takes less space and time to read than JSON, but is
+/// not meant to be read by humans.
+pub struct BincodeCodec;
+
+const FRAME_HEADER_LEN: usize = 12;
+
+/// Reads up to `len` bytes, fewer only at the end of the log.
+fn read_up_to(reader: &mut dyn BufRead, len: usize) -> io::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(len.min(1 << 20));
+    reader.take(len as u64).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes.try_into().expect("slice of 4 bytes"))
+}
+
+impl EventCodec for BincodeCodec {
+    fn encode(&self, record: &LogRecord, buf: &mut Vec<u8>) -> Result<()> {
+        let payload = bincode::serialize(record)?;
+        let len = u32::try_from(payload.len())
+            .context("record is too large")?
+            .to_le_bytes();
+        buf.extend_from_slice(&len);
+        buf.extend_from_slice(&crc32fast::hash(&len).to_le_bytes());
+        buf.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        buf.extend_from_slice(&payload);
+        Ok(())
+    }
+
+    fn decode(&self, reader: &mut dyn BufRead) -> io::Result<Option<(Decoded, u64)>> {
+        let header = read_up_to(reader, FRAME_HEADER_LEN)?;
+        if header.is_empty() {
+            return Ok(None);
+        }
+        if header.len() < FRAME_HEADER_LEN {
+            let torn = Decoded::Torn(anyhow!("record header is cut short"));
+            return Ok(Some((torn, header.len() as u64)));
+        }
+        // Without a trustworthy length there is no telling where the next
+        // record starts, so the rest of the log cannot be read.
+        if crc32fast::hash(&header[..4]) != read_u32(&header[4..8]) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "record header is corrupt",
+            ));
+        }
+        let len = read_u32(&header[..4]) as usize;
+        let expected = read_u32(&header[8..]);
+
+        let payload = read_up_to(reader, len)?;
+        let size = (FRAME_HEADER_LEN + payload.len()) as u64;
+        if payload.len() < len {
+            let torn = Decoded::Torn(anyhow!(
+                "record is cut short: expected {} bytes, got {}",
+                len,
+                payload.len()
+            ));
+            return Ok(Some((torn, size)));
+        }
+        let actual = crc32fast::hash(&payload);
+        if actual != expected {
+            let corrupt = Decoded::Corrupt(anyhow!(
+                "checksum mismatch: expected {:08x}, got {:08x}",
+                expected,
+                actual
+            ));
+            return Ok(Some((corrupt, size)));
+        }
+        let decoded = match bincode::deserialize(&payload) {
+            Ok(record) => Decoded::Record(record),
+            Err(err) => Decoded::Corrupt(err.into()),
+        };
+        Ok(Some((decoded, size)))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::command_dispatcher::CommandDispatcherBuilder;
+
+    use std::collections::HashSet;
+
+    /// One event of every type, a line each.
+    const EVERY_EVENT: &str = r##"{"ts":1,"event":{"UserConnected":{"user":{"login":"alice","first_name":"Alice","last_name":null},"chat_id":1,"connected_at":1}}}
+{"ts":2,"event":{"UserProfileUpdated":{"login":"alice","first_name":"Alice","last_name":"Liddell"}}}
+{"ts":3,"event":{"ThreadStarted":{"login":"alice","other_login":"bob","my_thread_id":"@bob","other_thread_id":"#x","anon_mode":"Me","is_initiator":true,"created_at":3}}}
+{"ts":4,"event":{"ThreadMessageReceived":{"login":"bob","message_id":11,"thread_id":"#x","kind":"Received"}}}
+{"ts":5,"event":{"ThreadMediaReceived":{"login":"bob","message_id":12,"thread_id":"#x","kind":"Received"}}}
+{"ts":6,"event":{"ThreadTerminated":{"login":"alice","other_login":"bob","my_thread_id":"@bob","other_thread_id":"#x"}}}
+{"ts":7,"event":{"UserBanned":{"login":"bob","banned_login":"alice","banned_thread_id":"#x","reason":"spam","banned_at":7,"expires_at":null}}}
+{"ts":8,"event":{"UserUnbanned":{"login":"bob","unbanned_login":"alice"}}}
+{"ts":9,"event":{"UserStopped":{"login":"alice"}}}
+{"ts":10,"event":{"UserStarted":{"login":"alice"}}}
+{"ts":11,"event":{"UserForgotten":{"login":"carol"}}}
+{"ts":12,"event":{"UserSettingsChanged":{"login":"alice","settings":{"language":"Ru","notifications_sound_prefix":"🔔"}}}}
+{"ts":13,"event":{"UserBlocked":{"login":"alice","blocked_login":"bob"}}}
+{"ts":14,"event":{"UserUnblocked":{"login":"alice","unblocked_login":"bob"}}}
+{"ts":15,"event":{"ThreadArchived":{"login":"alice","thread_id":"@bob"}}}
+{"ts":16,"event":{"ThreadUnarchived":{"login":"alice","thread_id":"@bob"}}}
+{"ts":17,"event":{"ThreadPinned":{"login":"alice","thread_id":"@bob"}}}
+{"ts":18,"event":{"ThreadPauseChanged":{"login":"alice","other_login":"bob","my_thread_id":"@bob","other_thread_id":"#x","paused":true}}}
+{"ts":19,"event":{"ThreadUnpinned":{"login":"alice","thread_id":"@bob"}}}
+{"ts":20,"event":{"OutgoingMessage":{"login":"alice","thread_id":"@bob","message_id":20,"delete_at":null,"source_message_id":3,"text":{"Digest":{"length":5,"crc32":12345}}}}}
+{"ts":21,"event":{"OutgoingMessageDeleted":{"login":"alice","thread_id":"@bob","message_id":20}}}
+{"ts":22,"event":{"UserInterestsChanged":{"login":"alice","tags":["chess","tea"]}}}
+{"ts":23,"event":{"TopicChanged":{"login":"alice","topic":null}}}
+{"ts":24,"event":{"RandomQueued":{"login":"alice","text":"anyone?","expires_at":100}}}
+{"ts":25,"event":{"RandomDequeued":{"login":"alice"}}}
+{"ts":26,"event":{"ThreadRequested":{"login":"alice","other_login":"bob","my_thread_id":"@bob","other_thread_id":"#y","anon_mode":"Both","expires_at":100}}}
+{"ts":27,"event":{"ThreadRequestMessage":{"login":"alice","thread_id":"@bob","text":"hi\nthere"}}}
+{"ts":28,"event":{"ThreadSlowModeChanged":{"login":"alice","thread_id":"@bob","seconds":60}}}
+{"ts":29,"event":{"ThreadExpiryChanged":{"login":"alice","thread_id":"@bob","expires_at":1000}}}
+{"ts":30,"event":{"ThreadSecretChanged":{"login":"alice","thread_id":"@bob","seconds":30}}}
+{"ts":31,"event":{"DefaultThreadChanged":{"login":"alice","thread_id":"@bob"}}}
+{"ts":32,"event":{"GreetingChanged":{"login":"alice","text":"hello"}}}
+{"ts":33,"event":{"DndChanged":{"login":"alice","window":{"start_minute":1380,"end_minute":480}}}}
+{"ts":34,"event":{"IncomingLimitChanged":{"login":"alice","max_incoming":5}}}
+{"ts":35,"event":{"ThreadNoteChanged":{"login":"alice","thread_id":"@bob","text":"likes tea"}}}
+{"ts":36,"event":{"ThreadLabeled":{"login":"alice","thread_id":"@bob","label":"work"}}}
+{"ts":37,"event":{"ThreadUnlabeled":{"login":"alice","thread_id":"@bob","label":"work"}}}
+{"ts":38,"event":{"MessageScheduled":{"login":"alice","id":1,"thread_id":"@bob","message_id":21,"text":"later","deliver_at":100}}}
+{"ts":39,"event":{"ScheduledMessageRemoved":{"login":"alice","id":1}}}
+{"ts":40,"event":{"ReminderSet":{"login":"alice","id":2,"text":"call bob","fire_at":100}}}
+{"ts":41,"event":{"ReminderRemoved":{"login":"alice","id":2}}}
+{"ts":42,"event":{"PollReceived":{"login":"bob","thread_id":"#x","poll_id":"42","question":"tea?","options":["yes","no"]}}}
+{"ts":43,"event":{"ThreadReceiptsChanged":{"login":"alice","thread_id":"@bob","enabled":false}}}
+{"ts":44,"event":{"ThreadPreviewsChanged":{"login":"alice","thread_id":"@bob","enabled":true}}}
+{"ts":45,"event":{"ThreadPriorityChanged":{"login":"alice","thread_id":"@bob","priority":true}}}
+{"ts":46,"event":{"ReceiptSent":{"login":"bob","thread_id":"#x","message_id":11}}}
+{"ts":47,"event":{"RoomCreated":{"login":"alice","room":"#books"}}}
+{"ts":48,"event":{"RoomJoined":{"login":"alice","room":"#books","alias":1}}}
+{"ts":49,"event":{"RoomLeft":{"login":"alice","room":"#books"}}}
+{"ts":50,"event":{"RoomMessage":{"login":"alice","room":"#books","message_id":22}}}
+{"ts":null,"event":{"MediaPolicyChanged":{"login":"admin","policy":{"voice":false}}}}
+"##;
+
+    fn every_event() -> Vec<LogRecord> {
+        EVERY_EVENT
+            .lines()
+            .map(|line| LogRecord::Event(serde_json::from_str(line).unwrap()))
+            .collect()
+    }
+
+    fn to_json(record: &LogRecord) -> serde_json::Value {
+        match record {
+            LogRecord::Event(logged) => serde_json::to_value(logged).unwrap(),
+            LogRecord::Snapshot(snapshot) => serde_json::to_value(snapshot).unwrap(),
+        }
+    }
+
+    fn encode_all(codec: &dyn EventCodec, records: &[LogRecord]) -> Vec<u8> {
+        let mut buf = vec![];
+        for record in records {
+            codec.encode(record, &mut buf).unwrap();
+        }
+        buf
+    }
+
+    /// Decodes records until the end, returning what each of them turned into.
+    fn decode_all(codec: &dyn EventCodec, mut data: &[u8]) -> Vec<Decoded> {
+        let mut decoded = vec![];
+        while let Some((record, _)) = codec.decode(&mut data).unwrap() {
+            decoded.push(record);
+        }
+        decoded
+    }
+
+    /// Round-trips a snapshot with a user in it, followed by every event.
+    fn assert_round_trip(format: LogFormat) {
+        let events = every_event();
+        let names = events
+            .iter()
+            .filter_map(|record| match record {
+                LogRecord::Event(logged) => Some(logged.event.name()),
+                LogRecord::Snapshot(_) => None,
+            })
+            .collect::<HashSet<_>>();
+        assert_eq!(names.len(), events.len(), "an event type is repeated");
+
+        let mut builder = CommandDispatcherBuilder::default();
+        for line in EVERY_EVENT.lines().take(2) {
+            let record = LogRecord::Event(serde_json::from_str(line).unwrap());
+            builder.replay_record(record).unwrap();
+        }
+        let mut records = vec![LogRecord::Snapshot(Box::new(builder.snapshot()))];
+        records.extend(events);
+
+        let codec = format.codec();
+        let data = encode_all(codec.as_ref(), &records);
+        let decoded = decode_all(codec.as_ref(), &data);
+        assert_eq!(decoded.len(), records.len());
+        for (record, decoded) in records.iter().zip(decoded) {
+            match decoded {
+                Decoded::Record(decoded) => assert_eq!(to_json(&decoded), to_json(record)),
+                Decoded::Corrupt(err) | Decoded::Torn(err) => panic!("{}: {:#}", format, err),
+            }
+        }
+    }
+
+    #[test]
+    fn every_event_round_trips_through_json() {
+        assert_round_trip(LogFormat::Json);
+    }
+
+    #[test]
+    fn every_event_round_trips_through_bincode() {
+        assert_round_trip(LogFormat::Bincode);
+    }
+
+    #[test]
+    fn json_checksum_mismatch_is_corrupt() {
+        let codec = LogFormat::Json.codec();
+        let mut data = encode_all(codec.as_ref(), &every_event()[..3]);
+        // Flip a letter of the second record's JSON, keeping it valid JSON.
+        let second = data.iter().position(|&c| c == b'\n').unwrap() + 1;
+        let index = second
+            + data[second..]
+                .windows(5)
+                .position(|w| w == b"alice")
+                .unwrap();
+        data[index] = b'A';
+
+        let decoded = decode_all(codec.as_ref(), &data);
+        assert!(matches!(decoded[0], Decoded::Record(_)));
+        match &decoded[1] {
+            Decoded::Corrupt(err) => assert!(err.to_string().starts_with("checksum mismatch")),
+            _ => panic!("the changed record is not corrupt"),
+        }
+        assert!(matches!(decoded[2], Decoded::Record(_)));
+    }
+
+    #[test]
+    fn json_lines_without_checksum_are_read() {
+        let codec = LogFormat::Json.codec();
+        let line = EVERY_EVENT.lines().next().unwrap();
+        let data = format!("{}\n", line);
+        let decoded = decode_all(codec.as_ref(), data.as_bytes());
+        assert!(matches!(decoded[..], [Decoded::Record(_)]));
+    }
+
+    #[test]
+    fn bincode_checksum_mismatch_is_corrupt() {
+        let codec = LogFormat::Bincode.codec();
+        let records = every_event();
+        let first_len = encode_all(codec.as_ref(), &records[..1]).len();
+        let mut data = encode_all(codec.as_ref(), &records[..3]);
+        data[first_len + FRAME_HEADER_LEN] ^= 0xff;
+
+        let decoded = decode_all(codec.as_ref(), &data);
+        assert!(matches!(decoded[0], Decoded::Record(_)));
+        match &decoded[1] {
+            Decoded::Corrupt(err) => assert!(err.to_string().starts_with("checksum mismatch")),
+            _ => panic!("the changed record is not corrupt"),
+        }
+        assert!(matches!(decoded[2], Decoded::Record(_)));
+    }
+
+    #[test]
+    fn bincode_corrupt_length_stops_reading() {
+        let codec = LogFormat::Bincode.codec();
+        let mut data = encode_all(codec.as_ref(), &every_event()[..2]);
+        data[0] ^= 0xff;
+        let err = codec.decode(&mut &data[..]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn header_names_format_and_version() {
+        for header in [
+            LogHeader::current(LogFormat::Json),
+            LogHeader::current(LogFormat::Bincode),
+        ] {
+            let bytes = header.to_bytes();
+            let (read, len) = LogHeader::read(&mut &bytes[..]).unwrap().unwrap();
+            assert_eq!(read, header);
+            assert_eq!(len, bytes.len() as u64);
+        }
+        let (legacy, len) = LogHeader::read(&mut &b"{\"UserStopped\":{}}\n"[..])
+            .unwrap()
+            .unwrap();
+        assert_eq!((legacy, len), (LogHeader::legacy(), 0));
+        // The first headers had no version.
+        let (first, _) = LogHeader::read(&mut &b"lovebot-event-log bincode\n"[..])
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.version, 1);
+        assert!(LogHeader::read(&mut &b"lovebot-event-log js"[..])
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn newer_versions_are_refused() {
+        let header = LogHeader {
+            format: LogFormat::Json,
+            version: CURRENT_VERSION + 1,
+        };
+        assert!(header.codec().is_err());
+    }
+}
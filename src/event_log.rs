@@ -1,16 +1,32 @@
-use anyhow::{Context, Result};
-use log::{debug, error};
+use anyhow::{bail, Context, Error, Result};
+use log::{debug, error, warn};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use thiserror::Error;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{
+    mpsc::{self, error::TryRecvError},
+    oneshot,
+};
 
 use std::{
-    io::{BufRead, ErrorKind, Write},
-    sync::Arc,
+    io::{BufRead, Write},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, UNIX_EPOCH},
 };
 
 use crate::{
-    data::{ThreadAnonimityMode, ThreadId},
+    command_dispatcher::Snapshot,
+    data::{
+        deserialize_login, deserialize_optional_thread_id, deserialize_thread_id, DndWindow,
+        MediaPolicy, MessageKind, SentText, ThreadAnonimityMode, ThreadId, UserSettings,
+    },
+    event_codec::{Decoded, EventCodec, LogFormat, LogHeader},
+    event_store::EventStore,
+    util::now_timestamp,
     User,
 };
 
@@ -19,95 +35,894 @@ use crate::{
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Event {
     UserConnected(UserConnectedEvent),
+    UserProfileUpdated(UserProfileUpdatedEvent),
     ThreadStarted(ThreadStartedEvent),
     ThreadMessageReceived(ThreadMessageReceivedEvent),
+    ThreadMediaReceived(ThreadMediaReceivedEvent),
     ThreadTerminated(ThreadTerminatedEvent),
     UserBanned(UserBannedEvent),
     UserUnbanned(UserUnbannedEvent),
     UserStopped(UserStoppedEvent),
     UserStarted(UserStartedEvent),
+    UserForgotten(UserForgottenEvent),
+    UserSettingsChanged(UserSettingsChangedEvent),
+    UserBlocked(UserBlockedEvent),
+    UserUnblocked(UserUnblockedEvent),
+    ThreadArchived(ThreadArchivedEvent),
+    ThreadUnarchived(ThreadUnarchivedEvent),
+    ThreadPinned(ThreadPinnedEvent),
+    ThreadPauseChanged(ThreadPauseChangedEvent),
+    ThreadUnpinned(ThreadUnpinnedEvent),
+    OutgoingMessage(OutgoingMessageEvent),
+    OutgoingMessageDeleted(OutgoingMessageDeletedEvent),
+    UserInterestsChanged(UserInterestsChangedEvent),
+    TopicChanged(TopicChangedEvent),
+    RandomQueued(RandomQueuedEvent),
+    RandomDequeued(RandomDequeuedEvent),
+    ThreadRequested(ThreadRequestedEvent),
+    ThreadRequestMessage(ThreadRequestMessageEvent),
+    ThreadSlowModeChanged(ThreadSlowModeChangedEvent),
+    ThreadExpiryChanged(ThreadExpiryChangedEvent),
+    ThreadSecretChanged(ThreadSecretChangedEvent),
+    DefaultThreadChanged(DefaultThreadChangedEvent),
+    GreetingChanged(GreetingChangedEvent),
+    DndChanged(DndChangedEvent),
+    IncomingLimitChanged(IncomingLimitChangedEvent),
+    ThreadNoteChanged(ThreadNoteChangedEvent),
+    ThreadLabeled(ThreadLabeledEvent),
+    ThreadUnlabeled(ThreadUnlabeledEvent),
+    MessageScheduled(MessageScheduledEvent),
+    ScheduledMessageRemoved(ScheduledMessageRemovedEvent),
+    ReminderSet(ReminderSetEvent),
+    ReminderRemoved(ReminderRemovedEvent),
+    PollReceived(PollReceivedEvent),
+    ThreadReceiptsChanged(ThreadReceiptsChangedEvent),
+    ThreadPreviewsChanged(ThreadPreviewsChangedEvent),
+    ThreadPriorityChanged(ThreadPriorityChangedEvent),
+    ReceiptSent(ReceiptSentEvent),
+    RoomCreated(RoomCreatedEvent),
+    RoomJoined(RoomJoinedEvent),
+    RoomLeft(RoomLeftEvent),
+    RoomMessage(RoomMessageEvent),
+    MediaPolicyChanged(MediaPolicyChangedEvent),
+}
+
+impl Event {
+    /// Returns the name of the event, as it is tagged in the log.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::UserConnected(_) => "UserConnected",
+            Event::UserProfileUpdated(_) => "UserProfileUpdated",
+            Event::ThreadStarted(_) => "ThreadStarted",
+            Event::ThreadMessageReceived(_) => "ThreadMessageReceived",
+            Event::ThreadMediaReceived(_) => "ThreadMediaReceived",
+            Event::ThreadTerminated(_) => "ThreadTerminated",
+            Event::UserBanned(_) => "UserBanned",
+            Event::UserUnbanned(_) => "UserUnbanned",
+            Event::UserStopped(_) => "UserStopped",
+            Event::UserStarted(_) => "UserStarted",
+            Event::UserForgotten(_) => "UserForgotten",
+            Event::UserSettingsChanged(_) => "UserSettingsChanged",
+            Event::UserBlocked(_) => "UserBlocked",
+            Event::UserUnblocked(_) => "UserUnblocked",
+            Event::ThreadArchived(_) => "ThreadArchived",
+            Event::ThreadUnarchived(_) => "ThreadUnarchived",
+            Event::ThreadPinned(_) => "ThreadPinned",
+            Event::ThreadPauseChanged(_) => "ThreadPauseChanged",
+            Event::ThreadUnpinned(_) => "ThreadUnpinned",
+            Event::OutgoingMessage(_) => "OutgoingMessage",
+            Event::OutgoingMessageDeleted(_) => "OutgoingMessageDeleted",
+            Event::UserInterestsChanged(_) => "UserInterestsChanged",
+            Event::TopicChanged(_) => "TopicChanged",
+            Event::RandomQueued(_) => "RandomQueued",
+            Event::RandomDequeued(_) => "RandomDequeued",
+            Event::ThreadRequested(_) => "ThreadRequested",
+            Event::ThreadRequestMessage(_) => "ThreadRequestMessage",
+            Event::ThreadSlowModeChanged(_) => "ThreadSlowModeChanged",
+            Event::ThreadExpiryChanged(_) => "ThreadExpiryChanged",
+            Event::ThreadSecretChanged(_) => "ThreadSecretChanged",
+            Event::DefaultThreadChanged(_) => "DefaultThreadChanged",
+            Event::GreetingChanged(_) => "GreetingChanged",
+            Event::DndChanged(_) => "DndChanged",
+            Event::IncomingLimitChanged(_) => "IncomingLimitChanged",
+            Event::ThreadNoteChanged(_) => "ThreadNoteChanged",
+            Event::ThreadLabeled(_) => "ThreadLabeled",
+            Event::ThreadUnlabeled(_) => "ThreadUnlabeled",
+            Event::MessageScheduled(_) => "MessageScheduled",
+            Event::ScheduledMessageRemoved(_) => "ScheduledMessageRemoved",
+            Event::ReminderSet(_) => "ReminderSet",
+            Event::ReminderRemoved(_) => "ReminderRemoved",
+            Event::PollReceived(_) => "PollReceived",
+            Event::ThreadReceiptsChanged(_) => "ThreadReceiptsChanged",
+            Event::ThreadPreviewsChanged(_) => "ThreadPreviewsChanged",
+            Event::ThreadPriorityChanged(_) => "ThreadPriorityChanged",
+            Event::ReceiptSent(_) => "ReceiptSent",
+            Event::RoomCreated(_) => "RoomCreated",
+            Event::RoomJoined(_) => "RoomJoined",
+            Event::RoomLeft(_) => "RoomLeft",
+            Event::RoomMessage(_) => "RoomMessage",
+            Event::MediaPolicyChanged(_) => "MediaPolicyChanged",
+        }
+    }
+
+    /// Returns the login of the user this event belongs to.
+    pub fn login(&self) -> &str {
+        match self {
+            Event::UserConnected(ev) => &ev.user.login,
+            Event::UserProfileUpdated(ev) => &ev.login,
+            Event::ThreadStarted(ev) => &ev.login,
+            Event::ThreadMessageReceived(ev) => &ev.login,
+            Event::ThreadMediaReceived(ev) => &ev.login,
+            Event::ThreadTerminated(ev) => &ev.login,
+            Event::UserBanned(ev) => &ev.login,
+            Event::UserUnbanned(ev) => &ev.login,
+            Event::UserStopped(ev) => &ev.login,
+            Event::UserStarted(ev) => &ev.login,
+            Event::UserForgotten(ev) => &ev.login,
+            Event::UserSettingsChanged(ev) => &ev.login,
+            Event::UserBlocked(ev) => &ev.login,
+            Event::UserUnblocked(ev) => &ev.login,
+            Event::ThreadArchived(ev) => &ev.login,
+            Event::ThreadUnarchived(ev) => &ev.login,
+            Event::ThreadPinned(ev) => &ev.login,
+            Event::ThreadPauseChanged(ev) => &ev.login,
+            Event::ThreadUnpinned(ev) => &ev.login,
+            Event::OutgoingMessage(ev) => &ev.login,
+            Event::OutgoingMessageDeleted(ev) => &ev.login,
+            Event::UserInterestsChanged(ev) => &ev.login,
+            Event::TopicChanged(ev) => &ev.login,
+            Event::RandomQueued(ev) => &ev.login,
+            Event::RandomDequeued(ev) => &ev.login,
+            Event::ThreadRequested(ev) => &ev.login,
+            Event::ThreadRequestMessage(ev) => &ev.login,
+            Event::ThreadSlowModeChanged(ev) => &ev.login,
+            Event::ThreadExpiryChanged(ev) => &ev.login,
+            Event::ThreadSecretChanged(ev) => &ev.login,
+            Event::DefaultThreadChanged(ev) => &ev.login,
+            Event::GreetingChanged(ev) => &ev.login,
+            Event::DndChanged(ev) => &ev.login,
+            Event::IncomingLimitChanged(ev) => &ev.login,
+            Event::ThreadNoteChanged(ev) => &ev.login,
+            Event::ThreadLabeled(ev) => &ev.login,
+            Event::ThreadUnlabeled(ev) => &ev.login,
+            Event::MessageScheduled(ev) => &ev.login,
+            Event::ScheduledMessageRemoved(ev) => &ev.login,
+            Event::ReminderSet(ev) => &ev.login,
+            Event::ReminderRemoved(ev) => &ev.login,
+            Event::PollReceived(ev) => &ev.login,
+            Event::ThreadReceiptsChanged(ev) => &ev.login,
+            Event::ThreadPreviewsChanged(ev) => &ev.login,
+            Event::ThreadPriorityChanged(ev) => &ev.login,
+            Event::ReceiptSent(ev) => &ev.login,
+            Event::RoomCreated(ev) => &ev.login,
+            Event::RoomJoined(ev) => &ev.login,
+            Event::RoomLeft(ev) => &ev.login,
+            Event::RoomMessage(ev) => &ev.login,
+            Event::MediaPolicyChanged(ev) => &ev.login,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UserConnectedEvent {
     pub user: User,
     pub chat_id: i64,
+    #[serde(default)]
+    pub connected_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserProfileUpdatedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    pub first_name: String,
+    pub last_name: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ThreadStartedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    #[serde(deserialize_with = "deserialize_login")]
+    pub other_login: String,
+    #[serde(deserialize_with = "deserialize_thread_id")]
+    pub my_thread_id: ThreadId,
+    #[serde(deserialize_with = "deserialize_thread_id")]
+    pub other_thread_id: ThreadId,
+    pub anon_mode: ThreadAnonimityMode,
+    #[serde(default)]
+    pub is_initiator: Option<bool>,
+    #[serde(default)]
+    pub created_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThreadRequestedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
     pub login: String,
+    #[serde(deserialize_with = "deserialize_login")]
     pub other_login: String,
+    #[serde(deserialize_with = "deserialize_thread_id")]
     pub my_thread_id: ThreadId,
+    #[serde(deserialize_with = "deserialize_thread_id")]
     pub other_thread_id: ThreadId,
     pub anon_mode: ThreadAnonimityMode,
+    pub expires_at: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThreadRequestMessageEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    #[serde(deserialize_with = "deserialize_thread_id")]
+    pub thread_id: ThreadId,
+    pub text: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ThreadMessageReceivedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    pub message_id: i32,
+    #[serde(deserialize_with = "deserialize_thread_id")]
+    pub thread_id: String,
+    #[serde(default)]
+    pub kind: Option<MessageKind>,
+}
+
+/// A photo sent or received in a thread, see `ThreadMessageReceivedEvent`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThreadMediaReceivedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
     pub login: String,
     pub message_id: i32,
+    #[serde(deserialize_with = "deserialize_thread_id")]
     pub thread_id: String,
+    pub kind: MessageKind,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OutgoingMessageEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    #[serde(deserialize_with = "deserialize_thread_id")]
+    pub thread_id: ThreadId,
+    pub message_id: i32,
+    #[serde(default)]
+    pub delete_at: Option<i64>,
+    #[serde(default)]
+    pub source_message_id: Option<i32>,
+    /// Missing for media and for messages sent before texts were recorded.
+    #[serde(default)]
+    pub text: Option<SentText>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OutgoingMessageDeletedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    #[serde(deserialize_with = "deserialize_thread_id")]
+    pub thread_id: ThreadId,
+    pub message_id: i32,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ThreadTerminatedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    #[serde(deserialize_with = "deserialize_login")]
+    pub other_login: String,
+    #[serde(deserialize_with = "deserialize_thread_id")]
+    pub my_thread_id: ThreadId,
+    #[serde(deserialize_with = "deserialize_thread_id")]
+    pub other_thread_id: ThreadId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThreadArchivedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
     pub login: String,
+    #[serde(deserialize_with = "deserialize_thread_id")]
+    pub thread_id: ThreadId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThreadUnarchivedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    #[serde(deserialize_with = "deserialize_thread_id")]
+    pub thread_id: ThreadId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThreadPinnedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    #[serde(deserialize_with = "deserialize_thread_id")]
+    pub thread_id: ThreadId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThreadPauseChangedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    #[serde(deserialize_with = "deserialize_login")]
     pub other_login: String,
+    #[serde(deserialize_with = "deserialize_thread_id")]
     pub my_thread_id: ThreadId,
+    #[serde(deserialize_with = "deserialize_thread_id")]
     pub other_thread_id: ThreadId,
+    pub paused: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThreadUnpinnedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    #[serde(deserialize_with = "deserialize_thread_id")]
+    pub thread_id: ThreadId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThreadSlowModeChangedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    #[serde(deserialize_with = "deserialize_thread_id")]
+    pub thread_id: ThreadId,
+    pub seconds: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThreadExpiryChangedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    #[serde(deserialize_with = "deserialize_thread_id")]
+    pub thread_id: ThreadId,
+    pub expires_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThreadSecretChangedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    #[serde(deserialize_with = "deserialize_thread_id")]
+    pub thread_id: ThreadId,
+    pub seconds: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DefaultThreadChangedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    #[serde(deserialize_with = "deserialize_optional_thread_id")]
+    pub thread_id: Option<ThreadId>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThreadNoteChangedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    #[serde(deserialize_with = "deserialize_thread_id")]
+    pub thread_id: ThreadId,
+    pub text: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThreadLabeledEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    #[serde(deserialize_with = "deserialize_thread_id")]
+    pub thread_id: ThreadId,
+    pub label: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThreadUnlabeledEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    #[serde(deserialize_with = "deserialize_thread_id")]
+    pub thread_id: ThreadId,
+    pub label: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MessageScheduledEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    pub id: u64,
+    #[serde(deserialize_with = "deserialize_thread_id")]
+    pub thread_id: ThreadId,
+    pub message_id: i32,
+    pub text: String,
+    pub deliver_at: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScheduledMessageRemovedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    pub id: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReminderSetEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    pub id: u64,
+    pub text: String,
+    pub fire_at: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReminderRemovedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    pub id: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThreadReceiptsChangedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    #[serde(deserialize_with = "deserialize_thread_id")]
+    pub thread_id: ThreadId,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThreadPreviewsChangedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    #[serde(deserialize_with = "deserialize_thread_id")]
+    pub thread_id: ThreadId,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThreadPriorityChangedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    #[serde(deserialize_with = "deserialize_thread_id")]
+    pub thread_id: ThreadId,
+    pub priority: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReceiptSentEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    #[serde(deserialize_with = "deserialize_thread_id")]
+    pub thread_id: ThreadId,
+    pub message_id: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RoomCreatedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    pub room: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RoomJoinedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    pub room: String,
+    pub alias: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RoomLeftEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    pub room: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RoomMessageEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    pub room: String,
+    pub message_id: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PollReceivedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    #[serde(deserialize_with = "deserialize_thread_id")]
+    pub thread_id: ThreadId,
+    pub poll_id: String,
+    pub question: String,
+    pub options: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UserBannedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
     pub login: String,
+    #[serde(deserialize_with = "deserialize_login")]
     pub banned_login: String,
+    #[serde(deserialize_with = "deserialize_thread_id")]
     pub banned_thread_id: String,
+    #[serde(default)]
+    pub reason: Option<String>,
+    #[serde(default)]
+    pub banned_at: Option<i64>,
+    #[serde(default)]
+    pub expires_at: Option<i64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UserUnbannedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
     pub login: String,
+    #[serde(deserialize_with = "deserialize_login")]
     pub unbanned_login: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserInterestsChangedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TopicChangedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    pub topic: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RandomQueuedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    pub text: String,
+    pub expires_at: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RandomDequeuedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GreetingChangedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    pub text: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DndChangedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    pub window: Option<DndWindow>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IncomingLimitChangedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    pub max_incoming: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserBlockedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    #[serde(deserialize_with = "deserialize_login")]
+    pub blocked_login: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserUnblockedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    #[serde(deserialize_with = "deserialize_login")]
+    pub unblocked_login: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UserStoppedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
     pub login: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UserStartedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
     pub login: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserForgottenEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserSettingsChangedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    pub settings: UserSettings,
+}
+
+/// The media policy is global, `login` is the admin who has changed it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MediaPolicyChangedEvent {
+    #[serde(deserialize_with = "deserialize_login")]
+    pub login: String,
+    pub policy: MediaPolicy,
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 
+/// An event as it is stored in the log, with the time it has been written at.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoggedEvent {
+    /// Unix timestamp in seconds. Events written before timestamps were
+    /// introduced are stored bare and have none.
+    pub ts: Option<i64>,
+    pub event: Event,
+}
+
+/// A record of the event log. A snapshot may only be the first one.
+#[derive(Serialize, Deserialize)]
+pub enum LogRecord {
+    Event(LoggedEvent),
+    Snapshot(Box<Snapshot>),
+}
+
+/// Writes a snapshot as the first record of a new event log.
+pub fn write_snapshot<W: Write>(
+    mut writer: W,
+    format: LogFormat,
+    snapshot: Snapshot,
+) -> Result<()> {
+    let mut buf = LogHeader::current(format).to_bytes();
+    format
+        .codec()
+        .encode(&LogRecord::Snapshot(Box::new(snapshot)), &mut buf)?;
+    writer.write_all(&buf)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Rewrites an event log at the current version, keeping its format. A torn
+/// record at the end is dropped. Returns the number of records written.
+pub fn migrate_event_log<R: BufRead, W: Write>(reader: R, mut writer: W) -> Result<u64> {
+    let mut reader = EventLogReader::new(reader)?;
+    let format = reader.header().format;
+    let codec = format.codec();
+    writer.write_all(&LogHeader::current(format).to_bytes())?;
+    let mut count = 0;
+    let mut buf = vec![];
+    for record in reader.iter_records() {
+        buf.clear();
+        codec.encode(&record?, &mut buf)?;
+        writer.write_all(&buf)?;
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+/// How `EventLogReader::export` writes the events of a log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// An indented JSON array of events along with their timestamps.
+    PrettyJson,
+    /// A row per event with its time, type, login, other login and thread id,
+    /// and the rest of its fields as JSON. Fields that the event does not have
+    /// are left empty.
+    Csv,
+}
+
+impl FromStr for ExportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(ExportFormat::PrettyJson),
+            "csv" => Ok(ExportFormat::Csv),
+            _ => bail!("unknown export format: {}", s),
+        }
+    }
+}
+
+const CSV_HEADER: &str = "timestamp,type,login,other_login,thread_id,extra";
+
+/// Quotes a CSV field if it needs to be.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// Removes a field from the fields of an event and returns it as text.
+fn take_field(fields: &mut Map<String, Value>, name: &str) -> Option<String> {
+    match fields.remove(name)? {
+        Value::Null => None,
+        Value::String(text) => Some(text),
+        value => Some(value.to_string()),
+    }
+}
+
+fn write_csv_row<W: Write>(writer: &mut W, logged: &LoggedEvent) -> Result<()> {
+    let name = logged.event.name();
+    let mut fields = match serde_json::to_value(&logged.event)?[name].take() {
+        Value::Object(fields) => fields,
+        _ => Map::new(),
+    };
+    fields.remove("login");
+    let other_login = take_field(&mut fields, "other_login");
+    let thread_id =
+        take_field(&mut fields, "thread_id").or_else(|| take_field(&mut fields, "my_thread_id"));
+    let timestamp = logged.ts.map(|ts| {
+        let time = UNIX_EPOCH + Duration::from_secs(ts.max(0) as u64);
+        humantime::format_rfc3339_seconds(time).to_string()
+    });
+    let extra = (!fields.is_empty()).then(|| Value::Object(fields).to_string());
+    let row = [
+        timestamp.as_deref(),
+        Some(name),
+        Some(logged.event.login()),
+        other_login.as_deref(),
+        thread_id.as_deref(),
+        extra.as_deref(),
+    ]
+    .map(|field| csv_field(field.unwrap_or_default()));
+    writeln!(writer, "{}", row.join(","))?;
+    Ok(())
+}
+
+/// Writes the events of `records` in `format` and returns how many there were.
+/// A snapshot is left out, only the events after it are written.
+pub fn export_records<W: Write>(
+    records: impl Iterator<Item = Result<LogRecord>>,
+    format: ExportFormat,
+    mut writer: W,
+) -> Result<u64> {
+    let mut count = 0;
+    match format {
+        ExportFormat::PrettyJson => write!(writer, "[")?,
+        ExportFormat::Csv => writeln!(writer, "{}", CSV_HEADER)?,
+    }
+    for record in records {
+        let logged = match record? {
+            LogRecord::Event(logged) => logged,
+            LogRecord::Snapshot(_) => {
+                warn!("leaving the snapshot of event log out of the export");
+                continue;
+            }
+        };
+        match format {
+            ExportFormat::PrettyJson => {
+                let separator = if count == 0 { "\n" } else { ",\n" };
+                // Strings in JSON cannot contain raw newlines, so this only
+                // indents the lines of the record.
+                let json = serde_json::to_string_pretty(&logged)?.replace('\n', "\n  ");
+                write!(writer, "{}  {}", separator, json)?;
+            }
+            ExportFormat::Csv => write_csv_row(&mut writer, &logged)?,
+        }
+        count += 1;
+    }
+    if format == ExportFormat::PrettyJson {
+        writeln!(writer, "{}]", if count == 0 { "" } else { "\n" })?;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
 pub struct EventLogReader<R> {
     reader: R,
+    header: LogHeader,
+    codec: Box<dyn EventCodec>,
+    skip_corrupt: bool,
+    skipped: usize,
+    offset: u64,
+    torn_tail: Option<u64>,
+    record_number: usize,
 }
 
 impl<R: BufRead> EventLogReader<R> {
-    pub fn new(reader: R) -> Self {
-        Self { reader }
+    /// Reads the header of the log to pick the codec. A log without one is
+    /// read as JSON lines of version 0.
+    pub fn new(mut reader: R) -> Result<Self> {
+        let header = LogHeader::read(&mut reader).context("failed to read event log header")?;
+        let (header, offset, torn_tail) = match header {
+            Some((header, len)) => (header, len, None),
+            None => {
+                warn!("ignoring torn header of event log");
+                (LogHeader::legacy(), 0, Some(0))
+            }
+        };
+        Ok(Self {
+            reader,
+            header,
+            codec: header.codec()?,
+            skip_corrupt: false,
+            skipped: 0,
+            offset,
+            torn_tail,
+            record_number: 0,
+        })
+    }
+
+    pub fn header(&self) -> LogHeader {
+        self.header
+    }
+
+    /// Makes the reader log and skip records that fail their checksum or do
+    /// not parse, instead of failing.
+    pub fn skip_corrupt(mut self, skip_corrupt: bool) -> Self {
+        self.skip_corrupt = skip_corrupt;
+        self
+    }
+
+    /// How many corrupt records have been skipped so far.
+    pub fn skipped(&self) -> usize {
+        self.skipped
     }
 
-    pub fn iter_events(&mut self) -> impl Iterator<Item = Result<Event>> + '_ {
-        let mut data = vec![];
+    /// The offset of the record that has been cut short by a crash during
+    /// writing, if the log ends with one. The log should be truncated there
+    /// before anything is appended to it.
+    pub fn torn_tail(&self) -> Option<u64> {
+        self.torn_tail
+    }
+
+    /// Reads the records of the log. Records of older versions are upgraded to
+    /// the current one, so that old logs keep working.
+    pub fn iter_records(&mut self) -> impl Iterator<Item = Result<LogRecord>> + '_ {
+        std::iter::from_fn(move || self.next())
+    }
+}
+
+impl<R: BufRead> Iterator for EventLogReader<R> {
+    type Item = Result<LogRecord>;
 
-        std::iter::from_fn(move || -> Option<Result<Event>> {
-            data.clear();
-            if let Err(err) = self.reader.read_until(b'\n', &mut data) {
-                if err.kind() == ErrorKind::UnexpectedEof && data.is_empty() {
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let record_offset = self.offset;
+            let (decoded, size) = match self.codec.decode(&mut self.reader) {
+                Ok(Some(decoded)) => decoded,
+                Ok(None) => return None,
+                Err(err) => return Some(Err(err.into())),
+            };
+            self.record_number += 1;
+            self.offset += size;
+            match decoded {
+                Decoded::Record(record) => return Some(Ok(record)),
+                Decoded::Torn(err) => {
+                    warn!(
+                        "ignoring torn record at the end of event log (record {}): {:#}",
+                        self.record_number, err
+                    );
+                    self.torn_tail = Some(record_offset);
                     return None;
                 }
-                return Some(Err(err.into()));
-            }
-            if data.is_empty() {
-                return None;
+                Decoded::Corrupt(err) if self.skip_corrupt => {
+                    warn!(
+                        "skipping corrupt record {} of event log: {:#}",
+                        self.record_number, err
+                    );
+                    self.skipped += 1;
+                }
+                Decoded::Corrupt(err) => {
+                    return Some(Err(
+                        err.context(format!("record {} is corrupt", self.record_number))
+                    ))
+                }
             }
-            Some(serde_json::from_slice(&data).map_err(|err| err.into()))
-        })
+        }
     }
 }
 
@@ -128,9 +943,14 @@ enum EventCollection {
 #[derive(Clone)]
 pub struct EventServiceHandle {
     sender: mpsc::UnboundedSender<EventRequest>,
+    events_written: Arc<AtomicU64>,
 }
 
 impl EventServiceHandle {
+    pub fn events_written(&self) -> u64 {
+        self.events_written.load(Ordering::Relaxed)
+    }
+
     pub fn write(&self, event: Event) -> EventTracker {
         self.do_write(EventCollection::One(event))
     }
@@ -175,51 +995,122 @@ impl EventTracker {
 
 ////////////////////////////////////////////////////////////////////////////////
 
-pub struct EventService<W> {
+/// When `EventService` stops collecting events into a batch and writes it.
+#[derive(Debug, Clone, Copy)]
+pub struct BatchConfig {
+    /// A batch is written once it has this many events. A batch written with
+    /// `write_batch` is never split, so a batch may end up larger.
+    pub max_events: usize,
+    /// A batch is written once its events take up this many bytes, as told by
+    /// bincode; records in the log take up about as much.
+    pub max_bytes: usize,
+    /// How long to wait for more events after the first one of a batch. With
+    /// no delay, a batch only takes the events that are already waiting.
+    pub max_delay: Duration,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            max_events: 1000,
+            max_bytes: usize::MAX,
+            max_delay: Duration::ZERO,
+        }
+    }
+}
+
+fn event_size(event: &Event) -> usize {
+    bincode::serialized_size(event).unwrap_or_default() as usize
+}
+
+pub struct EventService<S> {
     receiver: mpsc::UnboundedReceiver<EventRequest>,
-    writer: W,
+    store: S,
+    config: BatchConfig,
+    events_written: Arc<AtomicU64>,
 }
 
-impl<W: Write> EventService<W> {
-    pub fn new(writer: W) -> (Self, EventServiceHandle) {
+impl<S: EventStore> EventService<S> {
+    pub fn new(store: S, config: BatchConfig) -> (Self, EventServiceHandle) {
         let (sender, receiver) = mpsc::unbounded_channel();
-        (Self { receiver, writer }, EventServiceHandle { sender })
+        let events_written = Arc::new(AtomicU64::new(0));
+        (
+            Self {
+                receiver,
+                store,
+                config,
+                events_written: events_written.clone(),
+            },
+            EventServiceHandle {
+                sender,
+                events_written,
+            },
+        )
     }
 
     pub async fn run(&mut self) -> Result<()> {
+        let config = self.config;
         loop {
             let mut events = vec![];
             let mut channels = vec![];
+            let mut bytes = 0;
 
             let mut process_request = |request: EventRequest| {
                 channels.push(request.result);
                 match request.events {
-                    EventCollection::One(ev) => events.push(ev),
-                    EventCollection::Many(evs) => events.extend(evs),
+                    EventCollection::One(ev) => {
+                        bytes += event_size(&ev);
+                        events.push(ev);
+                    }
+                    EventCollection::Many(evs) => {
+                        bytes += evs.iter().map(event_size).sum::<usize>();
+                        events.extend(evs);
+                    }
                 }
-                events.len() < 1000
+                events.len() < config.max_events && bytes < config.max_bytes
             };
 
             let request = match self.receiver.recv().await {
                 Some(request) => request,
                 None => return Ok(()),
             };
-            process_request(request);
+            let deadline = tokio::time::Instant::now() + config.max_delay;
+            let mut is_open = process_request(request);
 
-            while let Ok(request) = self.receiver.try_recv() {
-                if !process_request(request) {
-                    break;
-                }
+            while is_open {
+                let request = match self.receiver.try_recv() {
+                    Ok(request) => request,
+                    Err(TryRecvError::Empty) => {
+                        let remaining =
+                            deadline.saturating_duration_since(tokio::time::Instant::now());
+                        if remaining.is_zero() {
+                            break;
+                        }
+                        match tokio::time::timeout(remaining, self.receiver.recv()).await {
+                            Ok(Some(request)) => request,
+                            // The rest of the batch is still written before
+                            // the service stops.
+                            Ok(None) | Err(_) => break,
+                        }
+                    }
+                    Err(TryRecvError::Disconnected) => break,
+                };
+                is_open = process_request(request);
             }
 
+            let count = events.len();
             let result = self
-                .write_events(&events)
+                .write_events(events)
                 .context("failed to write events")
                 .map_err(|err| PersistenceError {
                     inner: Arc::new(err),
                 });
             match result.as_ref() {
-                Ok(()) => debug!("wrote {} events to log", events.len()),
+                Ok(()) => {
+                    self.events_written
+                        .fetch_add(count as u64, Ordering::Relaxed);
+                    debug!("wrote {} events to log", count);
+                }
                 Err(err) => error!("failed to write events: {}", err),
             }
 
@@ -229,12 +1120,351 @@ impl<W: Write> EventService<W> {
         }
     }
 
-    fn write_events(&mut self, events: &[Event]) -> Result<()> {
-        for event in events {
-            serde_json::to_writer(&mut self.writer, event)?;
-            self.writer.write(b"\n")?;
+    fn write_events(&mut self, events: Vec<Event>) -> Result<()> {
+        let ts = now_timestamp();
+        let events = events
+            .into_iter()
+            .map(|event| LoggedEvent {
+                ts: Some(ts),
+                event,
+            })
+            .collect();
+        self.store.append(events)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::command_dispatcher::CommandDispatcherBuilder;
+
+    use std::sync::Mutex;
+
+    /// Keeps the sizes of the batches it has been given and the timestamps
+    /// of the events.
+    #[derive(Clone, Default)]
+    struct MemoryStore {
+        batches: Arc<Mutex<Vec<usize>>>,
+        timestamps: Arc<Mutex<Vec<Option<i64>>>>,
+    }
+
+    impl MemoryStore {
+        fn batches(&self) -> Vec<usize> {
+            self.batches.lock().unwrap().clone()
+        }
+
+        fn timestamps(&self) -> Vec<Option<i64>> {
+            self.timestamps.lock().unwrap().clone()
+        }
+    }
+
+    impl EventStore for MemoryStore {
+        fn append(&mut self, events: Vec<LoggedEvent>) -> Result<()> {
+            self.batches.lock().unwrap().push(events.len());
+            self.timestamps
+                .lock()
+                .unwrap()
+                .extend(events.iter().map(|logged| logged.ts));
+            Ok(())
+        }
+
+        fn iter(&mut self) -> Box<dyn Iterator<Item = Result<LogRecord>> + '_> {
+            Box::new(std::iter::empty())
+        }
+    }
+
+    fn stopped(login: &str) -> Event {
+        Event::UserStopped(UserStoppedEvent {
+            login: login.into(),
+        })
+    }
+
+    fn spawn_service(
+        config: BatchConfig,
+    ) -> (
+        MemoryStore,
+        EventServiceHandle,
+        tokio::task::JoinHandle<Result<()>>,
+    ) {
+        let store = MemoryStore::default();
+        let (mut service, handle) = EventService::new(store.clone(), config);
+        let task = tokio::spawn(async move { service.run().await });
+        (store, handle, task)
+    }
+
+    async fn wait_all(trackers: Vec<EventTracker>) {
+        for tracker in trackers {
+            tracker.wait_written().await.unwrap();
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn default_config_writes_waiting_events_at_once() {
+        let (store, handle, task) = spawn_service(BatchConfig::default());
+        let trackers = (0..5).map(|_| handle.write(stopped("alice"))).collect();
+        wait_all(trackers).await;
+        let tracker = handle.write(stopped("alice"));
+        tracker.wait_written().await.unwrap();
+        drop(handle);
+        task.await.unwrap().unwrap();
+        assert_eq!(store.batches(), vec![5, 1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn batch_is_written_after_delay() {
+        let config = BatchConfig {
+            max_delay: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let (store, handle, task) = spawn_service(config);
+        let mut trackers = vec![];
+        for _ in 0..3 {
+            trackers.push(handle.write(stopped("alice")));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        wait_all(trackers).await;
+        assert_eq!(store.batches(), vec![3]);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        handle.write(stopped("bob")).wait_written().await.unwrap();
+        assert_eq!(store.batches(), vec![3, 1]);
+        drop(handle);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn batch_is_written_once_event_cap_is_hit() {
+        let config = BatchConfig {
+            max_events: 2,
+            max_delay: Duration::from_secs(3600),
+            ..Default::default()
+        };
+        let (store, handle, task) = spawn_service(config);
+        let trackers = (0..5).map(|_| handle.write(stopped("alice"))).collect();
+        wait_all(trackers).await;
+        assert_eq!(store.batches(), vec![2, 2, 1]);
+        drop(handle);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn batch_is_written_once_byte_cap_is_hit() {
+        let config = BatchConfig {
+            max_bytes: 3 * event_size(&stopped("alice")),
+            max_delay: Duration::from_secs(3600),
+            ..Default::default()
+        };
+        let (store, handle, task) = spawn_service(config);
+        let trackers = (0..7).map(|_| handle.write(stopped("alice"))).collect();
+        wait_all(trackers).await;
+        assert_eq!(store.batches(), vec![3, 3, 1]);
+        drop(handle);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn write_batch_is_never_split() {
+        let config = BatchConfig {
+            max_events: 2,
+            ..Default::default()
+        };
+        let (store, handle, task) = spawn_service(config);
+        let events = (0..5).map(|_| stopped("alice")).collect();
+        handle.write_batch(events).wait_written().await.unwrap();
+        assert_eq!(store.batches(), vec![5]);
+        drop(handle);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pending_batch_is_written_on_shutdown() {
+        let config = BatchConfig {
+            max_delay: Duration::from_secs(3600),
+            ..Default::default()
+        };
+        let (store, handle, task) = spawn_service(config);
+        let trackers: Vec<_> = (0..3).map(|_| handle.write(stopped("alice"))).collect();
+        drop(handle);
+        task.await.unwrap().unwrap();
+        wait_all(trackers).await;
+        assert_eq!(store.batches(), vec![3]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn every_tracker_is_answered_under_delayed_batching() {
+        let config = BatchConfig {
+            max_events: 4,
+            max_delay: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let (store, handle, task) = spawn_service(config);
+        let mut trackers = vec![];
+        for i in 0..50 {
+            trackers.push(handle.write(stopped("alice")));
+            if i % 7 == 0 {
+                tokio::time::sleep(Duration::from_millis(15)).await;
+            }
+        }
+        wait_all(trackers).await;
+        assert_eq!(handle.events_written(), 50);
+        assert_eq!(store.batches().iter().sum::<usize>(), 50);
+        assert!(store.batches().iter().all(|&size| size <= 4));
+        drop(handle);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn events_are_written_with_the_time() {
+        let (store, handle, task) = spawn_service(BatchConfig::default());
+        let before = now_timestamp();
+        handle.write(stopped("alice")).wait_written().await.unwrap();
+        let after = now_timestamp();
+        drop(handle);
+        task.await.unwrap().unwrap();
+        let timestamps = store.timestamps();
+        assert_eq!(timestamps.len(), 1);
+        assert!((before..=after).contains(&timestamps[0].unwrap()));
+    }
+
+    #[test]
+    fn log_of_mixed_formats_is_read() {
+        // Bare events came first, then events with timestamps, then with
+        // checksums. A log without a header may have all of them.
+        let mut log = String::from(r#"{"UserStopped":{"login":"alice"}}"#);
+        log.push('\n');
+        log.push_str(r#"{"ts":5,"event":{"UserStarted":{"login":"alice"}}}"#);
+        log.push('\n');
+        let mut buf = vec![];
+        let logged = LoggedEvent {
+            ts: Some(6),
+            event: stopped("bob"),
+        };
+        LogFormat::Json
+            .codec()
+            .encode(&LogRecord::Event(logged), &mut buf)
+            .unwrap();
+        log.push_str(std::str::from_utf8(&buf).unwrap());
+
+        let reader = EventLogReader::new(log.as_bytes()).unwrap();
+        assert_eq!(reader.header(), LogHeader::legacy());
+        let events = reader
+            .map(|record| match record.unwrap() {
+                LogRecord::Event(logged) => (
+                    logged.ts,
+                    logged.event.name(),
+                    logged.event.login().to_string(),
+                ),
+                LogRecord::Snapshot(_) => unreachable!("no snapshot has been written"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            [
+                (None, "UserStopped", "alice".to_string()),
+                (Some(5), "UserStarted", "alice".to_string()),
+                (Some(6), "UserStopped", "bob".to_string()),
+            ]
+        );
+    }
+
+    fn encode_event(format: LogFormat, login: &str, buf: &mut Vec<u8>) {
+        let logged = LoggedEvent {
+            ts: Some(1),
+            event: stopped(login),
+        };
+        format
+            .codec()
+            .encode(&LogRecord::Event(logged), buf)
+            .unwrap();
+    }
+
+    /// Reads the logins of the events of a log along with its torn tail.
+    fn read_logins(log: &[u8]) -> (Vec<String>, Option<u64>) {
+        let mut reader = EventLogReader::new(log).unwrap();
+        let logins = reader
+            .iter_records()
+            .map(|record| match record.unwrap() {
+                LogRecord::Event(logged) => logged.event.login().to_string(),
+                LogRecord::Snapshot(_) => unreachable!("no snapshot has been written"),
+            })
+            .collect();
+        (logins, reader.torn_tail())
+    }
+
+    #[test]
+    fn log_cut_anywhere_keeps_complete_records() {
+        let logins = ["alice", "bob", "carol"];
+        for format in [LogFormat::Json, LogFormat::Bincode] {
+            let mut log = LogHeader::current(format).to_bytes();
+            let mut ends = vec![log.len()];
+            for login in logins {
+                encode_event(format, login, &mut log);
+                ends.push(log.len());
+            }
+
+            for cut in 0..=log.len() {
+                let (read, torn_tail) = read_logins(&log[..cut]);
+                let complete = ends[1..].iter().filter(|&&end| end <= cut).count();
+                let start = ends[complete];
+                let context = format!("{:?} log cut at {}", format, cut);
+                if cut == 0 || cut == start {
+                    assert_eq!(read, logins[..complete], "{}", context);
+                    assert_eq!(torn_tail, None, "{}", context);
+                    continue;
+                }
+                if cut < ends[0] {
+                    assert!(read.is_empty(), "{}", context);
+                    assert_eq!(torn_tail, Some(0), "{}", context);
+                    continue;
+                }
+                // A JSON line is whole without its checksum or its newline.
+                let whole_line = format == LogFormat::Json
+                    && (cut == ends[complete + 1] - 1 || log[cut] == b'\t');
+                if whole_line {
+                    assert_eq!(read, logins[..complete + 1], "{}", context);
+                    assert_eq!(torn_tail, None, "{}", context);
+                    continue;
+                }
+                assert_eq!(read, logins[..complete], "{}", context);
+                assert_eq!(torn_tail, Some(start as u64), "{}", context);
+
+                // Appending after the truncated log keeps it readable, which is
+                // what happens on start.
+                let mut repaired = log[..start].to_vec();
+                encode_event(format, "dave", &mut repaired);
+                let (read, torn_tail) = read_logins(&repaired);
+                assert_eq!(read.last().unwrap(), "dave", "{}", context);
+                assert_eq!(read[..complete], logins[..complete], "{}", context);
+                assert_eq!(torn_tail, None, "{}", context);
+            }
         }
-        self.writer.flush()?;
-        Ok(())
+    }
+
+    #[test]
+    fn migrated_log_replays_the_same() {
+        let log = r##"{"UserConnected":{"user":{"login":"alice","first_name":"Alice","last_name":null},"chat_id":1}}
+{"ts":6,"event":{"UserConnected":{"user":{"login":"bob","first_name":"Bob","last_name":null},"chat_id":2}}}
+{"ts":7,"event":{"ThreadStarted":{"login":"alice","other_login":"bob","my_thread_id":"@bob","other_thread_id":"#x","anon_mode":"Me"}}}
+{"ts":7,"event":{"ThreadStarted":{"login":"bob","other_login":"alice","my_thread_id":"#x","other_thread_id":"@bob","anon_mode":"Me"}}}
+{"UserStopped":{"login":"bob"}}
+"##;
+        let mut migrated = vec![];
+        assert_eq!(migrate_event_log(log.as_bytes(), &mut migrated).unwrap(), 5);
+        assert!(migrated.starts_with(&LogHeader::current(LogFormat::Json).to_bytes()));
+        let reader = EventLogReader::new(migrated.as_slice()).unwrap();
+        assert_eq!(reader.header(), LogHeader::current(LogFormat::Json));
+
+        let replay = |log: &[u8]| {
+            let builder = CommandDispatcherBuilder::from_event_log(log, false).unwrap();
+            serde_json::to_value(builder.snapshot()).unwrap()
+        };
+        assert_eq!(replay(&migrated), replay(log.as_bytes()));
+        // Migrating again changes nothing.
+        let mut remigrated = vec![];
+        migrate_event_log(migrated.as_slice(), &mut remigrated).unwrap();
+        assert_eq!(remigrated, migrated);
     }
 }
@@ -0,0 +1,970 @@
+use anyhow::{bail, ensure, Context, Error, Result};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use log::{error, info};
+use rusqlite::{params, Connection, OpenFlags};
+use serde_json::json;
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, ErrorKind, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::mpsc,
+    thread::{self, JoinHandle},
+};
+
+use crate::{
+    event_codec::{EventCodec, LogFormat, LogHeader},
+    event_log::{EventLogReader, LogRecord, LoggedEvent},
+    migrations::{self, CURRENT_VERSION},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Where events are persisted and replayed from.
+pub trait EventStore: Send {
+    fn append(&mut self, events: Vec<LoggedEvent>) -> Result<()>;
+
+    /// Reads the records of the store from the start.
+    fn iter(&mut self) -> Box<dyn Iterator<Item = Result<LogRecord>> + '_>;
+}
+
+impl<S: EventStore + ?Sized> EventStore for Box<S> {
+    fn append(&mut self, events: Vec<LoggedEvent>) -> Result<()> {
+        (**self).append(events)
+    }
+
+    fn iter(&mut self) -> Box<dyn Iterator<Item = Result<LogRecord>> + '_> {
+        (**self).iter()
+    }
+}
+
+/// The store given on the command line: `sqlite:<path>` for a SQLite database,
+/// `file:<path>` or just a path for an event log.
+#[derive(Debug, Clone)]
+pub enum Storage {
+    File(PathBuf),
+    Sqlite(PathBuf),
+}
+
+impl FromStr for Storage {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, path) = s.split_once(':').unwrap_or(("file", s));
+        ensure!(!path.is_empty(), "storage path is empty");
+        match kind {
+            "file" => Ok(Storage::File(path.into())),
+            "sqlite" => Ok(Storage::Sqlite(path.into())),
+            _ => bail!("unknown storage: {}", kind),
+        }
+    }
+}
+
+impl Storage {
+    /// Reads the records of the store without locking it for writing, so that
+    /// it can be done while the bot is running.
+    pub fn for_each_record(&self, mut f: impl FnMut(LogRecord) -> Result<()>) -> Result<()> {
+        match self {
+            Storage::File(path) => {
+                for segment in live_segments(path)? {
+                    for record in EventLogReader::new(segment.open()?)? {
+                        f(record?)?;
+                    }
+                }
+                let file = File::open(path).context("failed to open event log")?;
+                for record in EventLogReader::new(BufReader::new(file))? {
+                    f(record?)?;
+                }
+            }
+            Storage::Sqlite(path) => {
+                let mut store = SqliteEventStore::open_read_only(path)?;
+                for record in store.iter() {
+                    f(record?)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// When written events are considered safe. Flushing only hands them over to
+/// the OS, which may lose them in a crash of its own; syncing waits for the
+/// disk, once per batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    Flush,
+    Fsync,
+    /// Syncs once at least `n` events have been written since the last sync,
+    /// so that up to `n - 1` acknowledged events may be lost.
+    FsyncEveryN {
+        n: u64,
+    },
+}
+
+impl FromStr for Durability {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "flush" => Ok(Durability::Flush),
+            "fsync" => Ok(Durability::Fsync),
+            _ => {
+                let n = s
+                    .strip_prefix("fsync-every-")
+                    .and_then(|n| n.parse().ok())
+                    .filter(|&n| n > 0)
+                    .with_context(|| format!("unknown durability: {}", s))?;
+                Ok(Durability::FsyncEveryN { n })
+            }
+        }
+    }
+}
+
+impl Durability {
+    /// Whether a batch has to be synced, given how many events have been
+    /// written since the last sync, the batch included.
+    fn should_sync(self, unsynced: u64) -> bool {
+        match self {
+            Durability::Flush => false,
+            Durability::Fsync => true,
+            Durability::FsyncEveryN { n } => unsynced >= n,
+        }
+    }
+}
+
+/// An append-only event log file, see `EventLogReader`. A record cut short by
+/// a crash is dropped on the next start.
+pub struct FileEventStore {
+    file: File,
+    format: LogFormat,
+    codec: Box<dyn EventCodec>,
+    durability: Durability,
+    unsynced: u64,
+    rotation: Option<Rotation>,
+}
+
+struct Rotation {
+    path: PathBuf,
+    max_bytes: u64,
+    next_segment: u64,
+    compressor: Compressor,
+}
+
+impl FileEventStore {
+    /// Appends to a log that already has a header of `format`.
+    pub fn new(file: File, format: LogFormat, durability: Durability) -> Self {
+        Self {
+            file,
+            format,
+            codec: format.codec(),
+            durability,
+            unsynced: 0,
+            rotation: None,
+        }
+    }
+
+    /// Starts a new segment once the log at `path` has grown to `max_bytes`.
+    /// The full one is closed as `<path>.000001` and so on, and gzipped in the
+    /// background; the log being appended to always stays at `path`.
+    pub fn rotate(mut self, path: &Path, max_bytes: u64) -> Result<Self> {
+        let next_segment = log_segments(path)?
+            .last()
+            .map_or(1, |segment| segment.number + 1);
+        self.rotation = Some(Rotation {
+            path: path.to_owned(),
+            max_bytes,
+            next_segment,
+            compressor: Compressor::spawn(path.to_owned())?,
+        });
+        Ok(self)
+    }
+
+    /// Closes the current segment if it is full. Done before a batch is
+    /// written, so that a failure leaves the batch unwritten.
+    fn rotate_if_full(&mut self) -> Result<()> {
+        let rotation = match &mut self.rotation {
+            Some(rotation) => rotation,
+            None => return Ok(()),
+        };
+        if self.file.metadata()?.len() < rotation.max_bytes {
+            return Ok(());
+        }
+        self.file.sync_all().context("failed to sync event log")?;
+        self.unsynced = 0;
+        let segment_path = segment_path(&rotation.path, rotation.next_segment);
+        fs::rename(&rotation.path, &segment_path).context("failed to close event log segment")?;
+        rotation.next_segment += 1;
+
+        let mut file = OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .open(&rotation.path)
+            .context("failed to create event log")?;
+        file.write_all(&LogHeader::current(self.format).to_bytes())?;
+        file.sync_all().context("failed to sync event log")?;
+        self.file = file;
+        info!("Closed event log segment {}", segment_path.display());
+        rotation.compressor.wake();
+        Ok(())
+    }
+}
+
+impl EventStore for FileEventStore {
+    fn append(&mut self, events: Vec<LoggedEvent>) -> Result<()> {
+        self.rotate_if_full()?;
+        let count = events.len() as u64;
+        let mut buf = vec![];
+        for logged in events {
+            self.codec.encode(&LogRecord::Event(logged), &mut buf)?;
+        }
+        // Reading moves the position away from the end.
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(&buf)?;
+        self.unsynced += count;
+        if self.durability.should_sync(self.unsynced) {
+            self.file.sync_data().context("failed to sync event log")?;
+            self.unsynced = 0;
+        }
+        Ok(())
+    }
+
+    fn iter(&mut self) -> Box<dyn Iterator<Item = Result<LogRecord>> + '_> {
+        let segments = match &self.rotation {
+            Some(rotation) => live_segments(&rotation.path),
+            None => Ok(vec![]),
+        };
+        let segments = match segments {
+            Ok(segments) => segments,
+            Err(err) => return Box::new(std::iter::once(Err(err))),
+        };
+        let closed = segments.into_iter().flat_map(|segment| {
+            match segment.open().and_then(EventLogReader::new) {
+                Ok(reader) => Box::new(reader) as Box<dyn Iterator<Item = _>>,
+                Err(err) => Box::new(std::iter::once(Err(err))),
+            }
+        });
+        let reader = self
+            .file
+            .seek(SeekFrom::Start(0))
+            .map_err(Error::from)
+            .and_then(|_| EventLogReader::new(BufReader::new(&self.file)));
+        let active = match reader {
+            Ok(reader) => Box::new(reader) as Box<dyn Iterator<Item = _>>,
+            Err(err) => Box::new(std::iter::once(Err(err))),
+        };
+        Box::new(closed.chain(active))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+const SEGMENT_DIGITS: usize = 6;
+
+/// A closed segment of an event log that has been rotated, see
+/// `FileEventStore::rotate`. Segments are complete logs of their own, with a
+/// header, and are replayed oldest first before the log itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    number: u64,
+    path: PathBuf,
+    is_compressed: bool,
+}
+
+impl Segment {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn open(&self) -> Result<Box<dyn BufRead + Send>> {
+        let open = |path: &Path| {
+            File::open(path)
+                .with_context(|| format!("failed to open event log segment {}", path.display()))
+        };
+        if self.is_compressed {
+            return Ok(Box::new(BufReader::new(GzDecoder::new(open(&self.path)?))));
+        }
+        match File::open(&self.path) {
+            Ok(file) => Ok(Box::new(BufReader::new(file))),
+            // It may have just been compressed.
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                let file = open(&path_with_suffix(&self.path, ".gz"))?;
+                Ok(Box::new(BufReader::new(GzDecoder::new(file))))
+            }
+            Err(err) => Err(err).context("failed to open event log segment"),
+        }
+    }
+
+    fn remove(&self) -> Result<()> {
+        let uncompressed = if self.is_compressed {
+            self.path.with_extension("")
+        } else {
+            self.path.clone()
+        };
+        for path in [path_with_suffix(&uncompressed, ".gz"), uncompressed] {
+            match fs::remove_file(&path) {
+                Err(err) if err.kind() != ErrorKind::NotFound => {
+                    return Err(err).with_context(|| {
+                        format!("failed to remove event log segment {}", path.display())
+                    })
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+fn path_with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+fn segment_path(path: &Path, number: u64) -> PathBuf {
+    path_with_suffix(
+        path,
+        &format!(".{:0width$}", number, width = SEGMENT_DIGITS),
+    )
+}
+
+/// Lists the files of the closed segments of the log at `path` as they are
+/// found, both compressed and not.
+fn segment_files(path: &Path) -> Result<Vec<Segment>> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| format!("{}.", name))
+        .context("malformed event log path")?;
+    let mut segments = vec![];
+    for entry in fs::read_dir(dir).context("failed to list event log segments")? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let suffix = match name.to_str().and_then(|name| name.strip_prefix(&prefix)) {
+            Some(suffix) => suffix,
+            None => continue,
+        };
+        let (digits, is_compressed) = match suffix.strip_suffix(".gz") {
+            Some(digits) => (digits, true),
+            None => (suffix, false),
+        };
+        if digits.len() != SEGMENT_DIGITS || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            continue;
+        }
+        segments.push(Segment {
+            number: digits.parse()?,
+            path: entry.path(),
+            is_compressed,
+        });
+    }
+    Ok(segments)
+}
+
+/// Lists the closed segments of the log at `path`, oldest first. A compressed
+/// copy is complete once it has its name, so it is preferred to an original
+/// that has not been removed yet.
+pub fn log_segments(path: &Path) -> Result<Vec<Segment>> {
+    let mut segments = BTreeMap::new();
+    for segment in segment_files(path)? {
+        let replaces = |old: &Segment| segment.is_compressed && !old.is_compressed;
+        if segments.get(&segment.number).is_none_or(replaces) {
+            segments.insert(segment.number, segment);
+        }
+    }
+    Ok(segments.into_values().collect())
+}
+
+fn starts_with_snapshot(reader: impl BufRead) -> Result<bool> {
+    let mut reader = EventLogReader::new(reader)?;
+    Ok(matches!(
+        reader.next().transpose()?,
+        Some(LogRecord::Snapshot(_))
+    ))
+}
+
+/// Splits the closed segments of the log at `path` into the ones that come
+/// before a snapshot, which are no longer needed, and the ones the state is
+/// restored from.
+fn split_superseded_segments(path: &Path) -> Result<(Vec<Segment>, Vec<Segment>)> {
+    let mut segments = log_segments(path)?;
+    let log_starts_with_snapshot = match File::open(path) {
+        Ok(file) => starts_with_snapshot(BufReader::new(file))?,
+        Err(err) if err.kind() == ErrorKind::NotFound => false,
+        Err(err) => return Err(err).context("failed to open event log"),
+    };
+    let first_live = if log_starts_with_snapshot {
+        segments.len()
+    } else {
+        let mut first_live = 0;
+        for (index, segment) in segments.iter().enumerate().rev() {
+            if starts_with_snapshot(segment.open()?)? {
+                first_live = index;
+                break;
+            }
+        }
+        first_live
+    };
+    let live = segments.split_off(first_live);
+    Ok((segments, live))
+}
+
+/// Lists the closed segments of the log at `path` the state is restored from,
+/// oldest first: the ones since the last snapshot.
+pub fn live_segments(path: &Path) -> Result<Vec<Segment>> {
+    Ok(split_superseded_segments(path)?.1)
+}
+
+/// Removes the closed segments of the log at `path` that come before a
+/// snapshot, e.g. after the log has been replaced with one from
+/// `--snapshot-to`, and returns the remaining ones.
+pub fn remove_superseded_segments(path: &Path) -> Result<Vec<Segment>> {
+    let (superseded, live) = split_superseded_segments(path)?;
+    for segment in &superseded {
+        segment.remove()?;
+        info!(
+            "Removed superseded event log segment {}",
+            segment.path.display()
+        );
+    }
+    Ok(live)
+}
+
+/// Gzips a closed segment next to it and removes the original. The compressed
+/// copy is written under a temporary name and synced first, so that a crash
+/// leaves at least one of them complete.
+fn compress_segment(segment: &Segment) -> Result<()> {
+    let compressed_path = path_with_suffix(&segment.path, ".gz");
+    if !compressed_path.exists() {
+        let temp_path = path_with_suffix(&segment.path, ".gz.tmp");
+        let mut encoder = GzEncoder::new(File::create(&temp_path)?, Compression::default());
+        io::copy(&mut File::open(&segment.path)?, &mut encoder)?;
+        encoder.finish()?.sync_all()?;
+        fs::rename(&temp_path, &compressed_path)?;
+    }
+    fs::remove_file(&segment.path)?;
+    Ok(())
+}
+
+/// Compresses the closed segments of the log at `path` whenever it is woken
+/// up. Only files named as segments are touched, never the log being
+/// appended to.
+struct Compressor {
+    wake: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Compressor {
+    fn spawn(path: PathBuf) -> Result<Self> {
+        let (wake, woken) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("segment-compressor".into())
+            .spawn(move || {
+                while woken.recv().is_ok() {
+                    let segments = match segment_files(&path) {
+                        Ok(segments) => segments,
+                        Err(err) => {
+                            error!("failed to list event log segments: {:#}", err);
+                            continue;
+                        }
+                    };
+                    for segment in segments.iter().filter(|segment| !segment.is_compressed) {
+                        if let Err(err) = compress_segment(segment) {
+                            error!(
+                                "failed to compress event log segment {}: {:#}",
+                                segment.path.display(),
+                                err
+                            );
+                        }
+                    }
+                }
+            })
+            .context("failed to spawn segment compressor")?;
+        // Segments may have been left uncompressed by the last run.
+        wake.send(()).ok();
+        Ok(Self {
+            wake: Some(wake),
+            thread: Some(thread),
+        })
+    }
+
+    fn wake(&self) {
+        if let Some(wake) = &self.wake {
+            wake.send(()).ok();
+        }
+    }
+}
+
+impl Drop for Compressor {
+    /// Lets the compression that has been asked for finish.
+    fn drop(&mut self) {
+        self.wake.take();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("segment compressor has panicked");
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Events in a SQLite database, a row per event with its payload as JSON. A
+/// batch is written in a single transaction, so it is either stored whole or
+/// not at all. Snapshots are not supported. The version of the payloads is
+/// kept in `user_version`, see `migrations`; older databases are upgraded in
+/// place when opened.
+pub struct SqliteEventStore {
+    connection: Connection,
+}
+
+const PAGE_SIZE: usize = 1000;
+
+impl SqliteEventStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        let connection = Connection::open(path).context("failed to open event database")?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS events (
+                id INTEGER PRIMARY KEY,
+                ts INTEGER,
+                payload BLOB NOT NULL
+            )",
+        )?;
+        let version: u32 = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        ensure!(
+            version <= CURRENT_VERSION,
+            "event database version {} is newer than supported version {}",
+            version,
+            CURRENT_VERSION
+        );
+        let mut store = Self { connection };
+        // A new database has no version yet.
+        if version == 0 {
+            store
+                .connection
+                .pragma_update(None, "user_version", CURRENT_VERSION)?;
+        } else if version < CURRENT_VERSION {
+            store
+                .migrate(version)
+                .context("failed to migrate event database")?;
+        }
+        Ok(store)
+    }
+
+    /// Upgrades the payloads of all events to the current version in a single
+    /// transaction.
+    fn migrate(&mut self, version: u32) -> Result<()> {
+        let transaction = self.connection.transaction()?;
+        let rows = transaction
+            .prepare("SELECT id, ts, payload FROM events ORDER BY id")?
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, Option<i64>>(1)?,
+                    row.get::<_, Vec<u8>>(2)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        {
+            let mut statement =
+                transaction.prepare("UPDATE events SET payload = ?1 WHERE id = ?2")?;
+            for (id, ts, payload) in &rows {
+                let event: serde_json::Value = serde_json::from_slice(payload)
+                    .with_context(|| format!("event {} is corrupt", id))?;
+                let record = migrations::migrate(version, json!({ "ts": ts, "event": event }));
+                statement.execute(params![serde_json::to_vec(&record["event"])?, id])?;
+            }
+        }
+        transaction.pragma_update(None, "user_version", CURRENT_VERSION)?;
+        transaction.commit()?;
+        info!(
+            "Migrated {} events of event database from version {}",
+            rows.len(),
+            version
+        );
+        Ok(())
+    }
+
+    fn open_read_only(path: &Path) -> Result<Self> {
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .context("failed to open event database")?;
+        Ok(Self { connection })
+    }
+
+    /// Reads up to `PAGE_SIZE` events that follow the one with `after_id`.
+    fn read_page(&self, after_id: i64) -> Result<Vec<(i64, Result<LogRecord>)>> {
+        let mut statement = self.connection.prepare_cached(
+            "SELECT id, ts, payload FROM events WHERE id > ?1 ORDER BY id LIMIT ?2",
+        )?;
+        let rows = statement.query_map(params![after_id, PAGE_SIZE as i64], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, Option<i64>>(1)?,
+                row.get::<_, Vec<u8>>(2)?,
+            ))
+        })?;
+        let mut page = vec![];
+        for row in rows {
+            let (id, ts, payload) = row?;
+            let record = serde_json::from_slice(&payload)
+                .map(|event| LogRecord::Event(LoggedEvent { ts, event }))
+                .with_context(|| format!("event {} is corrupt", id));
+            page.push((id, record));
+        }
+        Ok(page)
+    }
+}
+
+impl EventStore for SqliteEventStore {
+    fn append(&mut self, events: Vec<LoggedEvent>) -> Result<()> {
+        let transaction = self.connection.transaction()?;
+        {
+            let mut statement =
+                transaction.prepare_cached("INSERT INTO events (ts, payload) VALUES (?1, ?2)")?;
+            for logged in events {
+                statement.execute(params![logged.ts, serde_json::to_vec(&logged.event)?])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn iter(&mut self) -> Box<dyn Iterator<Item = Result<LogRecord>> + '_> {
+        let mut page = VecDeque::new();
+        let mut last_id = 0;
+        let mut is_done = false;
+        Box::new(std::iter::from_fn(move || {
+            if page.is_empty() && !is_done {
+                match self.read_page(last_id) {
+                    Ok(rows) => {
+                        is_done = rows.len() < PAGE_SIZE;
+                        if let Some((id, _)) = rows.last() {
+                            last_id = *id;
+                        }
+                        page.extend(rows);
+                    }
+                    Err(err) => {
+                        is_done = true;
+                        return Some(Err(err));
+                    }
+                }
+            }
+            page.pop_front().map(|(_, record)| record)
+        }))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{command_dispatcher::CommandDispatcherBuilder, event_log::write_snapshot};
+
+    use std::io::Read;
+
+    fn connected(login: &str) -> LoggedEvent {
+        serde_json::from_value(json!({
+            "ts": 1,
+            "event": {
+                "UserConnected": {
+                    "user": { "login": login, "first_name": login, "last_name": null },
+                    "chat_id": 1,
+                    "connected_at": 1,
+                },
+            },
+        }))
+        .unwrap()
+    }
+
+    fn open_log(path: &Path) -> File {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap()
+    }
+
+    /// Writes an event per segment: `a` and `b` end up in closed segments and
+    /// `c` in the log itself. Dropping the store waits for the compression.
+    fn write_rotated_log(path: &Path) {
+        let header = LogHeader::current(LogFormat::Json).to_bytes();
+        fs::write(path, &header).unwrap();
+        let mut store = FileEventStore::new(open_log(path), LogFormat::Json, Durability::Flush)
+            .rotate(path, header.len() as u64 + 1)
+            .unwrap();
+        for login in ["a", "b", "c"] {
+            store.append(vec![connected(login)]).unwrap();
+        }
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    fn logins(store: &mut dyn EventStore) -> Vec<String> {
+        store
+            .iter()
+            .map(|record| match record.unwrap() {
+                LogRecord::Event(logged) => logged.event.login().to_string(),
+                LogRecord::Snapshot(_) => unreachable!("no snapshot has been written"),
+            })
+            .collect()
+    }
+
+    fn replay(path: &Path) -> CommandDispatcherBuilder {
+        CommandDispatcherBuilder::from_rotated_event_log(
+            &live_segments(path).unwrap(),
+            BufReader::new(File::open(path).unwrap()),
+            false,
+        )
+        .unwrap()
+    }
+
+    /// Appends batches of the given sizes to a new log and returns how many
+    /// events are left unsynced after each of them; none are after a sync.
+    fn unsynced_after(durability: Durability, batches: &[usize]) -> Vec<u64> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.log");
+        fs::write(&path, LogHeader::current(LogFormat::Json).to_bytes()).unwrap();
+        let mut store = FileEventStore::new(open_log(&path), LogFormat::Json, durability);
+        batches
+            .iter()
+            .map(|&size| {
+                store
+                    .append((0..size).map(|_| connected("a")).collect())
+                    .unwrap();
+                store.unsynced
+            })
+            .collect()
+    }
+
+    #[test]
+    fn durability_is_parsed() {
+        assert_eq!("flush".parse::<Durability>().unwrap(), Durability::Flush);
+        assert_eq!("fsync".parse::<Durability>().unwrap(), Durability::Fsync);
+        assert_eq!(
+            "fsync-every-10".parse::<Durability>().unwrap(),
+            Durability::FsyncEveryN { n: 10 }
+        );
+        for bad in ["", "sync", "fsync-every-", "fsync-every-0", "fsync-every-x"] {
+            let err = bad.parse::<Durability>().unwrap_err();
+            assert_eq!(err.to_string(), format!("unknown durability: {}", bad));
+        }
+    }
+
+    #[test]
+    fn syncs_are_amortized_over_batches() {
+        let batches = [1, 1, 1, 5, 2, 1];
+        assert_eq!(
+            unsynced_after(Durability::Flush, &batches),
+            [1, 2, 3, 8, 10, 11]
+        );
+        assert_eq!(unsynced_after(Durability::Fsync, &batches), [0; 6]);
+        // Synced after 3 events, after 5 more and after the last 3.
+        assert_eq!(
+            unsynced_after(Durability::FsyncEveryN { n: 3 }, &batches),
+            [1, 2, 0, 0, 2, 0]
+        );
+        // A large batch is synced once as a whole.
+        assert_eq!(
+            unsynced_after(Durability::FsyncEveryN { n: 2 }, &[10, 1]),
+            [0, 1]
+        );
+    }
+
+    #[test]
+    fn synced_events_are_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.log");
+        for durability in [
+            Durability::Flush,
+            Durability::Fsync,
+            Durability::FsyncEveryN { n: 2 },
+        ] {
+            fs::write(&path, LogHeader::current(LogFormat::Bincode).to_bytes()).unwrap();
+            let mut store = FileEventStore::new(open_log(&path), LogFormat::Bincode, durability);
+            store.append(vec![connected("a")]).unwrap();
+            store.append(vec![connected("b"), connected("c")]).unwrap();
+            assert_eq!(logins(&mut store), ["a", "b", "c"], "{:?}", durability);
+            assert_eq!(
+                store.unsynced,
+                if durability == Durability::Flush {
+                    3
+                } else {
+                    0
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn full_segments_are_closed_and_compressed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.log");
+        write_rotated_log(&path);
+
+        assert_eq!(
+            file_names(dir.path()),
+            ["events.log", "events.log.000001.gz", "events.log.000002.gz"]
+        );
+        let mut store = FileEventStore::new(open_log(&path), LogFormat::Json, Durability::Flush)
+            .rotate(&path, u64::MAX)
+            .unwrap();
+        assert_eq!(logins(&mut store), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn plain_and_gzipped_segments_are_replayed_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.log");
+        write_rotated_log(&path);
+        // As if the compressor had not got to the first segment yet.
+        let compressed = dir.path().join("events.log.000001.gz");
+        let mut plain = vec![];
+        GzDecoder::new(File::open(&compressed).unwrap())
+            .read_to_end(&mut plain)
+            .unwrap();
+        fs::write(dir.path().join("events.log.000001"), plain).unwrap();
+        fs::remove_file(&compressed).unwrap();
+
+        let mut logins = vec![];
+        Storage::File(path.clone())
+            .for_each_record(|record| {
+                if let LogRecord::Event(logged) = record {
+                    logins.push(logged.event.login().to_string());
+                }
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(logins, ["a", "b", "c"]);
+        assert_eq!(replay(&path).snapshot().user_count(), 3);
+    }
+
+    #[test]
+    fn interrupted_compression_keeps_the_complete_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.log");
+        write_rotated_log(&path);
+        // A crash after the compressed copy got its name, before the original
+        // was removed.
+        let mut plain = vec![];
+        GzDecoder::new(File::open(dir.path().join("events.log.000002.gz")).unwrap())
+            .read_to_end(&mut plain)
+            .unwrap();
+        fs::write(dir.path().join("events.log.000002"), plain).unwrap();
+        fs::write(dir.path().join("events.log.000002.gz.tmp"), b"partial").unwrap();
+
+        let segments = log_segments(&path).unwrap();
+        assert_eq!(segments.len(), 2);
+        assert!(segments.iter().all(|segment| segment.is_compressed));
+        assert_eq!(replay(&path).snapshot().user_count(), 3);
+    }
+
+    #[test]
+    fn segments_before_a_snapshot_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.log");
+        write_rotated_log(&path);
+        // The second segment is replaced with a snapshot of the state it
+        // ends with, as `--snapshot-to` would write.
+        let segments = log_segments(&path).unwrap();
+        let snapshot = CommandDispatcherBuilder::from_rotated_event_log(
+            &segments[..1],
+            segments[1].open().unwrap(),
+            false,
+        )
+        .unwrap()
+        .snapshot();
+        segments[1].remove().unwrap();
+        let snapshot_path = dir.path().join("events.log.000002");
+        write_snapshot(
+            File::create(&snapshot_path).unwrap(),
+            LogFormat::Json,
+            snapshot,
+        )
+        .unwrap();
+
+        let live = remove_superseded_segments(&path).unwrap();
+        assert_eq!(live.len(), 1);
+        assert_eq!(live[0].path(), snapshot_path);
+        assert_eq!(file_names(dir.path()), ["events.log", "events.log.000002"]);
+        assert_eq!(replay(&path).snapshot().user_count(), 3);
+    }
+
+    /// Checks what every store must do: keep batches in order along with
+    /// their timestamps, across pages of SQLite and across reopening.
+    fn assert_conforms(mut open: impl FnMut() -> Box<dyn EventStore>) {
+        let logged = |login: String, ts| {
+            let mut logged = connected(&login);
+            logged.ts = ts;
+            logged
+        };
+        let mut expected = vec![("a".to_string(), Some(1)), ("b".to_string(), None)];
+        expected.extend((0..PAGE_SIZE as i64 + 1).map(|i| (format!("u{}", i), Some(i))));
+        expected.push(("c".to_string(), Some(-1)));
+        let read = |store: &mut dyn EventStore| {
+            store
+                .iter()
+                .map(|record| match record.unwrap() {
+                    LogRecord::Event(logged) => (logged.event.login().to_string(), logged.ts),
+                    LogRecord::Snapshot(_) => unreachable!("no snapshot has been written"),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut store = open();
+        assert_eq!(read(store.as_mut()), []);
+        for batch in [&expected[..1], &expected[1..3], &expected[3..]] {
+            let batch = batch.iter().map(|(login, ts)| logged(login.clone(), *ts));
+            store.append(batch.collect()).unwrap();
+        }
+        store.append(vec![]).unwrap();
+        assert_eq!(read(store.as_mut()), expected);
+        // Reading does not get in the way of appending.
+        store.append(vec![logged("d".into(), Some(2))]).unwrap();
+        expected.push(("d".to_string(), Some(2)));
+        drop(store);
+
+        let mut store = open();
+        assert_eq!(read(store.as_mut()), expected);
+        let builder = CommandDispatcherBuilder::from_event_store(store.as_mut()).unwrap();
+        assert_eq!(builder.snapshot().user_count(), expected.len());
+    }
+
+    #[test]
+    fn file_store_conforms() {
+        for format in [LogFormat::Json, LogFormat::Bincode] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("events.log");
+            fs::write(&path, LogHeader::current(format).to_bytes()).unwrap();
+            assert_conforms(|| {
+                Box::new(FileEventStore::new(
+                    open_log(&path),
+                    format,
+                    Durability::Flush,
+                ))
+            });
+        }
+    }
+
+    #[test]
+    fn sqlite_store_conforms() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.db");
+        assert_conforms(|| Box::new(SqliteEventStore::open(&path).unwrap()));
+    }
+}
@@ -1,24 +1,100 @@
 use crate::{
-    command_dispatcher::UserHandle,
-    data::{ThreadAnonimityMode, ThreadId},
+    command_dispatcher::{QueuedRandomRequest, RandomQueue, Rooms, UserHandle},
+    data::{
+        normalize_thread_id, DndWindow, MediaKind, MediaLimits, MediaPolicy, MessageKind, SentText,
+        ThreadAnonimityMode, ThreadId, User, UserSettings,
+    },
     event_log::{
-        Event, ThreadMessageReceivedEvent, ThreadStartedEvent, ThreadTerminatedEvent,
-        UserBannedEvent, UserStartedEvent, UserStoppedEvent, UserUnbannedEvent,
+        DefaultThreadChangedEvent, DndChangedEvent, Event, GreetingChangedEvent,
+        IncomingLimitChangedEvent, LogRecord, MediaPolicyChangedEvent, MessageScheduledEvent,
+        OutgoingMessageDeletedEvent, OutgoingMessageEvent, PollReceivedEvent, RandomDequeuedEvent,
+        RandomQueuedEvent, ReceiptSentEvent, ReminderRemovedEvent, ReminderSetEvent,
+        RoomCreatedEvent, RoomJoinedEvent, RoomLeftEvent, RoomMessageEvent,
+        ScheduledMessageRemovedEvent, ThreadArchivedEvent, ThreadExpiryChangedEvent,
+        ThreadLabeledEvent, ThreadMediaReceivedEvent, ThreadMessageReceivedEvent,
+        ThreadNoteChangedEvent, ThreadPauseChangedEvent, ThreadPinnedEvent,
+        ThreadPreviewsChangedEvent, ThreadPriorityChangedEvent, ThreadReceiptsChangedEvent,
+        ThreadRequestMessageEvent, ThreadRequestedEvent, ThreadSecretChangedEvent,
+        ThreadSlowModeChangedEvent, ThreadStartedEvent, ThreadTerminatedEvent,
+        ThreadUnarchivedEvent, ThreadUnlabeledEvent, ThreadUnpinnedEvent, TopicChangedEvent,
+        UserBannedEvent, UserBlockedEvent, UserForgottenEvent, UserInterestsChangedEvent,
+        UserProfileUpdatedEvent, UserSettingsChangedEvent, UserStartedEvent, UserStoppedEvent,
+        UserUnbannedEvent, UserUnblockedEvent,
+    },
+    event_store::Storage,
+    messages::{self, Key, Language},
+    reactions, spoiler,
+    util::{
+        escape_markup, format_age, format_duration, format_timestamp, markup_to_html,
+        markup_to_plain, now_timestamp, random_adjective, random_noun, slice_entities, split_text,
+        split_text_with_entities, utf16_len, Reader,
     },
-    util::{random_adjective, random_noun, Reader, HELP_MESSAGE, START_MESSAGE, STOP_MESSAGE},
-    Command, EventServiceHandle,
+    BroadcastAudience, Command, EventServiceHandle, StartPayload,
 };
 
-use anyhow::{bail, ensure, Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use image::codecs::jpeg::JpegEncoder;
 use log::*;
 use rand::prelude::IteratorRandom;
-use teloxide::{adaptors::AutoSend, prelude::Requester, types::Message, Bot};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use teloxide::{
+    adaptors::AutoSend,
+    net::Download,
+    payloads::{
+        EditMessageTextSetters, SendAnimationSetters, SendAudioSetters, SendContactSetters,
+        SendDiceSetters, SendDocumentSetters, SendMessageSetters, SendPhotoSetters,
+        SendPollSetters, SendVideoNoteSetters, SendVideoSetters, SendVoiceSetters,
+    },
+    prelude::Requester,
+    types::{
+        DiceEmoji, ForceReply, InlineKeyboardButton, InlineKeyboardMarkup, InputFile, InputMedia,
+        InputMediaPhoto, InputMediaVideo, Message, MessageEntity, ParseMode, PollType,
+    },
+    Bot,
+};
 use tokio::{
     select,
     sync::{mpsc, oneshot},
 };
 
-use std::{collections::HashMap, sync::atomic::Ordering, time::Duration};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    fmt,
+    io::Cursor,
+    sync::{atomic::Ordering, Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const ONLINE_WINDOW_SECS: i64 = 24 * 60 * 60;
+const MAX_DELIVERED_MESSAGES: usize = 100;
+const MAX_QUOTED_TEXTS: usize = 1000;
+const QUOTE_LENGTH: usize = 100;
+const MAX_INTERESTS: usize = 5;
+const MAX_RECENT_PARTNERS: usize = 3;
+const PENDING_THREAD_TTL_SECS: i64 = 24 * 60 * 60;
+const NOTE_PREVIEW_LENGTH: usize = 40;
+const MAX_DEFERRED_ITEMS: usize = 100;
+const UNDO_WINDOW: Duration = Duration::from_secs(5 * 60);
+// Put on a message once it has been relayed, one of the reactions Telegram
+// allows.
+const DELIVERED_REACTION: &str = "👌";
+// Telegram refuses to delete messages older than 48 hours.
+const DELETE_MESSAGE_WINDOW_SECS: i64 = 48 * 60 * 60;
+const MAX_SCHEDULED_MESSAGES: usize = 20;
+const MAX_REMINDERS: usize = 20;
+const CONFIRMATION_TTL_SECS: i64 = 60;
+const MAX_PENDING_CONFIRMATIONS: usize = 5;
+// Items of an album arrive as separate messages, wait a bit for the rest.
+const ALBUM_WINDOW: Duration = Duration::from_millis(800);
+const MAX_ALBUM_ITEMS: usize = 10;
+// Bounds for decoding photos that are scrubbed, against decompression bombs.
+const MAX_SCRUBBED_PHOTO_DIMENSION: u32 = 10_000;
+const MAX_SCRUBBED_PHOTO_ALLOC: u64 = 256 * 1024 * 1024;
+// Telegram allows up to 4096 characters, leave some room for markup.
+const MAX_MESSAGE_LENGTH: usize = 4000;
 
 ////////////////////////////////////////////////////////////////////////////////
 
@@ -35,9 +111,78 @@ pub struct ActionRequest {
 }
 
 pub enum Action {
-    StartAnonymousThread(Thread),
-    SendText(ThreadId, String),
+    StartAnonymousThread(Box<Thread>),
+    SendText(
+        ThreadId,
+        String,
+        Option<String>,
+        Vec<MessageEntity>,
+        oneshot::Sender<i32>,
+    ),
+    SendPhoto {
+        thread_id: ThreadId,
+        file_id: String,
+        caption: Option<String>,
+        spoiler: bool,
+        scrub: bool,
+        delivered_sender: oneshot::Sender<i32>,
+    },
+    SendSticker(ThreadId, String, oneshot::Sender<i32>),
+    SendAnimation(ThreadId, String, Option<String>, oneshot::Sender<i32>),
+    SendVoice(ThreadId, String, Option<String>, u32, oneshot::Sender<i32>),
+    SendVideo {
+        thread_id: ThreadId,
+        file_id: String,
+        caption: Option<String>,
+        duration: u32,
+        spoiler: bool,
+        delivered_sender: oneshot::Sender<i32>,
+    },
+    SendVideoNote(ThreadId, String, u32, oneshot::Sender<i32>),
+    SendMediaGroup(
+        ThreadId,
+        Vec<(MediaKind, String, Option<String>)>,
+        oneshot::Sender<Vec<i32>>,
+    ),
+    SendAudio(ThreadId, String, Option<String>, u32, oneshot::Sender<i32>),
+    /// A document without a file name is re-uploaded under an anonymous one.
+    SendDocument(
+        ThreadId,
+        String,
+        Option<String>,
+        Option<String>,
+        Option<String>,
+        oneshot::Sender<i32>,
+    ),
+    SendContact(
+        ThreadId,
+        String,
+        String,
+        Option<String>,
+        oneshot::Sender<i32>,
+    ),
+    /// A venue comes with a title and an address.
+    SendLocation(
+        ThreadId,
+        f64,
+        f64,
+        Option<String>,
+        Option<String>,
+        oneshot::Sender<i32>,
+    ),
+    EditText(ThreadId, i32, String),
     TerminateThread(ThreadId),
+    ReopenThread(ThreadId),
+    SendPoll(ThreadId, String, Vec<String>),
+    SendDice(ThreadId, DiceEmoji, oneshot::Sender<i32>),
+    NotifySeen(ThreadId, i32),
+    PauseThread(ThreadId),
+    ResumeThread(ThreadId),
+    RoomMessage(String, String, String),
+    PollAnswered(ThreadId, String, Vec<String>),
+    /// Carries the id of the reacted message in the chat of the reacting side,
+    /// which is a delivered copy for the other side.
+    SendReaction(ThreadId, i32, Option<String>),
     Broadcast(String),
 }
 
@@ -48,13 +193,251 @@ pub struct Thread {
     anon_mode: ThreadAnonimityMode,
     other_id: ThreadId,
     other_handle: UserHandle,
+    pinned: bool,
+    slow_mode: Option<Duration>,
+    last_sent_at: Option<Instant>,
+    expires_at: Option<i64>,
+    secret: Option<Duration>,
+    created_at: Option<i64>,
+    messages_sent: u64,
+    messages_received: u64,
+    note: Option<String>,
+    labels: BTreeSet<String>,
+    delivered_message_ids: VecDeque<i32>,
+    delivered_sources: HashMap<i32, i32>,
+    delivered_texts: HashMap<i32, SentText>,
+    receipts: bool,
+    receipts_sent: HashSet<i32>,
+    /// Overrides the `link_previews` setting for incoming messages.
+    link_previews: Option<bool>,
+    paused_by_me: bool,
+    paused_by_other: bool,
+    priority: bool,
 }
 
 impl Thread {
-    async fn send_text(&self, text: String) -> Result<()> {
+    fn new(
+        id: ThreadId,
+        anon_mode: ThreadAnonimityMode,
+        other_id: ThreadId,
+        other_handle: UserHandle,
+    ) -> Self {
+        Self {
+            id,
+            anon_mode,
+            other_id,
+            other_handle,
+            pinned: false,
+            slow_mode: None,
+            last_sent_at: None,
+            expires_at: None,
+            secret: None,
+            created_at: Some(now_timestamp()),
+            messages_sent: 0,
+            messages_received: 0,
+            note: None,
+            labels: BTreeSet::new(),
+            delivered_message_ids: VecDeque::new(),
+            delivered_sources: HashMap::new(),
+            delivered_texts: HashMap::new(),
+            receipts: false,
+            receipts_sent: HashSet::new(),
+            link_previews: None,
+            paused_by_me: false,
+            paused_by_other: false,
+            priority: false,
+        }
+    }
+
+    async fn send_text(
+        &self,
+        text: String,
+        quote: Option<String>,
+        entities: Vec<MessageEntity>,
+    ) -> Result<Option<i32>> {
+        let (delivered_sender, delivered_receiver) = oneshot::channel();
+        self.other_handle
+            .send_action(Action::SendText(
+                self.other_id.clone(),
+                text,
+                quote,
+                entities,
+                delivered_sender,
+            ))
+            .await?;
+        Ok(delivered_receiver.await.ok())
+    }
+
+    async fn send_media(
+        &self,
+        kind: MediaKind,
+        file_id: String,
+        caption: Option<String>,
+    ) -> Result<Option<i32>> {
+        let (delivered_sender, delivered_receiver) = oneshot::channel();
+        let action = match kind {
+            MediaKind::Photo { spoiler, scrub } => Action::SendPhoto {
+                thread_id: self.other_id.clone(),
+                file_id,
+                caption,
+                spoiler,
+                scrub,
+                delivered_sender,
+            },
+            MediaKind::Sticker => {
+                Action::SendSticker(self.other_id.clone(), file_id, delivered_sender)
+            }
+            MediaKind::Animation => {
+                Action::SendAnimation(self.other_id.clone(), file_id, caption, delivered_sender)
+            }
+            MediaKind::Voice { duration } => Action::SendVoice(
+                self.other_id.clone(),
+                file_id,
+                caption,
+                duration,
+                delivered_sender,
+            ),
+            MediaKind::Video { duration, spoiler } => Action::SendVideo {
+                thread_id: self.other_id.clone(),
+                file_id,
+                caption,
+                duration,
+                spoiler,
+                delivered_sender,
+            },
+            MediaKind::VideoNote { duration } => {
+                Action::SendVideoNote(self.other_id.clone(), file_id, duration, delivered_sender)
+            }
+            MediaKind::Audio { duration, .. } => Action::SendAudio(
+                self.other_id.clone(),
+                file_id,
+                caption,
+                duration,
+                delivered_sender,
+            ),
+            MediaKind::Document {
+                file_name,
+                mime_type,
+            } => Action::SendDocument(
+                self.other_id.clone(),
+                file_id,
+                file_name,
+                mime_type,
+                caption,
+                delivered_sender,
+            ),
+            MediaKind::Contact {
+                phone_number,
+                first_name,
+                last_name,
+            } => Action::SendContact(
+                self.other_id.clone(),
+                phone_number,
+                first_name,
+                last_name,
+                delivered_sender,
+            ),
+            MediaKind::Location {
+                latitude,
+                longitude,
+                title,
+                address,
+            } => Action::SendLocation(
+                self.other_id.clone(),
+                latitude,
+                longitude,
+                title,
+                address,
+                delivered_sender,
+            ),
+        };
+        self.other_handle.send_action(action).await?;
+        Ok(delivered_receiver.await.ok())
+    }
+
+    async fn send_media_group(
+        &self,
+        items: Vec<(MediaKind, String, Option<String>)>,
+    ) -> Result<Option<Vec<i32>>> {
+        let (delivered_sender, delivered_receiver) = oneshot::channel();
+        self.other_handle
+            .send_action(Action::SendMediaGroup(
+                self.other_id.clone(),
+                items,
+                delivered_sender,
+            ))
+            .await?;
+        Ok(delivered_receiver.await.ok())
+    }
+
+    async fn edit_text(&self, message_id: i32, text: String) -> Result<()> {
+        self.other_handle
+            .send_action(Action::EditText(self.other_id.clone(), message_id, text))
+            .await
+    }
+
+    async fn send_poll(&self, question: String, options: Vec<String>) -> Result<()> {
+        self.other_handle
+            .send_action(Action::SendPoll(self.other_id.clone(), question, options))
+            .await
+    }
+
+    async fn send_dice(&self, emoji: DiceEmoji) -> Result<i32> {
+        let (value_sender, value_receiver) = oneshot::channel();
         self.other_handle
-            .send_action(Action::SendText(self.other_id.clone(), text))
+            .send_action(Action::SendDice(self.other_id.clone(), emoji, value_sender))
+            .await?;
+        value_receiver
             .await
+            .context("the other side did not report the dice value")
+    }
+
+    fn check_paused(&self) -> Result<()> {
+        ensure!(
+            !self.paused_by_me,
+            "thread {} is paused; use `/resume` first",
+            self.id
+        );
+        ensure!(
+            !self.paused_by_other,
+            "the other side has paused thread {}",
+            self.id
+        );
+        Ok(())
+    }
+
+    fn check_slow_mode(&self) -> Result<()> {
+        if let (Some(interval), Some(last_sent_at)) = (self.slow_mode, self.last_sent_at) {
+            let elapsed = last_sent_at.elapsed();
+            if elapsed < interval {
+                bail!(
+                    "slow mode: wait {}s",
+                    (interval - elapsed).as_secs_f64().ceil() as u64
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn record_delivered(
+        &mut self,
+        message_id: i32,
+        source_message_id: Option<i32>,
+        text: Option<SentText>,
+    ) {
+        self.delivered_message_ids.push_back(message_id);
+        if let Some(source_message_id) = source_message_id {
+            self.delivered_sources.insert(message_id, source_message_id);
+        }
+        if let Some(text) = text {
+            self.delivered_texts.insert(message_id, text);
+        }
+        if self.delivered_message_ids.len() > MAX_DELIVERED_MESSAGES {
+            if let Some(message_id) = self.delivered_message_ids.pop_front() {
+                self.delivered_sources.remove(&message_id);
+                self.delivered_texts.remove(&message_id);
+            }
+        }
     }
 
     async fn terminate(&self) -> Result<()> {
@@ -62,19 +445,280 @@ impl Thread {
             .send_action(Action::TerminateThread(self.other_id.clone()))
             .await
     }
+
+    fn snapshot(&self) -> ThreadSnapshot {
+        ThreadSnapshot {
+            id: self.id.clone(),
+            anon_mode: self.anon_mode,
+            other_id: self.other_id.clone(),
+            other_login: self.other_handle.user.login.clone(),
+            pinned: self.pinned,
+            slow_mode: self.slow_mode,
+            expires_at: self.expires_at,
+            secret: self.secret,
+            created_at: self.created_at,
+            messages_sent: self.messages_sent,
+            messages_received: self.messages_received,
+            note: self.note.clone(),
+            labels: self.labels.clone(),
+            delivered_message_ids: self.delivered_message_ids.clone(),
+            delivered_sources: sorted_pairs(&self.delivered_sources),
+            delivered_texts: sorted_pairs(&self.delivered_texts),
+            receipts: self.receipts,
+            receipts_sent: self.receipts_sent.iter().copied().collect(),
+            link_previews: self.link_previews,
+            paused_by_me: self.paused_by_me,
+            paused_by_other: self.paused_by_other,
+            priority: self.priority,
+        }
+    }
+
+    /// Slow mode starts anew: the time of the last message is not kept.
+    fn from_snapshot(snapshot: ThreadSnapshot, other_handle: UserHandle) -> Self {
+        Self {
+            id: snapshot.id,
+            anon_mode: snapshot.anon_mode,
+            other_id: snapshot.other_id,
+            other_handle,
+            pinned: snapshot.pinned,
+            slow_mode: snapshot.slow_mode,
+            last_sent_at: None,
+            expires_at: snapshot.expires_at,
+            secret: snapshot.secret,
+            created_at: snapshot.created_at,
+            messages_sent: snapshot.messages_sent,
+            messages_received: snapshot.messages_received,
+            note: snapshot.note,
+            labels: snapshot.labels,
+            delivered_message_ids: snapshot.delivered_message_ids,
+            delivered_sources: snapshot.delivered_sources.into_iter().collect(),
+            delivered_texts: snapshot.delivered_texts.into_iter().collect(),
+            receipts: snapshot.receipts,
+            receipts_sent: snapshot.receipts_sent.into_iter().collect(),
+            link_previews: snapshot.link_previews,
+            paused_by_me: snapshot.paused_by_me,
+            paused_by_other: snapshot.paused_by_other,
+            priority: snapshot.priority,
+        }
+    }
+}
+
+/// A thread as it is stored in a snapshot, with the other side referred to
+/// by login.
+#[derive(Serialize, Deserialize)]
+struct ThreadSnapshot {
+    id: ThreadId,
+    anon_mode: ThreadAnonimityMode,
+    other_id: ThreadId,
+    other_login: String,
+    pinned: bool,
+    slow_mode: Option<Duration>,
+    expires_at: Option<i64>,
+    secret: Option<Duration>,
+    created_at: Option<i64>,
+    messages_sent: u64,
+    messages_received: u64,
+    note: Option<String>,
+    labels: BTreeSet<String>,
+    delivered_message_ids: VecDeque<i32>,
+    delivered_sources: Vec<(i32, i32)>,
+    #[serde(default)]
+    delivered_texts: Vec<(i32, SentText)>,
+    receipts: bool,
+    receipts_sent: BTreeSet<i32>,
+    link_previews: Option<bool>,
+    paused_by_me: bool,
+    paused_by_other: bool,
+    priority: bool,
+}
+
+/// An incoming thread that waits for `/accept` or `/decline`.
+struct PendingThread {
+    thread: Thread,
+    messages: Vec<String>,
+    expires_at: i64,
+}
+
+/// Something that arrived during the do-not-disturb window.
+enum DeferredItem {
+    Message(ThreadId, String, Option<String>, Vec<MessageEntity>),
+    Media(ThreadId, MediaKind, String, Option<String>),
+    Notice(String),
+}
+
+/// The last destructive operation, kept for `/undo`.
+enum UndoOp {
+    Close {
+        thread_id: ThreadId,
+        anon_mode: ThreadAnonimityMode,
+        other_thread_id: ThreadId,
+        other_login: String,
+    },
+    Ban {
+        thread_id: ThreadId,
+    },
+    Archive {
+        thread_id: ThreadId,
+    },
+}
+
+struct UndoRecord {
+    op: UndoOp,
+    recorded_at: Instant,
+}
+
+/// An entry of the ban list, keyed by the banned user's login.
+#[derive(Clone, Serialize, Deserialize)]
+struct BanRecord {
+    thread_id: ThreadId,
+    reason: Option<String>,
+    banned_at: Option<i64>,
+    expires_at: Option<i64>,
+}
+
+/// A destructive command that waits for the "Confirm" or "Cancel" button.
+struct PendingConfirmation {
+    command: Command,
+    question: String,
+    message_id: i32,
+    expires_at: i64,
+}
+
+/// Photos and videos sent as an album, collected until `flush_at`.
+struct PendingAlbum {
+    thread_id: ThreadId,
+    items: Vec<AlbumItem>,
+    flush_at: Instant,
+}
+
+struct AlbumItem {
+    message_id: i32,
+    kind: MediaKind,
+    file_id: String,
+    caption: Option<String>,
+}
+
+/// A message that waits to be sent with `/schedule`.
+#[derive(Clone, Serialize, Deserialize)]
+struct ScheduledMessage {
+    id: u64,
+    thread_id: ThreadId,
+    message_id: i32,
+    text: String,
+    deliver_at: i64,
+}
+
+/// A poll relayed to us with `/poll`, kept to route our votes back.
+#[derive(Clone, Serialize, Deserialize)]
+struct ReceivedPoll {
+    thread_id: ThreadId,
+    question: String,
+    options: Vec<String>,
+}
+
+/// A personal reminder set with `/remind`.
+#[derive(Clone, Serialize, Deserialize)]
+struct Reminder {
+    id: u64,
+    text: String,
+    fire_at: i64,
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
+#[derive(Default, Clone, Serialize, Deserialize)]
+struct Stats {
+    threads_started: u64,
+    messages_sent: u64,
+    messages_received: u64,
+    bans_issued: u64,
+}
+
+struct RandomPartner {
+    login: String,
+    queued_text: Option<String>,
+    by_topic: bool,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// What a `HandlerBuilder` has restored from the event log, as it is stored in
+/// a snapshot. Everything is kept sorted, so that the same state always gives
+/// the same snapshot. Maps with integer keys are stored as lists of pairs:
+/// JSON object keys are strings, which the log reader does not turn back into
+/// integers.
+#[derive(Serialize, Deserialize)]
+pub struct HandlerSnapshot {
+    threads: Vec<ThreadSnapshot>,
+    archived_threads: Vec<ThreadSnapshot>,
+    pending_threads: Vec<PendingThreadSnapshot>,
+    message_id_to_thread_id: Vec<(i32, ThreadId)>,
+    banlist: BTreeMap<String, BanRecord>,
+    blocklist: BTreeSet<String>,
+    recent_partners: VecDeque<String>,
+    scheduled_deletions: BTreeSet<(i64, i64, i32)>,
+    default_thread_id: Option<ThreadId>,
+    last_used_thread_id: Option<ThreadId>,
+    greeting: Option<String>,
+    dnd: Option<DndWindow>,
+    max_incoming: Option<usize>,
+    scheduled_messages: Vec<ScheduledMessage>,
+    next_scheduled_id: u64,
+    reminders: Vec<Reminder>,
+    next_reminder_id: u64,
+    received_polls: BTreeMap<String, ReceivedPoll>,
+    stats: Stats,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PendingThreadSnapshot {
+    thread: ThreadSnapshot,
+    messages: Vec<String>,
+    expires_at: i64,
+}
+
+fn sorted_pairs<K: Ord + Copy, V: Clone>(map: &HashMap<K, V>) -> Vec<(K, V)> {
+    let mut pairs = map
+        .iter()
+        .map(|(key, value)| (*key, value.clone()))
+        .collect::<Vec<_>>();
+    pairs.sort_by_key(|(key, _)| *key);
+    pairs
+}
+
+fn sorted_thread_snapshots(threads: &HashMap<ThreadId, Thread>) -> Vec<ThreadSnapshot> {
+    let mut snapshots = threads.values().map(Thread::snapshot).collect::<Vec<_>>();
+    snapshots.sort_by(|a, b| a.id.cmp(&b.id));
+    snapshots
+}
+
 pub struct HandlerBuilder {
     user_handle: UserHandle,
     chat_id: i64,
     handle_registry: Reader<HashMap<String, UserHandle>>,
+    random_queue: Arc<Mutex<RandomQueue>>,
+    rooms: Arc<Mutex<Rooms>>,
     command_receiver: mpsc::Receiver<CommandRequest>,
     action_receiver: mpsc::Receiver<ActionRequest>,
     threads: HashMap<ThreadId, Thread>,
+    archived_threads: HashMap<ThreadId, Thread>,
+    pending_threads: HashMap<ThreadId, PendingThread>,
     message_id_to_thread_id: HashMap<i32, ThreadId>,
-    banlist: HashMap<String, ThreadId>,
+    banlist: HashMap<String, BanRecord>,
+    blocklist: HashSet<String>,
+    recent_partners: VecDeque<String>,
+    scheduled_deletions: BTreeSet<(i64, i64, i32)>,
+    default_thread_id: Option<ThreadId>,
+    last_used_thread_id: Option<ThreadId>,
+    greeting: Option<String>,
+    dnd: Option<DndWindow>,
+    max_incoming: Option<usize>,
+    scheduled_messages: Vec<ScheduledMessage>,
+    next_scheduled_id: u64,
+    reminders: Vec<Reminder>,
+    next_reminder_id: u64,
+    received_polls: HashMap<String, ReceivedPoll>,
+    stats: Stats,
 }
 
 impl HandlerBuilder {
@@ -82,6 +726,8 @@ impl HandlerBuilder {
         user_handle: UserHandle,
         chat_id: i64,
         handle_registry: Reader<HashMap<String, UserHandle>>,
+        random_queue: Arc<Mutex<RandomQueue>>,
+        rooms: Arc<Mutex<Rooms>>,
         command_receiver: mpsc::Receiver<CommandRequest>,
         action_receiver: mpsc::Receiver<ActionRequest>,
     ) -> Self {
@@ -89,15 +735,133 @@ impl HandlerBuilder {
             user_handle,
             chat_id,
             handle_registry,
+            random_queue,
+            rooms,
             command_receiver,
             action_receiver,
             threads: HashMap::new(),
+            archived_threads: HashMap::new(),
+            pending_threads: HashMap::new(),
             message_id_to_thread_id: HashMap::new(),
             banlist: HashMap::new(),
+            blocklist: HashSet::new(),
+            recent_partners: VecDeque::new(),
+            scheduled_deletions: BTreeSet::new(),
+            default_thread_id: None,
+            last_used_thread_id: None,
+            greeting: None,
+            dnd: None,
+            max_incoming: None,
+            scheduled_messages: vec![],
+            next_scheduled_id: 0,
+            reminders: vec![],
+            next_reminder_id: 0,
+            received_polls: HashMap::new(),
+            stats: Stats::default(),
+        }
+    }
+
+    /// The state of the handler to be written into a snapshot of the event
+    /// log.
+    pub fn snapshot(&self) -> HandlerSnapshot {
+        let mut pending_threads = self
+            .pending_threads
+            .values()
+            .map(|pending| PendingThreadSnapshot {
+                thread: pending.thread.snapshot(),
+                messages: pending.messages.clone(),
+                expires_at: pending.expires_at,
+            })
+            .collect::<Vec<_>>();
+        pending_threads.sort_by(|a, b| a.thread.id.cmp(&b.thread.id));
+        HandlerSnapshot {
+            threads: sorted_thread_snapshots(&self.threads),
+            archived_threads: sorted_thread_snapshots(&self.archived_threads),
+            pending_threads,
+            message_id_to_thread_id: sorted_pairs(&self.message_id_to_thread_id),
+            banlist: self.banlist.clone().into_iter().collect(),
+            blocklist: self.blocklist.iter().cloned().collect(),
+            recent_partners: self.recent_partners.clone(),
+            scheduled_deletions: self.scheduled_deletions.clone(),
+            default_thread_id: self.default_thread_id.clone(),
+            last_used_thread_id: self.last_used_thread_id.clone(),
+            greeting: self.greeting.clone(),
+            dnd: self.dnd,
+            max_incoming: self.max_incoming,
+            scheduled_messages: self.scheduled_messages.clone(),
+            next_scheduled_id: self.next_scheduled_id,
+            reminders: self.reminders.clone(),
+            next_reminder_id: self.next_reminder_id,
+            received_polls: self.received_polls.clone().into_iter().collect(),
+            stats: self.stats.clone(),
+        }
+    }
+
+    /// Replaces the state with the one from a snapshot. The users the threads
+    /// lead to must have been restored already.
+    pub fn restore_snapshot(&mut self, snapshot: HandlerSnapshot) -> Result<()> {
+        let restore_thread = |snapshot: ThreadSnapshot| -> Result<Thread> {
+            let other_handle = self
+                .handle_registry
+                .read()
+                .expect("HandlerBuilder handle_registry.read() failed")
+                .get(&snapshot.other_login)
+                .with_context(|| format!("user not found: @{}", snapshot.other_login))?
+                .clone();
+            Ok(Thread::from_snapshot(snapshot, other_handle))
+        };
+        let mut threads = HashMap::new();
+        for thread in snapshot.threads {
+            let thread = restore_thread(thread)?;
+            threads.insert(thread.id.clone(), thread);
         }
+        let mut archived_threads = HashMap::new();
+        for thread in snapshot.archived_threads {
+            let thread = restore_thread(thread)?;
+            archived_threads.insert(thread.id.clone(), thread);
+        }
+        let mut pending_threads = HashMap::new();
+        for pending in snapshot.pending_threads {
+            let thread = restore_thread(pending.thread)?;
+            pending_threads.insert(
+                thread.id.clone(),
+                PendingThread {
+                    thread,
+                    messages: pending.messages,
+                    expires_at: pending.expires_at,
+                },
+            );
+        }
+
+        self.threads = threads;
+        self.archived_threads = archived_threads;
+        self.pending_threads = pending_threads;
+        self.message_id_to_thread_id = snapshot.message_id_to_thread_id.into_iter().collect();
+        self.banlist = snapshot.banlist.into_iter().collect();
+        self.blocklist = snapshot.blocklist.into_iter().collect();
+        self.recent_partners = snapshot.recent_partners;
+        self.scheduled_deletions = snapshot.scheduled_deletions;
+        self.default_thread_id = snapshot.default_thread_id;
+        self.last_used_thread_id = snapshot.last_used_thread_id;
+        self.greeting = snapshot.greeting;
+        self.dnd = snapshot.dnd;
+        self.max_incoming = snapshot.max_incoming;
+        self.scheduled_messages = snapshot.scheduled_messages;
+        self.next_scheduled_id = snapshot.next_scheduled_id;
+        self.reminders = snapshot.reminders;
+        self.next_reminder_id = snapshot.next_reminder_id;
+        self.received_polls = snapshot.received_polls.into_iter().collect();
+        self.stats = snapshot.stats;
+        Ok(())
     }
 
-    pub fn handle_thread_started(&mut self, event: ThreadStartedEvent) -> Result<()> {
+    /// `ts` is the time the event has been written at, it stands in for the
+    /// creation time of threads started before that was recorded.
+    pub fn handle_thread_started(
+        &mut self,
+        event: ThreadStartedEvent,
+        ts: Option<i64>,
+    ) -> Result<()> {
         let other_handle = self
             .handle_registry
             .read()
@@ -105,119 +869,579 @@ impl HandlerBuilder {
             .get(&event.other_login)
             .with_context(|| format!("user not found: @{}", event.other_login))?
             .clone();
-        let thread = Thread {
-            id: event.my_thread_id.clone(),
-            anon_mode: event.anon_mode,
-            other_id: event.other_thread_id,
-            other_handle,
+        if event
+            .is_initiator
+            .unwrap_or(event.anon_mode != ThreadAnonimityMode::Them)
+        {
+            self.stats.threads_started += 1;
+        }
+        if event.anon_mode == ThreadAnonimityMode::Both {
+            remember_partner(&mut self.recent_partners, event.other_login.clone());
+        }
+        let mut thread = match self.pending_threads.remove(&event.my_thread_id) {
+            Some(pending) => pending.thread,
+            None => Thread::new(
+                event.my_thread_id.clone(),
+                event.anon_mode,
+                event.other_thread_id,
+                other_handle,
+            ),
         };
+        thread.created_at = event.created_at.or(ts);
         self.threads.insert(event.my_thread_id, thread);
         Ok(())
     }
 
+    pub fn handle_thread_requested(&mut self, event: ThreadRequestedEvent) -> Result<()> {
+        let other_handle = self
+            .handle_registry
+            .read()
+            .expect("HandlerBuilder handle_registry.read() failed")
+            .get(&event.other_login)
+            .with_context(|| format!("user not found: @{}", event.other_login))?
+            .clone();
+        let thread = Thread::new(
+            event.my_thread_id.clone(),
+            event.anon_mode,
+            event.other_thread_id,
+            other_handle,
+        );
+        self.pending_threads.insert(
+            event.my_thread_id,
+            PendingThread {
+                thread,
+                messages: vec![],
+                expires_at: event.expires_at,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn handle_thread_request_message(
+        &mut self,
+        event: ThreadRequestMessageEvent,
+    ) -> Result<()> {
+        self.pending_threads
+            .get_mut(&event.thread_id)
+            .with_context(|| format!("pending thread is not found: {}", event.thread_id))?
+            .messages
+            .push(event.text);
+        Ok(())
+    }
+
     pub fn handle_thread_message_received(&mut self, event: ThreadMessageReceivedEvent) {
+        match event.kind {
+            Some(MessageKind::Sent) => self.stats.messages_sent += 1,
+            Some(MessageKind::Received) => self.stats.messages_received += 1,
+            Some(MessageKind::Service) | None => {}
+        }
+        if event.kind == Some(MessageKind::Sent) {
+            self.last_used_thread_id = Some(event.thread_id.clone());
+        }
+        if let Some(thread) = self
+            .threads
+            .get_mut(&event.thread_id)
+            .or_else(|| self.archived_threads.get_mut(&event.thread_id))
+        {
+            match event.kind {
+                Some(MessageKind::Sent) => thread.messages_sent += 1,
+                Some(MessageKind::Received) => thread.messages_received += 1,
+                Some(MessageKind::Service) | None => {}
+            }
+        }
         self.message_id_to_thread_id
             .insert(event.message_id, event.thread_id);
     }
 
+    pub fn handle_thread_media_received(&mut self, event: ThreadMediaReceivedEvent) {
+        self.handle_thread_message_received(ThreadMessageReceivedEvent {
+            login: event.login,
+            message_id: event.message_id,
+            thread_id: event.thread_id,
+            kind: Some(event.kind),
+        });
+    }
+
+    pub fn handle_outgoing_message(&mut self, event: OutgoingMessageEvent) -> Result<()> {
+        let thread = self
+            .threads
+            .get_mut(&event.thread_id)
+            .with_context(|| format!("thread is not found: {}", event.thread_id))?;
+        thread.record_delivered(event.message_id, event.source_message_id, event.text);
+        if let Some(delete_at) = event.delete_at {
+            if delete_at > now_timestamp() - DELETE_MESSAGE_WINDOW_SECS {
+                self.scheduled_deletions.insert((
+                    delete_at,
+                    thread.other_handle.chat_id,
+                    event.message_id,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn handle_outgoing_message_deleted(&mut self, event: OutgoingMessageDeletedEvent) {
+        if let Some(thread) = self.threads.get_mut(&event.thread_id) {
+            let chat_id = thread.other_handle.chat_id;
+            self.scheduled_deletions
+                .retain(|&(_, c, m)| (c, m) != (chat_id, event.message_id));
+            thread
+                .delivered_message_ids
+                .retain(|&id| id != event.message_id);
+            thread.delivered_texts.remove(&event.message_id);
+        }
+    }
+
     pub fn terminate_thread(&mut self, thread_id: &str) -> Result<()> {
         ensure!(
-            self.threads.remove(thread_id).is_some(),
+            self.threads.remove(thread_id).is_some()
+                || self.archived_threads.remove(thread_id).is_some()
+                || self.pending_threads.remove(thread_id).is_some(),
             "thread is not found: {}",
             thread_id,
         );
+        self.forget_default_thread(thread_id);
+        Ok(())
+    }
+
+    pub fn handle_default_thread_changed(&mut self, event: DefaultThreadChangedEvent) {
+        self.default_thread_id = event.thread_id;
+    }
+
+    fn forget_default_thread(&mut self, thread_id: &str) {
+        if self.default_thread_id.as_deref() == Some(thread_id) {
+            self.default_thread_id = None;
+        }
+        if self.last_used_thread_id.as_deref() == Some(thread_id) {
+            self.last_used_thread_id = None;
+        }
+    }
+
+    pub fn handle_thread_archived(&mut self, event: ThreadArchivedEvent) -> Result<()> {
+        let thread = self
+            .threads
+            .remove(&event.thread_id)
+            .with_context(|| format!("thread is not found: {}", event.thread_id))?;
+        self.archived_threads.insert(event.thread_id, thread);
         Ok(())
     }
 
-    pub fn handle_user_banned(&mut self, event: UserBannedEvent) -> Result<()> {
+    pub fn handle_thread_pinned(&mut self, event: ThreadPinnedEvent) -> Result<()> {
         self.threads
-            .remove(&event.banned_thread_id)
-            .with_context(|| format!("thread is not found: {}", event.banned_thread_id))?;
-        self.banlist
-            .insert(event.banned_login, event.banned_thread_id);
+            .get_mut(&event.thread_id)
+            .with_context(|| format!("thread is not found: {}", event.thread_id))?
+            .pinned = true;
         Ok(())
     }
 
-    pub fn handle_user_unbanned(&mut self, event: UserUnbannedEvent) -> Result<()> {
-        self.banlist
-            .remove(&event.unbanned_login)
-            .with_context(|| format!("user is not banned: {}", event.unbanned_login))?;
+    pub fn handle_thread_slow_mode_changed(
+        &mut self,
+        event: ThreadSlowModeChangedEvent,
+    ) -> Result<()> {
+        let thread = self
+            .threads
+            .get_mut(&event.thread_id)
+            .or_else(|| self.archived_threads.get_mut(&event.thread_id))
+            .with_context(|| format!("thread is not found: {}", event.thread_id))?;
+        thread.slow_mode = seconds_to_interval(event.seconds);
         Ok(())
     }
 
-    pub fn handle_user_stopped(&mut self) {
-        self.user_handle.is_stopped.store(true, Ordering::Relaxed);
+    pub fn handle_thread_expiry_changed(&mut self, event: ThreadExpiryChangedEvent) -> Result<()> {
+        let thread = self
+            .threads
+            .get_mut(&event.thread_id)
+            .or_else(|| self.archived_threads.get_mut(&event.thread_id))
+            .with_context(|| format!("thread is not found: {}", event.thread_id))?;
+        thread.expires_at = event.expires_at;
+        Ok(())
     }
 
-    pub fn handle_user_started(&mut self) {
-        self.user_handle.is_stopped.store(false, Ordering::Relaxed);
+    pub fn handle_thread_note_changed(&mut self, event: ThreadNoteChangedEvent) -> Result<()> {
+        let thread = self
+            .threads
+            .get_mut(&event.thread_id)
+            .or_else(|| self.archived_threads.get_mut(&event.thread_id))
+            .with_context(|| format!("thread is not found: {}", event.thread_id))?;
+        thread.note = event.text;
+        Ok(())
     }
 
-    pub fn build(self, bot: AutoSend<Bot>, event_service: EventServiceHandle) -> Handler {
-        Handler {
-            bot,
-            event_service,
-            chat_id: self.chat_id,
-            user_handle: self.user_handle,
-            handle_registry: self.handle_registry,
-            command_receiver: self.command_receiver,
-            action_receiver: self.action_receiver,
-            threads: self.threads,
-            message_id_to_thread_id: self.message_id_to_thread_id,
-            banlist: self.banlist,
-        }
+    pub fn handle_thread_labeled(&mut self, event: ThreadLabeledEvent) -> Result<()> {
+        self.threads
+            .get_mut(&event.thread_id)
+            .or_else(|| self.archived_threads.get_mut(&event.thread_id))
+            .with_context(|| format!("thread is not found: {}", event.thread_id))?
+            .labels
+            .insert(event.label);
+        Ok(())
     }
-}
 
-////////////////////////////////////////////////////////////////////////////////
+    pub fn handle_thread_unlabeled(&mut self, event: ThreadUnlabeledEvent) -> Result<()> {
+        self.threads
+            .get_mut(&event.thread_id)
+            .or_else(|| self.archived_threads.get_mut(&event.thread_id))
+            .with_context(|| format!("thread is not found: {}", event.thread_id))?
+            .labels
+            .remove(&event.label);
+        Ok(())
+    }
 
-pub struct Handler {
-    bot: AutoSend<Bot>,
-    event_service: EventServiceHandle,
-    chat_id: i64,
-    user_handle: UserHandle,
-    handle_registry: Reader<HashMap<String, UserHandle>>,
-    command_receiver: mpsc::Receiver<CommandRequest>,
-    action_receiver: mpsc::Receiver<ActionRequest>,
-    threads: HashMap<ThreadId, Thread>,
-    message_id_to_thread_id: HashMap<i32, ThreadId>,
-    banlist: HashMap<String, ThreadId>,
-}
+    pub fn set_thread_paused(&mut self, thread_id: &str, by_me: bool, paused: bool) -> Result<()> {
+        let thread = self
+            .threads
+            .get_mut(thread_id)
+            .or_else(|| self.archived_threads.get_mut(thread_id))
+            .with_context(|| format!("thread is not found: {}", thread_id))?;
+        if by_me {
+            thread.paused_by_me = paused;
+        } else {
+            thread.paused_by_other = paused;
+        }
+        Ok(())
+    }
 
-impl Handler {
-    pub fn new(
-        bot: AutoSend<Bot>,
-        event_service: EventServiceHandle,
-        chat_id: i64,
-        user_handle: UserHandle,
-        handle_registry: Reader<HashMap<String, UserHandle>>,
-        command_receiver: mpsc::Receiver<CommandRequest>,
-        action_receiver: mpsc::Receiver<ActionRequest>,
-    ) -> Self {
-        Self {
+    pub fn handle_thread_receipts_changed(
+        &mut self,
+        event: ThreadReceiptsChangedEvent,
+    ) -> Result<()> {
+        let thread = self
+            .threads
+            .get_mut(&event.thread_id)
+            .or_else(|| self.archived_threads.get_mut(&event.thread_id))
+            .with_context(|| format!("thread is not found: {}", event.thread_id))?;
+        thread.receipts = event.enabled;
+        Ok(())
+    }
+
+    pub fn handle_thread_previews_changed(
+        &mut self,
+        event: ThreadPreviewsChangedEvent,
+    ) -> Result<()> {
+        let thread = self
+            .threads
+            .get_mut(&event.thread_id)
+            .or_else(|| self.archived_threads.get_mut(&event.thread_id))
+            .with_context(|| format!("thread is not found: {}", event.thread_id))?;
+        thread.link_previews = Some(event.enabled);
+        Ok(())
+    }
+
+    pub fn handle_thread_priority_changed(
+        &mut self,
+        event: ThreadPriorityChangedEvent,
+    ) -> Result<()> {
+        let thread = self
+            .threads
+            .get_mut(&event.thread_id)
+            .or_else(|| self.archived_threads.get_mut(&event.thread_id))
+            .with_context(|| format!("thread is not found: {}", event.thread_id))?;
+        thread.priority = event.priority;
+        Ok(())
+    }
+
+    pub fn handle_receipt_sent(&mut self, event: ReceiptSentEvent) {
+        if let Some(thread) = self
+            .threads
+            .get_mut(&event.thread_id)
+            .or_else(|| self.archived_threads.get_mut(&event.thread_id))
+        {
+            thread.receipts_sent.insert(event.message_id);
+        }
+    }
+
+    pub fn handle_thread_secret_changed(&mut self, event: ThreadSecretChangedEvent) -> Result<()> {
+        let thread = self
+            .threads
+            .get_mut(&event.thread_id)
+            .or_else(|| self.archived_threads.get_mut(&event.thread_id))
+            .with_context(|| format!("thread is not found: {}", event.thread_id))?;
+        thread.secret = seconds_to_interval(event.seconds);
+        Ok(())
+    }
+
+    pub fn handle_thread_unpinned(&mut self, event: ThreadUnpinnedEvent) -> Result<()> {
+        self.threads
+            .get_mut(&event.thread_id)
+            .with_context(|| format!("thread is not found: {}", event.thread_id))?
+            .pinned = false;
+        Ok(())
+    }
+
+    pub fn handle_thread_unarchived(&mut self, event: ThreadUnarchivedEvent) -> Result<()> {
+        let thread = self
+            .archived_threads
+            .remove(&event.thread_id)
+            .with_context(|| format!("thread is not archived: {}", event.thread_id))?;
+        self.threads.insert(event.thread_id, thread);
+        Ok(())
+    }
+
+    /// `ts` stands in for the ban time of bans issued before it was recorded.
+    pub fn handle_user_banned(&mut self, event: UserBannedEvent, ts: Option<i64>) -> Result<()> {
+        self.stats.bans_issued += 1;
+        self.threads
+            .remove(&event.banned_thread_id)
+            .with_context(|| format!("thread is not found: {}", event.banned_thread_id))?;
+        self.forget_default_thread(&event.banned_thread_id);
+        self.banlist.insert(
+            event.banned_login,
+            BanRecord {
+                thread_id: event.banned_thread_id,
+                reason: event.reason,
+                banned_at: event.banned_at.or(ts),
+                expires_at: event.expires_at,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn handle_user_unbanned(&mut self, event: UserUnbannedEvent) -> Result<()> {
+        self.banlist
+            .remove(&event.unbanned_login)
+            .with_context(|| format!("user is not banned: {}", event.unbanned_login))?;
+        Ok(())
+    }
+
+    pub fn handle_user_blocked(&mut self, event: UserBlockedEvent) {
+        self.blocklist.insert(event.blocked_login);
+    }
+
+    pub fn handle_user_unblocked(&mut self, event: UserUnblockedEvent) -> Result<()> {
+        ensure!(
+            self.blocklist.remove(&event.unblocked_login),
+            "user is not blocked: {}",
+            event.unblocked_login,
+        );
+        Ok(())
+    }
+
+    pub fn handle_topic_changed(&mut self, event: TopicChangedEvent) {
+        *self
+            .user_handle
+            .topic
+            .write()
+            .expect("HandlerBuilder topic.write() failed") = event.topic;
+    }
+
+    pub fn handle_user_interests_changed(&mut self, event: UserInterestsChangedEvent) {
+        *self
+            .user_handle
+            .interests
+            .write()
+            .expect("HandlerBuilder interests.write() failed") = event.tags;
+    }
+
+    pub fn handle_message_scheduled(&mut self, event: MessageScheduledEvent) {
+        self.next_scheduled_id = self.next_scheduled_id.max(event.id + 1);
+        insert_scheduled_message(
+            &mut self.scheduled_messages,
+            ScheduledMessage {
+                id: event.id,
+                thread_id: event.thread_id,
+                message_id: event.message_id,
+                text: event.text,
+                deliver_at: event.deliver_at,
+            },
+        );
+    }
+
+    pub fn handle_scheduled_message_removed(&mut self, event: ScheduledMessageRemovedEvent) {
+        self.scheduled_messages.retain(|m| m.id != event.id);
+    }
+
+    pub fn handle_poll_received(&mut self, event: PollReceivedEvent) {
+        self.received_polls.insert(
+            event.poll_id,
+            ReceivedPoll {
+                thread_id: event.thread_id,
+                question: event.question,
+                options: event.options,
+            },
+        );
+    }
+
+    pub fn handle_reminder_set(&mut self, event: ReminderSetEvent) {
+        self.next_reminder_id = self.next_reminder_id.max(event.id + 1);
+        self.reminders.push(Reminder {
+            id: event.id,
+            text: event.text,
+            fire_at: event.fire_at,
+        });
+        self.reminders.sort_by_key(|r| (r.fire_at, r.id));
+    }
+
+    pub fn handle_reminder_removed(&mut self, event: ReminderRemovedEvent) {
+        self.reminders.retain(|r| r.id != event.id);
+    }
+
+    pub fn handle_incoming_limit_changed(&mut self, event: IncomingLimitChangedEvent) {
+        self.max_incoming = event.max_incoming;
+    }
+
+    pub fn handle_dnd_changed(&mut self, event: DndChangedEvent) {
+        self.dnd = event.window;
+    }
+
+    pub fn handle_greeting_changed(&mut self, event: GreetingChangedEvent) {
+        self.greeting = event.text;
+    }
+
+    pub fn handle_user_profile_updated(&mut self, event: UserProfileUpdatedEvent) -> Arc<User> {
+        let user = Arc::new(User {
+            login: event.login,
+            first_name: event.first_name,
+            last_name: event.last_name,
+        });
+        self.user_handle.user = user.clone();
+        user
+    }
+
+    pub fn handle_user_stopped(&mut self) {
+        self.user_handle.is_stopped.store(true, Ordering::Relaxed);
+    }
+
+    pub fn handle_user_started(&mut self) {
+        self.user_handle.is_stopped.store(false, Ordering::Relaxed);
+    }
+
+    pub fn handle_user_settings_changed(&mut self, event: UserSettingsChangedEvent) {
+        *self
+            .user_handle
+            .settings
+            .write()
+            .expect("HandlerBuilder settings.write() failed") = event.settings;
+    }
+
+    pub fn build(
+        self,
+        bot: AutoSend<Bot>,
+        event_service: EventServiceHandle,
+        started_at: Instant,
+        storage: Option<Storage>,
+        media_limits: MediaLimits,
+        media_policy: Arc<RwLock<MediaPolicy>>,
+    ) -> Handler {
+        Handler {
             bot,
             event_service,
-            chat_id,
-            user_handle,
-            handle_registry,
-            command_receiver,
-            action_receiver,
-            threads: HashMap::new(),
-            message_id_to_thread_id: HashMap::new(),
-            banlist: HashMap::new(),
+            started_at,
+            storage,
+            media_limits,
+            media_policy,
+            chat_id: self.chat_id,
+            user_handle: self.user_handle,
+            handle_registry: self.handle_registry,
+            random_queue: self.random_queue,
+            rooms: self.rooms,
+            command_receiver: self.command_receiver,
+            action_receiver: self.action_receiver,
+            threads: self.threads,
+            archived_threads: self.archived_threads,
+            pending_threads: self.pending_threads,
+            archive_notices_sent: HashSet::new(),
+            quoted_texts: HashMap::new(),
+            quoted_message_ids: VecDeque::new(),
+            message_id_to_thread_id: self.message_id_to_thread_id,
+            banlist: self.banlist,
+            blocklist: self.blocklist,
+            recent_partners: self.recent_partners,
+            scheduled_deletions: self.scheduled_deletions,
+            default_thread_id: self.default_thread_id,
+            last_used_thread_id: self.last_used_thread_id,
+            last_undo: None,
+            pending_confirmations: HashMap::new(),
+            location_confirmed_threads: HashSet::new(),
+            albums: HashMap::new(),
+            greeting: self.greeting,
+            dnd: self.dnd,
+            deferred_items: VecDeque::new(),
+            deferred_items_dropped: 0,
+            max_incoming: self.max_incoming,
+            scheduled_messages: self.scheduled_messages,
+            next_scheduled_id: self.next_scheduled_id,
+            reminders: self.reminders,
+            next_reminder_id: self.next_reminder_id,
+            received_polls: self.received_polls,
+            stats: self.stats,
+            is_forgotten: false,
         }
     }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub struct Handler {
+    bot: AutoSend<Bot>,
+    event_service: EventServiceHandle,
+    started_at: Instant,
+    storage: Option<Storage>,
+    media_limits: MediaLimits,
+    media_policy: Arc<RwLock<MediaPolicy>>,
+    chat_id: i64,
+    user_handle: UserHandle,
+    handle_registry: Reader<HashMap<String, UserHandle>>,
+    random_queue: Arc<Mutex<RandomQueue>>,
+    rooms: Arc<Mutex<Rooms>>,
+    command_receiver: mpsc::Receiver<CommandRequest>,
+    action_receiver: mpsc::Receiver<ActionRequest>,
+    threads: HashMap<ThreadId, Thread>,
+    archived_threads: HashMap<ThreadId, Thread>,
+    pending_threads: HashMap<ThreadId, PendingThread>,
+    archive_notices_sent: HashSet<ThreadId>,
+    quoted_texts: HashMap<i32, String>,
+    quoted_message_ids: VecDeque<i32>,
+    message_id_to_thread_id: HashMap<i32, ThreadId>,
+    banlist: HashMap<String, BanRecord>,
+    blocklist: HashSet<String>,
+    recent_partners: VecDeque<String>,
+    scheduled_deletions: BTreeSet<(i64, i64, i32)>,
+    default_thread_id: Option<ThreadId>,
+    last_used_thread_id: Option<ThreadId>,
+    last_undo: Option<UndoRecord>,
+    pending_confirmations: HashMap<u64, PendingConfirmation>,
+    location_confirmed_threads: HashSet<ThreadId>,
+    albums: HashMap<String, PendingAlbum>,
+    greeting: Option<String>,
+    dnd: Option<DndWindow>,
+    deferred_items: VecDeque<DeferredItem>,
+    deferred_items_dropped: usize,
+    max_incoming: Option<usize>,
+    scheduled_messages: Vec<ScheduledMessage>,
+    next_scheduled_id: u64,
+    reminders: Vec<Reminder>,
+    next_reminder_id: u64,
+    received_polls: HashMap<String, ReceivedPoll>,
+    stats: Stats,
+    is_forgotten: bool,
+}
 
+impl Handler {
     pub async fn run(&mut self) {
         loop {
+            let deadline = self.next_deadline();
+            let album_flush = self.next_album_flush();
             select! {
                 mb_request = self.command_receiver.recv() => {
                     let request = match mb_request {
                         Some(request) => request,
                         None => break,
                     };
-                    let result = self.handle_command(request.command).await;
+                    self.user_handle
+                        .last_activity
+                        .store(now_timestamp(), Ordering::Relaxed);
+                    let name = request.command.name();
+                    let result = self
+                        .handle_command(request.command)
+                        .await
+                        .map_err(|err| match name {
+                            Some(name) => err.context(format!("/{}", name)),
+                            None => err,
+                        });
                     request.result_sender.send(result).ok();
+                    if self.is_forgotten {
+                        break;
+                    }
                     tokio::time::sleep(Duration::from_millis(250)).await;
                 }
                 mb_request = self.action_receiver.recv() => {
@@ -228,6 +1452,17 @@ impl Handler {
                     let result = self.handle_action(request.action).await;
                     request.result_sender.send(result).ok();
                 }
+                _ = sleep_until_instant(album_flush) => {
+                    self.flush_due_albums().await;
+                }
+                _ = sleep_until(deadline) => {
+                    if let Err(err) = self.handle_deadlines().await {
+                        error!(
+                            "failed to handle deadlines of @{}: {:#}",
+                            self.user_handle.user.login, err
+                        );
+                    }
+                }
             }
         }
         debug!(
@@ -236,75 +1471,807 @@ impl Handler {
         );
     }
 
-    async fn handle_command(&mut self, command: Command) -> Result<()> {
+    fn next_deadline(&self) -> Option<i64> {
+        let random_deadline = self
+            .random_queue
+            .lock()
+            .expect("handler random_queue.lock() failed")
+            .get(&self.user_handle.user.login)
+            .map(|request| request.expires_at);
+        let pending_deadline = self.pending_threads.values().map(|p| p.expires_at).min();
+        let deletion_deadline = self
+            .scheduled_deletions
+            .iter()
+            .next()
+            .map(|&(delete_at, _, _)| delete_at);
+        let thread_deadline = self
+            .threads
+            .values()
+            .chain(self.archived_threads.values())
+            .filter_map(|th| th.expires_at)
+            .min();
+        let dnd_deadline = if self.deferred_items.is_empty() {
+            None
+        } else if self.is_dnd_active() {
+            self.dnd.map(|window| window.next_end(now_timestamp()))
+        } else {
+            Some(now_timestamp())
+        };
+        let scheduled_deadline = self.scheduled_messages.first().map(|m| m.deliver_at);
+        let reminder_deadline = self.reminders.first().map(|r| r.fire_at);
+        let ban_deadline = self.banlist.values().filter_map(|ban| ban.expires_at).min();
+        let confirmation_deadline = self
+            .pending_confirmations
+            .values()
+            .map(|c| c.expires_at)
+            .min();
+        random_deadline
+            .into_iter()
+            .chain(confirmation_deadline)
+            .chain(scheduled_deadline)
+            .chain(reminder_deadline)
+            .chain(ban_deadline)
+            .chain(dnd_deadline)
+            .chain(pending_deadline)
+            .chain(thread_deadline)
+            .chain(deletion_deadline)
+            .min()
+    }
+
+    async fn handle_deadlines(&mut self) -> Result<()> {
+        let now = now_timestamp();
+
+        if !self.deferred_items.is_empty() && !self.is_dnd_active() {
+            self.flush_deferred_items().await;
+        }
+
+        while let Some(&(delete_at, chat_id, message_id)) = self.scheduled_deletions.iter().next() {
+            if delete_at > now {
+                break;
+            }
+            self.scheduled_deletions
+                .remove(&(delete_at, chat_id, message_id));
+            if let Err(err) = self.bot.delete_message(chat_id, message_id).await {
+                debug!(
+                    "failed to delete secret message {} in chat {}: {}",
+                    message_id, chat_id, err
+                );
+                continue;
+            }
+            for thread in self.threads.values_mut() {
+                if thread.other_handle.chat_id == chat_id {
+                    thread.delivered_message_ids.retain(|&id| id != message_id);
+                }
+            }
+        }
+
+        while self
+            .scheduled_messages
+            .first()
+            .is_some_and(|m| m.deliver_at <= now)
+        {
+            let scheduled = self.scheduled_messages.remove(0);
+            self.deliver_scheduled_message(scheduled).await?;
+        }
+
+        while self.reminders.first().is_some_and(|r| r.fire_at <= now) {
+            let reminder = self.reminders.remove(0);
+            self.event_service
+                .write(Event::ReminderRemoved(ReminderRemovedEvent {
+                    login: self.user_handle.user.login.clone(),
+                    id: reminder.id,
+                }))
+                .wait_written()
+                .await?;
+            self.send_to_self(self.format_text(Key::Reminder, &[&escape_markup(&reminder.text)]))
+                .await?;
+        }
+
+        let mut expired_bans = self
+            .banlist
+            .iter()
+            .filter(|(_, ban)| ban.expires_at.is_some_and(|at| at <= now))
+            .map(|(login, ban)| (login.clone(), ban.thread_id.clone()))
+            .collect::<Vec<_>>();
+        expired_bans.sort();
+        for (unbanned_login, thread_id) in expired_bans {
+            self.event_service
+                .write(Event::UserUnbanned(UserUnbannedEvent {
+                    login: self.user_handle.user.login.clone(),
+                    unbanned_login: unbanned_login.clone(),
+                }))
+                .wait_written()
+                .await?;
+            self.banlist.remove(&unbanned_login);
+            self.notify_or_defer(self.format_text(Key::BanExpired, &[&thread_id]))
+                .await?;
+        }
+
+        let mut expired_nonces = self
+            .pending_confirmations
+            .iter()
+            .filter(|(_, pending)| pending.expires_at <= now)
+            .map(|(&nonce, _)| nonce)
+            .collect::<Vec<_>>();
+        expired_nonces.sort();
+        for nonce in expired_nonces {
+            if let Some(pending) = self.pending_confirmations.remove(&nonce) {
+                self.close_confirmation(&pending, self.text(Key::ConfirmationExpired))
+                    .await;
+            }
+        }
+
+        let login = self.user_handle.user.login.clone();
+
+        let expired_request = {
+            let mut random_queue = self
+                .random_queue
+                .lock()
+                .expect("handler random_queue.lock() failed");
+            match random_queue.get(&login) {
+                Some(request) if request.expires_at <= now => random_queue.remove(&login),
+                _ => None,
+            }
+        };
+        if expired_request.is_some() {
+            self.event_service
+                .write(Event::RandomDequeued(RandomDequeuedEvent { login }))
+                .wait_written()
+                .await?;
+            self.send_to_self(self.text(Key::RandomRequestExpired))
+                .await?;
+        }
+
+        let mut expired_thread_ids = self
+            .pending_threads
+            .iter()
+            .filter(|(_, pending)| pending.expires_at <= now)
+            .map(|(thread_id, _)| thread_id.clone())
+            .collect::<Vec<_>>();
+        expired_thread_ids.sort();
+        for thread_id in expired_thread_ids {
+            self.reject_pending_thread(&thread_id).await?;
+            self.send_to_self(self.format_text(Key::ThreadRequestExpired, &[&thread_id]))
+                .await?;
+        }
+
+        let mut expired_thread_ids = self
+            .threads
+            .values()
+            .chain(self.archived_threads.values())
+            .filter(|th| th.expires_at.is_some_and(|expires_at| expires_at <= now))
+            .map(|th| th.id.clone())
+            .collect::<Vec<_>>();
+        expired_thread_ids.sort();
+        for thread_id in expired_thread_ids {
+            if let Err(err) = self.close_thread(&thread_id).await {
+                // Do not retry on every loop iteration; the thread can still be closed manually.
+                if let Some(thread) = self
+                    .threads
+                    .get_mut(&thread_id)
+                    .or_else(|| self.archived_threads.get_mut(&thread_id))
+                {
+                    thread.expires_at = None;
+                }
+                return Err(err);
+            }
+            self.send_to_self(self.format_text(Key::ThreadExpired, &[&thread_id]))
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_command(&mut self, mut command: Command) -> Result<()> {
         ensure!(
-            !self.user_handle.is_stopped.load(Ordering::Relaxed) || command == Command::Start,
-            "you have stopped the bot. Use `/start` to restart it"
+            !self.user_handle.is_stopped.load(Ordering::Relaxed)
+                || matches!(command, Command::Start { .. } | Command::Forget { .. }),
+            "{}",
+            self.text(Key::BotStopped),
         );
+        if let Command::Confirm { nonce, confirmed } = command {
+            command = match self.take_confirmed_command(nonce, confirmed).await? {
+                Some(command) => command,
+                None => return Ok(()),
+            };
+        } else {
+            if let Some((prefix, thread_id)) = self.expand_thread_id(&mut command)? {
+                self.send_to_self(self.format_text(Key::ThreadIdExpanded, &[&thread_id, &prefix]))
+                    .await?;
+            }
+            if let Command::SendMedia {
+                kind, file_size, ..
+            } = &command
+            {
+                self.media_policy
+                    .read()
+                    .expect("handler media_policy.read() failed")
+                    .check(kind)?;
+                self.check_media_limits(kind, *file_size)?;
+            }
+            if self.needs_confirmation(&command) {
+                return self.request_confirmation(command).await;
+            }
+        }
         match command {
-            Command::Start => {
-                self.handle_command_start().await?;
+            Command::Start { payload } => {
+                self.handle_command_start(payload).await?;
             }
             Command::Stop => {
                 self.handle_command_stop().await?;
             }
-            Command::Help => {
-                self.send_to_self(HELP_MESSAGE).await?;
+            Command::Help { topic: None } => {
+                self.send_to_self_long(self.text(Key::Help)).await?;
+            }
+            Command::Help { topic: Some(name) } => {
+                let language = self
+                    .user_handle
+                    .settings
+                    .read()
+                    .expect("handler settings.read() failed")
+                    .language;
+                self.send_to_self(messages::command_help(language, name))
+                    .await?;
             }
             Command::Users => {
                 self.handle_command_users().await?;
             }
-            Command::Threads => {
-                self.handle_command_threads().await?;
+            Command::Pick => {
+                self.handle_command_pick().await?;
+            }
+            Command::Online => {
+                self.handle_command_online().await?;
+            }
+            Command::Find { query } => {
+                self.handle_command_find(query).await?;
+            }
+            Command::Threads { archived, label } => {
+                if archived {
+                    self.handle_command_archived_threads().await?;
+                } else {
+                    self.handle_command_threads(label).await?;
+                }
+            }
+            Command::Label { thread_id, label } => {
+                self.handle_command_label(thread_id, label, true).await?;
+            }
+            Command::Unlabel { thread_id, label } => {
+                self.handle_command_label(thread_id, label, false).await?;
+            }
+            Command::Labels => {
+                self.handle_command_labels().await?;
+            }
+            Command::Random {
+                message_id,
+                tag,
+                text,
+                entities,
+            } => {
+                self.handle_command_random(message_id, tag, text, entities)
+                    .await?;
+            }
+            Command::Skip {
+                thread_id,
+                message_id,
+                text,
+            } => {
+                self.handle_command_skip(thread_id, message_id, text)
+                    .await?;
+            }
+            Command::Accept { thread_id } => {
+                self.handle_command_accept(thread_id).await?;
+            }
+            Command::Decline { thread_id } => {
+                self.handle_command_decline(thread_id).await?;
+            }
+            Command::Cancel => {
+                self.handle_command_cancel().await?;
+            }
+            Command::Topic { topic } => {
+                self.handle_command_topic(topic).await?;
             }
-            Command::Random { message_id, text } => {
-                self.handle_command_random(message_id, text).await?;
+            Command::Interests { tags } => {
+                self.handle_command_interests(tags).await?;
             }
             Command::Send {
                 thread_id,
                 message_id,
                 text,
+                entities,
             } => {
-                self.handle_command_send(thread_id, message_id, text)
+                self.handle_command_send(thread_id, message_id, text, entities)
                     .await?;
             }
-            Command::Reply {
-                reply_message_id,
+            Command::SendMany {
+                thread_ids,
                 message_id,
                 text,
             } => {
-                self.handle_command_reply(reply_message_id, message_id, text)
+                self.handle_command_send_many(thread_ids, message_id, text)
                     .await?;
             }
-            Command::Close { thread_id } => {
-                self.handle_command_close(thread_id).await?;
+            Command::Schedule {
+                delay,
+                thread_id,
+                message_id,
+                text,
+            } => {
+                self.handle_command_schedule(delay, thread_id, message_id, text)
+                    .await?;
             }
-            Command::Ban { thread_id } => {
-                self.handle_command_ban(thread_id).await?;
+            Command::Scheduled => {
+                self.handle_command_scheduled().await?;
             }
-            Command::Unban { thread_id } => {
-                self.handle_command_unban(thread_id).await?;
+            Command::Unschedule { index } => {
+                self.handle_command_unschedule(index).await?;
             }
-            Command::Banlist => {
-                self.handle_command_banlist().await?;
+            Command::Create { room } => {
+                self.handle_command_create(room).await?;
             }
-            Command::Broadcast { text } => {
-                self.handle_command_broadcast(text).await?;
+            Command::Join { room } => {
+                self.handle_command_join(room).await?;
             }
-        }
-        Ok(())
-    }
-
-    async fn handle_command_start(&mut self) -> Result<()> {
-        self.user_handle.is_stopped.store(false, Ordering::Relaxed);
-        self.event_service
-            .write(Event::UserStarted(UserStartedEvent {
-                login: self.user_handle.user.login.clone(),
-            }))
-            .wait_written()
-            .await?;
-        self.send_to_self(START_MESSAGE).await?;
-        Ok(())
+            Command::Leave { room } => {
+                self.handle_command_leave(room).await?;
+            }
+            Command::Poll {
+                thread_id,
+                question,
+                options,
+            } => {
+                self.handle_command_poll(thread_id, question, options)
+                    .await?;
+            }
+            Command::Dice { thread_id, emoji } => {
+                self.handle_command_dice(thread_id, emoji).await?;
+            }
+            Command::PollAnswer {
+                poll_id,
+                option_ids,
+            } => {
+                self.handle_poll_answer(poll_id, option_ids).await?;
+            }
+            Command::Reaction { message_id, emoji } => {
+                self.handle_reaction(message_id, emoji).await?;
+            }
+            Command::StartThreadWith { login } => {
+                self.handle_command_start_thread_with(login).await?;
+            }
+            Command::Remind { delay, text } => {
+                self.handle_command_remind(delay, text).await?;
+            }
+            Command::Reminders => {
+                self.handle_command_reminders().await?;
+            }
+            Command::CancelReminder { index } => {
+                self.handle_command_cancel_reminder(index).await?;
+            }
+            Command::SendDefault {
+                message_id,
+                text,
+                entities,
+                forwarded,
+            } => {
+                self.handle_command_send_default(message_id, text, entities, forwarded)
+                    .await?;
+            }
+            Command::Default { thread_id } => {
+                self.handle_command_default(thread_id).await?;
+            }
+            Command::Reply {
+                reply_message_id,
+                reply_text,
+                message_id,
+                text,
+                entities,
+            } => {
+                self.handle_command_reply(reply_message_id, reply_text, message_id, text, entities)
+                    .await?;
+            }
+            Command::SendMedia {
+                message_id,
+                thread_id,
+                reply_message_id,
+                kind,
+                file_id,
+                caption,
+                media_group_id: Some(group_id),
+                forwarded,
+                ..
+            } => {
+                let item = AlbumItem {
+                    message_id,
+                    kind,
+                    file_id,
+                    caption,
+                };
+                self.handle_album_item(group_id, thread_id, reply_message_id, item, forwarded)
+                    .await?;
+            }
+            Command::SendMedia {
+                message_id,
+                thread_id,
+                reply_message_id,
+                kind,
+                file_id,
+                caption,
+                forwarded,
+                ..
+            } => {
+                self.start_media_thread(thread_id.as_ref()).await?;
+                let thread_id = self.resolve_media_thread(thread_id, reply_message_id)?;
+                self.handle_command_send_media(
+                    message_id, thread_id, kind, file_id, caption, forwarded,
+                )
+                .await?;
+            }
+            Command::Edited {
+                message_id,
+                new_text,
+            } => {
+                self.handle_command_edited(message_id, new_text).await?;
+            }
+            Command::Edit {
+                thread_id,
+                new_text,
+            } => {
+                self.handle_command_edit(thread_id, new_text).await?;
+            }
+            Command::Delete { thread_id } => {
+                self.handle_command_delete(thread_id).await?;
+            }
+            Command::Close {
+                thread_id: Some(thread_id),
+            } => {
+                self.handle_command_close(thread_id).await?;
+            }
+            Command::Close { thread_id: None } => {
+                let thread_id = self.sole_thread_id("close", |th| {
+                    matches!(
+                        th.anon_mode,
+                        ThreadAnonimityMode::Both | ThreadAnonimityMode::Me
+                    )
+                })?;
+                self.handle_command_close(thread_id.clone()).await?;
+                self.send_to_self(self.format_text(Key::ThreadClosed, &[&thread_id]))
+                    .await?;
+            }
+            Command::CloseAll => {
+                self.handle_command_close_all().await?;
+            }
+            Command::Ban {
+                thread_id,
+                duration,
+                reason,
+            } => match thread_id {
+                Some(thread_id) => {
+                    self.handle_command_ban(thread_id, duration, reason).await?;
+                }
+                None => {
+                    let thread_id =
+                        self.sole_thread_id("ban", |th| th.anon_mode == ThreadAnonimityMode::Them)?;
+                    self.handle_command_ban(thread_id.clone(), duration, reason)
+                        .await?;
+                    self.send_to_self(self.format_text(Key::ThreadBanned, &[&thread_id]))
+                        .await?;
+                }
+            },
+            Command::BanInfo { thread_id } => {
+                self.handle_command_ban_info(thread_id).await?;
+            }
+            Command::Unban { thread_id } => {
+                self.handle_command_unban(thread_id).await?;
+            }
+            Command::Pin { thread_id } => {
+                self.handle_command_pin(thread_id, true).await?;
+            }
+            Command::Unpin { thread_id } => {
+                self.handle_command_pin(thread_id, false).await?;
+            }
+            Command::Priority { thread_id } => {
+                self.handle_command_priority(thread_id).await?;
+            }
+            Command::Pause { thread_id } => {
+                self.handle_command_pause(thread_id, true).await?;
+            }
+            Command::Resume { thread_id } => {
+                self.handle_command_pause(thread_id, false).await?;
+            }
+            Command::Expire {
+                thread_id,
+                duration,
+            } => {
+                self.handle_command_expire(thread_id, duration).await?;
+            }
+            Command::Undo => {
+                self.handle_command_undo().await?;
+            }
+            Command::ThreadInfo { thread_id } => {
+                self.handle_command_thread_info(thread_id).await?;
+            }
+            Command::Note { thread_id, text } => {
+                self.handle_command_note(thread_id, text).await?;
+            }
+            Command::Secret { thread_id, seconds } => {
+                self.handle_command_secret(thread_id, seconds).await?;
+            }
+            Command::Receipts { thread_id, enabled } => {
+                self.handle_command_receipts(thread_id, enabled).await?;
+            }
+            Command::Previews { thread_id, enabled } => {
+                self.handle_command_previews(thread_id, enabled).await?;
+            }
+            Command::Slow { thread_id, seconds } => {
+                self.handle_command_slow(thread_id, seconds).await?;
+            }
+            Command::Archive { thread_id } => {
+                self.handle_command_archive(thread_id).await?;
+            }
+            Command::Unarchive { thread_id } => {
+                self.handle_command_unarchive(thread_id).await?;
+            }
+            Command::Banlist => {
+                self.handle_command_banlist().await?;
+            }
+            Command::Block { login } => {
+                self.handle_command_block(login).await?;
+            }
+            Command::Unblock { login } => {
+                self.handle_command_unblock(login).await?;
+            }
+            Command::Broadcast { audience, text } => {
+                self.handle_command_broadcast(audience, text).await?;
+            }
+            Command::ShowSettings => {
+                self.handle_command_show_settings().await?;
+            }
+            Command::Settings { key, value } => {
+                self.handle_command_settings(key, value).await?;
+            }
+            Command::ShowPolicy => {
+                self.handle_command_show_policy().await?;
+            }
+            Command::Policy { key, value } => {
+                self.handle_command_policy(key, value).await?;
+            }
+            Command::Language { code } => {
+                self.handle_command_language(code).await?;
+            }
+            Command::Limit { max_incoming } => {
+                self.handle_command_limit(max_incoming).await?;
+            }
+            Command::Status => {
+                self.handle_command_status().await?;
+            }
+            Command::Dnd { window } => {
+                self.handle_command_dnd(window).await?;
+            }
+            Command::Greeting { text } => {
+                self.handle_command_greeting(text).await?;
+            }
+            Command::Export => {
+                self.handle_command_export().await?;
+            }
+            Command::Forget { confirmed } => {
+                self.handle_command_forget(confirmed).await?;
+            }
+            Command::Whoami {
+                refresh,
+                first_name,
+                last_name,
+            } => {
+                if refresh {
+                    self.handle_command_refresh_profile(first_name, last_name)
+                        .await?;
+                } else {
+                    self.handle_command_whoami(first_name, last_name).await?;
+                }
+            }
+            Command::Stats => {
+                self.handle_command_stats().await?;
+            }
+            Command::About => {
+                self.handle_command_about().await?;
+            }
+            Command::Confirm { .. } => bail!("there is nothing to confirm"),
+        }
+        Ok(())
+    }
+
+    ////////////////////////////////////////////////////////////////////////////
+
+    fn needs_confirmation(&self, command: &Command) -> bool {
+        let settings = self
+            .user_handle
+            .settings
+            .read()
+            .expect("handler settings.read() failed");
+        match command {
+            Command::Close { .. }
+            | Command::CloseAll
+            | Command::Ban { .. }
+            | Command::Broadcast { .. } => settings.confirm_actions,
+            // Voice reveals the sender, so it is confirmed once regardless of
+            // `confirm_actions`.
+            Command::SendMedia {
+                kind: MediaKind::Voice { .. },
+                ..
+            } => !settings.voice_confirmed,
+            // Locations are confirmed once per thread and only until restart.
+            Command::SendMedia {
+                kind: MediaKind::Location { .. },
+                thread_id,
+                reply_message_id,
+                ..
+            } => thread_id
+                .clone()
+                .or_else(|| self.resolve_media_thread(None, *reply_message_id).ok())
+                .is_some_and(|thread_id| !self.location_confirmed_threads.contains(&thread_id)),
+            _ => false,
+        }
+    }
+
+    /// Replies with "Confirm" and "Cancel" buttons and keeps the command until
+    /// one of them is pressed, see `take_confirmed_command`.
+    async fn request_confirmation(&mut self, command: Command) -> Result<()> {
+        let question = match &command {
+            Command::Close {
+                thread_id: Some(thread_id),
+            } => self.format_text(Key::ConfirmCloseThread, &[&thread_id]),
+            Command::Close { thread_id: None } => {
+                self.text(Key::ConfirmCloseOnlyThread).to_string()
+            }
+            Command::CloseAll => self.text(Key::ConfirmCloseAll).to_string(),
+            Command::Ban {
+                thread_id: Some(thread_id),
+                ..
+            } => self.format_text(Key::ConfirmBan, &[&thread_id]),
+            Command::Ban {
+                thread_id: None, ..
+            } => self.text(Key::ConfirmBanOnlyPartner).to_string(),
+            Command::Broadcast { audience, .. } => match audience {
+                BroadcastAudience::All => self.text(Key::ConfirmBroadcastAll).to_string(),
+                BroadcastAudience::Active => self.text(Key::ConfirmBroadcastActive).to_string(),
+                BroadcastAudience::Users(logins) => {
+                    let logins = logins
+                        .iter()
+                        .map(|login| format!("@{}", login))
+                        .collect::<Vec<_>>();
+                    self.format_text(Key::ConfirmBroadcastUsers, &[&logins.join(", ")])
+                }
+            },
+            Command::SendMedia {
+                kind: MediaKind::Location { .. },
+                thread_id,
+                reply_message_id,
+                ..
+            } => {
+                let thread_id = match thread_id {
+                    Some(thread_id) => thread_id.clone(),
+                    None => self.resolve_media_thread(None, *reply_message_id)?,
+                };
+                self.format_text(Key::ConfirmLocation, &[&thread_id])
+            }
+            Command::SendMedia { .. } => self.text(Key::ConfirmVoice).to_string(),
+            _ => bail!("command does not need confirmation"),
+        };
+
+        let nonce = rand::random::<u64>();
+        let buttons = [
+            (self.text(Key::ConfirmButton), true),
+            (self.text(Key::CancelButton), false),
+        ]
+        .into_iter()
+        .filter_map(|(label, confirmed)| {
+            let data = Command::Confirm { nonce, confirmed }.callback_data()?;
+            Some(InlineKeyboardButton::callback(label.to_string(), data))
+        })
+        .collect::<Vec<_>>();
+        let message = self
+            .bot
+            .send_message(self.chat_id, markup_to_html(&question))
+            .parse_mode(ParseMode::Html)
+            .reply_markup(InlineKeyboardMarkup::new(vec![buttons]))
+            .await
+            .context("failed to send message to user")?;
+
+        if self.pending_confirmations.len() >= MAX_PENDING_CONFIRMATIONS {
+            let oldest = self
+                .pending_confirmations
+                .iter()
+                .min_by_key(|(_, pending)| pending.expires_at)
+                .map(|(&nonce, _)| nonce);
+            if let Some(pending) = oldest.and_then(|n| self.pending_confirmations.remove(&n)) {
+                self.close_confirmation(&pending, self.text(Key::ConfirmationCancelled))
+                    .await;
+            }
+        }
+        self.pending_confirmations.insert(
+            nonce,
+            PendingConfirmation {
+                command,
+                question,
+                message_id: message.id,
+                expires_at: now_timestamp() + CONFIRMATION_TTL_SECS,
+            },
+        );
+        Ok(())
+    }
+
+    /// Resolves a pressed confirmation button. Returns the command to run
+    /// if it has been confirmed.
+    async fn take_confirmed_command(
+        &mut self,
+        nonce: u64,
+        confirmed: bool,
+    ) -> Result<Option<Command>> {
+        let pending = self
+            .pending_confirmations
+            .remove(&nonce)
+            .context("this confirmation has expired")?;
+        let status = if confirmed {
+            self.text(Key::ConfirmationConfirmed)
+        } else {
+            self.text(Key::ConfirmationCancelled)
+        };
+        self.close_confirmation(&pending, status).await;
+        Ok(confirmed.then_some(pending.command))
+    }
+
+    /// Removes the buttons from a confirmation message and appends the outcome.
+    async fn close_confirmation(&self, pending: &PendingConfirmation, status: &str) {
+        let text = markup_to_html(&format!("{} {}", pending.question, status));
+        if let Err(err) = self
+            .bot
+            .edit_message_text(self.chat_id, pending.message_id, text)
+            .parse_mode(ParseMode::Html)
+            .await
+        {
+            debug!(
+                "failed to update confirmation message {} of @{}: {}",
+                pending.message_id, self.user_handle.user.login, err
+            );
+        }
+    }
+
+    async fn handle_command_start(&mut self, payload: Option<String>) -> Result<()> {
+        self.user_handle.is_stopped.store(false, Ordering::Relaxed);
+        self.event_service
+            .write(Event::UserStarted(UserStartedEvent {
+                login: self.user_handle.user.login.clone(),
+            }))
+            .wait_written()
+            .await?;
+        self.send_to_self(self.text(Key::Start)).await?;
+        match payload.as_deref().and_then(StartPayload::parse) {
+            Some(StartPayload::DirectMessage { login }) => {
+                self.handle_start_direct_message(login).await?;
+            }
+            None => {
+                if let Some(payload) = payload {
+                    debug!(
+                        "@{} started the bot with unknown payload: {}",
+                        self.user_handle.user.login, payload
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts a thread with the user of a "message me anonymously" link, as
+    /// `/send @login` would, and asks for the first message.
+    async fn handle_start_direct_message(&mut self, login: String) -> Result<()> {
+        let thread_id = format!("@{}", login);
+        ensure!(
+            !self.archived_threads.contains_key(&thread_id),
+            "thread {} is archived; use `/unarchive` first",
+            thread_id
+        );
+        if !self.threads.contains_key(&thread_id) {
+            let event = self.start_login_thread(&thread_id).await?;
+            self.event_service.write(event).wait_written().await?;
+        }
+        self.prompt_first_message(thread_id).await
     }
 
     async fn handle_command_stop(&mut self) -> Result<()> {
@@ -315,7 +2282,7 @@ impl Handler {
             }))
             .wait_written()
             .await?;
-        self.send_to_self(STOP_MESSAGE).await?;
+        self.send_to_self(self.text(Key::Stop)).await?;
         Ok(())
     }
 
@@ -325,430 +2292,4781 @@ impl Handler {
             .read()
             .expect("handler handle_registry.read() failed")
             .values()
-            .filter_map(|h| {
-                if h.is_stopped.load(Ordering::Relaxed) {
-                    None
-                } else if let Some(last_name) = h.user.last_name.as_ref() {
-                    Some(format!(
-                        "{} {} @{}",
-                        h.user.first_name, last_name, h.user.login
-                    ))
-                } else {
-                    Some(format!("{} @{}", h.user.first_name, h.user.login))
-                }
-            })
+            .filter(|h| h.is_listed())
+            .map(|h| escape_markup(&h.display_line()))
             .collect::<Vec<_>>();
         usernames.sort();
-        self.send_to_self(format!("Available users:\n* {}", usernames.join("\n* "),))
+        self.send_to_self_long(self.format_text(Key::AvailableUsers, &[&usernames.join("\n* ")]))
             .await?;
         Ok(())
     }
 
-    async fn handle_command_threads(&mut self) -> Result<()> {
-        let mut thread_ids = self
-            .threads
-            .keys()
-            .filter(|s| s.starts_with("#"))
-            .cloned()
+    async fn handle_command_pick(&mut self) -> Result<()> {
+        const MAX_BUTTONS: usize = 50;
+
+        let mut users = self
+            .handle_registry
+            .read()
+            .expect("handler handle_registry.read() failed")
+            .values()
+            .filter(|h| h.is_listed() && h.user.login != self.user_handle.user.login)
+            .map(|h| (h.user.login.clone(), h.display_line()))
             .collect::<Vec<_>>();
-        thread_ids.sort();
-        if thread_ids.is_empty() {
-            self.send_to_self("There are no active threads.").await?;
-        } else {
-            self.send_to_self(format!("Active threads:\n* {}", thread_ids.join("\n* ")))
-                .await?;
+        if users.is_empty() {
+            self.send_to_self(self.text(Key::NobodyToWriteTo)).await?;
+            return Ok(());
         }
+        users.sort_by(|a, b| a.1.cmp(&b.1));
+
+        let text = if users.len() > MAX_BUTTONS {
+            self.format_text(Key::PickUserTruncated, &[&MAX_BUTTONS, &users.len()])
+        } else {
+            self.text(Key::PickUser).to_string()
+        };
+        let buttons = users
+            .into_iter()
+            .take(MAX_BUTTONS)
+            .filter_map(|(login, line)| {
+                let data = Command::StartThreadWith { login }.callback_data()?;
+                Some(vec![InlineKeyboardButton::callback(line, data)])
+            })
+            .collect::<Vec<_>>();
+        self.bot
+            .send_message(self.chat_id, markup_to_html(&text))
+            .parse_mode(ParseMode::Html)
+            .reply_markup(InlineKeyboardMarkup::new(buttons))
+            .await
+            .context("failed to send message to user")?;
         Ok(())
     }
 
-    async fn handle_command_random(&mut self, message_id: i32, text: String) -> Result<()> {
-        let other_login = self
+    /// Asks for the first message to a user picked with `/pick`. Replying to
+    /// the prompt sends the message as `/send @login` would.
+    async fn handle_command_start_thread_with(&mut self, login: String) -> Result<()> {
+        ensure!(
+            login != self.user_handle.user.login,
+            "cannot send a message to self"
+        );
+        let is_available = self
             .handle_registry
             .read()
             .expect("handler handle_registry.read() failed")
-            .values()
-            .map(|h| h.user.login.clone())
-            .filter(|login| login != &self.user_handle.user.login)
-            .choose(&mut rand::thread_rng())
-            .context("there are currently no other users to chat with")?;
+            .get(&login)
+            .is_some_and(|h| h.is_listed());
+        ensure!(is_available, "user @{} is not available", login);
+        self.prompt_first_message(format!("@{}", login)).await
+    }
 
-        let my_thread_id = Self::random_thread_id();
-        let other_thread_id = Self::random_thread_id();
-        self.create_thread(my_thread_id.clone(), other_thread_id.clone(), &other_login)
+    /// Asks for a message to the thread with a reply prompt.
+    async fn prompt_first_message(&mut self, thread_id: ThreadId) -> Result<()> {
+        let prompt = self
+            .bot
+            .send_message(
+                self.chat_id,
+                markup_to_html(&self.format_text(Key::ReplyToWrite, &[&thread_id])),
+            )
+            .parse_mode(ParseMode::Html)
+            .reply_markup(ForceReply::new())
+            .await
+            .context("failed to send message to user")?;
+        self.event_service
+            .write(Event::ThreadMessageReceived(ThreadMessageReceivedEvent {
+                login: self.user_handle.user.login.clone(),
+                message_id: prompt.id,
+                thread_id: thread_id.clone(),
+                kind: Some(MessageKind::Service),
+            }))
+            .wait_written()
             .await?;
-        self.message_id_to_thread_id
-            .insert(message_id, my_thread_id.clone());
+        self.message_id_to_thread_id.insert(prompt.id, thread_id);
+        Ok(())
+    }
 
-        let first_tracker = self.event_service.write_batch(vec![
-            Event::ThreadStarted(ThreadStartedEvent {
-                login: self.user_handle.user.login.clone(),
-                other_login,
-                my_thread_id: my_thread_id.clone(),
-                other_thread_id,
-                anon_mode: ThreadAnonimityMode::Both,
-            }),
-            Event::ThreadMessageReceived(ThreadMessageReceivedEvent {
-                login: self.user_handle.user.login.clone(),
-                message_id,
-                thread_id: my_thread_id.clone(),
-            }),
-        ]);
+    async fn handle_command_find(&mut self, query: String) -> Result<()> {
+        const MAX_RESULTS: usize = 25;
 
-        self.threads[&my_thread_id].send_text(text).await?;
+        let query = query.to_lowercase();
+        let mut usernames = self
+            .handle_registry
+            .read()
+            .expect("handler handle_registry.read() failed")
+            .values()
+            .filter(|h| h.is_listed())
+            .filter(|h| {
+                h.user.login.to_lowercase().contains(&query)
+                    || h.user.first_name.to_lowercase().contains(&query)
+                    || h.user
+                        .last_name
+                        .as_ref()
+                        .is_some_and(|name| name.to_lowercase().contains(&query))
+            })
+            .map(|h| escape_markup(&h.display_line()))
+            .collect::<Vec<_>>();
+        usernames.sort();
 
-        let message = self
-            .send_to_self(format!("Started a new anonymous thread {}.", my_thread_id))
+        if usernames.is_empty() {
+            self.send_to_self(self.text(Key::NoUsersFound)).await?;
+        } else if usernames.len() > MAX_RESULTS {
+            self.send_to_self_long(self.format_text(
+                Key::FoundUsersTruncated,
+                &[
+                    &usernames.len(),
+                    &MAX_RESULTS,
+                    &usernames[..MAX_RESULTS].join("\n* "),
+                ],
+            ))
             .await?;
-        self.message_id_to_thread_id
-            .insert(message.id, my_thread_id.clone());
-        let second_tracker =
-            self.event_service
-                .write(Event::ThreadMessageReceived(ThreadMessageReceivedEvent {
-                    login: self.user_handle.user.login.clone(),
-                    message_id,
-                    thread_id: my_thread_id,
-                }));
-
-        first_tracker.wait_written().await?;
-        second_tracker.wait_written().await?;
+        } else {
+            self.send_to_self_long(self.format_text(
+                Key::FoundUsers,
+                &[&usernames.len(), &usernames.join("\n* ")],
+            ))
+            .await?;
+        }
         Ok(())
     }
 
-    async fn handle_command_send(
-        &mut self,
-        thread_id: ThreadId,
-        message_id: i32,
-        text: String,
-    ) -> Result<()> {
-        let mut events = vec![];
-
-        if !self.threads.contains_key(&thread_id) {
-            if !thread_id.starts_with("@") {
-                bail!("unknown thread: {}", thread_id);
-            }
+    async fn handle_command_online(&mut self) -> Result<()> {
+        let now = now_timestamp();
+        let mut users = self
+            .handle_registry
+            .read()
+            .expect("handler handle_registry.read() failed")
+            .values()
+            .filter(|h| h.is_listed() && h.is_active_within(ONLINE_WINDOW_SECS))
+            .map(|h| {
+                (
+                    h.last_activity.load(Ordering::Relaxed),
+                    escape_markup(&h.display_line()),
+                )
+            })
+            .collect::<Vec<_>>();
+        users.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
 
-            let other_login = &thread_id[1..];
-            ensure!(
-                other_login != self.user_handle.user.login,
-                "cannot send a message to self"
-            );
-            let other_thread_id = Self::random_thread_id();
-            self.create_thread(thread_id.clone(), other_thread_id.clone(), other_login)
+        if users.is_empty() {
+            self.send_to_self(self.text(Key::NobodyActive)).await?;
+        } else {
+            let lines = users
+                .iter()
+                .map(|(last_activity, line)| {
+                    format!(
+                        "{} (active {} ago)",
+                        line,
+                        format_duration(Duration::from_secs((now - last_activity).max(0) as u64))
+                    )
+                })
+                .collect::<Vec<_>>();
+            self.send_to_self_long(self.format_text(Key::ActiveUsers, &[&lines.join("\n* ")]))
                 .await?;
-
-            events.push(Event::ThreadStarted(ThreadStartedEvent {
-                login: self.user_handle.user.login.clone(),
-                other_login: other_login.to_string(),
-                my_thread_id: thread_id.clone(),
-                other_thread_id,
-                anon_mode: ThreadAnonimityMode::Me,
-            }));
         }
+        Ok(())
+    }
 
-        self.message_id_to_thread_id
-            .insert(message_id, thread_id.clone());
-        events.push(Event::ThreadMessageReceived(ThreadMessageReceivedEvent {
-            login: self.user_handle.user.login.clone(),
-            message_id,
-            thread_id: thread_id.clone(),
-        }));
-        self.event_service
-            .write_batch(events)
-            .wait_written()
-            .await?;
-
-        self.threads[&thread_id].send_text(text).await?;
+    async fn handle_command_threads(&mut self, label: Option<String>) -> Result<()> {
+        let mut threads = self
+            .threads
+            .values()
+            .filter(|th| th.id.starts_with('#'))
+            .filter(|th| label.as_ref().is_none_or(|label| th.labels.contains(label)))
+            .collect::<Vec<_>>();
+        threads.sort_by(|a, b| b.pinned.cmp(&a.pinned).then_with(|| a.id.cmp(&b.id)));
+        let lines = threads
+            .iter()
+            .map(|th| {
+                let mut line = if th.pinned {
+                    format!("📌 {}", th.id)
+                } else {
+                    th.id.clone()
+                };
+                if th.priority {
+                    line.push_str(" ⭐");
+                }
+                if let Some(interval) = th.slow_mode {
+                    line.push_str(
+                        &self.format_text(Key::ThreadSlowModeMark, &[&format_duration(interval)]),
+                    );
+                }
+                if let Some(delay) = th.secret {
+                    line.push_str(
+                        &self.format_text(Key::ThreadSecretMark, &[&format_duration(delay)]),
+                    );
+                }
+                if let Some(expires_at) = th.expires_at {
+                    let left = (expires_at - now_timestamp()).max(0) as u64;
+                    line.push_str(&self.format_text(
+                        Key::ThreadExpiryMark,
+                        &[&format_duration(Duration::from_secs(left))],
+                    ));
+                }
+                if !th.labels.is_empty() {
+                    let labels = th
+                        .labels
+                        .iter()
+                        .map(|label| escape_markup(label))
+                        .collect::<Vec<_>>();
+                    line.push_str(&format!(" [{}]", labels.join(", ")));
+                }
+                if let Some(note) = &th.note {
+                    line.push_str(&format!(" - {}", preview(note, NOTE_PREVIEW_LENGTH)));
+                }
+                line
+            })
+            .collect::<Vec<_>>();
+        if lines.is_empty() {
+            match label {
+                Some(label) => {
+                    self.send_to_self(
+                        self.format_text(Key::NoThreadsLabeled, &[&escape_markup(&label)]),
+                    )
+                    .await?;
+                }
+                None => {
+                    self.send_to_self(self.text(Key::NoThreads)).await?;
+                }
+            }
+        } else {
+            self.send_to_self_long(self.format_text(Key::ActiveThreads, &[&lines.join("\n* ")]))
+                .await?;
+        }
         Ok(())
     }
 
-    async fn handle_command_reply(
+    async fn handle_command_label(
         &mut self,
-        reply_message_id: i32,
-        message_id: i32,
-        text: String,
+        thread_id: ThreadId,
+        label: String,
+        add: bool,
     ) -> Result<()> {
-        let thread_id = self
-            .message_id_to_thread_id
-            .get(&reply_message_id)
-            .context("message you are replying to does not belong to a thread")?
-            .clone();
-        self.threads
-            .get(&thread_id)
-            .context("thread does not exist anymore")?
-            .send_text(text)
-            .await?;
-
-        self.message_id_to_thread_id
-            .insert(message_id, thread_id.clone());
-        self.event_service
-            .write(Event::ThreadMessageReceived(ThreadMessageReceivedEvent {
-                login: self.user_handle.user.login.clone(),
-                message_id,
-                thread_id,
-            }))
-            .wait_written()
-            .await?;
-        Ok(())
-    }
-
-    async fn handle_command_close(&mut self, thread_id: ThreadId) -> Result<()> {
         let thread = self
             .threads
             .get(&thread_id)
+            .or_else(|| self.archived_threads.get(&thread_id))
             .with_context(|| format!("thread {} does not exist", thread_id))?;
+        if add {
+            ensure!(
+                !thread.labels.contains(&label),
+                "thread {} is already labeled {}",
+                thread_id,
+                label
+            );
+        } else {
+            ensure!(
+                thread.labels.contains(&label),
+                "thread {} is not labeled {}",
+                thread_id,
+                label
+            );
+        }
 
-        ensure!(
-            matches!(
-                thread.anon_mode,
-                ThreadAnonimityMode::Both | ThreadAnonimityMode::Me
-            ),
-            "cannot close a semi-anonimous thread; use `/ban` instead"
-        );
-
-        thread
-            .terminate()
-            .await
-            .context("failed to terminate peer thread")?;
-        let thread = self.threads.remove(&thread_id).unwrap();
-
-        self.event_service
-            .write(Event::ThreadTerminated(ThreadTerminatedEvent {
+        let event = if add {
+            Event::ThreadLabeled(ThreadLabeledEvent {
                 login: self.user_handle.user.login.clone(),
-                other_login: thread.other_handle.user.login.clone(),
-                my_thread_id: thread.id,
-                other_thread_id: thread.other_id,
-            }))
-            .wait_written()
-            .await?;
-
-        Ok(())
-    }
+                thread_id: thread_id.clone(),
+                label: label.clone(),
+            })
+        } else {
+            Event::ThreadUnlabeled(ThreadUnlabeledEvent {
+                login: self.user_handle.user.login.clone(),
+                thread_id: thread_id.clone(),
+                label: label.clone(),
+            })
+        };
+        self.event_service.write(event).wait_written().await?;
 
-    async fn handle_command_ban(&mut self, thread_id: ThreadId) -> Result<()> {
         let thread = self
             .threads
-            .get(&thread_id)
-            .with_context(|| format!("thread {} does not exist", thread_id))?;
-
-        ensure!(
-            thread.anon_mode == ThreadAnonimityMode::Them,
-            "cannot ban random or non-anonimous chat; use `/close` instead",
-        );
-
-        thread
-            .terminate()
-            .await
-            .context("failed to terminate peer thread")?;
-        let thread = self.threads.remove(&thread_id).unwrap();
-
-        self.event_service
-            .write(Event::UserBanned(UserBannedEvent {
-                login: self.user_handle.user.login.clone(),
-                banned_login: thread.other_handle.user.login.clone(),
-                banned_thread_id: thread_id.clone(),
-            }))
-            .wait_written()
+            .get_mut(&thread_id)
+            .or_else(|| self.archived_threads.get_mut(&thread_id))
+            .unwrap();
+        if add {
+            thread.labels.insert(label.clone());
+            self.send_to_self(
+                self.format_text(Key::ThreadLabeled, &[&thread_id, &escape_markup(&label)]),
+            )
             .await?;
-        self.banlist
-            .insert(thread.other_handle.user.login.clone(), thread_id);
-
+        } else {
+            thread.labels.remove(&label);
+            self.send_to_self(
+                self.format_text(Key::LabelRemoved, &[&escape_markup(&label), &thread_id]),
+            )
+            .await?;
+        }
         Ok(())
     }
 
-    async fn handle_command_unban(&mut self, thread_id: ThreadId) -> Result<()> {
-        let login = self
-            .banlist
+    async fn handle_command_labels(&mut self) -> Result<()> {
+        let mut counts = BTreeMap::<&str, usize>::new();
+        for thread in self.threads.values().chain(self.archived_threads.values()) {
+            for label in &thread.labels {
+                *counts.entry(label).or_default() += 1;
+            }
+        }
+        let lines = counts
             .iter()
-            .find(|&(_, th)| th == &thread_id)
-            .with_context(|| format!("no {} in your ban list", thread_id))?
-            .0
-            .clone();
-
-        self.event_service
-            .write(Event::UserUnbanned(UserUnbannedEvent {
-                login: self.user_handle.user.login.clone(),
-                unbanned_login: login.clone(),
-            }))
-            .wait_written()
-            .await?;
-        self.banlist.remove(&login);
+            .map(|(label, count)| format!("{}: {}", escape_markup(label), count))
+            .collect::<Vec<_>>();
 
+        if lines.is_empty() {
+            self.send_to_self(self.text(Key::NoLabels)).await?;
+        } else {
+            self.send_to_self_long(self.format_text(Key::Labels, &[&lines.join("\n* ")]))
+                .await?;
+        }
         Ok(())
     }
 
-    async fn handle_command_banlist(&mut self) -> Result<()> {
-        let mut banlist = self.banlist.values().cloned().collect::<Vec<_>>();
-        banlist.sort();
-        if banlist.is_empty() {
-            self.send_to_self("You have not banned anybody.").await?;
-        } else {
-            self.send_to_self(format!("Banned threads:\n* {}", banlist.join("\n* ")))
-                .await?;
+    async fn handle_command_thread_info(&mut self, thread_id: ThreadId) -> Result<()> {
+        let (thread, is_archived) = match self.threads.get(&thread_id) {
+            Some(thread) => (thread, false),
+            None => (
+                self.archived_threads
+                    .get(&thread_id)
+                    .with_context(|| format!("thread {} does not exist", thread_id))?,
+                true,
+            ),
+        };
+
+        let anonymity = match thread.anon_mode {
+            ThreadAnonimityMode::Me => self.text(Key::AnonymityMe),
+            ThreadAnonimityMode::Them => self.text(Key::AnonymityThem),
+            ThreadAnonimityMode::Both => self.text(Key::AnonymityBoth),
+        };
+        let started = thread
+            .created_at
+            .map(format_timestamp)
+            .unwrap_or_else(|| self.text(Key::Unknown).to_string());
+        let mut lines = vec![
+            self.format_text(Key::ThreadInfoStarted, &[&started]),
+            self.format_text(Key::ThreadInfoAnonymity, &[&anonymity]),
+            self.format_text(Key::ThreadInfoMessagesSent, &[&thread.messages_sent]),
+            self.format_text(
+                Key::ThreadInfoMessagesReceived,
+                &[&thread.messages_received],
+            ),
+            self.format_text(Key::ThreadInfoPinned, &[&self.flag(thread.pinned)]),
+            self.format_text(Key::ThreadInfoArchived, &[&self.flag(is_archived)]),
+            self.format_text(Key::ThreadInfoReceipts, &[&self.flag(thread.receipts)]),
+            self.format_text(
+                Key::ThreadInfoLinkPreviews,
+                &[&self.flag(self.link_previews_enabled(&thread_id))],
+            ),
+            self.format_text(Key::ThreadInfoPriority, &[&self.flag(thread.priority)]),
+        ];
+        if let Some(interval) = thread.slow_mode {
+            lines.push(self.format_text(Key::ThreadInfoSlowMode, &[&format_duration(interval)]));
+        }
+        if let Some(delay) = thread.secret {
+            lines.push(self.format_text(Key::ThreadInfoSecret, &[&format_duration(delay)]));
         }
+        if let Some(expires_at) = thread.expires_at {
+            lines.push(self.format_text(Key::ThreadInfoExpires, &[&format_timestamp(expires_at)]));
+        }
+        if let Some(note) = &thread.note {
+            lines.push(self.format_text(Key::ThreadInfoNote, &[&escape_markup(note)]));
+        }
+
+        self.send_to_self_long(
+            self.format_text(Key::ThreadInfo, &[&thread_id, &lines.join("\n* ")]),
+        )
+        .await?;
         Ok(())
     }
 
-    async fn handle_command_broadcast(&mut self, text: String) -> Result<()> {
-        ensure!(
-            self.user_handle.user.login == "sergio_4min",
-            "you are not admin"
-        );
-        let handles = self
-            .handle_registry
-            .read()
-            .expect("handle_registry.read() failed")
-            .values()
-            .cloned()
-            .collect::<Vec<_>>();
-        self.send_to_self(format!("Starting broadcast to {} users...", handles.len()))
+    async fn handle_command_archived_threads(&mut self) -> Result<()> {
+        let mut thread_ids = self.archived_threads.keys().cloned().collect::<Vec<_>>();
+        thread_ids.sort();
+        if thread_ids.is_empty() {
+            self.send_to_self(self.text(Key::NoArchivedThreads)).await?;
+        } else {
+            self.send_to_self_long(
+                self.format_text(Key::ArchivedThreads, &[&thread_ids.join("\n* ")]),
+            )
             .await?;
-        for handle in handles {
-            if handle.user.login == self.user_handle.user.login {
-                continue;
-            }
-            let result = handle.send_action(Action::Broadcast(text.clone())).await;
-            if let Err(err) = result {
-                self.send_to_self(format!(
-                    "Failed to send broadcast to user @{}: {:#}",
-                    handle.user.login, err,
-                ))
-                .await?;
-            }
         }
-        self.send_to_self("Broadcast is finished.").await?;
         Ok(())
     }
 
-    async fn create_thread(
+    async fn handle_command_random(
         &mut self,
-        my_thread_id: ThreadId,
-        other_thread_id: ThreadId,
-        other_login: &str,
+        message_id: i32,
+        tag: Option<String>,
+        text: String,
+        entities: Vec<MessageEntity>,
     ) -> Result<()> {
-        let other_handle = self
-            .handle_registry
-            .read()
-            .expect("handler handle_registry.read() failed")
-            .get(other_login)
-            .context("user has not started this bot")?
-            .clone();
+        let my_login = self.user_handle.user.login.clone();
+        let mut events = vec![];
+        let was_queued = self
+            .random_queue
+            .lock()
+            .expect("handler random_queue.lock() failed")
+            .remove(&my_login)
+            .is_some();
+        if was_queued {
+            events.push(Event::RandomDequeued(RandomDequeuedEvent {
+                login: my_login,
+            }));
+        }
 
-        let my_thread = Thread {
-            id: my_thread_id.clone(),
-            anon_mode: ThreadAnonimityMode::Me,
-            other_id: other_thread_id.clone(),
-            other_handle: other_handle.clone(),
-        };
-        let other_thread = Thread {
-            id: other_thread_id,
-            anon_mode: ThreadAnonimityMode::Them,
-            other_id: my_thread_id.clone(),
-            other_handle: self.user_handle.clone(),
+        let (topic, text, entities) = match tag {
+            Some(_) => (None, text, entities),
+            None => match self.split_topic(&text) {
+                Some((topic, rest)) => {
+                    let start = utf16_len(&text) - utf16_len(&rest);
+                    let entities = slice_entities(&entities, start, utf16_len(&rest));
+                    (Some(topic), rest, entities)
+                }
+                None => (None, text, entities),
+            },
         };
 
-        let (result_sender, result_receiver) = oneshot::channel();
-        let start_request = ActionRequest {
-            action: Action::StartAnonymousThread(other_thread),
-            result_sender,
-        };
-        other_handle
-            .channel
-            .send(start_request)
-            .await
-            .unwrap_or_else(|err| {
-                panic!(
-                    "failed to send thread start request to @{} handler: {}",
-                    other_login, err
-                )
-            });
-        result_receiver.await.unwrap_or_else(|err| {
-            panic!(
-                "failed to get thread start result of @{} handler: {}",
-                other_login, err
-            )
-        })?;
+        let partner =
+            match self.match_random_partner(tag.as_deref(), topic.as_deref(), &[], &mut events) {
+                Ok(partner) => partner,
+                Err(err) => {
+                    if !events.is_empty() {
+                        self.event_service
+                            .write_batch(events)
+                            .wait_written()
+                            .await?;
+                    }
+                    let use_queue = self
+                        .user_handle
+                        .settings
+                        .read()
+                        .expect("handler settings.read() failed")
+                        .random_queue;
+                    if !use_queue {
+                        return Err(err.context(
+                            "use `/settings random_queue on` to wait for a partner instead",
+                        ));
+                    }
+                    return self.enqueue_random(text).await;
+                }
+            };
 
-        self.threads.insert(my_thread_id, my_thread);
+        let by_topic = partner.by_topic;
+        self.start_random_thread(message_id, partner, text, entities, events)
+            .await?;
+        if let Some(topic) = topic {
+            if by_topic {
+                self.send_to_self(self.format_text(Key::MatchedByTopic, &[&escape_markup(&topic)]))
+                    .await?;
+            } else {
+                self.send_to_self(
+                    self.format_text(Key::TopicNotMatched, &[&escape_markup(&topic)]),
+                )
+                .await?;
+            }
+        }
         Ok(())
     }
 
-    fn random_thread_id() -> ThreadId {
-        format!("#{}_{}", random_adjective(), random_noun())
+    /// Splits `/random books hello` into a topic and a message if somebody has that topic.
+    fn split_topic(&self, text: &str) -> Option<(String, String)> {
+        let (first, rest) = text.split_once(' ')?;
+        let topic = first.to_lowercase();
+        let is_known = self
+            .handle_registry
+            .read()
+            .expect("handler handle_registry.read() failed")
+            .values()
+            .any(|h| {
+                h.topic
+                    .read()
+                    .expect("handler topic.read() failed")
+                    .as_ref()
+                    == Some(&topic)
+            });
+        (is_known && !rest.trim().is_empty()).then(|| (topic, rest.trim().to_string()))
     }
 
-    async fn handle_action(&mut self, action: Action) -> Result<()> {
+    async fn handle_command_skip(
+        &mut self,
+        thread_id: ThreadId,
+        message_id: i32,
+        text: String,
+    ) -> Result<()> {
+        let thread = self
+            .threads
+            .get(&thread_id)
+            .with_context(|| format!("thread {} does not exist", thread_id))?;
         ensure!(
-            !self.user_handle.is_stopped.load(Ordering::Relaxed),
-            "user has stopped the bot"
+            thread.anon_mode == ThreadAnonimityMode::Both,
+            "only threads started with `/random` can be skipped; use `/close` instead"
         );
-        match action {
-            Action::StartAnonymousThread(thread) => {
-                ensure!(
-                    !self.threads.contains_key(&thread.id),
-                    "thread id {} is already used",
-                    thread.id,
-                );
+        let skipped_login = thread.other_handle.user.login.clone();
 
-                ensure!(
-                    !self.banlist.contains_key(&thread.other_handle.user.login)
-                        || thread.anon_mode != ThreadAnonimityMode::Them,
+        let mut events = vec![];
+        let recent_partners = self.recent_partners.iter().cloned().collect::<Vec<_>>();
+        let partner = self
+            .match_random_partner(None, None, &recent_partners, &mut events)
+            .or_else(|_| self.match_random_partner(None, None, &[skipped_login], &mut events))
+            .context("there is nobody else to chat with; the thread has not been closed")?;
+
+        let thread = &self.threads[&thread_id];
+        if let Err(err) = thread.terminate().await {
+            if !events.is_empty() {
+                self.event_service
+                    .write_batch(events)
+                    .wait_written()
+                    .await?;
+            }
+            return Err(err.context("failed to terminate peer thread"));
+        }
+        let thread = self.threads.remove(&thread_id).unwrap();
+        self.forget_default_thread(&thread_id);
+        events.push(Event::ThreadTerminated(ThreadTerminatedEvent {
+            login: self.user_handle.user.login.clone(),
+            other_login: thread.other_handle.user.login.clone(),
+            my_thread_id: thread.id,
+            other_thread_id: thread.other_id,
+        }));
+
+        self.start_random_thread(message_id, partner, text, vec![], events)
+            .await
+    }
+
+    /// Picks a partner for a new random thread, preferring users who wait in the
+    /// `/random` queue. Events for the queue changes are appended to `events`.
+    fn match_random_partner(
+        &mut self,
+        tag: Option<&str>,
+        topic: Option<&str>,
+        excluded: &[String],
+        events: &mut Vec<Event>,
+    ) -> Result<RandomPartner> {
+        if let Some(topic) = topic {
+            if let Ok(partner) = self.find_random_partner(tag, Some(topic), excluded, events) {
+                return Ok(partner);
+            }
+        }
+        self.find_random_partner(tag, None, excluded, events)
+    }
+
+    fn find_random_partner(
+        &mut self,
+        tag: Option<&str>,
+        topic: Option<&str>,
+        excluded: &[String],
+        events: &mut Vec<Event>,
+    ) -> Result<RandomPartner> {
+        let my_login = &self.user_handle.user.login;
+        let mb_queued_request = {
+            let registry = self
+                .handle_registry
+                .read()
+                .expect("handler handle_registry.read() failed");
+            self.random_queue
+                .lock()
+                .expect("handler random_queue.lock() failed")
+                .pop_match(|login| {
+                    login != my_login
+                        && !excluded.iter().any(|l| l == login)
+                        && registry.get(login).is_some_and(|h| {
+                            !h.is_stopped.load(Ordering::Relaxed) && has_topic(h, topic)
+                        })
+                })
+        };
+        if let Some(request) = mb_queued_request {
+            events.push(Event::RandomDequeued(RandomDequeuedEvent {
+                login: request.login.clone(),
+            }));
+            return Ok(RandomPartner {
+                login: request.login,
+                queued_text: Some(request.text),
+                by_topic: topic.is_some(),
+            });
+        }
+
+        let login = self.choose_random_partner(tag, topic, excluded)?;
+        Ok(RandomPartner {
+            login,
+            queued_text: None,
+            by_topic: topic.is_some(),
+        })
+    }
+
+    async fn start_random_thread(
+        &mut self,
+        message_id: i32,
+        partner: RandomPartner,
+        text: String,
+        entities: Vec<MessageEntity>,
+        mut events: Vec<Event>,
+    ) -> Result<()> {
+        let my_thread_id = Self::random_thread_id();
+        let other_thread_id = Self::random_thread_id();
+        let result = self
+            .create_thread(
+                my_thread_id.clone(),
+                other_thread_id.clone(),
+                &partner.login,
+                ThreadAnonimityMode::Both,
+            )
+            .await;
+        if let Err(err) = result {
+            if !events.is_empty() {
+                self.event_service
+                    .write_batch(events)
+                    .wait_written()
+                    .await?;
+            }
+            return Err(err);
+        }
+        self.message_id_to_thread_id
+            .insert(message_id, my_thread_id.clone());
+        self.remember_partner(partner.login.clone());
+
+        events.push(Event::ThreadStarted(ThreadStartedEvent {
+            login: self.user_handle.user.login.clone(),
+            other_login: partner.login,
+            my_thread_id: my_thread_id.clone(),
+            other_thread_id,
+            anon_mode: ThreadAnonimityMode::Both,
+            is_initiator: Some(true),
+            created_at: Some(now_timestamp()),
+        }));
+        events.push(Event::ThreadMessageReceived(ThreadMessageReceivedEvent {
+            login: self.user_handle.user.login.clone(),
+            message_id,
+            thread_id: my_thread_id.clone(),
+            kind: Some(MessageKind::Sent),
+        }));
+        let first_tracker = self.event_service.write_batch(events);
+
+        self.relay_text(&my_thread_id, text, None, None, entities)
+            .await?;
+        self.stats.threads_started += 1;
+
+        let message = self
+            .send_to_self(self.format_text(Key::AnonymousThreadStarted, &[&my_thread_id]))
+            .await?;
+        self.message_id_to_thread_id
+            .insert(message.id, my_thread_id.clone());
+        let second_tracker =
+            self.event_service
+                .write(Event::ThreadMessageReceived(ThreadMessageReceivedEvent {
+                    login: self.user_handle.user.login.clone(),
+                    message_id: message.id,
+                    thread_id: my_thread_id.clone(),
+                    kind: Some(MessageKind::Service),
+                }));
+
+        first_tracker.wait_written().await?;
+        second_tracker.wait_written().await?;
+
+        if let Some(queued_text) = partner.queued_text {
+            self.receive_text(&my_thread_id, &queued_text, None, vec![])
+                .await?;
+        }
+        Ok(())
+    }
+
+    fn remember_partner(&mut self, login: String) {
+        remember_partner(&mut self.recent_partners, login);
+    }
+
+    async fn enqueue_random(&mut self, text: String) -> Result<()> {
+        let login = self.user_handle.user.login.clone();
+        let timeout = self
+            .random_queue
+            .lock()
+            .expect("handler random_queue.lock() failed")
+            .timeout();
+        let expires_at = now_timestamp() + timeout.as_secs() as i64;
+
+        self.event_service
+            .write(Event::RandomQueued(RandomQueuedEvent {
+                login: login.clone(),
+                text: text.clone(),
+                expires_at,
+            }))
+            .wait_written()
+            .await?;
+        self.random_queue
+            .lock()
+            .expect("handler random_queue.lock() failed")
+            .push(QueuedRandomRequest {
+                login,
+                text,
+                expires_at,
+            });
+
+        self.send_to_self(self.format_text(Key::RandomQueued, &[&format_duration(timeout)]))
+            .await?;
+        Ok(())
+    }
+
+    async fn handle_command_cancel(&mut self) -> Result<()> {
+        let login = self.user_handle.user.login.clone();
+        ensure!(
+            self.random_queue
+                .lock()
+                .expect("handler random_queue.lock() failed")
+                .get(&login)
+                .is_some(),
+            "you are not waiting in the `/random` queue"
+        );
+
+        self.event_service
+            .write(Event::RandomDequeued(RandomDequeuedEvent {
+                login: login.clone(),
+            }))
+            .wait_written()
+            .await?;
+        self.random_queue
+            .lock()
+            .expect("handler random_queue.lock() failed")
+            .remove(&login);
+
+        self.send_to_self(self.text(Key::RandomQueueLeft)).await?;
+        Ok(())
+    }
+
+    fn choose_random_partner(
+        &self,
+        tag: Option<&str>,
+        topic: Option<&str>,
+        excluded: &[String],
+    ) -> Result<String> {
+        struct Candidate {
+            login: String,
+            is_active: bool,
+            has_tag: bool,
+        }
+
+        let candidates = self
+            .handle_registry
+            .read()
+            .expect("handler handle_registry.read() failed")
+            .values()
+            .filter(|h| {
+                h.settings
+                    .read()
+                    .expect("handler settings.read() failed")
+                    .receive_random
+            })
+            .filter(|h| h.user.login != self.user_handle.user.login)
+            .filter(|h| !excluded.contains(&h.user.login))
+            .filter(|h| has_topic(h, topic))
+            .map(|h| Candidate {
+                login: h.user.login.clone(),
+                is_active: h.is_active_within(ONLINE_WINDOW_SECS),
+                has_tag: tag.is_some_and(|tag| {
+                    h.interests
+                        .read()
+                        .expect("handler interests.read() failed")
+                        .iter()
+                        .any(|t| t == tag)
+                }),
+            })
+            .collect::<Vec<_>>();
+
+        let tagged = candidates.iter().filter(|c| c.has_tag).collect::<Vec<_>>();
+        let pool = if tagged.is_empty() {
+            candidates.iter().collect::<Vec<_>>()
+        } else {
+            tagged
+        };
+        pool.iter()
+            .filter(|c| c.is_active)
+            .choose(&mut rand::thread_rng())
+            .or_else(|| pool.iter().choose(&mut rand::thread_rng()))
+            .map(|c| c.login.clone())
+            .context("there are currently no other users to chat with")
+    }
+
+    async fn handle_command_send(
+        &mut self,
+        thread_id: ThreadId,
+        message_id: i32,
+        text: String,
+        entities: Vec<MessageEntity>,
+    ) -> Result<()> {
+        let mut events = vec![];
+
+        ensure!(
+            !self.archived_threads.contains_key(&thread_id),
+            "thread {} is archived; use `/unarchive` first",
+            thread_id
+        );
+        if let Some(thread) = self.threads.get(&thread_id) {
+            thread.check_slow_mode()?;
+        }
+        if !self.threads.contains_key(&thread_id) {
+            if self
+                .rooms
+                .lock()
+                .expect("handler rooms.lock() failed")
+                .get(&thread_id)
+                .is_some()
+            {
+                return self.send_to_room(thread_id, message_id, text).await;
+            }
+            if !thread_id.starts_with("@") {
+                bail!("unknown thread: {}", thread_id);
+            }
+            events.push(self.start_login_thread(&thread_id).await?);
+        }
+
+        self.message_id_to_thread_id
+            .insert(message_id, thread_id.clone());
+        events.push(Event::ThreadMessageReceived(ThreadMessageReceivedEvent {
+            login: self.user_handle.user.login.clone(),
+            message_id,
+            thread_id: thread_id.clone(),
+            kind: Some(MessageKind::Sent),
+        }));
+        self.event_service
+            .write_batch(events)
+            .wait_written()
+            .await?;
+
+        self.relay_text(&thread_id, text, None, Some(message_id), entities)
+            .await?;
+        self.schedule_own_message_deletion(&thread_id, message_id);
+        Ok(())
+    }
+
+    async fn handle_command_send_many(
+        &mut self,
+        thread_ids: Vec<ThreadId>,
+        message_id: i32,
+        text: String,
+    ) -> Result<()> {
+        let total = thread_ids.len();
+        let mut lines = vec![];
+        let mut delivered = 0;
+        for thread_id in thread_ids {
+            match self
+                .handle_command_send(thread_id.clone(), message_id, text.clone(), vec![])
+                .await
+            {
+                Ok(()) => {
+                    delivered += 1;
+                    lines.push(self.format_text(Key::SendManySent, &[&thread_id]));
+                }
+                Err(err) => lines.push(format!("{}: {:#}", thread_id, err)),
+            }
+        }
+        self.send_to_self(self.format_text(
+            Key::SendManyReport,
+            &[&delivered, &total, &lines.join("\n* ")],
+        ))
+        .await?;
+        Ok(())
+    }
+
+    async fn handle_command_schedule(
+        &mut self,
+        delay: Duration,
+        thread_id: ThreadId,
+        message_id: i32,
+        text: String,
+    ) -> Result<()> {
+        ensure!(
+            !self.archived_threads.contains_key(&thread_id),
+            "thread {} is archived; use `/unarchive` first",
+            thread_id
+        );
+        ensure!(
+            self.threads.contains_key(&thread_id),
+            "thread {} does not exist",
+            thread_id
+        );
+        ensure!(
+            self.scheduled_messages.len() < MAX_SCHEDULED_MESSAGES,
+            "you cannot have more than {} scheduled messages",
+            MAX_SCHEDULED_MESSAGES
+        );
+
+        let scheduled = ScheduledMessage {
+            id: self.next_scheduled_id,
+            thread_id,
+            message_id,
+            text,
+            deliver_at: now_timestamp() + delay.as_secs() as i64,
+        };
+        self.event_service
+            .write(Event::MessageScheduled(MessageScheduledEvent {
+                login: self.user_handle.user.login.clone(),
+                id: scheduled.id,
+                thread_id: scheduled.thread_id.clone(),
+                message_id,
+                text: scheduled.text.clone(),
+                deliver_at: scheduled.deliver_at,
+            }))
+            .wait_written()
+            .await?;
+        self.next_scheduled_id += 1;
+        let reply = self.format_text(
+            Key::MessageScheduled,
+            &[&scheduled.thread_id, &format_duration(delay)],
+        );
+        insert_scheduled_message(&mut self.scheduled_messages, scheduled);
+
+        self.send_to_self(reply).await?;
+        Ok(())
+    }
+
+    async fn handle_command_scheduled(&mut self) -> Result<()> {
+        if self.scheduled_messages.is_empty() {
+            self.send_to_self(self.text(Key::NoScheduledMessages))
+                .await?;
+            return Ok(());
+        }
+        let lines = self
+            .scheduled_messages
+            .iter()
+            .enumerate()
+            .map(|(i, m)| {
+                self.format_text(
+                    Key::ScheduledMessageLine,
+                    &[
+                        &(i + 1),
+                        &m.thread_id,
+                        &format_timestamp(m.deliver_at),
+                        &preview(&m.text, NOTE_PREVIEW_LENGTH),
+                    ],
+                )
+            })
+            .collect::<Vec<_>>();
+        self.send_to_self_long(self.format_text(Key::ScheduledMessages, &[&lines.join("\n")]))
+            .await?;
+        Ok(())
+    }
+
+    async fn handle_command_unschedule(&mut self, index: usize) -> Result<()> {
+        ensure!(
+            (1..=self.scheduled_messages.len()).contains(&index),
+            "there is no scheduled message #{}; see `/scheduled`",
+            index
+        );
+        let id = self.scheduled_messages[index - 1].id;
+
+        self.event_service
+            .write(Event::ScheduledMessageRemoved(
+                ScheduledMessageRemovedEvent {
+                    login: self.user_handle.user.login.clone(),
+                    id,
+                },
+            ))
+            .wait_written()
+            .await?;
+        let scheduled = self.scheduled_messages.remove(index - 1);
+
+        self.send_to_self(
+            self.format_text(Key::ScheduledMessageCancelled, &[&scheduled.thread_id]),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn deliver_scheduled_message(&mut self, scheduled: ScheduledMessage) -> Result<()> {
+        let login = self.user_handle.user.login.clone();
+        let mut events = vec![Event::ScheduledMessageRemoved(
+            ScheduledMessageRemovedEvent {
+                login: login.clone(),
+                id: scheduled.id,
+            },
+        )];
+        let is_active = self.threads.contains_key(&scheduled.thread_id);
+        if is_active {
+            self.message_id_to_thread_id
+                .insert(scheduled.message_id, scheduled.thread_id.clone());
+            events.push(Event::ThreadMessageReceived(ThreadMessageReceivedEvent {
+                login,
+                message_id: scheduled.message_id,
+                thread_id: scheduled.thread_id.clone(),
+                kind: Some(MessageKind::Sent),
+            }));
+        }
+        self.event_service
+            .write_batch(events)
+            .wait_written()
+            .await?;
+
+        let result = if is_active {
+            self.relay_text(
+                &scheduled.thread_id,
+                scheduled.text.clone(),
+                None,
+                Some(scheduled.message_id),
+                vec![],
+            )
+            .await
+        } else {
+            Err(anyhow!("the thread is no longer active"))
+        };
+        match result {
+            Ok(()) => {
+                self.schedule_own_message_deletion(&scheduled.thread_id, scheduled.message_id)
+            }
+            Err(err) => {
+                self.send_to_self(self.format_text(
+                    Key::ScheduledMessageFailed,
+                    &[
+                        &scheduled.thread_id,
+                        &format!("{:#}", err),
+                        &escape_markup(&scheduled.text),
+                    ],
+                ))
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_command_create(&mut self, room: String) -> Result<()> {
+        ensure!(
+            !self.threads.contains_key(&room) && !self.archived_threads.contains_key(&room),
+            "{} is already one of your threads",
+            room
+        );
+        {
+            let mut rooms = self.rooms.lock().expect("handler rooms.lock() failed");
+            ensure!(rooms.get(&room).is_none(), "room {} already exists", room);
+            rooms.create(room.clone());
+            rooms.join(&room, self.user_handle.user.login.clone(), 1);
+        }
+
+        let login = self.user_handle.user.login.clone();
+        self.event_service
+            .write_batch(vec![
+                Event::RoomCreated(RoomCreatedEvent {
+                    login: login.clone(),
+                    room: room.clone(),
+                }),
+                Event::RoomJoined(RoomJoinedEvent {
+                    login,
+                    room: room.clone(),
+                    alias: 1,
+                }),
+            ])
+            .wait_written()
+            .await?;
+
+        self.send_to_self(self.format_text(Key::RoomCreated, &[&room, &room]))
+            .await?;
+        Ok(())
+    }
+
+    async fn handle_command_join(&mut self, room: String) -> Result<()> {
+        let login = self.user_handle.user.login.clone();
+        let alias = {
+            let mut rooms = self.rooms.lock().expect("handler rooms.lock() failed");
+            let existing = rooms
+                .get(&room)
+                .with_context(|| format!("room {} does not exist; use `/create` first", room))?;
+            ensure!(
+                !existing.is_member(&login),
+                "you are already in room {}",
+                room
+            );
+            let alias = existing.new_alias();
+            rooms.join(&room, login.clone(), alias);
+            alias
+        };
+
+        self.event_service
+            .write(Event::RoomJoined(RoomJoinedEvent {
+                login,
+                room: room.clone(),
+                alias,
+            }))
+            .wait_written()
+            .await?;
+
+        self.send_to_self(self.format_text(Key::RoomJoined, &[&room, &alias]))
+            .await?;
+        Ok(())
+    }
+
+    async fn handle_command_leave(&mut self, room: String) -> Result<()> {
+        let login = self.user_handle.user.login.clone();
+        {
+            let mut rooms = self.rooms.lock().expect("handler rooms.lock() failed");
+            ensure!(
+                rooms.get(&room).is_some_and(|r| r.is_member(&login)),
+                "you are not in room {}",
+                room
+            );
+            rooms.leave(&room, &login);
+        }
+
+        self.event_service
+            .write(Event::RoomLeft(RoomLeftEvent {
+                login,
+                room: room.clone(),
+            }))
+            .wait_written()
+            .await?;
+
+        self.send_to_self(self.format_text(Key::RoomLeft, &[&room]))
+            .await?;
+        Ok(())
+    }
+
+    async fn send_to_room(&mut self, room: String, message_id: i32, text: String) -> Result<()> {
+        let login = self.user_handle.user.login.clone();
+        let (alias, members) = {
+            let rooms = self.rooms.lock().expect("handler rooms.lock() failed");
+            let existing = rooms
+                .get(&room)
+                .with_context(|| format!("room {} does not exist", room))?;
+            let alias = existing
+                .alias(&login)
+                .with_context(|| format!("you are not in room {}; use `/join` first", room))?;
+            let members = existing
+                .members()
+                .filter(|member| **member != login)
+                .cloned()
+                .collect::<Vec<_>>();
+            (alias, members)
+        };
+
+        self.event_service
+            .write(Event::RoomMessage(RoomMessageEvent {
+                login,
+                room: room.clone(),
+                message_id,
+            }))
+            .wait_written()
+            .await?;
+
+        let handles = {
+            let registry = self
+                .handle_registry
+                .read()
+                .expect("handler handle_registry.read() failed");
+            members
+                .iter()
+                .filter_map(|member| registry.get(member).cloned())
+                .collect::<Vec<_>>()
+        };
+        let count = handles.len();
+        // NB: members may be writing to the room at the same time, so do not wait for them.
+        for handle in handles {
+            let action = Action::RoomMessage(room.clone(), alias.clone(), text.clone());
+            tokio::spawn(async move {
+                if let Err(err) = handle.send_action(action).await {
+                    debug!(
+                        "failed to deliver room message to @{}: {:#}",
+                        handle.user.login, err
+                    );
+                }
+            });
+        }
+
+        self.reply_to_self(
+            message_id,
+            self.format_text(Key::SentToRoom, &[&count, &room]),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn handle_command_poll(
+        &mut self,
+        thread_id: ThreadId,
+        question: String,
+        options: Vec<String>,
+    ) -> Result<()> {
+        ensure!(
+            !self.archived_threads.contains_key(&thread_id),
+            "thread {} is archived; use `/unarchive` first",
+            thread_id
+        );
+        let thread = self
+            .threads
+            .get_mut(&thread_id)
+            .with_context(|| format!("thread {} does not exist", thread_id))?;
+        thread.check_paused()?;
+        thread.check_slow_mode()?;
+        thread.send_poll(question, options).await?;
+        thread.last_sent_at = Some(Instant::now());
+        self.last_used_thread_id = Some(thread_id.clone());
+
+        self.send_to_self(self.format_text(Key::PollSent, &[&thread_id]))
+            .await?;
+        Ok(())
+    }
+
+    async fn handle_command_dice(&mut self, thread_id: ThreadId, emoji: DiceEmoji) -> Result<()> {
+        ensure!(
+            !self.archived_threads.contains_key(&thread_id),
+            "thread {} is archived; use `/unarchive` first",
+            thread_id
+        );
+        let thread = self
+            .threads
+            .get_mut(&thread_id)
+            .with_context(|| format!("thread {} does not exist", thread_id))?;
+        thread.check_paused()?;
+        thread.check_slow_mode()?;
+        let value = thread.send_dice(emoji).await?;
+        thread.last_sent_at = Some(Instant::now());
+        self.last_used_thread_id = Some(thread_id.clone());
+
+        self.send_to_self(
+            self.format_text(Key::DiceRolled, &[&dice_symbol(emoji), &value, &thread_id]),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn handle_poll_answer(&mut self, poll_id: String, option_ids: Vec<i32>) -> Result<()> {
+        let poll = self
+            .received_polls
+            .get(&poll_id)
+            .with_context(|| format!("unknown poll: {}", poll_id))?;
+        let thread = self
+            .threads
+            .get(&poll.thread_id)
+            .or_else(|| self.archived_threads.get(&poll.thread_id))
+            .with_context(|| format!("thread {} does not exist anymore", poll.thread_id))?;
+        let chosen = option_ids
+            .iter()
+            .filter_map(|&id| poll.options.get(id as usize).cloned())
+            .collect();
+        thread
+            .other_handle
+            .send_action(Action::PollAnswered(
+                thread.other_id.clone(),
+                poll.question.clone(),
+                chosen,
+            ))
+            .await
+    }
+
+    /// Passes a reaction on a thread message to the other side. Reactions on
+    /// messages that do not belong to a thread are ignored.
+    async fn handle_reaction(&mut self, message_id: i32, emoji: Option<String>) -> Result<()> {
+        let thread = match self
+            .message_id_to_thread_id
+            .get(&message_id)
+            .and_then(|thread_id| self.threads.get(thread_id))
+        {
+            Some(thread) => thread,
+            None => return Ok(()),
+        };
+        thread
+            .other_handle
+            .send_action(Action::SendReaction(
+                thread.other_id.clone(),
+                message_id,
+                emoji,
+            ))
+            .await
+    }
+
+    async fn handle_command_remind(&mut self, delay: Duration, text: String) -> Result<()> {
+        ensure!(
+            self.reminders.len() < MAX_REMINDERS,
+            "you cannot have more than {} reminders",
+            MAX_REMINDERS
+        );
+
+        let reminder = Reminder {
+            id: self.next_reminder_id,
+            text,
+            fire_at: now_timestamp() + delay.as_secs() as i64,
+        };
+        self.event_service
+            .write(Event::ReminderSet(ReminderSetEvent {
+                login: self.user_handle.user.login.clone(),
+                id: reminder.id,
+                text: reminder.text.clone(),
+                fire_at: reminder.fire_at,
+            }))
+            .wait_written()
+            .await?;
+        self.next_reminder_id += 1;
+        self.reminders.push(reminder);
+        self.reminders.sort_by_key(|r| (r.fire_at, r.id));
+
+        self.send_to_self(self.format_text(Key::ReminderSet, &[&format_duration(delay)]))
+            .await?;
+        Ok(())
+    }
+
+    async fn handle_command_reminders(&mut self) -> Result<()> {
+        if self.reminders.is_empty() {
+            self.send_to_self(self.text(Key::NoReminders)).await?;
+            return Ok(());
+        }
+        let lines = self
+            .reminders
+            .iter()
+            .enumerate()
+            .map(|(i, r)| {
+                self.format_text(
+                    Key::ReminderLine,
+                    &[
+                        &(i + 1),
+                        &format_timestamp(r.fire_at),
+                        &preview(&r.text, NOTE_PREVIEW_LENGTH),
+                    ],
+                )
+            })
+            .collect::<Vec<_>>();
+        self.send_to_self_long(self.format_text(Key::Reminders, &[&lines.join("\n")]))
+            .await?;
+        Ok(())
+    }
+
+    async fn handle_command_cancel_reminder(&mut self, index: usize) -> Result<()> {
+        ensure!(
+            (1..=self.reminders.len()).contains(&index),
+            "there is no reminder #{}; see `/reminders`",
+            index
+        );
+        let id = self.reminders[index - 1].id;
+
+        self.event_service
+            .write(Event::ReminderRemoved(ReminderRemovedEvent {
+                login: self.user_handle.user.login.clone(),
+                id,
+            }))
+            .wait_written()
+            .await?;
+        self.reminders.remove(index - 1);
+
+        self.send_to_self(self.text(Key::ReminderCancelled)).await?;
+        Ok(())
+    }
+
+    async fn handle_command_send_media(
+        &mut self,
+        message_id: i32,
+        thread_id: ThreadId,
+        kind: MediaKind,
+        file_id: String,
+        caption: Option<String>,
+        forwarded: bool,
+    ) -> Result<()> {
+        if forwarded {
+            self.check_revealing_allowed(&thread_id, "forwarded messages")?;
+        }
+        if let MediaKind::Contact { .. } = kind {
+            self.check_revealing_allowed(&thread_id, "contacts")?;
+        }
+        if let MediaKind::Location { .. } = kind {
+            // Getting here means the location warning has been confirmed.
+            self.location_confirmed_threads.insert(thread_id.clone());
+        }
+        if let MediaKind::Voice { .. } = kind {
+            // Getting here means the anonymity warning has been confirmed.
+            let voice_confirmed = self
+                .user_handle
+                .settings
+                .read()
+                .expect("handler settings.read() failed")
+                .voice_confirmed;
+            if !voice_confirmed {
+                self.update_settings(|settings| {
+                    settings.voice_confirmed = true;
+                    Ok(())
+                })
+                .await?;
+            }
+        }
+        let (hide_file_names, hide_audio_metadata, scrub_media) = {
+            let settings = self
+                .user_handle
+                .settings
+                .read()
+                .expect("handler settings.read() failed");
+            (
+                settings.hide_file_names,
+                settings.hide_audio_metadata,
+                settings.scrub_media,
+            )
+        };
+        // Hidden names are sent as anonymous documents and scrubbed photos are
+        // re-encoded, see `receive_media`.
+        let kind = match kind {
+            // Spoilers are sent by file id, which would keep the metadata.
+            MediaKind::Photo { spoiler: true, .. } if scrub_media => {
+                bail!("photos under a spoiler cannot be scrubbed; send it without `/spoiler`")
+            }
+            MediaKind::Photo { spoiler, .. } if scrub_media => MediaKind::Photo {
+                spoiler,
+                scrub: true,
+            },
+            MediaKind::Document { mime_type, .. } if hide_file_names => MediaKind::Document {
+                file_name: None,
+                mime_type,
+            },
+            MediaKind::Audio { mime_type, .. } if hide_audio_metadata => MediaKind::Document {
+                file_name: None,
+                mime_type,
+            },
+            kind => kind,
+        };
+        let thread = self
+            .threads
+            .get_mut(&thread_id)
+            .with_context(|| format!("thread {} no longer exists", thread_id))?;
+        thread.check_slow_mode()?;
+        thread.check_paused()?;
+        let mb_message_id = match thread.send_media(kind, file_id, caption).await {
+            Ok(mb_message_id) => mb_message_id,
+            Err(err) if thread.anon_mode != ThreadAnonimityMode::Me => {
+                bail!("failed to deliver to {}: {}", thread_id, err.root_cause())
+            }
+            Err(err) => return Err(err.context(format!("failed to deliver to {}", thread_id))),
+        };
+        thread.last_sent_at = Some(Instant::now());
+        thread.messages_sent += 1;
+        if let Some(delivered_message_id) = mb_message_id {
+            thread.record_delivered(delivered_message_id, Some(message_id), None);
+        }
+        self.stats.messages_sent += 1;
+        self.last_used_thread_id = Some(thread_id.clone());
+
+        self.event_service
+            .write(Event::ThreadMediaReceived(ThreadMediaReceivedEvent {
+                login: self.user_handle.user.login.clone(),
+                message_id,
+                thread_id: thread_id.clone(),
+                kind: MessageKind::Sent,
+            }))
+            .wait_written()
+            .await?;
+        self.message_id_to_thread_id.insert(message_id, thread_id);
+        Ok(())
+    }
+
+    /// Starts a thread with `@login`, the user being anonymous to the other
+    /// side. Returns the event to write.
+    async fn start_login_thread(&mut self, thread_id: &ThreadId) -> Result<Event> {
+        let other_login = &thread_id[1..];
+        ensure!(
+            other_login != self.user_handle.user.login,
+            "cannot send a message to self"
+        );
+        let other_thread_id = Self::random_thread_id();
+        self.create_thread(
+            thread_id.clone(),
+            other_thread_id.clone(),
+            other_login,
+            ThreadAnonimityMode::Me,
+        )
+        .await?;
+        self.stats.threads_started += 1;
+
+        Ok(Event::ThreadStarted(ThreadStartedEvent {
+            login: self.user_handle.user.login.clone(),
+            other_login: other_login.to_string(),
+            my_thread_id: thread_id.clone(),
+            other_thread_id,
+            anon_mode: ThreadAnonimityMode::Me,
+            is_initiator: Some(true),
+            created_at: Some(now_timestamp()),
+        }))
+    }
+
+    /// Starts the thread of a `/send @login` caption, like the text `/send`
+    /// does, if there is none yet.
+    async fn start_media_thread(&mut self, thread_id: Option<&ThreadId>) -> Result<()> {
+        let thread_id = match thread_id {
+            Some(thread_id)
+                if thread_id.starts_with('@')
+                    && !self.threads.contains_key(thread_id)
+                    && !self.archived_threads.contains_key(thread_id) =>
+            {
+                thread_id.clone()
+            }
+            _ => return Ok(()),
+        };
+        let event = self.start_login_thread(&thread_id).await?;
+        self.event_service.write(event).wait_written().await?;
+        Ok(())
+    }
+
+    /// Finds the thread for media: the one given in the caption, the one of the
+    /// replied message, or the default one.
+    fn resolve_media_thread(
+        &self,
+        thread_id: Option<ThreadId>,
+        reply_message_id: Option<i32>,
+    ) -> Result<ThreadId> {
+        let thread_id = match (thread_id, reply_message_id) {
+            (Some(thread_id), _) => thread_id,
+            (None, Some(reply_message_id)) => self
+                .message_id_to_thread_id
+                .get(&reply_message_id)
+                .cloned()
+                .context("message you are replying to does not belong to a thread")?,
+            (None, None) => self
+                .default_thread_id
+                .clone()
+                .or_else(|| self.last_used_thread_id.clone())
+                .context(
+                    "no default thread; reply to a message or use `/send [thread]` as the caption",
+                )?,
+        };
+        ensure!(
+            !self.archived_threads.contains_key(&thread_id),
+            "thread {} is archived; use `/unarchive` first",
+            thread_id
+        );
+        ensure!(
+            self.threads.contains_key(&thread_id),
+            "thread {} does not exist; media can only be sent to existing threads",
+            thread_id
+        );
+        Ok(thread_id)
+    }
+
+    /// Collects an album item. Only the first item may carry a reply or a
+    /// `/send` caption, so it picks the thread for the whole album.
+    async fn handle_album_item(
+        &mut self,
+        group_id: String,
+        thread_id: Option<ThreadId>,
+        reply_message_id: Option<i32>,
+        item: AlbumItem,
+        forwarded: bool,
+    ) -> Result<()> {
+        if let Some(album) = self.albums.get(&group_id) {
+            if forwarded {
+                self.check_revealing_allowed(&album.thread_id, "forwarded messages")?;
+            }
+        }
+        if let Some(album) = self.albums.get_mut(&group_id) {
+            album.items.push(item);
+            album.flush_at = Instant::now() + ALBUM_WINDOW;
+            if album.items.len() >= MAX_ALBUM_ITEMS {
+                if let Some(album) = self.albums.remove(&group_id) {
+                    self.send_album(album).await?;
+                }
+            }
+            return Ok(());
+        }
+        self.start_media_thread(thread_id.as_ref()).await?;
+        let thread_id = self.resolve_media_thread(thread_id, reply_message_id)?;
+        if forwarded {
+            self.check_revealing_allowed(&thread_id, "forwarded messages")?;
+        }
+        self.albums.insert(
+            group_id,
+            PendingAlbum {
+                thread_id,
+                items: vec![item],
+                flush_at: Instant::now() + ALBUM_WINDOW,
+            },
+        );
+        Ok(())
+    }
+
+    fn next_album_flush(&self) -> Option<Instant> {
+        self.albums.values().map(|album| album.flush_at).min()
+    }
+
+    /// Sends the albums that have stopped growing. Nobody waits for the result,
+    /// so errors are reported to the user right away.
+    async fn flush_due_albums(&mut self) {
+        let now = Instant::now();
+        let group_ids = self
+            .albums
+            .iter()
+            .filter(|(_, album)| album.flush_at <= now)
+            .map(|(group_id, _)| group_id.clone())
+            .collect::<Vec<_>>();
+        for group_id in group_ids {
+            let album = match self.albums.remove(&group_id) {
+                Some(album) => album,
+                None => continue,
+            };
+            if let Err(err) = self.send_album(album).await {
+                let text = self.format_text(Key::Error, &[&escape_markup(&format!("{:#}", err))]);
+                if let Err(err) = self.send_to_self(text).await {
+                    error!(
+                        "failed to report album error to @{}: {:#}",
+                        self.user_handle.user.login, err
+                    );
+                }
+            }
+        }
+    }
+
+    async fn send_album(&mut self, album: PendingAlbum) -> Result<()> {
+        let PendingAlbum {
+            thread_id, items, ..
+        } = album;
+        ensure!(
+            !self.archived_threads.contains_key(&thread_id),
+            "thread {} is archived; use `/unarchive` first",
+            thread_id
+        );
+        let thread = self
+            .threads
+            .get_mut(&thread_id)
+            .with_context(|| format!("thread {} no longer exists", thread_id))?;
+        thread.check_slow_mode()?;
+        thread.check_paused()?;
+        let scrub_media = self
+            .user_handle
+            .settings
+            .read()
+            .expect("handler settings.read() failed")
+            .scrub_media;
+        let media = items
+            .iter()
+            .map(|item| {
+                let kind = match &item.kind {
+                    MediaKind::Photo { spoiler, .. } if scrub_media => MediaKind::Photo {
+                        spoiler: *spoiler,
+                        scrub: true,
+                    },
+                    kind => kind.clone(),
+                };
+                (kind, item.file_id.clone(), item.caption.clone())
+            })
+            .collect();
+        let mb_message_ids = match thread.send_media_group(media).await {
+            Ok(mb_message_ids) => mb_message_ids,
+            Err(err) if thread.anon_mode != ThreadAnonimityMode::Me => {
+                bail!("failed to deliver to {}: {}", thread_id, err.root_cause())
+            }
+            Err(err) => return Err(err.context(format!("failed to deliver to {}", thread_id))),
+        };
+        thread.last_sent_at = Some(Instant::now());
+        thread.messages_sent += items.len() as u64;
+        for (delivered_message_id, item) in mb_message_ids.unwrap_or_default().iter().zip(&items) {
+            thread.record_delivered(*delivered_message_id, Some(item.message_id), None);
+        }
+        self.stats.messages_sent += items.len() as u64;
+        self.last_used_thread_id = Some(thread_id.clone());
+
+        for item in items {
+            self.event_service
+                .write(Event::ThreadMediaReceived(ThreadMediaReceivedEvent {
+                    login: self.user_handle.user.login.clone(),
+                    message_id: item.message_id,
+                    thread_id: thread_id.clone(),
+                    kind: MessageKind::Sent,
+                }))
+                .wait_written()
+                .await?;
+            self.message_id_to_thread_id
+                .insert(item.message_id, thread_id.clone());
+        }
+        Ok(())
+    }
+
+    /// Contacts and forwarded messages show who they come from, so they are
+    /// kept out of threads where the user is anonymous unless allowed in settings.
+    fn check_revealing_allowed(&self, thread_id: &ThreadId, what: &str) -> Result<()> {
+        let anonymous = self
+            .threads
+            .get(thread_id)
+            .is_none_or(|thread| thread.anon_mode != ThreadAnonimityMode::Them);
+        let allowed = self
+            .user_handle
+            .settings
+            .read()
+            .expect("handler settings.read() failed")
+            .allow_revealing_content;
+        ensure!(
+            !anonymous || allowed,
+            "{} may reveal who you are, so they are not sent to {} where you are anonymous; \
+             use `/settings allow_revealing_content on` to allow them",
+            what,
+            thread_id
+        );
+        Ok(())
+    }
+
+    fn check_media_limits(&self, kind: &MediaKind, file_size: Option<u64>) -> Result<()> {
+        let limits = self.media_limits;
+        let (name, max_file_size) = match kind {
+            MediaKind::Voice { duration } => {
+                ensure!(
+                    *duration <= limits.max_voice_duration,
+                    "voice message is too long: {}s, at most {}s is allowed",
+                    duration,
+                    limits.max_voice_duration
+                );
+                ("voice message", limits.max_voice_size)
+            }
+            MediaKind::Video { duration, .. } | MediaKind::VideoNote { duration } => {
+                ensure!(
+                    *duration <= limits.max_video_duration,
+                    "video is too long: {}s, at most {}s is allowed",
+                    duration,
+                    limits.max_video_duration
+                );
+                ("video", limits.max_video_size)
+            }
+            MediaKind::Audio { .. } => ("audio file", limits.max_audio_size),
+            MediaKind::Document { .. } => ("file", limits.max_document_size),
+            MediaKind::Photo { .. }
+            | MediaKind::Sticker
+            | MediaKind::Animation
+            | MediaKind::Contact { .. }
+            | MediaKind::Location { .. } => return Ok(()),
+        };
+        if let Some(file_size) = file_size {
+            ensure!(
+                file_size <= max_file_size,
+                "{} is too large: {} KiB, at most {} KiB is allowed",
+                name,
+                file_size / 1024,
+                max_file_size / 1024
+            );
+        }
+        Ok(())
+    }
+
+    async fn handle_command_send_default(
+        &mut self,
+        message_id: i32,
+        text: String,
+        entities: Vec<MessageEntity>,
+        forwarded: bool,
+    ) -> Result<()> {
+        let thread_id = self
+            .default_thread_id
+            .clone()
+            .or_else(|| self.last_used_thread_id.clone())
+            .context("no default thread; use `/default [thread]` or `/send [thread] [message]`")?;
+        if forwarded {
+            self.check_revealing_allowed(&thread_id, "forwarded messages")?;
+        }
+        self.handle_command_send(thread_id, message_id, text, entities)
+            .await
+    }
+
+    async fn handle_command_default(&mut self, thread_id: Option<ThreadId>) -> Result<()> {
+        if let Some(thread_id) = &thread_id {
+            ensure!(
+                self.threads.contains_key(thread_id),
+                "thread {} does not exist",
+                thread_id
+            );
+        }
+
+        self.event_service
+            .write(Event::DefaultThreadChanged(DefaultThreadChangedEvent {
+                login: self.user_handle.user.login.clone(),
+                thread_id: thread_id.clone(),
+            }))
+            .wait_written()
+            .await?;
+        self.default_thread_id = thread_id.clone();
+
+        match thread_id {
+            Some(thread_id) => {
+                self.send_to_self(self.format_text(Key::DefaultThreadSet, &[&thread_id]))
+                    .await?
+            }
+            None => {
+                self.send_to_self(self.text(Key::DefaultThreadCleared))
+                    .await?
+            }
+        };
+        Ok(())
+    }
+
+    fn forget_default_thread(&mut self, thread_id: &str) {
+        if self.default_thread_id.as_deref() == Some(thread_id) {
+            self.default_thread_id = None;
+        }
+        if self.last_used_thread_id.as_deref() == Some(thread_id) {
+            self.last_used_thread_id = None;
+        }
+    }
+
+    async fn handle_command_reply(
+        &mut self,
+        reply_message_id: i32,
+        reply_text: Option<String>,
+        message_id: i32,
+        text: String,
+        entities: Vec<MessageEntity>,
+    ) -> Result<()> {
+        let thread_id = match self.message_id_to_thread_id.get(&reply_message_id) {
+            Some(thread_id) => thread_id.clone(),
+            None => {
+                self.recover_reply_thread_id(reply_message_id, reply_text.as_deref())
+                    .await?
+            }
+        };
+        if thread_id.starts_with('@')
+            && !self.threads.contains_key(&thread_id)
+            && !self.archived_threads.contains_key(&thread_id)
+        {
+            // A reply to a `/pick` prompt, the thread does not exist yet.
+            return self
+                .handle_command_send(thread_id, message_id, text, entities)
+                .await;
+        }
+        ensure!(
+            !self.archived_threads.contains_key(&thread_id),
+            "thread {} is archived; use `/unarchive` first",
+            thread_id
+        );
+        if let Some(thread) = self.threads.get(&thread_id) {
+            thread.check_slow_mode()?;
+        }
+        let quote = if self
+            .user_handle
+            .settings
+            .read()
+            .expect("handler settings.read() failed")
+            .quote_replies
+        {
+            self.quoted_texts.get(&reply_message_id).cloned()
+        } else {
+            None
+        };
+        self.relay_text(&thread_id, text, quote, Some(message_id), entities)
+            .await?;
+        self.send_read_receipt(&thread_id, reply_message_id).await?;
+
+        self.message_id_to_thread_id
+            .insert(message_id, thread_id.clone());
+        self.event_service
+            .write(Event::ThreadMessageReceived(ThreadMessageReceivedEvent {
+                login: self.user_handle.user.login.clone(),
+                message_id,
+                thread_id: thread_id.clone(),
+                kind: Some(MessageKind::Sent),
+            }))
+            .wait_written()
+            .await?;
+        self.schedule_own_message_deletion(&thread_id, message_id);
+        Ok(())
+    }
+
+    /// Finds the thread of a message that is missing from `message_id_to_thread_id`
+    /// by looking for a thread id in its text, and remembers the mapping.
+    async fn recover_reply_thread_id(
+        &mut self,
+        reply_message_id: i32,
+        reply_text: Option<&str>,
+    ) -> Result<ThreadId> {
+        let mentioned = reply_text
+            .unwrap_or_default()
+            .split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '#' || c == '@'))
+            .filter(|token| token.len() > 1 && (token.starts_with('#') || token.starts_with('@')))
+            .map(normalize_thread_id)
+            .filter(|id| self.threads.contains_key(id) || self.archived_threads.contains_key(id))
+            .collect::<BTreeSet<_>>();
+        let thread_id = match mentioned.len() {
+            1 => mentioned.into_iter().next().unwrap(),
+            0 => bail!(
+                "message you are replying to does not belong to a thread, and it does not mention any of your threads either"
+            ),
+            _ => bail!(
+                "message you are replying to does not belong to a thread, and it mentions several of your threads: {}; use `/send [thread] [message]`",
+                mentioned.into_iter().collect::<Vec<_>>().join(", ")
+            ),
+        };
+
+        self.event_service
+            .write(Event::ThreadMessageReceived(ThreadMessageReceivedEvent {
+                login: self.user_handle.user.login.clone(),
+                message_id: reply_message_id,
+                thread_id: thread_id.clone(),
+                kind: Some(MessageKind::Service),
+            }))
+            .wait_written()
+            .await?;
+        self.message_id_to_thread_id
+            .insert(reply_message_id, thread_id.clone());
+        Ok(thread_id)
+    }
+
+    /// Lets the other side know that we have replied to their message, once per message.
+    async fn send_read_receipt(&mut self, thread_id: &str, message_id: i32) -> Result<()> {
+        let thread = match self.threads.get_mut(thread_id) {
+            Some(thread) if thread.receipts && !thread.receipts_sent.contains(&message_id) => {
+                thread
+            }
+            _ => return Ok(()),
+        };
+        thread
+            .other_handle
+            .send_action(Action::NotifySeen(thread.other_id.clone(), message_id))
+            .await
+            .context("failed to send read receipt")?;
+        thread.receipts_sent.insert(message_id);
+
+        self.event_service
+            .write(Event::ReceiptSent(ReceiptSentEvent {
+                login: self.user_handle.user.login.clone(),
+                thread_id: thread_id.to_string(),
+                message_id,
+            }))
+            .wait_written()
+            .await?;
+        Ok(())
+    }
+
+    /// Sends a text to the other side of a thread. If `source_message_id` is given, replies
+    /// to it with a delivery receipt once the peer's handler has shown the message.
+    async fn relay_text(
+        &mut self,
+        thread_id: &str,
+        text: String,
+        quote: Option<String>,
+        source_message_id: Option<i32>,
+        entities: Vec<MessageEntity>,
+    ) -> Result<()> {
+        let thread = self
+            .threads
+            .get_mut(thread_id)
+            .context("thread does not exist anymore")?;
+        thread.check_paused()?;
+        let keep_sent_texts = self
+            .user_handle
+            .settings
+            .read()
+            .expect("handler settings.read() failed")
+            .keep_sent_texts;
+        let sent_text = SentText::new(&text, keep_sent_texts);
+        let mb_message_id = match thread.send_text(text, quote, entities).await {
+            Ok(mb_message_id) => mb_message_id,
+            // Intermediate contexts come from the peer's handler; keep only the cause
+            // when the peer is anonymous.
+            Err(err) if thread.anon_mode != ThreadAnonimityMode::Me => {
+                bail!("failed to deliver to {}: {}", thread_id, err.root_cause())
+            }
+            Err(err) => return Err(err.context(format!("failed to deliver to {}", thread_id))),
+        };
+        thread.last_sent_at = Some(Instant::now());
+        thread.messages_sent += 1;
+        self.stats.messages_sent += 1;
+        self.last_used_thread_id = Some(thread_id.to_string());
+
+        if let Some(message_id) = mb_message_id {
+            thread.record_delivered(message_id, source_message_id, Some(sent_text.clone()));
+            let delete_at = thread
+                .secret
+                .map(|delay| now_timestamp() + delay.as_secs() as i64);
+            if let Some(delete_at) = delete_at {
+                self.scheduled_deletions.insert((
+                    delete_at,
+                    thread.other_handle.chat_id,
+                    message_id,
+                ));
+            }
+            self.event_service
+                .write(Event::OutgoingMessage(OutgoingMessageEvent {
+                    login: self.user_handle.user.login.clone(),
+                    thread_id: thread_id.to_string(),
+                    message_id,
+                    delete_at,
+                    source_message_id,
+                    text: Some(sent_text),
+                }))
+                .wait_written()
+                .await?;
+            // Marks the message as delivered without sending another one. A
+            // reaction relayed from the other side replaces it later.
+            if let Some(source_message_id) = source_message_id {
+                let emoji = Some(DELIVERED_REACTION.to_string());
+                if let Err(err) =
+                    reactions::set_reaction(&self.bot, self.chat_id, source_message_id, emoji).await
+                {
+                    debug!(
+                        "failed to mark message of @{} as delivered: {}",
+                        self.user_handle.user.login, err
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Schedules deletion of my own message if the thread is secret. Unlike delivered
+    /// messages, these are not persisted and are left in place after a restart.
+    fn schedule_own_message_deletion(&mut self, thread_id: &str, message_id: i32) {
+        let mb_secret = self.threads.get(thread_id).and_then(|th| th.secret);
+        if let Some(delay) = mb_secret {
+            let delete_at = now_timestamp() + delay.as_secs() as i64;
+            self.scheduled_deletions
+                .insert((delete_at, self.chat_id, message_id));
+        }
+    }
+
+    async fn handle_command_edit(&mut self, thread_id: ThreadId, new_text: String) -> Result<()> {
+        let thread = self
+            .threads
+            .get(&thread_id)
+            .with_context(|| format!("thread {} no longer exists", thread_id))?;
+        let message_id = *thread
+            .delivered_message_ids
+            .back()
+            .with_context(|| format!("there are no messages to edit in {}", thread_id))?;
+
+        thread
+            .edit_text(message_id, new_text)
+            .await
+            .context("failed to edit message")?;
+
+        self.send_to_self(self.format_text(Key::LastMessageEdited, &[&thread_id]))
+            .await?;
+        Ok(())
+    }
+
+    /// Propagates an edit of a message we have sent into a thread. The
+    /// delivered copy is edited in place if it is still known, otherwise the
+    /// new text is sent as a follow-up.
+    async fn handle_command_edited(&mut self, message_id: i32, new_text: String) -> Result<()> {
+        let thread = match self
+            .message_id_to_thread_id
+            .get(&message_id)
+            .and_then(|thread_id| self.threads.get(thread_id))
+        {
+            Some(thread) => thread,
+            None => {
+                debug!(
+                    "ignoring edit of message {} of @{} outside of threads",
+                    message_id, self.user_handle.user.login
+                );
+                return Ok(());
+            }
+        };
+        let delivered_message_id = thread
+            .delivered_sources
+            .iter()
+            .find(|&(_, &source_message_id)| source_message_id == message_id)
+            .map(|(&delivered_message_id, _)| delivered_message_id);
+        match delivered_message_id {
+            Some(delivered_message_id) => thread
+                .edit_text(delivered_message_id, new_text)
+                .await
+                .context("failed to edit message")?,
+            None => {
+                let thread_id = thread.id.clone();
+                self.relay_text(
+                    &thread_id,
+                    format!("(edited) {}", new_text),
+                    None,
+                    None,
+                    vec![],
+                )
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_command_delete(&mut self, thread_id: ThreadId) -> Result<()> {
+        let thread = self
+            .threads
+            .get_mut(&thread_id)
+            .with_context(|| format!("thread {} does not exist", thread_id))?;
+        let message_id = *thread
+            .delivered_message_ids
+            .back()
+            .with_context(|| format!("there are no messages to delete in {}", thread_id))?;
+
+        self.bot
+            .delete_message(thread.other_handle.chat_id, message_id)
+            .await
+            .context("failed to delete message")?;
+        thread.delivered_message_ids.pop_back();
+        let description = thread
+            .delivered_texts
+            .remove(&message_id)
+            .map(|text| format!(" ({})", text.describe()))
+            .unwrap_or_default();
+
+        self.event_service
+            .write(Event::OutgoingMessageDeleted(OutgoingMessageDeletedEvent {
+                login: self.user_handle.user.login.clone(),
+                thread_id: thread_id.clone(),
+                message_id,
+            }))
+            .wait_written()
+            .await?;
+
+        self.send_to_self(self.format_text(Key::LastMessageDeleted, &[&thread_id, &description]))
+            .await?;
+        Ok(())
+    }
+
+    /// Expands an unknown `#thread` argument that is a unique prefix of one of
+    /// the known thread ids. Returns the prefix and the full id if it did.
+    fn expand_thread_id(&self, command: &mut Command) -> Result<Option<(String, ThreadId)>> {
+        let is_banned_thread = matches!(command, Command::Unban { .. } | Command::BanInfo { .. });
+        let thread_id = match command.thread_id_mut() {
+            Some(thread_id) if thread_id.starts_with('#') => thread_id,
+            _ => return Ok(None),
+        };
+
+        let candidates = if is_banned_thread {
+            self.banlist
+                .values()
+                .map(|ban| ban.thread_id.clone())
+                .collect::<Vec<_>>()
+        } else {
+            self.threads
+                .keys()
+                .chain(self.archived_threads.keys())
+                .chain(self.pending_threads.keys())
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        let is_room = self
+            .rooms
+            .lock()
+            .expect("handler rooms.lock() failed")
+            .get(thread_id)
+            .is_some();
+        if is_room || candidates.contains(thread_id) {
+            return Ok(None);
+        }
+
+        match expand_prefix(thread_id, candidates)? {
+            Some(full_id) => {
+                let prefix = std::mem::replace(thread_id, full_id);
+                Ok(Some((prefix, thread_id.clone())))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Picks the thread for a command issued without one, which is only
+    /// possible when exactly one thread is eligible.
+    fn sole_thread_id(
+        &self,
+        command: &str,
+        is_eligible: impl Fn(&Thread) -> bool,
+    ) -> Result<ThreadId> {
+        let mut thread_ids = self
+            .threads
+            .values()
+            .filter(|th| is_eligible(th))
+            .map(|th| th.id.clone())
+            .collect::<Vec<_>>();
+        thread_ids.sort();
+        match thread_ids.len() {
+            0 => bail!("you have no threads to {}", command),
+            1 => Ok(thread_ids.remove(0)),
+            _ => bail!(
+                "you have several threads to {}: {}; use `/{} [thread]`",
+                command,
+                thread_ids.join(", "),
+                command
+            ),
+        }
+    }
+
+    async fn handle_command_close(&mut self, thread_id: ThreadId) -> Result<()> {
+        let thread = self
+            .threads
+            .get(&thread_id)
+            .with_context(|| format!("thread {} does not exist", thread_id))?;
+
+        ensure!(
+            matches!(
+                thread.anon_mode,
+                ThreadAnonimityMode::Both | ThreadAnonimityMode::Me
+            ),
+            "cannot close a semi-anonimous thread; use `/ban` instead"
+        );
+        let op = UndoOp::Close {
+            thread_id: thread_id.clone(),
+            anon_mode: thread.anon_mode,
+            other_thread_id: thread.other_id.clone(),
+            other_login: thread.other_handle.user.login.clone(),
+        };
+
+        self.close_thread(&thread_id).await?;
+        self.remember_undo(op);
+        Ok(())
+    }
+
+    async fn handle_command_note(
+        &mut self,
+        thread_id: ThreadId,
+        text: Option<String>,
+    ) -> Result<()> {
+        let thread = self
+            .threads
+            .get(&thread_id)
+            .or_else(|| self.archived_threads.get(&thread_id))
+            .with_context(|| format!("thread {} does not exist", thread_id))?;
+
+        let text = match text {
+            Some(text) => text,
+            None => {
+                let message = match &thread.note {
+                    Some(note) => self.format_text(Key::Note, &[&thread_id, &escape_markup(note)]),
+                    None => self.format_text(Key::NoNote, &[&thread_id]),
+                };
+                self.send_to_self(message).await?;
+                return Ok(());
+            }
+        };
+        let note = if text.is_empty() { None } else { Some(text) };
+
+        self.event_service
+            .write(Event::ThreadNoteChanged(ThreadNoteChangedEvent {
+                login: self.user_handle.user.login.clone(),
+                thread_id: thread_id.clone(),
+                text: note.clone(),
+            }))
+            .wait_written()
+            .await?;
+        let is_cleared = note.is_none();
+        self.threads
+            .get_mut(&thread_id)
+            .or_else(|| self.archived_threads.get_mut(&thread_id))
+            .unwrap()
+            .note = note;
+
+        if is_cleared {
+            self.send_to_self(self.format_text(Key::NoteCleared, &[&thread_id]))
+                .await?;
+        } else {
+            self.send_to_self(self.format_text(Key::NoteSaved, &[&thread_id]))
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_command_receipts(&mut self, thread_id: ThreadId, enabled: bool) -> Result<()> {
+        ensure!(
+            self.threads.contains_key(&thread_id),
+            "thread {} does not exist",
+            thread_id
+        );
+
+        self.event_service
+            .write(Event::ThreadReceiptsChanged(ThreadReceiptsChangedEvent {
+                login: self.user_handle.user.login.clone(),
+                thread_id: thread_id.clone(),
+                enabled,
+            }))
+            .wait_written()
+            .await?;
+        self.threads.get_mut(&thread_id).unwrap().receipts = enabled;
+
+        if enabled {
+            self.send_to_self(self.format_text(Key::ReceiptsEnabled, &[&thread_id]))
+                .await?;
+        } else {
+            self.send_to_self(self.format_text(Key::ReceiptsDisabled, &[&thread_id]))
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_command_previews(&mut self, thread_id: ThreadId, enabled: bool) -> Result<()> {
+        ensure!(
+            self.threads.contains_key(&thread_id),
+            "thread {} does not exist",
+            thread_id
+        );
+
+        self.event_service
+            .write(Event::ThreadPreviewsChanged(ThreadPreviewsChangedEvent {
+                login: self.user_handle.user.login.clone(),
+                thread_id: thread_id.clone(),
+                enabled,
+            }))
+            .wait_written()
+            .await?;
+        self.threads.get_mut(&thread_id).unwrap().link_previews = Some(enabled);
+
+        if enabled {
+            self.send_to_self(self.format_text(Key::LinkPreviewsShown, &[&thread_id]))
+                .await?;
+        } else {
+            self.send_to_self(self.format_text(Key::LinkPreviewsHidden, &[&thread_id]))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// The thread's own preference, or the user's setting if it has none.
+    fn link_previews_enabled(&self, thread_id: &str) -> bool {
+        self.threads
+            .get(thread_id)
+            .or_else(|| self.archived_threads.get(thread_id))
+            .and_then(|thread| thread.link_previews)
+            .unwrap_or_else(|| {
+                self.user_handle
+                    .settings
+                    .read()
+                    .expect("handler settings.read() failed")
+                    .link_previews
+            })
+    }
+
+    async fn handle_command_secret(&mut self, thread_id: ThreadId, seconds: u64) -> Result<()> {
+        ensure!(
+            self.threads.contains_key(&thread_id),
+            "thread {} does not exist",
+            thread_id
+        );
+
+        self.event_service
+            .write(Event::ThreadSecretChanged(ThreadSecretChangedEvent {
+                login: self.user_handle.user.login.clone(),
+                thread_id: thread_id.clone(),
+                seconds,
+            }))
+            .wait_written()
+            .await?;
+        self.threads.get_mut(&thread_id).unwrap().secret = seconds_to_interval(seconds);
+
+        if seconds == 0 {
+            self.send_to_self(self.format_text(Key::SecretDisabled, &[&thread_id]))
+                .await?;
+        } else {
+            self.send_to_self(self.format_text(
+                Key::SecretEnabled,
+                &[&thread_id, &format_duration(Duration::from_secs(seconds))],
+            ))
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_command_undo(&mut self) -> Result<()> {
+        let record = self.last_undo.take().context("there is nothing to undo")?;
+        ensure!(
+            record.recorded_at.elapsed() <= UNDO_WINDOW,
+            "the last operation is more than {} old and cannot be undone",
+            format_duration(UNDO_WINDOW)
+        );
+
+        match record.op {
+            UndoOp::Close {
+                thread_id,
+                anon_mode,
+                other_thread_id,
+                other_login,
+            } => {
+                self.create_thread(
+                    thread_id.clone(),
+                    other_thread_id.clone(),
+                    &other_login,
+                    anon_mode,
+                )
+                .await
+                .context("failed to reopen thread")?;
+                self.event_service
+                    .write(Event::ThreadStarted(ThreadStartedEvent {
+                        login: self.user_handle.user.login.clone(),
+                        other_login,
+                        my_thread_id: thread_id.clone(),
+                        other_thread_id,
+                        anon_mode,
+                        is_initiator: Some(false),
+                        created_at: Some(now_timestamp()),
+                    }))
+                    .wait_written()
+                    .await?;
+                self.threads[&thread_id]
+                    .other_handle
+                    .send_action(Action::ReopenThread(
+                        self.threads[&thread_id].other_id.clone(),
+                    ))
+                    .await?;
+                self.send_to_self(self.format_text(Key::ThreadReopened, &[&thread_id]))
+                    .await?;
+            }
+            UndoOp::Ban { thread_id } => {
+                self.handle_command_unban(thread_id.clone()).await?;
+                self.send_to_self(self.format_text(Key::BanLifted, &[&thread_id]))
+                    .await?;
+            }
+            UndoOp::Archive { thread_id } => {
+                self.handle_command_unarchive(thread_id).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remember_undo(&mut self, op: UndoOp) {
+        self.last_undo = Some(UndoRecord {
+            op,
+            recorded_at: Instant::now(),
+        });
+    }
+
+    async fn handle_command_expire(
+        &mut self,
+        thread_id: ThreadId,
+        duration: Option<Duration>,
+    ) -> Result<()> {
+        let thread = self
+            .threads
+            .get(&thread_id)
+            .with_context(|| format!("thread {} does not exist", thread_id))?;
+        ensure!(
+            matches!(
+                thread.anon_mode,
+                ThreadAnonimityMode::Both | ThreadAnonimityMode::Me
+            ),
+            "cannot set expiry on a semi-anonimous thread"
+        );
+
+        let expires_at = duration.map(|duration| now_timestamp() + duration.as_secs() as i64);
+        self.event_service
+            .write(Event::ThreadExpiryChanged(ThreadExpiryChangedEvent {
+                login: self.user_handle.user.login.clone(),
+                thread_id: thread_id.clone(),
+                expires_at,
+            }))
+            .wait_written()
+            .await?;
+        self.threads.get_mut(&thread_id).unwrap().expires_at = expires_at;
+
+        match duration {
+            Some(duration) => {
+                self.send_to_self(self.format_text(
+                    Key::ThreadExpirySet,
+                    &[&thread_id, &format_duration(duration)],
+                ))
+                .await?
+            }
+            None => {
+                self.send_to_self(self.format_text(Key::ThreadExpiryCleared, &[&thread_id]))
+                    .await?
+            }
+        };
+        Ok(())
+    }
+
+    async fn close_thread(&mut self, thread_id: &str) -> Result<()> {
+        let thread = self
+            .threads
+            .get(thread_id)
+            .or_else(|| self.archived_threads.get(thread_id))
+            .with_context(|| format!("thread {} does not exist", thread_id))?;
+        thread
+            .terminate()
+            .await
+            .context("failed to terminate peer thread")?;
+        let thread = match self.threads.remove(thread_id) {
+            Some(thread) => thread,
+            None => self.archived_threads.remove(thread_id).unwrap(),
+        };
+        self.forget_default_thread(thread_id);
+
+        self.event_service
+            .write(Event::ThreadTerminated(ThreadTerminatedEvent {
+                login: self.user_handle.user.login.clone(),
+                other_login: thread.other_handle.user.login.clone(),
+                my_thread_id: thread.id,
+                other_thread_id: thread.other_id,
+            }))
+            .wait_written()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn handle_command_close_all(&mut self) -> Result<()> {
+        ensure!(!self.threads.is_empty(), "you have no active threads");
+
+        let mut thread_ids = self.threads.keys().cloned().collect::<Vec<_>>();
+        thread_ids.sort();
+
+        let mut events = vec![];
+        let mut failures = vec![];
+        for thread_id in thread_ids {
+            if let Err(err) = self.threads[&thread_id].terminate().await {
+                failures.push(format!("{}: {:#}", thread_id, err));
+                continue;
+            }
+            let thread = self.threads.remove(&thread_id).unwrap();
+            self.forget_default_thread(&thread_id);
+            events.push(Event::ThreadTerminated(ThreadTerminatedEvent {
+                login: self.user_handle.user.login.clone(),
+                other_login: thread.other_handle.user.login.clone(),
+                my_thread_id: thread.id,
+                other_thread_id: thread.other_id,
+            }));
+        }
+
+        let closed = events.len();
+        if !events.is_empty() {
+            self.event_service
+                .write_batch(events)
+                .wait_written()
+                .await?;
+        }
+
+        if failures.is_empty() {
+            self.send_to_self(self.format_text(Key::ThreadsClosed, &[&closed]))
+                .await?;
+        } else {
+            self.send_to_self_long(self.format_text(
+                Key::ThreadsClosedWithFailures,
+                &[&closed, &failures.len(), &failures.join("\n* ")],
+            ))
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_command_accept(&mut self, thread_id: ThreadId) -> Result<()> {
+        let pending = self
+            .pending_threads
+            .remove(&thread_id)
+            .with_context(|| format!("there is no pending request for thread {}", thread_id))?;
+
+        self.event_service
+            .write(Event::ThreadStarted(ThreadStartedEvent {
+                login: self.user_handle.user.login.clone(),
+                other_login: pending.thread.other_handle.user.login.clone(),
+                my_thread_id: thread_id.clone(),
+                other_thread_id: pending.thread.other_id.clone(),
+                anon_mode: pending.thread.anon_mode,
+                is_initiator: Some(false),
+                created_at: Some(now_timestamp()),
+            }))
+            .wait_written()
+            .await?;
+        if pending.thread.anon_mode == ThreadAnonimityMode::Both {
+            self.remember_partner(pending.thread.other_handle.user.login.clone());
+        }
+        self.send_greeting(&pending.thread);
+        self.threads.insert(thread_id.clone(), pending.thread);
+
+        self.send_to_self(self.format_text(Key::ThreadAccepted, &[&thread_id]))
+            .await?;
+        for text in pending.messages {
+            self.receive_text(&thread_id, &text, None, vec![]).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_command_decline(&mut self, thread_id: ThreadId) -> Result<()> {
+        ensure!(
+            self.pending_threads.contains_key(&thread_id),
+            "there is no pending request for thread {}",
+            thread_id
+        );
+        self.reject_pending_thread(&thread_id).await?;
+        self.send_to_self(self.format_text(Key::ThreadDeclined, &[&thread_id]))
+            .await?;
+        Ok(())
+    }
+
+    async fn reject_pending_thread(&mut self, thread_id: &str) -> Result<()> {
+        let pending = self
+            .pending_threads
+            .remove(thread_id)
+            .context("pending thread is not found")?;
+        pending
+            .thread
+            .terminate()
+            .await
+            .context("failed to terminate peer thread")?;
+
+        self.event_service
+            .write(Event::ThreadTerminated(ThreadTerminatedEvent {
+                login: self.user_handle.user.login.clone(),
+                other_login: pending.thread.other_handle.user.login.clone(),
+                my_thread_id: pending.thread.id,
+                other_thread_id: pending.thread.other_id,
+            }))
+            .wait_written()
+            .await?;
+        Ok(())
+    }
+
+    async fn handle_command_ban(
+        &mut self,
+        thread_id: ThreadId,
+        duration: Option<Duration>,
+        reason: Option<String>,
+    ) -> Result<()> {
+        let thread = self
+            .threads
+            .get(&thread_id)
+            .with_context(|| format!("thread {} does not exist", thread_id))?;
+
+        ensure!(
+            thread.anon_mode == ThreadAnonimityMode::Them,
+            "cannot ban random or non-anonimous chat; use `/close` instead",
+        );
+
+        thread
+            .terminate()
+            .await
+            .context("failed to terminate peer thread")?;
+        let thread = self.threads.remove(&thread_id).unwrap();
+        self.forget_default_thread(&thread_id);
+
+        let banned_at = now_timestamp();
+        let expires_at = duration.map(|duration| banned_at + duration.as_secs() as i64);
+        self.event_service
+            .write(Event::UserBanned(UserBannedEvent {
+                login: self.user_handle.user.login.clone(),
+                banned_login: thread.other_handle.user.login.clone(),
+                banned_thread_id: thread_id.clone(),
+                reason: reason.clone(),
+                banned_at: Some(banned_at),
+                expires_at,
+            }))
+            .wait_written()
+            .await?;
+        self.banlist.insert(
+            thread.other_handle.user.login.clone(),
+            BanRecord {
+                thread_id: thread_id.clone(),
+                reason,
+                banned_at: Some(banned_at),
+                expires_at,
+            },
+        );
+        self.stats.bans_issued += 1;
+        self.remember_undo(UndoOp::Ban { thread_id });
+
+        Ok(())
+    }
+
+    async fn handle_command_unban(&mut self, thread_id: ThreadId) -> Result<()> {
+        let login = self
+            .banlist
+            .iter()
+            .find(|&(_, ban)| ban.thread_id == thread_id)
+            .with_context(|| format!("no {} in your ban list", thread_id))?
+            .0
+            .clone();
+
+        self.event_service
+            .write(Event::UserUnbanned(UserUnbannedEvent {
+                login: self.user_handle.user.login.clone(),
+                unbanned_login: login.clone(),
+            }))
+            .wait_written()
+            .await?;
+        self.banlist.remove(&login);
+
+        Ok(())
+    }
+
+    async fn handle_command_ban_info(&mut self, thread_id: ThreadId) -> Result<()> {
+        let ban = self
+            .banlist
+            .values()
+            .find(|ban| ban.thread_id == thread_id)
+            .with_context(|| format!("no {} in your ban list", thread_id))?;
+        let banned = match ban.banned_at {
+            Some(banned_at) => format!(
+                "{} ({})",
+                format_timestamp(banned_at),
+                format_age(banned_at)
+            ),
+            None => self.text(Key::Unknown).to_string(),
+        };
+        let reason = ban
+            .reason
+            .as_deref()
+            .map(escape_markup)
+            .unwrap_or_else(|| "not specified".to_string());
+        self.send_to_self(self.format_text(
+            Key::BanInfo,
+            &[
+                &thread_id,
+                &banned,
+                &format_ban_expiry(self.language(), ban),
+                &reason,
+            ],
+        ))
+        .await?;
+        Ok(())
+    }
+
+    async fn handle_command_pin(&mut self, thread_id: ThreadId, pinned: bool) -> Result<()> {
+        let thread = self
+            .threads
+            .get(&thread_id)
+            .with_context(|| format!("thread {} does not exist", thread_id))?;
+        ensure!(
+            thread.pinned != pinned,
+            "thread {} is already {}",
+            thread_id,
+            if pinned { "pinned" } else { "unpinned" },
+        );
+
+        let login = self.user_handle.user.login.clone();
+        let event = if pinned {
+            Event::ThreadPinned(ThreadPinnedEvent {
+                login,
+                thread_id: thread_id.clone(),
+            })
+        } else {
+            Event::ThreadUnpinned(ThreadUnpinnedEvent {
+                login,
+                thread_id: thread_id.clone(),
+            })
+        };
+        self.event_service.write(event).wait_written().await?;
+        self.threads.get_mut(&thread_id).unwrap().pinned = pinned;
+
+        let key = if pinned {
+            Key::ThreadPinned
+        } else {
+            Key::ThreadUnpinned
+        };
+        self.send_to_self(self.format_text(key, &[&thread_id]))
+            .await?;
+        Ok(())
+    }
+
+    async fn handle_command_priority(&mut self, thread_id: ThreadId) -> Result<()> {
+        let priority = !self
+            .threads
+            .get(&thread_id)
+            .with_context(|| format!("thread {} does not exist", thread_id))?
+            .priority;
+
+        self.event_service
+            .write(Event::ThreadPriorityChanged(ThreadPriorityChangedEvent {
+                login: self.user_handle.user.login.clone(),
+                thread_id: thread_id.clone(),
+                priority,
+            }))
+            .wait_written()
+            .await?;
+        self.threads.get_mut(&thread_id).unwrap().priority = priority;
+
+        if priority {
+            self.send_to_self(self.format_text(Key::ThreadPrioritized, &[&thread_id]))
+                .await?;
+        } else {
+            self.send_to_self(self.format_text(Key::ThreadDeprioritized, &[&thread_id]))
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_command_pause(&mut self, thread_id: ThreadId, paused: bool) -> Result<()> {
+        let thread = self
+            .threads
+            .get(&thread_id)
+            .with_context(|| format!("thread {} does not exist", thread_id))?;
+        if paused {
+            ensure!(
+                !thread.paused_by_me,
+                "thread {} is already paused",
+                thread_id
+            );
+        } else {
+            ensure!(
+                thread.paused_by_me || !thread.paused_by_other,
+                "thread {} has been paused by the other side; only they can resume it",
+                thread_id
+            );
+            ensure!(thread.paused_by_me, "thread {} is not paused", thread_id);
+        }
+
+        let action = if paused {
+            Action::PauseThread(thread.other_id.clone())
+        } else {
+            Action::ResumeThread(thread.other_id.clone())
+        };
+        thread
+            .other_handle
+            .send_action(action)
+            .await
+            .context("failed to update peer thread")?;
+
+        self.event_service
+            .write(Event::ThreadPauseChanged(ThreadPauseChangedEvent {
+                login: self.user_handle.user.login.clone(),
+                other_login: thread.other_handle.user.login.clone(),
+                my_thread_id: thread_id.clone(),
+                other_thread_id: thread.other_id.clone(),
+                paused,
+            }))
+            .wait_written()
+            .await?;
+        self.threads.get_mut(&thread_id).unwrap().paused_by_me = paused;
+
+        if paused {
+            self.send_to_self(self.format_text(Key::ThreadPaused, &[&thread_id, &thread_id]))
+                .await?;
+        } else {
+            self.send_to_self(self.format_text(Key::ThreadResumed, &[&thread_id]))
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_command_slow(&mut self, thread_id: ThreadId, seconds: u64) -> Result<()> {
+        ensure!(
+            self.threads.contains_key(&thread_id),
+            "thread {} does not exist",
+            thread_id
+        );
+
+        self.event_service
+            .write(Event::ThreadSlowModeChanged(ThreadSlowModeChangedEvent {
+                login: self.user_handle.user.login.clone(),
+                thread_id: thread_id.clone(),
+                seconds,
+            }))
+            .wait_written()
+            .await?;
+        self.threads.get_mut(&thread_id).unwrap().slow_mode = seconds_to_interval(seconds);
+
+        if seconds == 0 {
+            self.send_to_self(self.format_text(Key::SlowModeDisabled, &[&thread_id]))
+                .await?;
+        } else {
+            self.send_to_self(self.format_text(
+                Key::SlowModeSet,
+                &[&thread_id, &format_duration(Duration::from_secs(seconds))],
+            ))
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_command_archive(&mut self, thread_id: ThreadId) -> Result<()> {
+        ensure!(
+            self.threads.contains_key(&thread_id),
+            "thread {} does not exist",
+            thread_id
+        );
+
+        self.event_service
+            .write(Event::ThreadArchived(ThreadArchivedEvent {
+                login: self.user_handle.user.login.clone(),
+                thread_id: thread_id.clone(),
+            }))
+            .wait_written()
+            .await?;
+        let thread = self.threads.remove(&thread_id).unwrap();
+        self.archived_threads.insert(thread_id.clone(), thread);
+        self.archive_notices_sent.remove(&thread_id);
+        self.remember_undo(UndoOp::Archive {
+            thread_id: thread_id.clone(),
+        });
+
+        self.send_to_self(self.format_text(Key::ThreadArchived, &[&thread_id, &thread_id]))
+            .await?;
+        Ok(())
+    }
+
+    async fn handle_command_unarchive(&mut self, thread_id: ThreadId) -> Result<()> {
+        ensure!(
+            self.archived_threads.contains_key(&thread_id),
+            "thread {} is not archived",
+            thread_id
+        );
+
+        self.event_service
+            .write(Event::ThreadUnarchived(ThreadUnarchivedEvent {
+                login: self.user_handle.user.login.clone(),
+                thread_id: thread_id.clone(),
+            }))
+            .wait_written()
+            .await?;
+        let thread = self.archived_threads.remove(&thread_id).unwrap();
+        self.threads.insert(thread_id.clone(), thread);
+        self.archive_notices_sent.remove(&thread_id);
+
+        self.send_to_self(self.format_text(Key::ThreadRestored, &[&thread_id]))
+            .await?;
+        Ok(())
+    }
+
+    async fn handle_command_banlist(&mut self) -> Result<()> {
+        let mut bans = self.banlist.values().collect::<Vec<_>>();
+        bans.sort_by(|a, b| a.thread_id.cmp(&b.thread_id));
+        let banlist = bans
+            .into_iter()
+            .map(|ban| match ban.banned_at {
+                Some(banned_at) => format!(
+                    "{} (banned {}, {})",
+                    ban.thread_id,
+                    format_age(banned_at),
+                    format_ban_expiry(self.language(), ban)
+                ),
+                None => format!(
+                    "{} ({})",
+                    ban.thread_id,
+                    format_ban_expiry(self.language(), ban)
+                ),
+            })
+            .collect::<Vec<_>>();
+        let mut blocklist = self
+            .blocklist
+            .iter()
+            .map(|login| format!("@{}", login))
+            .collect::<Vec<_>>();
+        blocklist.sort();
+
+        if banlist.is_empty() && blocklist.is_empty() {
+            self.send_to_self(self.text(Key::NoBans)).await?;
+            return Ok(());
+        }
+
+        let mut sections = vec![];
+        if !banlist.is_empty() {
+            sections.push(self.format_text(Key::BannedThreads, &[&banlist.join("\n* ")]));
+        }
+        if !blocklist.is_empty() {
+            sections.push(self.format_text(Key::BlockedUsers, &[&blocklist.join("\n* ")]));
+        }
+        self.send_to_self_long(sections.join("\n\n")).await?;
+        Ok(())
+    }
+
+    async fn handle_command_block(&mut self, login: String) -> Result<()> {
+        ensure!(
+            login != self.user_handle.user.login,
+            "cannot block yourself"
+        );
+        ensure!(
+            !self.blocklist.contains(&login),
+            "@{} is already blocked",
+            login
+        );
+
+        self.event_service
+            .write(Event::UserBlocked(UserBlockedEvent {
+                login: self.user_handle.user.login.clone(),
+                blocked_login: login.clone(),
+            }))
+            .wait_written()
+            .await?;
+        self.blocklist.insert(login.clone());
+
+        self.send_to_self(self.format_text(Key::UserBlocked, &[&login]))
+            .await?;
+        Ok(())
+    }
+
+    async fn handle_command_unblock(&mut self, login: String) -> Result<()> {
+        ensure!(
+            self.blocklist.contains(&login),
+            "@{} is not in your block list",
+            login
+        );
+
+        self.event_service
+            .write(Event::UserUnblocked(UserUnblockedEvent {
+                login: self.user_handle.user.login.clone(),
+                unblocked_login: login.clone(),
+            }))
+            .wait_written()
+            .await?;
+        self.blocklist.remove(&login);
+
+        self.send_to_self(self.format_text(Key::UserUnblocked, &[&login]))
+            .await?;
+        Ok(())
+    }
+
+    async fn handle_command_broadcast(
+        &mut self,
+        audience: BroadcastAudience,
+        text: String,
+    ) -> Result<()> {
+        self.check_admin()?;
+        let mut skipped = 0;
+        let handles = {
+            let registry = self
+                .handle_registry
+                .read()
+                .expect("handle_registry.read() failed");
+            let mut handles = match &audience {
+                BroadcastAudience::All | BroadcastAudience::Active => {
+                    registry.values().cloned().collect::<Vec<_>>()
+                }
+                BroadcastAudience::Users(logins) => {
+                    let handles = logins
+                        .iter()
+                        .filter_map(|login| registry.get(login).cloned())
+                        .collect::<Vec<_>>();
+                    skipped += logins.len() - handles.len();
+                    handles
+                }
+            };
+            handles.retain(|handle| handle.user.login != self.user_handle.user.login);
+            if audience == BroadcastAudience::Active {
+                let count = handles.len();
+                handles.retain(|handle| !handle.is_stopped.load(Ordering::Relaxed));
+                skipped += count - handles.len();
+            }
+            handles
+        };
+        ensure!(
+            !handles.is_empty(),
+            "nobody matches the broadcast audience `{}`",
+            audience
+        );
+
+        self.send_to_self(self.format_text(Key::BroadcastStarted, &[&handles.len()]))
+            .await?;
+        let (mut delivered, mut failed) = (0, 0);
+        for handle in handles {
+            let result = handle.send_action(Action::Broadcast(text.clone())).await;
+            match result {
+                Ok(()) => delivered += 1,
+                Err(err) => {
+                    failed += 1;
+                    self.send_to_self(self.format_text(
+                        Key::BroadcastFailed,
+                        &[&handle.user.login, &format!("{:#}", err)],
+                    ))
+                    .await?;
+                }
+            }
+        }
+        self.send_to_self(
+            self.format_text(Key::BroadcastFinished, &[&delivered, &failed, &skipped]),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn handle_command_show_settings(&mut self) -> Result<()> {
+        let description = self
+            .user_handle
+            .settings
+            .read()
+            .expect("handler settings.read() failed")
+            .describe();
+        self.send_to_self(self.format_text(Key::Settings, &[&description]))
+            .await?;
+        Ok(())
+    }
+
+    async fn handle_command_settings(&mut self, key: String, value: String) -> Result<()> {
+        self.update_settings(|settings| settings.set(&key, &value))
+            .await?;
+        self.send_to_self(self.format_text(Key::SettingUpdated, &[&key]))
+            .await?;
+        Ok(())
+    }
+
+    async fn handle_command_show_policy(&mut self) -> Result<()> {
+        self.check_admin()?;
+        let policy = self
+            .media_policy
+            .read()
+            .expect("handler media_policy.read() failed")
+            .describe();
+        self.send_to_self(self.format_text(Key::MediaPolicy, &[&policy]))
+            .await?;
+        Ok(())
+    }
+
+    async fn handle_command_policy(&mut self, key: String, value: String) -> Result<()> {
+        self.check_admin()?;
+        let mut policy = self
+            .media_policy
+            .read()
+            .expect("handler media_policy.read() failed")
+            .clone();
+        policy.set(&key, &value)?;
+
+        self.event_service
+            .write(Event::MediaPolicyChanged(MediaPolicyChangedEvent {
+                login: self.user_handle.user.login.clone(),
+                policy: policy.clone(),
+            }))
+            .wait_written()
+            .await?;
+        *self
+            .media_policy
+            .write()
+            .expect("handler media_policy.write() failed") = policy;
+
+        self.send_to_self(self.format_text(Key::MediaPolicyUpdated, &[&key]))
+            .await?;
+        Ok(())
+    }
+
+    fn check_admin(&self) -> Result<()> {
+        ensure!(
+            self.user_handle.user.login == "sergio_4min",
+            "you are not admin"
+        );
+        Ok(())
+    }
+
+    async fn handle_command_language(&mut self, code: String) -> Result<()> {
+        let language = Language::from_code(&code)?;
+        self.update_settings(|settings| {
+            settings.language = language;
+            Ok(())
+        })
+        .await?;
+        self.send_to_self(self.text(Key::LanguageChanged)).await?;
+        Ok(())
+    }
+
+    async fn handle_command_topic(&mut self, topic: Option<String>) -> Result<()> {
+        let topic = match topic {
+            Some(topic) => topic,
+            None => {
+                let message = match &*self
+                    .user_handle
+                    .topic
+                    .read()
+                    .expect("handler topic.read() failed")
+                {
+                    Some(topic) => self.format_text(Key::CurrentTopic, &[&escape_markup(topic)]),
+                    None => self.text(Key::NoTopic).to_string(),
+                };
+                self.send_to_self(message).await?;
+                return Ok(());
+            }
+        };
+        let topic = if topic.is_empty() { None } else { Some(topic) };
+
+        self.event_service
+            .write(Event::TopicChanged(TopicChangedEvent {
+                login: self.user_handle.user.login.clone(),
+                topic: topic.clone(),
+            }))
+            .wait_written()
+            .await?;
+        let message = match &topic {
+            Some(topic) => self.format_text(
+                Key::TopicSet,
+                &[&escape_markup(topic), &escape_markup(topic)],
+            ),
+            None => self.text(Key::TopicCleared).to_string(),
+        };
+        *self
+            .user_handle
+            .topic
+            .write()
+            .expect("handler topic.write() failed") = topic;
+
+        self.send_to_self(message).await?;
+        Ok(())
+    }
+
+    async fn handle_command_interests(&mut self, tags: Option<Vec<String>>) -> Result<()> {
+        let tags = match tags {
+            Some(tags) => tags,
+            None => {
+                let tags = self
+                    .user_handle
+                    .interests
+                    .read()
+                    .expect("handler interests.read() failed")
+                    .clone();
+                if tags.is_empty() {
+                    self.send_to_self(self.text(Key::NoInterests)).await?;
+                } else {
+                    self.send_to_self(self.format_text(Key::Interests, &[&tags.join(" #")]))
+                        .await?;
+                }
+                return Ok(());
+            }
+        };
+        ensure!(
+            tags.len() <= MAX_INTERESTS,
+            "you can have at most {} interests",
+            MAX_INTERESTS
+        );
+
+        self.event_service
+            .write(Event::UserInterestsChanged(UserInterestsChangedEvent {
+                login: self.user_handle.user.login.clone(),
+                tags: tags.clone(),
+            }))
+            .wait_written()
+            .await?;
+        let message = if tags.is_empty() {
+            self.text(Key::InterestsCleared).to_string()
+        } else {
+            self.format_text(Key::InterestsUpdated, &[&tags.join(" #")])
+        };
+        *self
+            .user_handle
+            .interests
+            .write()
+            .expect("handler interests.write() failed") = tags;
+
+        self.send_to_self(message).await?;
+        Ok(())
+    }
+
+    async fn handle_command_limit(&mut self, max_incoming: Option<usize>) -> Result<()> {
+        self.event_service
+            .write(Event::IncomingLimitChanged(IncomingLimitChangedEvent {
+                login: self.user_handle.user.login.clone(),
+                max_incoming,
+            }))
+            .wait_written()
+            .await?;
+        self.max_incoming = max_incoming;
+
+        match max_incoming {
+            Some(max) => {
+                self.send_to_self(self.format_text(Key::MaxIncomingSet, &[&max]))
+                    .await?;
+            }
+            None => {
+                self.send_to_self(self.text(Key::MaxIncomingCleared))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_command_status(&mut self) -> Result<()> {
+        let incoming = match self.max_incoming {
+            Some(max) => {
+                self.format_text(Key::IncomingOfMax, &[&self.incoming_thread_count(), &max])
+            }
+            None => self.format_text(Key::IncomingUnlimited, &[&self.incoming_thread_count()]),
+        };
+        let dnd = match self.dnd {
+            Some(window) => window.describe(),
+            None => self.text(Key::Off).to_string(),
+        };
+        let default_thread = self
+            .default_thread_id
+            .clone()
+            .unwrap_or_else(|| "last used thread".to_string());
+        let is_queued = self
+            .random_queue
+            .lock()
+            .expect("handler random_queue.lock() failed")
+            .get(&self.user_handle.user.login)
+            .is_some();
+
+        self.send_to_self(self.format_text(
+            Key::Status,
+            &[&incoming, &dnd, &default_thread, &self.flag(is_queued)],
+        ))
+        .await?;
+        Ok(())
+    }
+
+    async fn handle_command_dnd(&mut self, window: Option<DndWindow>) -> Result<()> {
+        self.event_service
+            .write(Event::DndChanged(DndChangedEvent {
+                login: self.user_handle.user.login.clone(),
+                window,
+            }))
+            .wait_written()
+            .await?;
+        self.dnd = window;
+
+        match window {
+            Some(window) => {
+                self.send_to_self(self.format_text(Key::DndSet, &[&window.describe()]))
+                    .await?;
+            }
+            None => {
+                self.send_to_self(self.text(Key::DndDisabled)).await?;
+                if !self.deferred_items.is_empty() {
+                    self.flush_deferred_items().await;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_command_greeting(&mut self, text: Option<String>) -> Result<()> {
+        self.event_service
+            .write(Event::GreetingChanged(GreetingChangedEvent {
+                login: self.user_handle.user.login.clone(),
+                text: text.clone(),
+            }))
+            .wait_written()
+            .await?;
+        self.greeting = text;
+
+        if self.greeting.is_some() {
+            self.send_to_self(self.text(Key::GreetingSet)).await?;
+        } else {
+            self.send_to_self(self.text(Key::GreetingCleared)).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_command_export(&mut self) -> Result<()> {
+        let storage = self.storage.clone().context("export is not available")?;
+        let login = self.user_handle.user.login.clone();
+        let events = tokio::task::spawn_blocking(move || read_exported_events(&storage, &login))
+            .await
+            .context("failed to read event log")??;
+
+        let threads = self
+            .threads
+            .values()
+            .map(|th| (th, false))
+            .chain(self.archived_threads.values().map(|th| (th, true)))
+            .map(|(th, is_archived)| {
+                let other = match th.anon_mode {
+                    ThreadAnonimityMode::Me => Some(th.other_handle.user.login.clone()),
+                    ThreadAnonimityMode::Them | ThreadAnonimityMode::Both => None,
+                };
+                json!({
+                    "id": th.id,
+                    "anon_mode": th.anon_mode,
+                    "other": other,
+                    "archived": is_archived,
+                    "pinned": th.pinned,
+                    "priority": th.priority,
+                    "created_at": th.created_at,
+                    "messages_sent": th.messages_sent,
+                    "messages_received": th.messages_received,
+                    "slow_mode_secs": th.slow_mode.map(|d| d.as_secs()),
+                    "secret_secs": th.secret.map(|d| d.as_secs()),
+                    "expires_at": th.expires_at,
+                    "note": th.note,
+                    "labels": th.labels,
+                })
+            })
+            .collect::<Vec<_>>();
+        let settings = self
+            .user_handle
+            .settings
+            .read()
+            .expect("handler settings.read() failed")
+            .clone();
+        let interests = self
+            .user_handle
+            .interests
+            .read()
+            .expect("handler interests.read() failed")
+            .clone();
+        let export = json!({
+            "user": *self.user_handle.user,
+            "settings": settings,
+            "interests": interests,
+            "topic": *self.user_handle.topic.read().expect("handler topic.read() failed"),
+            "threads": threads,
+            "banned_threads": self
+                .banlist
+                .values()
+                .map(|ban| json!({
+                    "thread_id": ban.thread_id,
+                    "reason": ban.reason,
+                    "banned_at": ban.banned_at,
+                    "expires_at": ban.expires_at,
+                }))
+                .collect::<Vec<_>>(),
+            "blocked_users": self.blocklist,
+            "message_ids": self.message_id_to_thread_id,
+            "default_thread": self.default_thread_id,
+            "greeting": self.greeting,
+            "stats": {
+                "threads_started": self.stats.threads_started,
+                "messages_sent": self.stats.messages_sent,
+                "messages_received": self.stats.messages_received,
+                "bans_issued": self.stats.bans_issued,
+            },
+            "events": events,
+        });
+        let data = serde_json::to_vec_pretty(&export).context("failed to serialize export")?;
+
+        self.bot
+            .send_document(
+                self.chat_id,
+                InputFile::memory(format!("{}.json", self.user_handle.user.login), data),
+            )
+            .await
+            .context("failed to send export")?;
+        Ok(())
+    }
+
+    async fn handle_command_whoami(
+        &mut self,
+        first_name: String,
+        last_name: Option<String>,
+    ) -> Result<()> {
+        let user = &self.user_handle.user;
+        let shown_last_name = user
+            .last_name
+            .as_deref()
+            .map(escape_markup)
+            .unwrap_or_else(|| self.text(Key::Missing).to_string());
+        let connected_at = self
+            .user_handle
+            .connected_at
+            .map(format_timestamp)
+            .unwrap_or_else(|| self.text(Key::Unknown).to_string());
+        let mut message = self.format_text(
+            Key::Profile,
+            &[
+                &user.login,
+                &escape_markup(&user.first_name),
+                &shown_last_name,
+                &self.chat_id,
+                &connected_at,
+                &self.flag(self.user_handle.is_listed()),
+            ],
+        );
+        if user.first_name != first_name || user.last_name != last_name {
+            let current = match &last_name {
+                Some(last_name) => format!("{} {}", first_name, last_name),
+                None => first_name,
+            };
+            message
+                .push_str(&self.format_text(Key::ProfileNameChanged, &[&escape_markup(&current)]));
+        }
+        self.send_to_self(message).await?;
+        Ok(())
+    }
+
+    async fn handle_command_refresh_profile(
+        &mut self,
+        first_name: String,
+        last_name: Option<String>,
+    ) -> Result<()> {
+        let user = &self.user_handle.user;
+        ensure!(
+            user.first_name != first_name || user.last_name != last_name,
+            "your profile is already up to date"
+        );
+        let user = User {
+            login: user.login.clone(),
+            first_name,
+            last_name,
+        };
+
+        self.event_service
+            .write(Event::UserProfileUpdated(UserProfileUpdatedEvent {
+                login: user.login.clone(),
+                first_name: user.first_name.clone(),
+                last_name: user.last_name.clone(),
+            }))
+            .wait_written()
+            .await?;
+        self.user_handle.user = Arc::new(user);
+
+        self.send_to_self(self.text(Key::ProfileUpdated)).await?;
+        Ok(())
+    }
+
+    async fn handle_command_forget(&mut self, confirmed: bool) -> Result<()> {
+        if !confirmed {
+            self.send_to_self(self.text(Key::ForgetWarning)).await?;
+            return Ok(());
+        }
+
+        let login = self.user_handle.user.login.clone();
+        let mut threads = self
+            .threads
+            .drain()
+            .chain(self.archived_threads.drain())
+            .map(|(_, thread)| thread)
+            .chain(self.pending_threads.drain().map(|(_, p)| p.thread))
+            .collect::<Vec<_>>();
+        threads.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let mut events = vec![];
+        for thread in threads {
+            if let Err(err) = thread.terminate().await {
+                warn!(
+                    "failed to notify @{} about thread {} of forgotten @{}: {:#}",
+                    thread.other_handle.user.login, thread.other_id, login, err
+                );
+            }
+            events.push(Event::ThreadTerminated(ThreadTerminatedEvent {
+                login: login.clone(),
+                other_login: thread.other_handle.user.login.clone(),
+                my_thread_id: thread.id,
+                other_thread_id: thread.other_id,
+            }));
+        }
+        self.random_queue
+            .lock()
+            .expect("handler random_queue.lock() failed")
+            .remove(&login);
+        {
+            let mut rooms = self.rooms.lock().expect("handler rooms.lock() failed");
+            for room in rooms.rooms_of(&login) {
+                rooms.leave(&room, &login);
+                events.push(Event::RoomLeft(RoomLeftEvent {
+                    login: login.clone(),
+                    room,
+                }));
+            }
+        }
+        events.push(Event::UserForgotten(UserForgottenEvent {
+            login: login.clone(),
+        }));
+
+        self.event_service
+            .write_batch(events)
+            .wait_written()
+            .await?;
+        self.user_handle.is_stopped.store(true, Ordering::Relaxed);
+        self.is_forgotten = true;
+
+        self.send_to_self(self.text(Key::Forgotten)).await?;
+        Ok(())
+    }
+
+    async fn handle_command_stats(&mut self) -> Result<()> {
+        self.send_to_self(self.format_text(
+            Key::Stats,
+            &[
+                &self.stats.threads_started,
+                &self.stats.messages_sent,
+                &self.stats.messages_received,
+                &self.stats.bans_issued,
+            ],
+        ))
+        .await?;
+        Ok(())
+    }
+
+    async fn handle_command_about(&mut self) -> Result<()> {
+        let user_count = self
+            .handle_registry
+            .read()
+            .expect("handler handle_registry.read() failed")
+            .len();
+        self.send_to_self(self.format_text(
+            Key::BotStatus,
+            &[
+                &env!("CARGO_PKG_VERSION"),
+                &format_duration(self.started_at.elapsed()),
+                &user_count,
+                &self.event_service.events_written(),
+            ],
+        ))
+        .await?;
+        Ok(())
+    }
+
+    async fn update_settings(
+        &mut self,
+        update: impl FnOnce(&mut UserSettings) -> Result<()>,
+    ) -> Result<()> {
+        let mut settings = self
+            .user_handle
+            .settings
+            .read()
+            .expect("handler settings.read() failed")
+            .clone();
+        update(&mut settings)?;
+
+        self.event_service
+            .write(Event::UserSettingsChanged(UserSettingsChangedEvent {
+                login: self.user_handle.user.login.clone(),
+                settings: settings.clone(),
+            }))
+            .wait_written()
+            .await?;
+        *self
+            .user_handle
+            .settings
+            .write()
+            .expect("handler settings.write() failed") = settings;
+        Ok(())
+    }
+
+    async fn create_thread(
+        &mut self,
+        my_thread_id: ThreadId,
+        other_thread_id: ThreadId,
+        other_login: &str,
+        anon_mode: ThreadAnonimityMode,
+    ) -> Result<()> {
+        let other_handle = self
+            .handle_registry
+            .read()
+            .expect("handler handle_registry.read() failed")
+            .get(other_login)
+            .context("user has not started this bot")?
+            .clone();
+
+        let other_anon_mode = match anon_mode {
+            ThreadAnonimityMode::Me => ThreadAnonimityMode::Them,
+            ThreadAnonimityMode::Them => ThreadAnonimityMode::Me,
+            ThreadAnonimityMode::Both => ThreadAnonimityMode::Both,
+        };
+        let my_thread = Thread::new(
+            my_thread_id.clone(),
+            anon_mode,
+            other_thread_id.clone(),
+            other_handle.clone(),
+        );
+        let other_thread = Thread::new(
+            other_thread_id,
+            other_anon_mode,
+            my_thread_id.clone(),
+            self.user_handle.clone(),
+        );
+
+        let (result_sender, result_receiver) = oneshot::channel();
+        let start_request = ActionRequest {
+            action: Action::StartAnonymousThread(Box::new(other_thread)),
+            result_sender,
+        };
+        other_handle
+            .channel
+            .send(start_request)
+            .await
+            .unwrap_or_else(|err| {
+                panic!(
+                    "failed to send thread start request to @{} handler: {}",
+                    other_login, err
+                )
+            });
+        result_receiver.await.unwrap_or_else(|err| {
+            panic!(
+                "failed to get thread start result of @{} handler: {}",
+                other_login, err
+            )
+        })?;
+
+        self.threads.insert(my_thread_id, my_thread);
+        Ok(())
+    }
+
+    fn random_thread_id() -> ThreadId {
+        format!("#{}_{}", random_adjective(), random_noun())
+    }
+
+    async fn handle_action(&mut self, action: Action) -> Result<()> {
+        ensure!(
+            !self.user_handle.is_stopped.load(Ordering::Relaxed),
+            "user has stopped the bot"
+        );
+        match action {
+            Action::StartAnonymousThread(thread) => {
+                ensure!(
+                    !self.threads.contains_key(&thread.id)
+                        && !self.archived_threads.contains_key(&thread.id),
+                    "thread id {} is already used",
+                    thread.id,
+                );
+
+                ensure!(
+                    !self.banlist.contains_key(&thread.other_handle.user.login)
+                        || thread.anon_mode != ThreadAnonimityMode::Them,
+                    "you are banned by this user",
+                );
+                ensure!(
+                    !self.blocklist.contains(&thread.other_handle.user.login),
                     "you are banned by this user",
                 );
 
-                self.event_service
-                    .write(Event::ThreadStarted(ThreadStartedEvent {
-                        login: self.user_handle.user.login.clone(),
-                        other_login: thread.other_handle.user.login.clone(),
-                        my_thread_id: thread.id.clone(),
-                        other_thread_id: thread.other_id.clone(),
-                        anon_mode: thread.anon_mode,
-                    }))
-                    .wait_written()
+                ensure!(
+                    !self.pending_threads.contains_key(&thread.id),
+                    "thread id {} is already used",
+                    thread.id,
+                );
+                if thread.anon_mode == ThreadAnonimityMode::Them {
+                    ensure!(
+                        self.max_incoming
+                            .is_none_or(|max| self.incoming_thread_count() < max),
+                        "this user is not accepting new anonymous threads right now",
+                    );
+                }
+
+                let approve_threads = self
+                    .user_handle
+                    .settings
+                    .read()
+                    .expect("handler settings.read() failed")
+                    .approve_threads;
+                if approve_threads {
+                    let expires_at = now_timestamp() + PENDING_THREAD_TTL_SECS;
+                    self.event_service
+                        .write(Event::ThreadRequested(ThreadRequestedEvent {
+                            login: self.user_handle.user.login.clone(),
+                            other_login: thread.other_handle.user.login.clone(),
+                            my_thread_id: thread.id.clone(),
+                            other_thread_id: thread.other_id.clone(),
+                            anon_mode: thread.anon_mode,
+                            expires_at,
+                        }))
+                        .wait_written()
+                        .await?;
+                    let thread_id = thread.id.clone();
+                    self.pending_threads.insert(
+                        thread_id.clone(),
+                        PendingThread {
+                            thread: *thread,
+                            messages: vec![],
+                            expires_at,
+                        },
+                    );
+                    self.notify_or_defer(self.format_text(Key::ThreadRequested, &[&thread_id]))
+                        .await?;
+                    return Ok(());
+                }
+
+                self.event_service
+                    .write(Event::ThreadStarted(ThreadStartedEvent {
+                        login: self.user_handle.user.login.clone(),
+                        other_login: thread.other_handle.user.login.clone(),
+                        my_thread_id: thread.id.clone(),
+                        other_thread_id: thread.other_id.clone(),
+                        anon_mode: thread.anon_mode,
+                        is_initiator: Some(false),
+                        created_at: Some(now_timestamp()),
+                    }))
+                    .wait_written()
+                    .await?;
+                if thread.anon_mode == ThreadAnonimityMode::Both {
+                    self.remember_partner(thread.other_handle.user.login.clone());
+                }
+                self.send_greeting(&thread);
+                self.threads.insert(thread.id.clone(), *thread);
+            }
+            Action::SendText(thread_id, text, quote, entities, delivered_sender) => {
+                if let Some(pending) = self.pending_threads.get_mut(&thread_id) {
+                    self.event_service
+                        .write(Event::ThreadRequestMessage(ThreadRequestMessageEvent {
+                            login: self.user_handle.user.login.clone(),
+                            thread_id,
+                            text: text.clone(),
+                        }))
+                        .wait_written()
+                        .await?;
+                    pending.messages.push(text);
+                    return Ok(());
+                }
+                if self.archived_threads.contains_key(&thread_id) {
+                    if self.archive_notices_sent.insert(thread_id) {
+                        bail!("the other side has archived this thread; your messages will not be delivered");
+                    }
+                    return Ok(());
+                }
+                let mut priority = false;
+                if let Some(thread) = self.threads.get(&thread_id) {
+                    ensure!(
+                        !thread.paused_by_me,
+                        "the other side has paused this thread"
+                    );
+                    priority = thread.priority;
+                }
+                if !priority && self.is_dnd_active() {
+                    self.defer(DeferredItem::Message(thread_id, text, quote, entities));
+                    return Ok(());
+                }
+                let message = self
+                    .receive_text(&thread_id, &text, quote.as_deref(), entities)
+                    .await?;
+                delivered_sender.send(message.id).ok();
+            }
+            Action::SendPhoto {
+                thread_id,
+                file_id,
+                caption,
+                spoiler,
+                scrub,
+                delivered_sender,
+            } => {
+                self.accept_media(
+                    thread_id,
+                    MediaKind::Photo { spoiler, scrub },
+                    file_id,
+                    caption,
+                    delivered_sender,
+                )
+                .await?;
+            }
+            Action::SendSticker(thread_id, file_id, delivered_sender) => {
+                self.accept_media(
+                    thread_id,
+                    MediaKind::Sticker,
+                    file_id,
+                    None,
+                    delivered_sender,
+                )
+                .await?;
+            }
+            Action::SendAnimation(thread_id, file_id, caption, delivered_sender) => {
+                self.accept_media(
+                    thread_id,
+                    MediaKind::Animation,
+                    file_id,
+                    caption,
+                    delivered_sender,
+                )
+                .await?;
+            }
+            Action::SendVoice(thread_id, file_id, caption, duration, delivered_sender) => {
+                self.accept_media(
+                    thread_id,
+                    MediaKind::Voice { duration },
+                    file_id,
+                    caption,
+                    delivered_sender,
+                )
+                .await?;
+            }
+            Action::SendMediaGroup(thread_id, items, delivered_sender) => {
+                self.accept_media_group(thread_id, items, delivered_sender)
+                    .await?;
+            }
+            Action::SendVideo {
+                thread_id,
+                file_id,
+                caption,
+                duration,
+                spoiler,
+                delivered_sender,
+            } => {
+                self.accept_media(
+                    thread_id,
+                    MediaKind::Video { duration, spoiler },
+                    file_id,
+                    caption,
+                    delivered_sender,
+                )
+                .await?;
+            }
+            Action::SendVideoNote(thread_id, file_id, duration, delivered_sender) => {
+                self.accept_media(
+                    thread_id,
+                    MediaKind::VideoNote { duration },
+                    file_id,
+                    None,
+                    delivered_sender,
+                )
+                .await?;
+            }
+            Action::SendAudio(thread_id, file_id, caption, duration, delivered_sender) => {
+                self.accept_media(
+                    thread_id,
+                    MediaKind::Audio {
+                        duration,
+                        mime_type: None,
+                    },
+                    file_id,
+                    caption,
+                    delivered_sender,
+                )
+                .await?;
+            }
+            Action::SendDocument(
+                thread_id,
+                file_id,
+                file_name,
+                mime_type,
+                caption,
+                delivered_sender,
+            ) => {
+                self.accept_media(
+                    thread_id,
+                    MediaKind::Document {
+                        file_name,
+                        mime_type,
+                    },
+                    file_id,
+                    caption,
+                    delivered_sender,
+                )
+                .await?;
+            }
+            Action::SendContact(
+                thread_id,
+                phone_number,
+                first_name,
+                last_name,
+                delivered_sender,
+            ) => {
+                self.accept_media(
+                    thread_id,
+                    MediaKind::Contact {
+                        phone_number,
+                        first_name,
+                        last_name,
+                    },
+                    String::new(),
+                    None,
+                    delivered_sender,
+                )
+                .await?;
+            }
+            Action::SendLocation(
+                thread_id,
+                latitude,
+                longitude,
+                title,
+                address,
+                delivered_sender,
+            ) => {
+                self.accept_media(
+                    thread_id,
+                    MediaKind::Location {
+                        latitude,
+                        longitude,
+                        title,
+                        address,
+                    },
+                    String::new(),
+                    None,
+                    delivered_sender,
+                )
+                .await?;
+            }
+            Action::EditText(thread_id, message_id, text) => {
+                let formatted_text = self.format_incoming_text(&thread_id, &text, None)?;
+                self.bot
+                    .edit_message_text(self.chat_id, message_id, markup_to_html(&formatted_text))
+                    .parse_mode(ParseMode::Html)
+                    .await
+                    .context("failed to edit message")?;
+            }
+            Action::TerminateThread(thread_id) => {
+                if self.threads.remove(&thread_id).is_none()
+                    && self.pending_threads.remove(&thread_id).is_none()
+                {
+                    // The thread may already be gone if both sides closed it
+                    // at the same time.
+                    if self.archived_threads.remove(&thread_id).is_none() {
+                        warn!(
+                            "thread {} of @{} closed by the other side is not found",
+                            thread_id, self.user_handle.user.login
+                        );
+                        return Ok(());
+                    }
+                    self.archive_notices_sent.remove(&thread_id);
+                }
+                self.forget_default_thread(&thread_id);
+                self.send_to_self(self.format_text(Key::ThreadClosedByOther, &[&thread_id]))
+                    .await?;
+            }
+            Action::ReopenThread(thread_id) => {
+                self.send_to_self(self.format_text(Key::ThreadReopenedByOther, &[&thread_id]))
+                    .await?;
+            }
+            Action::SendPoll(thread_id, question, options) => {
+                let thread = self
+                    .threads
+                    .get(&thread_id)
+                    .with_context(|| format!("thread {} no longer exists", thread_id))?;
+                ensure!(
+                    !thread.paused_by_me,
+                    "the other side has paused this thread"
+                );
+                let attribution = match thread.anon_mode {
+                    ThreadAnonimityMode::Me => self.format_text(Key::PollFrom, &[&thread_id]),
+                    ThreadAnonimityMode::Them => {
+                        self.format_text(Key::PollFromAnonymous, &[&thread_id])
+                    }
+                    ThreadAnonimityMode::Both => {
+                        self.format_text(Key::PollFromRandomChat, &[&thread_id])
+                    }
+                };
+                self.send_to_self(attribution).await?;
+                let message = self
+                    .bot
+                    .send_poll(
+                        self.chat_id,
+                        question.clone(),
+                        options.clone(),
+                        PollType::Regular,
+                    )
+                    .is_anonymous(false)
+                    .await
+                    .context("failed to send poll")?;
+                let poll_id = message
+                    .poll()
+                    .context("sent message has no poll")?
+                    .id
+                    .clone();
+
+                self.event_service
+                    .write(Event::PollReceived(PollReceivedEvent {
+                        login: self.user_handle.user.login.clone(),
+                        thread_id: thread_id.clone(),
+                        poll_id: poll_id.clone(),
+                        question: question.clone(),
+                        options: options.clone(),
+                    }))
+                    .wait_written()
+                    .await?;
+                self.received_polls.insert(
+                    poll_id,
+                    ReceivedPoll {
+                        thread_id,
+                        question,
+                        options,
+                    },
+                );
+            }
+            Action::SendDice(thread_id, emoji, value_sender) => {
+                let thread = self
+                    .threads
+                    .get(&thread_id)
+                    .with_context(|| format!("thread {} no longer exists", thread_id))?;
+                ensure!(
+                    !thread.paused_by_me,
+                    "the other side has paused this thread"
+                );
+                let attribution = match thread.anon_mode {
+                    ThreadAnonimityMode::Me => self.format_text(Key::DiceFrom, &[&thread_id]),
+                    ThreadAnonimityMode::Them => {
+                        self.format_text(Key::DiceFromAnonymous, &[&thread_id])
+                    }
+                    ThreadAnonimityMode::Both => {
+                        self.format_text(Key::DiceFromRandomChat, &[&thread_id])
+                    }
+                };
+                self.send_to_self(attribution).await?;
+                let message = self
+                    .bot
+                    .send_dice(self.chat_id)
+                    .emoji(emoji)
+                    .await
+                    .context("failed to send dice")?;
+                let value = message.dice().context("sent message has no dice")?.value;
+                value_sender.send(value).ok();
+            }
+            Action::RoomMessage(room, alias, text) => {
+                self.notify_or_defer(self.format_text(
+                    Key::MessageFromRoom,
+                    &[&alias, &room, &escape_markup(&text)],
+                ))
+                .await?;
+            }
+            Action::PauseThread(thread_id) | Action::ResumeThread(thread_id)
+                if !self.threads.contains_key(&thread_id)
+                    && !self.archived_threads.contains_key(&thread_id) =>
+            {
+                bail!("thread {} no longer exists", thread_id);
+            }
+            Action::PauseThread(thread_id) => {
+                self.threads
+                    .get_mut(&thread_id)
+                    .or_else(|| self.archived_threads.get_mut(&thread_id))
+                    .unwrap()
+                    .paused_by_other = true;
+                self.notify_or_defer(self.format_text(Key::ThreadPausedByOther, &[&thread_id]))
+                    .await?;
+            }
+            Action::ResumeThread(thread_id) => {
+                self.threads
+                    .get_mut(&thread_id)
+                    .or_else(|| self.archived_threads.get_mut(&thread_id))
+                    .unwrap()
+                    .paused_by_other = false;
+                self.notify_or_defer(self.format_text(Key::ThreadResumedByOther, &[&thread_id]))
+                    .await?;
+            }
+            Action::NotifySeen(thread_id, message_id) => {
+                let source_message_id = match self.threads.get(&thread_id) {
+                    Some(thread)
+                        if thread.receipts
+                            && thread.delivered_message_ids.contains(&message_id) =>
+                    {
+                        thread.delivered_sources.get(&message_id).copied()
+                    }
+                    _ => return Ok(()),
+                };
+                let notice = self.format_text(Key::Seen, &[&thread_id]);
+                match source_message_id {
+                    Some(source_message_id) => {
+                        self.reply_to_self(source_message_id, notice).await?;
+                    }
+                    None => {
+                        self.send_to_self(notice).await?;
+                    }
+                }
+            }
+            Action::SendReaction(thread_id, message_id, emoji) => {
+                // Only the messages we have sent have a known copy here.
+                let source_message_id = match self
+                    .threads
+                    .get(&thread_id)
+                    .and_then(|thread| thread.delivered_sources.get(&message_id))
+                {
+                    Some(&source_message_id) => source_message_id,
+                    None => return Ok(()),
+                };
+                if let Err(err) =
+                    reactions::set_reaction(&self.bot, self.chat_id, source_message_id, emoji).await
+                {
+                    debug!(
+                        "failed to relay reaction to @{}: {}",
+                        self.user_handle.user.login, err
+                    );
+                }
+            }
+            Action::PollAnswered(thread_id, question, chosen) => {
+                let notice = if chosen.is_empty() {
+                    self.format_text(
+                        Key::PollVoteRetracted,
+                        &[&thread_id, &escape_markup(&question)],
+                    )
+                } else {
+                    self.format_text(
+                        Key::PollVoted,
+                        &[
+                            &thread_id,
+                            &escape_markup(&question),
+                            &escape_markup(&chosen.join(", ")),
+                        ],
+                    )
+                };
+                self.notify_or_defer(notice).await?;
+            }
+            Action::Broadcast(text) => {
+                self.send_to_self(text).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn incoming_thread_count(&self) -> usize {
+        self.threads
+            .values()
+            .chain(self.archived_threads.values())
+            .chain(self.pending_threads.values().map(|pending| &pending.thread))
+            .filter(|th| th.anon_mode == ThreadAnonimityMode::Them)
+            .count()
+    }
+
+    fn is_dnd_active(&self) -> bool {
+        self.dnd
+            .is_some_and(|window| window.contains(now_timestamp()))
+    }
+
+    fn defer(&mut self, item: DeferredItem) {
+        self.deferred_items.push_back(item);
+        if self.deferred_items.len() > MAX_DEFERRED_ITEMS {
+            self.deferred_items.pop_front();
+            self.deferred_items_dropped += 1;
+        }
+    }
+
+    async fn notify_or_defer(&mut self, notice: String) -> Result<()> {
+        if self.is_dnd_active() {
+            self.defer(DeferredItem::Notice(notice));
+        } else {
+            self.send_to_self(notice).await?;
+        }
+        Ok(())
+    }
+
+    /// Delivers what arrived during the do-not-disturb window. An item that
+    /// fails to be delivered is logged and skipped, so that it does not hold
+    /// back the ones after it.
+    async fn flush_deferred_items(&mut self) {
+        let items = std::mem::take(&mut self.deferred_items);
+        let dropped = std::mem::take(&mut self.deferred_items_dropped);
+        let login = self.user_handle.user.login.clone();
+
+        let mut summary = self.format_text(Key::DndOver, &[&(items.len() + dropped)]);
+        if dropped > 0 {
+            summary.push_str(&self.format_text(Key::DndDropped, &[&dropped]));
+        }
+        if let Err(err) = self.send_to_self(summary).await {
+            warn!("failed to send DND summary to @{}: {:#}", login, err);
+        }
+
+        for item in items {
+            let result = match item {
+                DeferredItem::Message(thread_id, text, quote, entities) => {
+                    if !self.threads.contains_key(&thread_id) {
+                        continue;
+                    }
+                    self.receive_text(&thread_id, &text, quote.as_deref(), entities)
+                        .await
+                }
+                DeferredItem::Media(thread_id, kind, file_id, caption) => {
+                    if !self.threads.contains_key(&thread_id) {
+                        continue;
+                    }
+                    self.receive_media(&thread_id, kind, file_id, caption.as_deref())
+                        .await
+                }
+                DeferredItem::Notice(notice) => self.send_to_self(notice).await,
+            };
+            if let Err(err) = result {
+                warn!("failed to deliver deferred item to @{}: {:#}", login, err);
+            }
+        }
+    }
+
+    /// Sends the greeting in the background: the initiator's handler is still
+    /// waiting for the thread to start and cannot take the message right away.
+    fn send_greeting(&self, thread: &Thread) {
+        let greeting = match &self.greeting {
+            Some(greeting) => greeting.clone(),
+            None => return,
+        };
+        let other_handle = thread.other_handle.clone();
+        let other_thread_id = thread.other_id.clone();
+        tokio::spawn(async move {
+            let (delivered_sender, _) = oneshot::channel();
+            let action =
+                Action::SendText(other_thread_id, greeting, None, vec![], delivered_sender);
+            if let Err(err) = other_handle.send_action(action).await {
+                error!(
+                    "failed to send greeting to @{}: {:#}",
+                    other_handle.user.login, err
+                );
+            }
+        });
+    }
+
+    /// Shows a text from a thread. A text that does not fit into one message
+    /// is split into numbered parts, only the first one has the header.
+    /// Returns the first message.
+    async fn receive_text(
+        &mut self,
+        thread_id: &str,
+        text: &str,
+        quote: Option<&str>,
+        entities: Vec<MessageEntity>,
+    ) -> Result<Message> {
+        let header = self.format_incoming_text(thread_id, "", quote)?;
+        let mut first_message = None;
+        for (prefix, part, part_entities) in relayed_text_parts(&header, text, &entities) {
+            let message = self
+                .send_text_part(thread_id, &prefix, &part, part_entities)
+                .await?;
+            self.remember_quoted_text(message.id, &part);
+
+            self.event_service
+                .write(Event::ThreadMessageReceived(ThreadMessageReceivedEvent {
+                    login: self.user_handle.user.login.clone(),
+                    message_id: message.id,
+                    thread_id: thread_id.to_string(),
+                    kind: Some(MessageKind::Received),
+                }))
+                .wait_written()
+                .await?;
+            self.message_id_to_thread_id
+                .insert(message.id, thread_id.to_string());
+            first_message.get_or_insert(message);
+        }
+        if let Some(thread) = self.threads.get_mut(thread_id) {
+            thread.messages_received += 1;
+        }
+        self.stats.messages_received += 1;
+        first_message.context("text has no parts")
+    }
+
+    /// Sends a part of a text from a thread after `prefix`, which is markup.
+    async fn send_text_part(
+        &self,
+        thread_id: &str,
+        prefix: &str,
+        text: &str,
+        entities: Vec<MessageEntity>,
+    ) -> Result<Message> {
+        let disable_previews = !self.link_previews_enabled(thread_id);
+        if entities.is_empty() {
+            let formatted_text = format!("{}{}", prefix, escape_markup(text));
+            return self
+                .bot
+                .send_message(self.chat_id, markup_to_html(&formatted_text))
+                .parse_mode(ParseMode::Html)
+                .disable_web_page_preview(disable_previews)
+                .await
+                .context("failed to send message to user");
+        }
+        let prefix = markup_to_plain(prefix);
+        let entities = shift_entities(&prefix, entities);
+        self.bot
+            .send_message(self.chat_id, format!("{}{}", prefix, text))
+            .entities(entities)
+            .disable_web_page_preview(disable_previews)
+            .await
+            .context("failed to send message to user")
+    }
+
+    /// Handles a photo or a sticker sent to us in a thread.
+    async fn accept_media(
+        &mut self,
+        thread_id: ThreadId,
+        kind: MediaKind,
+        file_id: String,
+        caption: Option<String>,
+        delivered_sender: oneshot::Sender<i32>,
+    ) -> Result<()> {
+        if !self.check_media_allowed(&thread_id)? {
+            return Ok(());
+        }
+        if self.should_defer(&thread_id) {
+            self.defer(DeferredItem::Media(thread_id, kind, file_id, caption));
+            return Ok(());
+        }
+        let message = self
+            .receive_media(&thread_id, kind, file_id, caption.as_deref())
+            .await?;
+        delivered_sender.send(message.id).ok();
+        Ok(())
+    }
+
+    /// Handles an album sent to us in a thread. During DND its items are
+    /// deferred one by one.
+    async fn accept_media_group(
+        &mut self,
+        thread_id: ThreadId,
+        items: Vec<(MediaKind, String, Option<String>)>,
+        delivered_sender: oneshot::Sender<Vec<i32>>,
+    ) -> Result<()> {
+        if !self.check_media_allowed(&thread_id)? {
+            return Ok(());
+        }
+        if self.should_defer(&thread_id) {
+            for (kind, file_id, caption) in items {
+                self.defer(DeferredItem::Media(
+                    thread_id.clone(),
+                    kind,
+                    file_id,
+                    caption,
+                ));
+            }
+            return Ok(());
+        }
+        let messages = self.receive_media_group(&thread_id, items).await?;
+        delivered_sender
+            .send(messages.iter().map(|message| message.id).collect())
+            .ok();
+        Ok(())
+    }
+
+    /// Checks that media may be delivered into a thread. Returns false if it
+    /// has to be dropped silently.
+    fn check_media_allowed(&mut self, thread_id: &ThreadId) -> Result<bool> {
+        ensure!(
+            !self.pending_threads.contains_key(thread_id),
+            "media cannot be sent before the thread request is accepted"
+        );
+        if self.archived_threads.contains_key(thread_id) {
+            if self.archive_notices_sent.insert(thread_id.clone()) {
+                bail!(
+                    "the other side has archived this thread; your messages will not be delivered"
+                );
+            }
+            return Ok(false);
+        }
+        let thread = self
+            .threads
+            .get(thread_id)
+            .with_context(|| format!("thread {} no longer exists", thread_id))?;
+        ensure!(
+            !thread.paused_by_me,
+            "the other side has paused this thread"
+        );
+        Ok(true)
+    }
+
+    fn should_defer(&self, thread_id: &str) -> bool {
+        let priority = self
+            .threads
+            .get(thread_id)
+            .is_some_and(|thread| thread.priority);
+        !priority && self.is_dnd_active()
+    }
+
+    async fn receive_media_group(
+        &mut self,
+        thread_id: &str,
+        items: Vec<(MediaKind, String, Option<String>)>,
+    ) -> Result<Vec<Message>> {
+        let mut media = Vec::with_capacity(items.len());
+        for (i, (kind, file_id, caption)) in items.into_iter().enumerate() {
+            // The attribution goes into the caption of the first item.
+            let caption = if i == 0 {
+                Some(self.format_incoming_text(
+                    thread_id,
+                    caption.as_deref().unwrap_or_default(),
+                    None,
+                )?)
+            } else {
+                caption.map(|caption| escape_markup(&caption))
+            };
+            let file = match kind {
+                MediaKind::Photo { scrub: true, .. } => {
+                    InputFile::memory("photo.jpg", self.scrub_photo(file_id).await?)
+                }
+                _ => InputFile::FileId(file_id),
+            };
+            media.push(match (kind, caption) {
+                (MediaKind::Photo { .. }, Some(caption)) => InputMedia::Photo(
+                    InputMediaPhoto::new(file)
+                        .caption(markup_to_html(&caption))
+                        .parse_mode(ParseMode::Html),
+                ),
+                (MediaKind::Photo { .. }, None) => InputMedia::Photo(InputMediaPhoto::new(file)),
+                (MediaKind::Video { .. }, Some(caption)) => InputMedia::Video(
+                    InputMediaVideo::new(file)
+                        .caption(markup_to_html(&caption))
+                        .parse_mode(ParseMode::Html),
+                ),
+                (MediaKind::Video { .. }, None) => InputMedia::Video(InputMediaVideo::new(file)),
+                _ => bail!("only photos and videos can be sent as an album"),
+            });
+        }
+        let messages = self
+            .bot
+            .send_media_group(self.chat_id, media)
+            .await
+            .context("failed to send album to user")?;
+
+        for message in &messages {
+            self.event_service
+                .write(Event::ThreadMediaReceived(ThreadMediaReceivedEvent {
+                    login: self.user_handle.user.login.clone(),
+                    message_id: message.id,
+                    thread_id: thread_id.to_string(),
+                    kind: MessageKind::Received,
+                }))
+                .wait_written()
+                .await?;
+            self.message_id_to_thread_id
+                .insert(message.id, thread_id.to_string());
+        }
+        if let Some(thread) = self.threads.get_mut(thread_id) {
+            thread.messages_received += messages.len() as u64;
+        }
+        self.stats.messages_received += messages.len() as u64;
+        Ok(messages)
+    }
+
+    async fn receive_media(
+        &mut self,
+        thread_id: &str,
+        kind: MediaKind,
+        file_id: String,
+        caption: Option<&str>,
+    ) -> Result<Message> {
+        let message = match kind {
+            MediaKind::Photo { spoiler, scrub } => {
+                let formatted_caption = markup_to_html(&self.format_incoming_text(
+                    thread_id,
+                    caption.unwrap_or_default(),
+                    None,
+                )?);
+                if scrub {
+                    // A failure here refuses the photo rather than sending
+                    // the original.
+                    let photo = self.scrub_photo(file_id).await?;
+                    self.bot
+                        .send_photo(self.chat_id, InputFile::memory("photo.jpg", photo))
+                        .caption(formatted_caption)
+                        .parse_mode(ParseMode::Html)
+                        .await
+                } else if spoiler {
+                    spoiler::send_photo(&self.bot, self.chat_id, file_id, formatted_caption).await
+                } else {
+                    self.bot
+                        .send_photo(self.chat_id, InputFile::FileId(file_id))
+                        .caption(formatted_caption)
+                        .parse_mode(ParseMode::Html)
+                        .await
+                }
+                .context("failed to send photo to user")?
+            }
+            MediaKind::Sticker => {
+                self.send_attribution(thread_id, self.text(Key::StickerAttribution))
                     .await?;
-                self.threads.insert(thread.id.clone(), thread);
+                self.bot
+                    .send_sticker(self.chat_id, InputFile::FileId(file_id))
+                    .await
+                    .context("failed to send sticker to user")?
             }
-            Action::SendText(thread_id, text) => {
-                let thread = &self.threads[&thread_id];
-                let formatted_text = match thread.anon_mode {
-                    ThreadAnonimityMode::Me => {
-                        format!(">>> Message from {}:\n{}", thread_id, text)
-                    }
-                    ThreadAnonimityMode::Them => {
-                        format!(">>> Message from anonymous {}:\n{}", thread_id, text)
-                    }
-                    ThreadAnonimityMode::Both => {
-                        format!(">>> Message from random chat {}:\n{}", thread_id, text)
-                    }
+            MediaKind::Animation => {
+                let formatted_caption =
+                    self.format_incoming_text(thread_id, caption.unwrap_or_default(), None)?;
+                self.bot
+                    .send_animation(self.chat_id, InputFile::FileId(file_id))
+                    .caption(markup_to_html(&formatted_caption))
+                    .parse_mode(ParseMode::Html)
+                    .await
+                    .context("failed to send animation to user")?
+            }
+            MediaKind::Voice { duration } => {
+                let formatted_caption =
+                    self.format_incoming_text(thread_id, caption.unwrap_or_default(), None)?;
+                self.bot
+                    .send_voice(self.chat_id, InputFile::FileId(file_id))
+                    .caption(markup_to_html(&formatted_caption))
+                    .parse_mode(ParseMode::Html)
+                    .duration(duration)
+                    .await
+                    .context("failed to send voice message to user")?
+            }
+            MediaKind::Video { duration, spoiler } => {
+                let formatted_caption = markup_to_html(&self.format_incoming_text(
+                    thread_id,
+                    caption.unwrap_or_default(),
+                    None,
+                )?);
+                if spoiler {
+                    spoiler::send_video(
+                        &self.bot,
+                        self.chat_id,
+                        file_id,
+                        formatted_caption,
+                        duration,
+                    )
+                    .await
+                } else {
+                    self.bot
+                        .send_video(self.chat_id, InputFile::FileId(file_id))
+                        .caption(formatted_caption)
+                        .parse_mode(ParseMode::Html)
+                        .duration(duration)
+                        .await
+                }
+                .context("failed to send video to user")?
+            }
+            MediaKind::VideoNote { duration } => {
+                self.send_attribution(thread_id, self.text(Key::VideoNoteAttribution))
+                    .await?;
+                self.bot
+                    .send_video_note(self.chat_id, InputFile::FileId(file_id))
+                    .duration(duration)
+                    .await
+                    .context("failed to send video note to user")?
+            }
+            MediaKind::Audio { duration, .. } => {
+                let formatted_caption =
+                    self.format_incoming_text(thread_id, caption.unwrap_or_default(), None)?;
+                self.bot
+                    .send_audio(self.chat_id, InputFile::FileId(file_id))
+                    .caption(markup_to_html(&formatted_caption))
+                    .parse_mode(ParseMode::Html)
+                    .duration(duration)
+                    .await
+                    .context("failed to send audio to user")?
+            }
+            MediaKind::Document {
+                file_name,
+                mime_type,
+            } => {
+                // Files keep their names when sent by id, so hiding the name
+                // takes a download and an upload.
+                let file = match file_name {
+                    Some(_) => InputFile::FileId(file_id),
+                    None => InputFile::memory(
+                        anonymous_file_name(mime_type.as_deref()),
+                        self.download_file(file_id, self.media_limits.max_document_size)
+                            .await?,
+                    ),
                 };
-                let message = self.send_to_self(formatted_text).await?;
-
-                self.event_service
-                    .write(Event::ThreadMessageReceived(ThreadMessageReceivedEvent {
-                        login: self.user_handle.user.login.clone(),
-                        message_id: message.id,
-                        thread_id: thread_id.clone(),
-                    }))
-                    .wait_written()
+                let formatted_caption =
+                    self.format_incoming_text(thread_id, caption.unwrap_or_default(), None)?;
+                self.bot
+                    .send_document(self.chat_id, file)
+                    .caption(markup_to_html(&formatted_caption))
+                    .parse_mode(ParseMode::Html)
+                    .await
+                    .context("failed to send file to user")?
+            }
+            MediaKind::Contact {
+                phone_number,
+                first_name,
+                last_name,
+            } => {
+                self.send_attribution(thread_id, self.text(Key::ContactAttribution))
                     .await?;
-                self.message_id_to_thread_id.insert(message.id, thread_id);
+                let mut request = self
+                    .bot
+                    .send_contact(self.chat_id, phone_number, first_name);
+                if let Some(last_name) = last_name {
+                    request = request.last_name(last_name);
+                }
+                request.await.context("failed to send contact to user")?
             }
-            Action::TerminateThread(thread_id) => {
-                self.send_to_self(format!(
-                    "Thread {} has been closed by the other side.",
-                    thread_id
-                ))
-                .await?;
-                self.threads
-                    .remove(&thread_id)
-                    .expect("thread is not found");
+            MediaKind::Location {
+                latitude,
+                longitude,
+                title: Some(title),
+                address: Some(address),
+            } => {
+                self.send_attribution(thread_id, self.text(Key::VenueAttribution))
+                    .await?;
+                self.bot
+                    .send_venue(self.chat_id, latitude, longitude, title, address)
+                    .await
+                    .context("failed to send venue to user")?
             }
-            Action::Broadcast(text) => {
-                self.send_to_self(text).await?;
+            MediaKind::Location {
+                latitude,
+                longitude,
+                ..
+            } => {
+                self.send_attribution(thread_id, self.text(Key::LocationAttribution))
+                    .await?;
+                self.bot
+                    .send_location(self.chat_id, latitude, longitude)
+                    .await
+                    .context("failed to send location to user")?
             }
+        };
+
+        self.event_service
+            .write(Event::ThreadMediaReceived(ThreadMediaReceivedEvent {
+                login: self.user_handle.user.login.clone(),
+                message_id: message.id,
+                thread_id: thread_id.to_string(),
+                kind: MessageKind::Received,
+            }))
+            .wait_written()
+            .await?;
+        self.message_id_to_thread_id
+            .insert(message.id, thread_id.to_string());
+        if let Some(thread) = self.threads.get_mut(thread_id) {
+            thread.messages_received += 1;
         }
+        self.stats.messages_received += 1;
+        Ok(message)
+    }
+
+    /// Tells who sent media that cannot have a caption, like stickers. The
+    /// line is mapped to the thread, so it can be replied to.
+    async fn send_attribution(&mut self, thread_id: &str, label: &str) -> Result<()> {
+        let attribution = self.format_incoming_text(thread_id, label, None)?;
+        let attribution = self.send_to_self(attribution).await?;
+        self.event_service
+            .write(Event::ThreadMessageReceived(ThreadMessageReceivedEvent {
+                login: self.user_handle.user.login.clone(),
+                message_id: attribution.id,
+                thread_id: thread_id.to_string(),
+                kind: Some(MessageKind::Service),
+            }))
+            .wait_written()
+            .await?;
+        self.message_id_to_thread_id
+            .insert(attribution.id, thread_id.to_string());
         Ok(())
     }
 
+    async fn download_file(&self, file_id: String, max_file_size: u64) -> Result<Vec<u8>> {
+        let file = self
+            .bot
+            .get_file(file_id)
+            .await
+            .context("failed to get file info")?;
+        ensure!(
+            file.file_size as u64 <= max_file_size,
+            "file is too large: {} KiB, at most {} KiB is allowed",
+            file.file_size / 1024,
+            max_file_size / 1024
+        );
+        let mut data = Vec::with_capacity(file.file_size as usize);
+        self.bot
+            .download_file(&file.file_path, &mut data)
+            .await
+            .context("failed to download file")?;
+        Ok(data)
+    }
+
+    /// Downloads a photo and re-encodes it without metadata, see `reencode_photo`.
+    async fn scrub_photo(&self, file_id: String) -> Result<Vec<u8>> {
+        let data = self
+            .download_file(file_id, self.media_limits.max_scrubbed_photo_size)
+            .await?;
+        tokio::task::spawn_blocking(move || reencode_photo(&data))
+            .await
+            .context("failed to scrub photo")?
+    }
+
+    fn format_incoming_text(
+        &self,
+        thread_id: &str,
+        text: &str,
+        quote: Option<&str>,
+    ) -> Result<String> {
+        let thread = self
+            .threads
+            .get(thread_id)
+            .with_context(|| format!("thread {} no longer exists", thread_id))?;
+        let text = match quote {
+            Some(quote) => self.format_text(
+                Key::InReplyTo,
+                &[&escape_markup(quote), &escape_markup(text)],
+            ),
+            None => escape_markup(text),
+        };
+        let formatted_text = match thread.anon_mode {
+            ThreadAnonimityMode::Me => self.format_text(Key::MessageFrom, &[&thread_id, &text]),
+            ThreadAnonimityMode::Them => {
+                self.format_text(Key::MessageFromAnonymous, &[&thread_id, &text])
+            }
+            ThreadAnonimityMode::Both => {
+                self.format_text(Key::MessageFromRandomChat, &[&thread_id, &text])
+            }
+        };
+        let formatted_text = match self
+            .user_handle
+            .settings
+            .read()
+            .expect("handler settings.read() failed")
+            .notifications_sound_prefix
+            .as_ref()
+        {
+            Some(prefix) => format!("{} {}", escape_markup(prefix), formatted_text),
+            None => formatted_text,
+        };
+        Ok(formatted_text)
+    }
+
+    fn remember_quoted_text(&mut self, message_id: i32, text: &str) {
+        let mut quote = text.chars().take(QUOTE_LENGTH).collect::<String>();
+        if quote.len() < text.len() {
+            quote.push('…');
+        }
+        self.quoted_texts.insert(message_id, quote);
+        self.quoted_message_ids.push_back(message_id);
+        if self.quoted_message_ids.len() > MAX_QUOTED_TEXTS {
+            if let Some(old_id) = self.quoted_message_ids.pop_front() {
+                self.quoted_texts.remove(&old_id);
+            }
+        }
+    }
+
+    fn language(&self) -> Language {
+        self.user_handle
+            .settings
+            .read()
+            .expect("handler settings.read() failed")
+            .language
+    }
+
+    fn text(&self, key: Key) -> &'static str {
+        messages::get(self.language(), key)
+    }
+
+    fn format_text(&self, key: Key, args: &[&(dyn fmt::Display + Sync)]) -> String {
+        messages::format(self.language(), key, args)
+    }
+
+    fn flag(&self, flag: bool) -> &'static str {
+        self.text(if flag { Key::On } else { Key::Off })
+    }
+
+    async fn reply_to_self(
+        &mut self,
+        reply_to_message_id: i32,
+        message: impl AsRef<str>,
+    ) -> Result<Message> {
+        self.bot
+            .send_message(self.chat_id, markup_to_html(message.as_ref()))
+            .parse_mode(ParseMode::Html)
+            .reply_to_message_id(reply_to_message_id)
+            .await
+            .context("failed to send message to user")
+    }
+
+    /// Sends a message that may exceed the Telegram limit, splitting it into
+    /// several messages on line boundaries. Returns the last sent message.
+    async fn send_to_self_long(&mut self, message: impl AsRef<str>) -> Result<Message> {
+        let mut chunks = split_text(message.as_ref(), MAX_MESSAGE_LENGTH).into_iter();
+        let mut last = self.send_to_self(chunks.next().unwrap_or_default()).await?;
+        for chunk in chunks {
+            last = self.send_to_self(chunk).await?;
+        }
+        Ok(last)
+    }
+
     async fn send_to_self(&mut self, message: impl AsRef<str>) -> Result<Message> {
         debug!(
             "sending message to @{}: {}",
@@ -756,8 +7074,372 @@ impl Handler {
             message.as_ref()
         );
         self.bot
-            .send_message(self.chat_id, message.as_ref())
+            .send_message(self.chat_id, markup_to_html(message.as_ref()))
+            .parse_mode(ParseMode::Html)
             .await
             .context("failed to send message to user")
     }
 }
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Reads the user's events from the log, hiding the other side of anonymous threads.
+fn read_exported_events(storage: &Storage, login: &str) -> Result<Vec<serde_json::Value>> {
+    let mut anonymous_thread_ids = HashSet::new();
+    let mut events = vec![];
+
+    storage.for_each_record(|record| {
+        let event = match record {
+            LogRecord::Event(logged) if logged.event.login() == login => logged.event,
+            _ => return Ok(()),
+        };
+        if let Event::UserForgotten(_) = event {
+            // Everything before `/forget` belongs to a previous account.
+            anonymous_thread_ids.clear();
+            events.clear();
+            return Ok(());
+        }
+        let redacted_field = match &event {
+            Event::ThreadStarted(ev) if ev.anon_mode != ThreadAnonimityMode::Me => {
+                anonymous_thread_ids.insert(ev.my_thread_id.clone());
+                Some("other_login")
+            }
+            Event::ThreadRequested(ev) if ev.anon_mode != ThreadAnonimityMode::Me => {
+                anonymous_thread_ids.insert(ev.my_thread_id.clone());
+                Some("other_login")
+            }
+            Event::ThreadTerminated(ev) if anonymous_thread_ids.contains(&ev.my_thread_id) => {
+                Some("other_login")
+            }
+            Event::UserBanned(_) => Some("banned_login"),
+            Event::UserUnbanned(_) => Some("unbanned_login"),
+            _ => None,
+        };
+
+        let mut value = serde_json::to_value(&event)?;
+        if let Some(field) = redacted_field {
+            if let Some(body) = value
+                .as_object_mut()
+                .and_then(|obj| obj.values_mut().next())
+            {
+                body[field] = serde_json::Value::Null;
+            }
+        }
+        events.push(value);
+        Ok(())
+    })?;
+    Ok(events)
+}
+
+/// Splits a relayed text into parts that fit into a message along with the
+/// header, which is markup. Each part comes with its prefix: the header and
+/// the part number for the first one, only the number for the rest.
+fn relayed_text_parts(
+    header: &str,
+    text: &str,
+    entities: &[MessageEntity],
+) -> Vec<(String, String, Vec<MessageEntity>)> {
+    let header_len = markup_to_plain(header).chars().count();
+    let parts = split_text_with_entities(
+        text,
+        entities,
+        MAX_MESSAGE_LENGTH.saturating_sub(header_len).max(1),
+    );
+    let part_count = parts.len();
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(i, (part, part_entities))| {
+            let label = if part_count > 1 {
+                format!("({}/{}) ", i + 1, part_count)
+            } else {
+                String::new()
+            };
+            let prefix = if i == 0 {
+                format!("{}{}", header, label)
+            } else {
+                label
+            };
+            (prefix, part, part_entities)
+        })
+        .collect()
+}
+
+/// Moves the entities of a text to follow `prefix`, which is plain text. The
+/// text always comes last, so the entities only shift by the length of the
+/// prefix in UTF-16 code units.
+fn shift_entities(prefix: &str, entities: Vec<MessageEntity>) -> Vec<MessageEntity> {
+    let offset = utf16_len(prefix);
+    entities
+        .into_iter()
+        .map(|entity| MessageEntity {
+            offset: entity.offset + offset,
+            ..entity
+        })
+        .collect()
+}
+
+/// Finds the only candidate that starts with `prefix`. Fails if there are
+/// several of them, listing them in order.
+fn expand_prefix(prefix: &str, mut candidates: Vec<ThreadId>) -> Result<Option<ThreadId>> {
+    candidates.retain(|candidate| candidate.starts_with(prefix));
+    candidates.sort();
+    candidates.dedup();
+    match candidates.len() {
+        0 => Ok(None),
+        1 => Ok(candidates.pop()),
+        _ => bail!(
+            "{} matches several threads: {}",
+            prefix,
+            candidates.join(", ")
+        ),
+    }
+}
+
+fn seconds_to_interval(seconds: u64) -> Option<Duration> {
+    if seconds == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(seconds))
+    }
+}
+
+/// Checks the user's current topic; `None` matches everybody.
+fn has_topic(handle: &UserHandle, topic: Option<&str>) -> bool {
+    topic.is_none_or(|topic| {
+        handle
+            .topic
+            .read()
+            .expect("handler topic.read() failed")
+            .as_deref()
+            == Some(topic)
+    })
+}
+
+fn dice_symbol(emoji: DiceEmoji) -> &'static str {
+    match emoji {
+        DiceEmoji::Dice => "🎲",
+        DiceEmoji::Darts => "🎯",
+        DiceEmoji::Basketball => "🏀",
+        DiceEmoji::Football => "⚽",
+        DiceEmoji::Bowling => "🎳",
+        DiceEmoji::SlotMachine => "🎰",
+    }
+}
+
+fn insert_scheduled_message(messages: &mut Vec<ScheduledMessage>, message: ScheduledMessage) {
+    let index =
+        messages.partition_point(|m| (m.deliver_at, m.id) <= (message.deliver_at, message.id));
+    messages.insert(index, message);
+}
+
+fn format_ban_expiry(language: Language, ban: &BanRecord) -> String {
+    match ban.expires_at {
+        Some(expires_at) => {
+            let left = (expires_at - now_timestamp()).max(0) as u64;
+            let left = format_duration(Duration::from_secs(left));
+            messages::format(language, Key::BanExpiresIn, &[&left])
+        }
+        None => messages::get(language, Key::BanPermanent).to_string(),
+    }
+}
+
+/// Shortens user-provided text for display in a bot message.
+fn preview(text: &str, length: usize) -> String {
+    let mut preview = text.chars().take(length).collect::<String>();
+    if preview.len() < text.len() {
+        preview.push('…');
+    }
+    escape_markup(&preview)
+}
+
+/// Decodes an image and encodes its pixels as JPEG again. Nothing but the
+/// pixels survives, so EXIF and other metadata are dropped.
+fn reencode_photo(data: &[u8]) -> Result<Vec<u8>> {
+    let mut reader = image::io::Reader::new(Cursor::new(data))
+        .with_guessed_format()
+        .context("failed to read image")?;
+    let mut limits = image::io::Limits::default();
+    limits.max_image_width = Some(MAX_SCRUBBED_PHOTO_DIMENSION);
+    limits.max_image_height = Some(MAX_SCRUBBED_PHOTO_DIMENSION);
+    limits.max_alloc = Some(MAX_SCRUBBED_PHOTO_ALLOC);
+    reader.limits(limits);
+    let image = reader.decode().context("failed to decode image")?.to_rgb8();
+    let mut jpeg = vec![];
+    JpegEncoder::new_with_quality(&mut jpeg, 90)
+        .encode_image(&image)
+        .context("failed to encode image")?;
+    Ok(jpeg)
+}
+
+/// Names a file "document", keeping an extension guessed from the MIME type.
+fn anonymous_file_name(mime_type: Option<&str>) -> String {
+    let extension = mime_type
+        .map(|mime_type| match mime_type {
+            "image/jpeg" => "jpg",
+            "audio/mpeg" => "mp3",
+            "text/plain" => "txt",
+            mime_type => mime_type.split('/').nth(1).unwrap_or_default(),
+        })
+        .filter(|ext| {
+            !ext.is_empty() && ext.len() <= 5 && ext.chars().all(|c| c.is_ascii_alphanumeric())
+        });
+    match extension {
+        Some(extension) => format!("document.{}", extension),
+        None => "document".to_string(),
+    }
+}
+
+fn remember_partner(recent_partners: &mut VecDeque<String>, login: String) {
+    recent_partners.retain(|l| *l != login);
+    recent_partners.push_back(login);
+    if recent_partners.len() > MAX_RECENT_PARTNERS {
+        recent_partners.pop_front();
+    }
+}
+
+async fn sleep_until_instant(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn sleep_until(deadline: Option<i64>) {
+    match deadline {
+        Some(deadline) => {
+            let delay = (deadline - now_timestamp()).max(0) as u64;
+            tokio::time::sleep(Duration::from_secs(delay)).await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use teloxide::types::MessageEntityKind;
+
+    fn ids(ids: &[&str]) -> Vec<ThreadId> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    fn entity(kind: MessageEntityKind, offset: usize, length: usize) -> MessageEntity {
+        MessageEntity {
+            kind,
+            offset,
+            length,
+        }
+    }
+
+    /// The text an entity covers, counting in UTF-16 code units as Telegram does.
+    fn covered(text: &str, entity: &MessageEntity) -> String {
+        let units = text.encode_utf16().collect::<Vec<_>>();
+        String::from_utf16(&units[entity.offset..entity.offset + entity.length]).unwrap()
+    }
+
+    #[test]
+    fn entities_follow_a_prefix_with_emoji() {
+        let prefix = "💌 Алиса 🌸 (1/2) ";
+        let text = "a 🙂 *bold* move";
+        let entities = vec![
+            entity(MessageEntityKind::Bold, 5, 6),
+            entity(MessageEntityKind::Italic, 2, 2),
+        ];
+        let shifted = shift_entities(prefix, entities);
+        let message = format!("{}{}", prefix, text);
+        assert_eq!(covered(&message, &shifted[0]), "*bold*");
+        assert_eq!(covered(&message, &shifted[1]), "🙂");
+        assert_eq!(shifted[0].offset, 5 + 18);
+    }
+
+    #[test]
+    fn entities_are_unchanged_without_a_prefix() {
+        let entities = vec![entity(MessageEntityKind::Code, 3, 4)];
+        assert_eq!(shift_entities("", entities.clone()), entities);
+    }
+
+    #[test]
+    fn relayed_text_right_at_the_limit_is_one_message() {
+        let header = "`#x`: ";
+        let text = "ж".repeat(MAX_MESSAGE_LENGTH - 4);
+        let parts = relayed_text_parts(header, &text, &[]);
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].0, header);
+        assert_eq!(parts[0].1, text);
+    }
+
+    #[test]
+    fn relayed_text_over_the_limit_is_numbered() {
+        let header = "`#x`: ";
+        let text = format!("{}\n{}", "ж".repeat(MAX_MESSAGE_LENGTH - 4), "🙂".repeat(3));
+        let parts = relayed_text_parts(header, &text, &[]);
+        let prefixes = parts
+            .iter()
+            .map(|(prefix, ..)| prefix.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(prefixes, ["`#x`: (1/2) ", "(2/2) "]);
+        assert_eq!(parts[0].1.chars().count(), MAX_MESSAGE_LENGTH - 4);
+        assert_eq!(parts[1].1, "🙂🙂🙂");
+
+        let text = "ж".repeat(MAX_MESSAGE_LENGTH - 3);
+        let parts = relayed_text_parts(header, &text, &[]);
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[1].1, "ж");
+    }
+
+    #[test]
+    fn relayed_text_parts_keep_their_entities() {
+        let text = format!("{}\nbold", "ж".repeat(MAX_MESSAGE_LENGTH));
+        let bold = entity(MessageEntityKind::Bold, MAX_MESSAGE_LENGTH + 1, 4);
+        let parts = relayed_text_parts("", &text, &[bold]);
+        assert_eq!(parts.len(), 2);
+        assert!(parts[0].2.is_empty());
+        assert_eq!(parts[1].1, "bold");
+        assert_eq!(parts[1].2, [entity(MessageEntityKind::Bold, 0, 4)]);
+    }
+
+    #[test]
+    fn unique_prefix_expands() {
+        let candidates = ids(&["#brave_lion", "#calm_otter", "@bob"]);
+        assert_eq!(
+            expand_prefix("#brave", candidates.clone()).unwrap(),
+            Some("#brave_lion".to_string())
+        );
+        assert_eq!(
+            expand_prefix("#c", candidates).unwrap(),
+            Some("#calm_otter".to_string())
+        );
+    }
+
+    #[test]
+    fn unknown_prefix_is_left_alone() {
+        let candidates = ids(&["#brave_lion", "#calm_otter"]);
+        assert_eq!(expand_prefix("#bold", candidates.clone()).unwrap(), None);
+        assert_eq!(expand_prefix("#brave_lions", candidates).unwrap(), None);
+        assert_eq!(expand_prefix("#x", vec![]).unwrap(), None);
+    }
+
+    #[test]
+    fn ambiguous_prefix_lists_the_matches() {
+        let candidates = ids(&["#brave_owl", "#calm_otter", "#brave_lion"]);
+        let err = expand_prefix("#brave", candidates).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "#brave matches several threads: #brave_lion, #brave_owl"
+        );
+    }
+
+    #[test]
+    fn the_same_thread_listed_twice_is_not_ambiguous() {
+        // Candidates are gathered from several maps, which may overlap.
+        let candidates = ids(&["#brave_lion", "#brave_lion"]);
+        assert_eq!(
+            expand_prefix("#brave", candidates).unwrap(),
+            Some("#brave_lion".to_string())
+        );
+    }
+}
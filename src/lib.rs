@@ -1,11 +1,28 @@
+mod anonymize;
 mod command;
 mod command_dispatcher;
 mod data;
+mod event_codec;
 mod event_log;
+mod event_store;
 mod handler;
+mod messages;
+mod migrations;
+mod reactions;
+mod spoiler;
 mod util;
 
-pub use command::Command;
+pub use anonymize::{anonymize_log, anonymize_records};
+pub use command::{is_private_chat, BroadcastAudience, Command, CommandParseError, StartPayload};
 pub use command_dispatcher::{CommandDispatcher, CommandDispatcherBuilder};
-pub use data::User;
-pub use event_log::{EventService, EventServiceHandle};
+pub use data::{MediaPolicy, User};
+pub use event_codec::{LogFormat, LogHeader};
+pub use event_log::{
+    export_records, migrate_event_log, BatchConfig, Event, EventLogReader, EventService,
+    EventServiceHandle, ExportFormat, LogRecord, LoggedEvent,
+};
+pub use event_store::{
+    live_segments, log_segments, remove_superseded_segments, Durability, EventStore,
+    FileEventStore, Segment, SqliteEventStore, Storage,
+};
+pub use reactions::{polling_with_reactions, MessageReactionUpdated};
@@ -1,28 +1,156 @@
 use std::{
+    collections::HashSet,
     fs,
-    io::{BufReader, BufWriter, Write},
-    path::PathBuf,
-    sync::Arc,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
     thread,
+    time::Duration,
 };
 
 use lovebot::{
-    Command, CommandDispatcher, CommandDispatcherBuilder, EventService, EventServiceHandle, User,
+    is_private_chat, live_segments, log_segments, migrate_event_log, polling_with_reactions,
+    remove_superseded_segments, BatchConfig, Command, CommandDispatcher, CommandDispatcherBuilder,
+    CommandParseError, Durability, EventService, EventServiceHandle, EventStore, FileEventStore,
+    LogFormat, LogHeader, MediaPolicy, MessageReactionUpdated, SqliteEventStore, Storage, User,
 };
 
-use anyhow::{Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use clap::Parser;
 use log::*;
 use teloxide::prelude::*;
-use tokio::runtime;
+use tokio::{runtime, sync::mpsc};
 
 ////////////////////////////////////////////////////////////////////////////////
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    #[clap(short, long)]
-    event_log: PathBuf,
+    /// The event log file, same as `--storage file:<path>`.
+    #[clap(
+        short,
+        long,
+        required_unless_present = "storage",
+        conflicts_with = "storage"
+    )]
+    event_log: Option<PathBuf>,
+
+    /// Where events are stored: `file:<path>` for an event log or
+    /// `sqlite:<path>` for a SQLite database.
+    #[clap(long)]
+    storage: Option<Storage>,
+
+    /// How long a `/random` request may wait in the queue, in seconds.
+    #[clap(long, default_value = "3600")]
+    random_queue_timeout: u64,
+
+    /// Longest voice message that can be sent into a thread, in seconds.
+    #[clap(long, default_value = "120")]
+    max_voice_duration: u32,
+
+    /// Largest voice message that can be sent into a thread, in bytes.
+    #[clap(long, default_value = "1048576")]
+    max_voice_size: u64,
+
+    /// Longest video or video note that can be sent into a thread, in seconds.
+    #[clap(long, default_value = "300")]
+    max_video_duration: u32,
+
+    /// Largest video or video note that can be sent into a thread, in bytes.
+    #[clap(long, default_value = "20971520")]
+    max_video_size: u64,
+
+    /// Largest audio file that can be sent into a thread, in bytes.
+    #[clap(long, default_value = "20971520")]
+    max_audio_size: u64,
+
+    /// Largest document that can be sent into a thread, in bytes.
+    #[clap(long, default_value = "20971520")]
+    max_document_size: u64,
+
+    /// Largest photo that is re-encoded to strip its metadata, in bytes.
+    #[clap(long, default_value = "10485760")]
+    max_scrubbed_photo_size: u64,
+
+    /// Media categories that cannot be relayed at all, e.g. `voice,document`.
+    /// Ignored once the admin has changed the policy with `/policy set`.
+    #[clap(long, use_delimiter = true)]
+    disabled_media: Vec<String>,
+
+    /// Skip event log lines that fail their checksum or do not parse, instead
+    /// of refusing to start.
+    #[clap(long)]
+    skip_corrupt_events: bool,
+
+    /// Write a snapshot of the state restored from the event log to a new
+    /// file and exit. The file can then replace the event log.
+    #[clap(long)]
+    snapshot_to: Option<PathBuf>,
+
+    /// Rewrite an event log of an older version at the current one and exit.
+    /// The old log is kept next to it, with its version appended to the name.
+    #[clap(long)]
+    migrate: bool,
+
+    /// Format of a new event log or snapshot, `json` or `bincode`. An existing
+    /// event log is kept in the format it has been written in.
+    #[clap(long, default_value = "json")]
+    event_log_format: LogFormat,
+
+    /// When written events are acknowledged: `flush`, `fsync` to sync every
+    /// batch to disk, or `fsync-every-N` to sync once per N events.
+    #[clap(long, default_value = "flush")]
+    durability: Durability,
+
+    /// Start a new segment of the event log once it has grown to this many
+    /// bytes. Full segments are kept next to it as `<event log>.000001` and so
+    /// on, gzipped in the background, until a snapshot makes them unneeded.
+    #[clap(long)]
+    rotate_bytes: Option<u64>,
+
+    /// Most events written to the event log at once.
+    #[clap(long, default_value = "1000")]
+    batch_max_events: usize,
+
+    /// Most bytes of events written to the event log at once. Unlimited by
+    /// default.
+    #[clap(long)]
+    batch_max_bytes: Option<usize>,
+
+    /// How long to wait for more events to write along with the first one, in
+    /// milliseconds. By default only events that are already waiting are.
+    #[clap(long, default_value = "0")]
+    batch_max_delay: u64,
+
+    /// Leave group chats and channels the bot has been added to.
+    #[clap(long)]
+    leave_groups: bool,
+}
+
+impl Args {
+    fn storage(&self) -> Storage {
+        match (&self.storage, &self.event_log) {
+            (Some(storage), _) => storage.clone(),
+            (None, Some(path)) => Storage::File(path.clone()),
+            (None, None) => unreachable!("clap requires one of them"),
+        }
+    }
+
+    fn batch_config(&self) -> BatchConfig {
+        BatchConfig {
+            max_events: self.batch_max_events,
+            max_bytes: self.batch_max_bytes.unwrap_or(usize::MAX),
+            max_delay: Duration::from_millis(self.batch_max_delay),
+        }
+    }
+
+    /// The event log file, for the modes that only work with one.
+    fn event_log_file(&self) -> Result<PathBuf> {
+        match self.storage() {
+            Storage::File(path) => Ok(path),
+            Storage::Sqlite(_) => bail!("this mode needs an event log file, not a database"),
+        }
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -30,19 +158,302 @@ struct Args {
 async fn handle_message(
     cx: &UpdateWithCx<AutoSend<Bot>, Message>,
     command_dispatcher: Arc<CommandDispatcher>,
+    bot_username: &str,
 ) -> Result<()> {
     let user = User::try_from(cx.update.from().context("message has unknown sender")?)
         .context("failed to parse user")?;
-    let command = Command::try_from(&cx.update).context("failed to parse command")?;
+    let command =
+        match Command::parse(&cx.update, bot_username).context("failed to parse command")? {
+            Some(command) => command,
+            None => {
+                debug!("ignoring command addressed to another bot");
+                return Ok(());
+            }
+        };
     command_dispatcher
         .handle_command(Arc::new(user), cx.chat_id(), command)
         .await
 }
 
-fn launch_event_service<W: Write + Send + 'static>(writer: W) -> EventServiceHandle {
-    let (mut service, handle) = EventService::new(writer);
+async fn handle_edited_message(
+    cx: &UpdateWithCx<AutoSend<Bot>, Message>,
+    command_dispatcher: Arc<CommandDispatcher>,
+    bot_username: &str,
+) -> Result<()> {
+    let user = User::try_from(cx.update.from().context("message has unknown sender")?)
+        .context("failed to parse user")?;
+    let command = match Command::parse_edited(&cx.update, bot_username) {
+        Some(command) => command,
+        None => {
+            debug!("ignoring edit of a message that was not sent into a thread");
+            return Ok(());
+        }
+    };
+    command_dispatcher
+        .handle_command(Arc::new(user), cx.chat_id(), command)
+        .await
+}
+
+/// Tells a group chat once that the bot only works in private chats, and
+/// leaves it if asked to.
+async fn reject_group_chat(
+    cx: &UpdateWithCx<AutoSend<Bot>, Message>,
+    rejected_chats: &Mutex<HashSet<i64>>,
+    leave_groups: bool,
+) {
+    let chat_id = cx.chat_id();
+    let is_new = rejected_chats
+        .lock()
+        .expect("failed to lock rejected chats")
+        .insert(chat_id);
+    if !is_new {
+        return;
+    }
+    info!("rejecting non-private chat {}", chat_id);
+    if let Err(err) = cx.answer("This bot only works in private chats.").await {
+        error!("failed to send reply to chat {}: {}", chat_id, err);
+    }
+    if leave_groups {
+        if let Err(err) = cx.requester.leave_chat(chat_id).await {
+            error!("failed to leave chat {}: {}", chat_id, err);
+        }
+    }
+}
+
+async fn reply_with_error(cx: &UpdateWithCx<AutoSend<Bot>, Message>, err: anyhow::Error) {
+    let message = match err.downcast_ref::<CommandParseError>() {
+        Some(parse_err) => parse_err.to_string(),
+        None => format!("Error: {:#}.", err),
+    };
+    debug!("Sending reply to chat {}: {}", cx.chat_id(), message);
+    if let Err(err) = cx.reply_to(message).await {
+        error!("failed to send reply to chat {}: {}", cx.chat_id(), err);
+    }
+}
+
+async fn handle_poll_answer(
+    cx: &UpdateWithCx<AutoSend<Bot>, PollAnswer>,
+    command_dispatcher: Arc<CommandDispatcher>,
+) -> Result<()> {
+    let user = User::try_from(&cx.update.user).context("failed to parse user")?;
+    command_dispatcher
+        .handle_poll_answer(
+            &user.login,
+            cx.update.poll_id.clone(),
+            cx.update.option_ids.clone(),
+        )
+        .await
+}
+
+async fn handle_callback_query(
+    cx: &UpdateWithCx<AutoSend<Bot>, CallbackQuery>,
+    command_dispatcher: Arc<CommandDispatcher>,
+) -> Result<()> {
+    let user = User::try_from(&cx.update.from).context("failed to parse user")?;
+    let chat_id = cx
+        .update
+        .message
+        .as_ref()
+        .context("callback query has no message")?
+        .chat_id();
+    let data = cx
+        .update
+        .data
+        .as_deref()
+        .context("callback query has no data")?;
+    let command = Command::parse_callback_data(data)?;
+    command_dispatcher
+        .handle_command(Arc::new(user), chat_id, command)
+        .await
+}
+
+async fn handle_reaction(
+    reaction: &MessageReactionUpdated,
+    command_dispatcher: Arc<CommandDispatcher>,
+) -> Result<()> {
+    // Private chats have the same id as the user.
+    let tg_user = match &reaction.user {
+        Some(tg_user) if tg_user.id == reaction.chat.id => tg_user,
+        _ => return Ok(()),
+    };
+    let emoji = match reaction.emoji() {
+        Some(emoji) => emoji.map(str::to_string),
+        None => return Ok(()),
+    };
+    let user = User::try_from(tg_user).context("failed to parse user")?;
+    command_dispatcher
+        .handle_reaction(&user.login, reaction.message_id, emoji)
+        .await
+}
+
+async fn delete_webhook_if_set(bot: &AutoSend<Bot>) {
+    match bot.get_webhook_info().await {
+        Ok(info) if !info.url.is_empty() => {
+            if let Err(err) = bot.delete_webhook().await {
+                error!("failed to delete webhook: {}", err);
+            }
+        }
+        Ok(_) => {}
+        Err(err) => error!("failed to get webhook info: {}", err),
+    }
+}
+
+/// Cuts off a record torn by a crash, so that new events are not appended to
+/// it. A log that turns out empty is started anew in `new_format`, otherwise
+/// it is kept in its own format and must be at the current version; JSON logs
+/// are made to end with a newline. Leaves the file positioned at its end and
+/// returns the format to append in.
+fn repair_event_log_tail(
+    file: &mut fs::File,
+    torn_tail: Option<u64>,
+    header: LogHeader,
+    new_format: LogFormat,
+) -> Result<LogFormat> {
+    if let Some(offset) = torn_tail {
+        warn!(
+            "truncating event log at offset {} to drop a torn record",
+            offset
+        );
+        file.set_len(offset)?;
+    }
+    let len = file.seek(SeekFrom::End(0))?;
+    if len == 0 {
+        file.write_all(&LogHeader::current(new_format).to_bytes())?;
+        return Ok(new_format);
+    }
+    let format = header.format;
+    ensure!(
+        header == LogHeader::current(format),
+        "event log is at version {}, rewrite it with --migrate first",
+        header.version
+    );
+    if format != new_format {
+        warn!(
+            "event log is in {} format, ignoring --event-log-format {}",
+            format, new_format
+        );
+    }
+    if format == LogFormat::Json {
+        let mut last = [0];
+        file.seek(SeekFrom::End(-1))?;
+        file.read_exact(&mut last)?;
+        if last[0] != b'\n' {
+            file.write_all(b"\n")?;
+        }
+    }
+    Ok(format)
+}
+
+/// Rewrites the event log at the current version. The rewritten log has to
+/// restore the same state as the old one before it replaces it; the old one is
+/// kept with its version appended to the name.
+fn migrate_event_log_file(path: &Path) -> Result<()> {
+    ensure!(
+        log_segments(path)?.is_empty(),
+        "a rotated event log cannot be migrated, replace it with a snapshot first"
+    );
+    let file = fs::File::open(path).context("failed to open event log file")?;
+    let old = CommandDispatcherBuilder::from_event_log(BufReader::new(file), false)
+        .context("failed to restore state from event log")?;
+    let header = old.event_log_header();
+    if header == LogHeader::current(header.format) {
+        info!("Event log is already at the current version");
+        return Ok(());
+    }
+
+    let migrated_path = path_with_suffix(path, ".migrated");
+    let migrated = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&migrated_path)
+        .context("failed to create migrated event log")?;
+    let mut writer = BufWriter::new(migrated);
+    let file = fs::File::open(path).context("failed to open event log file")?;
+    let count = migrate_event_log(BufReader::new(file), &mut writer)
+        .context("failed to migrate event log")?;
+    writer
+        .into_inner()
+        .map_err(|err| err.into_error())
+        .and_then(|file| file.sync_data())
+        .context("failed to sync migrated event log")?;
+
+    let file = fs::File::open(&migrated_path).context("failed to open migrated event log")?;
+    let new = CommandDispatcherBuilder::from_event_log(BufReader::new(file), false)
+        .context("failed to restore state from migrated event log")?;
+    ensure!(
+        serde_json::to_value(old.snapshot())? == serde_json::to_value(new.snapshot())?,
+        "migrated event log at {} restores a different state",
+        migrated_path.display()
+    );
+
+    let backup_path = path_with_suffix(path, &format!(".v{}", header.version));
+    fs::rename(path, &backup_path).context("failed to back up event log")?;
+    fs::rename(&migrated_path, path).context("failed to replace event log")?;
+    info!(
+        "Migrated {} records of event log from version {}, the old log is at {}",
+        count,
+        header.version,
+        backup_path.display()
+    );
+    Ok(())
+}
+
+fn path_with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+/// Restores the state from the storage and opens the storage for appending.
+fn open_storage(args: &Args) -> Result<(CommandDispatcherBuilder, Box<dyn EventStore>)> {
+    match args.storage() {
+        Storage::File(path) => {
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .read(true)
+                .write(true)
+                .open(&path)
+                .context("failed to open event log file")?;
+
+            let segments = remove_superseded_segments(&path)?;
+            let builder = CommandDispatcherBuilder::from_rotated_event_log(
+                &segments,
+                BufReader::new(&mut file),
+                args.skip_corrupt_events,
+            )
+            .context("failed to restore state from event log")?;
+            let format = repair_event_log_tail(
+                &mut file,
+                builder.torn_tail(),
+                builder.event_log_header(),
+                args.event_log_format,
+            )
+            .context("failed to repair the end of event log")?;
+            let mut store = FileEventStore::new(file, format, args.durability);
+            if let Some(max_bytes) = args.rotate_bytes {
+                store = store.rotate(&path, max_bytes)?;
+            }
+            Ok((builder, Box::new(store)))
+        }
+        Storage::Sqlite(path) => {
+            let mut store = SqliteEventStore::open(&path)?;
+            let builder = CommandDispatcherBuilder::from_event_store(&mut store)
+                .context("failed to restore state from event database")?;
+            Ok((builder, Box::new(store)))
+        }
+    }
+}
+
+fn launch_event_service<S: EventStore + 'static>(
+    store: S,
+    config: BatchConfig,
+) -> EventServiceHandle {
+    let (mut service, handle) = EventService::new(store, config);
     thread::spawn(move || {
+        // Batching waits for more events with a timer.
         let runtime = runtime::Builder::new_current_thread()
+            .enable_time()
             .build()
             .expect("failed to build tokio runtime");
         runtime.block_on(async move {
@@ -60,35 +471,160 @@ async fn main() -> Result<()> {
     let args = Args::parse();
 
     teloxide::enable_logging!();
+
+    if args.migrate {
+        return migrate_event_log_file(&args.event_log_file()?);
+    }
+
+    if let Some(path) = &args.snapshot_to {
+        let event_log_path = args.event_log_file()?;
+        let file = fs::File::open(&event_log_path).context("failed to open event log file")?;
+        let builder = CommandDispatcherBuilder::from_rotated_event_log(
+            &live_segments(&event_log_path)?,
+            BufReader::new(file),
+            args.skip_corrupt_events,
+        )
+        .context("failed to restore state from event log")?;
+        builder.snapshot_to(path, args.event_log_format)?;
+        info!("Wrote snapshot to {}", path.display());
+        return Ok(());
+    }
+
     info!("Starting lovebot...");
 
     let bot = Bot::from_env().auto_send();
+    let bot_username: Arc<str> = bot
+        .get_me()
+        .await
+        .context("failed to get bot info")?
+        .user
+        .username
+        .context("bot has no username")?
+        .into();
 
-    let mut file = fs::OpenOptions::new()
-        .create(true)
-        .read(true)
-        .write(true)
-        .open(&args.event_log)
-        .context("failed to open event log file")?;
-
-    let builder = CommandDispatcherBuilder::from_event_log(BufReader::new(&mut file))
-        .context("failed to restore state from event log")?;
-    let event_service = launch_event_service(BufWriter::new(file));
+    let (mut builder, store) = open_storage(&args)?;
+    builder.set_random_queue_timeout(Duration::from_secs(args.random_queue_timeout));
+    builder.set_voice_limits(args.max_voice_duration, args.max_voice_size);
+    builder.set_video_limits(args.max_video_duration, args.max_video_size);
+    builder.set_max_audio_size(args.max_audio_size);
+    builder.set_max_document_size(args.max_document_size);
+    builder.set_max_scrubbed_photo_size(args.max_scrubbed_photo_size);
+    let mut media_policy = MediaPolicy::default();
+    for category in &args.disabled_media {
+        media_policy
+            .set(category, "off")
+            .context("invalid --disabled-media")?;
+    }
+    builder.set_media_policy(media_policy);
+    builder.set_storage(args.storage());
+    let event_service = launch_event_service(store, args.batch_config());
     let command_dispatcher = Arc::new(builder.build(bot.clone(), event_service));
 
-    teloxide::repl(bot, move |cx| {
-        let command_dispatcher = command_dispatcher.clone();
-        async move {
-            debug!("Incoming: {:?}", cx.update);
-            if let Err(err) = handle_message(&cx, command_dispatcher).await {
-                let message = format!("Error: {:#}.", err);
-                debug!("Sending reply to chat {}: {}", cx.chat_id(), message);
-                cx.answer(message).await?;
-            }
-            respond(())
+    if let Err(err) = bot.set_my_commands(Command::bot_commands()).await {
+        warn!("failed to register bot commands: {}", err);
+    }
+
+    let leave_groups = args.leave_groups;
+    let rejected_chats = Arc::new(Mutex::new(HashSet::new()));
+    let edit_command_dispatcher = command_dispatcher.clone();
+    let edit_bot_username = bot_username.clone();
+    let poll_command_dispatcher = command_dispatcher.clone();
+    let callback_command_dispatcher = command_dispatcher.clone();
+
+    let (reaction_sender, mut reaction_receiver) = mpsc::unbounded_channel();
+    let reaction_command_dispatcher = command_dispatcher.clone();
+    tokio::spawn(async move {
+        while let Some(reaction) = reaction_receiver.recv().await {
+            let command_dispatcher = reaction_command_dispatcher.clone();
+            tokio::spawn(async move {
+                debug!("Incoming reaction: {:?}", reaction);
+                // Reactions are best effort, nobody is told about failures.
+                if let Err(err) = handle_reaction(&reaction, command_dispatcher).await {
+                    debug!("failed to handle reaction: {:#}", err);
+                }
+            });
         }
-    })
-    .await;
+    });
+    delete_webhook_if_set(&bot).await;
+    let update_listener = polling_with_reactions(bot.inner().clone(), reaction_sender);
+
+    Dispatcher::new(bot)
+        .messages_handler(
+            move |mut rx: DispatcherHandlerRx<AutoSend<Bot>, Message>| async move {
+                while let Some(cx) = rx.recv().await {
+                    if !is_private_chat(&cx.update) {
+                        reject_group_chat(&cx, &rejected_chats, leave_groups).await;
+                        continue;
+                    }
+                    let command_dispatcher = command_dispatcher.clone();
+                    let bot_username = bot_username.clone();
+                    tokio::spawn(async move {
+                        debug!("Incoming: {:?}", cx.update);
+                        if let Err(err) =
+                            handle_message(&cx, command_dispatcher, &bot_username).await
+                        {
+                            reply_with_error(&cx, err).await;
+                        }
+                    });
+                }
+            },
+        )
+        .edited_messages_handler(
+            move |mut rx: DispatcherHandlerRx<AutoSend<Bot>, Message>| async move {
+                while let Some(cx) = rx.recv().await {
+                    if !is_private_chat(&cx.update) {
+                        continue;
+                    }
+                    let command_dispatcher = edit_command_dispatcher.clone();
+                    let bot_username = edit_bot_username.clone();
+                    tokio::spawn(async move {
+                        debug!("Incoming edit: {:?}", cx.update);
+                        if let Err(err) =
+                            handle_edited_message(&cx, command_dispatcher, &bot_username).await
+                        {
+                            reply_with_error(&cx, err).await;
+                        }
+                    });
+                }
+            },
+        )
+        .poll_answers_handler(
+            move |mut rx: DispatcherHandlerRx<AutoSend<Bot>, PollAnswer>| async move {
+                while let Some(cx) = rx.recv().await {
+                    let command_dispatcher = poll_command_dispatcher.clone();
+                    tokio::spawn(async move {
+                        debug!("Incoming poll answer: {:?}", cx.update);
+                        if let Err(err) = handle_poll_answer(&cx, command_dispatcher).await {
+                            error!("failed to handle poll answer: {:#}", err);
+                        }
+                    });
+                }
+            },
+        )
+        .callback_queries_handler(
+            move |mut rx: DispatcherHandlerRx<AutoSend<Bot>, CallbackQuery>| async move {
+                while let Some(cx) = rx.recv().await {
+                    let command_dispatcher = callback_command_dispatcher.clone();
+                    tokio::spawn(async move {
+                        debug!("Incoming callback query: {:?}", cx.update);
+                        let mut answer = cx.requester.answer_callback_query(cx.update.id.clone());
+                        if let Err(err) = handle_callback_query(&cx, command_dispatcher).await {
+                            answer = answer.text(format!("Error: {:#}.", err));
+                        }
+                        // Stops the loading indicator on the button.
+                        if let Err(err) = answer.await {
+                            error!("failed to answer callback query: {}", err);
+                        }
+                    });
+                }
+            },
+        )
+        .setup_ctrlc_handler()
+        .dispatch_with_listener(
+            update_listener,
+            LoggingErrorHandler::with_custom_text("failed to get updates"),
+        )
+        .await;
 
     Ok(())
 }
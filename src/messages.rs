@@ -0,0 +1,1155 @@
+use anyhow::{bail, Result};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::command::{ALIASES, COMMANDS};
+
+use std::fmt::{Display, Write};
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Language {
+    #[default]
+    En,
+    Ru,
+}
+
+impl Language {
+    pub fn from_code(code: &str) -> Result<Self> {
+        match code.to_lowercase().as_str() {
+            "en" => Ok(Language::En),
+            "ru" => Ok(Language::Ru),
+            _ => bail!("unsupported language: {} (available: en, ru)", code),
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Language::En => "en",
+            Language::Ru => "ru",
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Start,
+    Stop,
+    Help,
+    BotStopped,
+    NoThreads,
+    NoArchivedThreads,
+    NoBans,
+    BroadcastFinished,
+    LanguageChanged,
+    MalformedCommand,
+    On,
+    ThreadPinned,
+    ThreadUnpinned,
+    Reminder,
+    BanExpired,
+    ConfirmationExpired,
+    RandomRequestExpired,
+    ThreadRequestExpired,
+    ThreadExpired,
+    ThreadIdExpanded,
+    ThreadClosed,
+    ThreadBanned,
+    ConfirmCloseThread,
+    ConfirmCloseOnlyThread,
+    ConfirmCloseAll,
+    ConfirmBan,
+    ConfirmBanOnlyPartner,
+    ConfirmBroadcastAll,
+    ConfirmBroadcastActive,
+    ConfirmBroadcastUsers,
+    ConfirmLocation,
+    ConfirmVoice,
+    ConfirmButton,
+    CancelButton,
+    ConfirmationCancelled,
+    ConfirmationConfirmed,
+    AvailableUsers,
+    NobodyToWriteTo,
+    PickUserTruncated,
+    PickUser,
+    ReplyToWrite,
+    NoUsersFound,
+    FoundUsersTruncated,
+    FoundUsers,
+    NobodyActive,
+    ActiveUsers,
+    ThreadSlowModeMark,
+    ThreadSecretMark,
+    ThreadExpiryMark,
+    NoThreadsLabeled,
+    ActiveThreads,
+    ThreadLabeled,
+    LabelRemoved,
+    NoLabels,
+    Labels,
+    AnonymityMe,
+    AnonymityThem,
+    AnonymityBoth,
+    Unknown,
+    ThreadInfoStarted,
+    ThreadInfoAnonymity,
+    ThreadInfoMessagesSent,
+    ThreadInfoMessagesReceived,
+    ThreadInfoPinned,
+    ThreadInfoArchived,
+    ThreadInfoReceipts,
+    ThreadInfoLinkPreviews,
+    ThreadInfoPriority,
+    ThreadInfoSlowMode,
+    ThreadInfoSecret,
+    ThreadInfoExpires,
+    ThreadInfoNote,
+    ThreadInfo,
+    ArchivedThreads,
+    MatchedByTopic,
+    TopicNotMatched,
+    AnonymousThreadStarted,
+    RandomQueued,
+    RandomQueueLeft,
+    SendManySent,
+    SendManyReport,
+    MessageScheduled,
+    NoScheduledMessages,
+    ScheduledMessages,
+    ScheduledMessageLine,
+    ScheduledMessageCancelled,
+    ScheduledMessageFailed,
+    RoomCreated,
+    RoomJoined,
+    RoomLeft,
+    SentToRoom,
+    PollSent,
+    DiceRolled,
+    ReminderSet,
+    NoReminders,
+    Reminders,
+    ReminderLine,
+    ReminderCancelled,
+    Error,
+    DefaultThreadSet,
+    DefaultThreadCleared,
+    LastMessageEdited,
+    LastMessageDeleted,
+    Note,
+    NoNote,
+    NoteCleared,
+    NoteSaved,
+    ReceiptsEnabled,
+    ReceiptsDisabled,
+    LinkPreviewsShown,
+    LinkPreviewsHidden,
+    SecretDisabled,
+    SecretEnabled,
+    ThreadReopened,
+    BanLifted,
+    ThreadExpirySet,
+    ThreadExpiryCleared,
+    ThreadsClosed,
+    ThreadsClosedWithFailures,
+    ThreadAccepted,
+    ThreadDeclined,
+    BanInfo,
+    ThreadPrioritized,
+    ThreadDeprioritized,
+    ThreadPaused,
+    ThreadResumed,
+    SlowModeDisabled,
+    SlowModeSet,
+    ThreadArchived,
+    ThreadRestored,
+    BannedThreads,
+    BlockedUsers,
+    UserBlocked,
+    UserUnblocked,
+    BroadcastStarted,
+    BroadcastFailed,
+    Settings,
+    SettingUpdated,
+    MediaPolicy,
+    MediaPolicyUpdated,
+    CurrentTopic,
+    NoTopic,
+    TopicSet,
+    TopicCleared,
+    NoInterests,
+    Interests,
+    InterestsCleared,
+    InterestsUpdated,
+    MaxIncomingSet,
+    MaxIncomingCleared,
+    IncomingOfMax,
+    IncomingUnlimited,
+    Off,
+    Status,
+    DndSet,
+    DndDisabled,
+    GreetingSet,
+    GreetingCleared,
+    Profile,
+    Missing,
+    ProfileNameChanged,
+    ProfileUpdated,
+    ForgetWarning,
+    Forgotten,
+    Stats,
+    BotStatus,
+    ThreadRequested,
+    ThreadClosedByOther,
+    ThreadReopenedByOther,
+    PollFrom,
+    PollFromAnonymous,
+    PollFromRandomChat,
+    DiceFrom,
+    DiceFromAnonymous,
+    DiceFromRandomChat,
+    MessageFromRoom,
+    ThreadPausedByOther,
+    ThreadResumedByOther,
+    Seen,
+    PollVoteRetracted,
+    PollVoted,
+    DndOver,
+    DndDropped,
+    VideoNoteAttribution,
+    StickerAttribution,
+    ContactAttribution,
+    VenueAttribution,
+    LocationAttribution,
+    InReplyTo,
+    MessageFrom,
+    MessageFromAnonymous,
+    MessageFromRandomChat,
+    BanExpiresIn,
+    BanPermanent,
+}
+
+pub fn get(language: Language, key: Key) -> &'static str {
+    match language {
+        Language::En => english(key),
+        Language::Ru => russian(key),
+    }
+}
+
+/// Fills the placeholders in the text of `key` with `args`: `{}` takes the
+/// next argument and `{0}` the argument with that index.
+pub fn format(language: Language, key: Key, args: &[&(dyn Display + Sync)]) -> String {
+    let mut result = String::new();
+    let mut rest = get(language, key);
+    let mut next_index = 0;
+    while let Some(start) = rest.find('{') {
+        let end = start + rest[start..].find('}').expect("unclosed placeholder");
+        let index = match &rest[start + 1..end] {
+            "" => {
+                next_index += 1;
+                next_index - 1
+            }
+            index => index.parse().expect("malformed placeholder"),
+        };
+        result.push_str(&rest[..start]);
+        write!(result, "{}", args[index]).expect("writing to a string failed");
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    result
+}
+
+fn english(key: Key) -> &'static str {
+    match key {
+        Key::Start => EN_START,
+        Key::Stop => "The bot is stopped. Use `/start` to restart it.",
+        Key::Help => help(Language::En),
+        Key::BotStopped => "you have stopped the bot. Use `/start` to restart it",
+        Key::NoThreads => "There are no active threads.",
+        Key::NoArchivedThreads => "There are no archived threads.",
+        Key::NoBans => "You have not banned anybody.",
+        Key::BroadcastFinished => "Broadcast is finished. Delivered: {}, failed: {}, skipped: {}.",
+        Key::LanguageChanged => "Language has been changed to English.",
+        Key::MalformedCommand => "malformed command, expected",
+        Key::On => "on",
+        Key::ThreadPinned => "Thread {} has been pinned.",
+        Key::ThreadUnpinned => "Thread {} has been unpinned.",
+        Key::Reminder => "⏰ Reminder: {}",
+        Key::BanExpired => "The ban of {} has expired.",
+        Key::ConfirmationExpired => "Expired.",
+        Key::RandomRequestExpired => {
+            "Nobody has shown up in time, so your `/random` request has expired."
+        }
+        Key::ThreadRequestExpired => "Thread request {} has expired.",
+        Key::ThreadExpired => "Thread {} has expired and has been closed.",
+        Key::ThreadIdExpanded => "Using {} for {}.",
+        Key::ThreadClosed => "Thread {} has been closed.",
+        Key::ThreadBanned => "Thread {} has been banned.",
+        Key::ConfirmCloseThread => "Close thread {}?",
+        Key::ConfirmCloseOnlyThread => "Close your only thread?",
+        Key::ConfirmCloseAll => "Close all threads?",
+        Key::ConfirmBan => "Ban {}?",
+        Key::ConfirmBanOnlyPartner => "Ban your only anonymous partner?",
+        Key::ConfirmBroadcastAll => "Send the broadcast to all users?",
+        Key::ConfirmBroadcastActive => "Send the broadcast to active users?",
+        Key::ConfirmBroadcastUsers => "Send the broadcast to {}?",
+        Key::ConfirmLocation => {
+            "Share this location with {}? You will not be asked again for this thread."
+        }
+        Key::ConfirmVoice => {
+            "Voice messages reveal your real voice to the other side. Send it anyway? You will \
+             not be asked again."
+        }
+        Key::ConfirmButton => "Confirm",
+        Key::CancelButton => "Cancel",
+        Key::ConfirmationCancelled => "Cancelled.",
+        Key::ConfirmationConfirmed => "Confirmed.",
+        Key::AvailableUsers => "Available users:\n* {}",
+        Key::NobodyToWriteTo => "There is nobody to write to yet.",
+        Key::PickUserTruncated => {
+            "Pick a user to write to (showing first {} of {}, use `/find` to narrow down):"
+        }
+        Key::PickUser => "Pick a user to write to:",
+        Key::ReplyToWrite => "Reply to this message to write to {}.",
+        Key::NoUsersFound => "No users found.",
+        Key::FoundUsersTruncated => "Found {} users, showing first {}:\n* {}",
+        Key::FoundUsers => "Found {} users:\n* {}",
+        Key::NobodyActive => "Nobody has been active in the last 24 hours.",
+        Key::ActiveUsers => "Users active in the last 24 hours:\n* {}",
+        Key::ThreadSlowModeMark => " (slow mode: {})",
+        Key::ThreadSecretMark => " (secret: {})",
+        Key::ThreadExpiryMark => " (expires in {})",
+        Key::NoThreadsLabeled => "There are no threads labeled {}.",
+        Key::ActiveThreads => "Active threads:\n* {}",
+        Key::ThreadLabeled => "Thread {} is labeled {}.",
+        Key::LabelRemoved => "Label {} is removed from {}.",
+        Key::NoLabels => "You have not labeled any threads.",
+        Key::Labels => "Labels:\n* {}",
+        Key::AnonymityMe => "you are anonymous",
+        Key::AnonymityThem => "they are anonymous",
+        Key::AnonymityBoth => "both anonymous",
+        Key::Unknown => "unknown",
+        Key::ThreadInfoStarted => "started: {}",
+        Key::ThreadInfoAnonymity => "anonymity: {}",
+        Key::ThreadInfoMessagesSent => "messages sent: {}",
+        Key::ThreadInfoMessagesReceived => "messages received: {}",
+        Key::ThreadInfoPinned => "pinned: {}",
+        Key::ThreadInfoArchived => "archived: {}",
+        Key::ThreadInfoReceipts => "read receipts: {}",
+        Key::ThreadInfoLinkPreviews => "link previews: {}",
+        Key::ThreadInfoPriority => "priority: {}",
+        Key::ThreadInfoSlowMode => "slow mode: {}",
+        Key::ThreadInfoSecret => "secret: {}",
+        Key::ThreadInfoExpires => "expires: {}",
+        Key::ThreadInfoNote => "note: {}",
+        Key::ThreadInfo => "Thread {}:\n* {}",
+        Key::ArchivedThreads => "Archived threads:\n* {}",
+        Key::MatchedByTopic => "Matched by topic \"{}\".",
+        Key::TopicNotMatched => {
+            "Nobody with topic \"{}\" is available, so your message went to a random user."
+        }
+        Key::AnonymousThreadStarted => "Started a new anonymous thread {}.",
+        Key::RandomQueued => {
+            "There is nobody to chat with right now. You have been placed in the waiting queue \
+             for {} and will be matched with the next user who uses `/random`. Use `/cancel` to \
+             leave the queue."
+        }
+        Key::RandomQueueLeft => "You have left the `/random` queue.",
+        Key::SendManySent => "{}: sent",
+        Key::SendManyReport => "Sent to {} of {} receivers:\n* {}",
+        Key::MessageScheduled => "The message to {} will be sent in {}.",
+        Key::NoScheduledMessages => "You have no scheduled messages.",
+        Key::ScheduledMessages => "Scheduled messages:\n{}",
+        Key::ScheduledMessageLine => "{}. {} at {}: {}",
+        Key::ScheduledMessageCancelled => "The scheduled message to {} has been cancelled.",
+        Key::ScheduledMessageFailed => "Failed to send the scheduled message to {}: {}\n\n{}",
+        Key::RoomCreated => "Room {} has been created. Others can join it with `/join {}`.",
+        Key::RoomJoined => "You have joined {} as participant-{}.",
+        Key::RoomLeft => "You have left {}.",
+        Key::SentToRoom => "✓ sent to {} members of {}",
+        Key::PollSent => "The poll has been sent to {}.",
+        Key::DiceRolled => "{} You rolled {} in {}.",
+        Key::ReminderSet => "I will remind you in {}.",
+        Key::NoReminders => "You have no reminders.",
+        Key::Reminders => "Reminders:\n{}",
+        Key::ReminderLine => "{}. at {}: {}",
+        Key::ReminderCancelled => "The reminder has been cancelled.",
+        Key::Error => "Error: {}.",
+        Key::DefaultThreadSet => "Messages without a command will be sent to {}.",
+        Key::DefaultThreadCleared => {
+            "Messages without a command will be sent to the last used thread."
+        }
+        Key::LastMessageEdited => "Your last message to {} has been edited.",
+        Key::LastMessageDeleted => "Your last message to {}{} has been deleted.",
+        Key::Note => "Note for {}:\n{}",
+        Key::NoNote => "There is no note for {}.",
+        Key::NoteCleared => "Note for {} has been cleared.",
+        Key::NoteSaved => "Note for {} has been saved.",
+        Key::ReceiptsEnabled => {
+            "Read receipts are enabled for {}. They only work if the other side enables them too."
+        }
+        Key::ReceiptsDisabled => "Read receipts are disabled for {}.",
+        Key::LinkPreviewsShown => "Link previews are shown for {}.",
+        Key::LinkPreviewsHidden => "Link previews are hidden for {}.",
+        Key::SecretDisabled => "Disappearing messages are disabled for {}.",
+        Key::SecretEnabled => "Your messages in {} will disappear after {}.",
+        Key::ThreadReopened => "Thread {} has been reopened.",
+        Key::BanLifted => "Ban from {} has been lifted.",
+        Key::ThreadExpirySet => "Thread {} will be closed in {}.",
+        Key::ThreadExpiryCleared => "Thread {} will no longer expire.",
+        Key::ThreadsClosed => "Closed {} threads.",
+        Key::ThreadsClosedWithFailures => "Closed {} threads, failed to close {}:\n* {}",
+        Key::ThreadAccepted => "Thread {} has been accepted.",
+        Key::ThreadDeclined => "Thread {} has been declined.",
+        Key::BanInfo => "Ban of {}:\n* banned: {}\n* term: {}\n* reason: {}",
+        Key::ThreadPrioritized => {
+            "Thread {} is now a priority thread. Its messages will reach you even during \
+             do-not-disturb."
+        }
+        Key::ThreadDeprioritized => "Thread {} is no longer a priority thread.",
+        Key::ThreadPaused => "Thread {} is paused. Use `/resume {}` to continue.",
+        Key::ThreadResumed => "Thread {} has been resumed.",
+        Key::SlowModeDisabled => "Slow mode is disabled for {}.",
+        Key::SlowModeSet => "Slow mode for {} is set to {}.",
+        Key::ThreadArchived => "Thread {} has been archived. Use `/unarchive {}` to restore it.",
+        Key::ThreadRestored => "Thread {} has been restored.",
+        Key::BannedThreads => "Banned threads:\n* {}",
+        Key::BlockedUsers => "Blocked users:\n* {}",
+        Key::UserBlocked => "User @{} has been blocked.",
+        Key::UserUnblocked => "User @{} has been unblocked.",
+        Key::BroadcastStarted => "Starting broadcast to {} users...",
+        Key::BroadcastFailed => "Failed to send broadcast to user @{}: {}",
+        Key::Settings => "Your settings:\n{}\n\nUse `/settings [key] [value]` to change a setting.",
+        Key::SettingUpdated => "Setting {} has been updated.",
+        Key::MediaPolicy => "Media policy:\n{}",
+        Key::MediaPolicyUpdated => "Media policy rule {} has been updated.",
+        Key::CurrentTopic => "Your current topic: {}",
+        Key::NoTopic => "You have no topic set. Use `/topic [topic]` to set one.",
+        Key::TopicSet => {
+            "Your topic is now \"{}\". Others can find you with `/random {} [message]`."
+        }
+        Key::TopicCleared => "Your topic has been cleared.",
+        Key::NoInterests => "You have no interests set. Use `/interests [tags]` to set them.",
+        Key::Interests => "Your interests: #{}",
+        Key::InterestsCleared => "Your interests have been cleared.",
+        Key::InterestsUpdated => "Your interests have been updated: #{}",
+        Key::MaxIncomingSet => "You will accept at most {} incoming anonymous threads.",
+        Key::MaxIncomingCleared => "Incoming anonymous threads are no longer limited.",
+        Key::IncomingOfMax => "{} of {}",
+        Key::IncomingUnlimited => "{} (no limit)",
+        Key::Off => "off",
+        Key::Status => {
+            "Status:\n* incoming threads: {}\n* do-not-disturb: {}\n* default thread: {}\n* \
+             waiting for `/random`: {}"
+        }
+        Key::DndSet => {
+            "Do-not-disturb is set to {}. Incoming messages will be held back during this time."
+        }
+        Key::DndDisabled => "Do-not-disturb is disabled.",
+        Key::GreetingSet => {
+            "Greeting has been set. It will be sent to everyone who starts a new thread with you."
+        }
+        Key::GreetingCleared => "Greeting has been cleared.",
+        Key::Profile => {
+            "Your profile:\n* login: @{}\n* first name: {}\n* last name: {}\n* chat id: {}\n* \
+             first connected: {}\n* listed in /users: {}"
+        }
+        Key::Missing => "none",
+        Key::ProfileNameChanged => {
+            "\n\nYour Telegram name is now \"{}\". Use `/whoami refresh` to update it."
+        }
+        Key::ProfileUpdated => "Your profile has been updated.",
+        Key::ForgetWarning => {
+            "This will close all your threads and remove you from the bot. Send `/forget confirm` \
+             to proceed."
+        }
+        Key::Forgotten => "You have been forgotten. Send any message to start over.",
+        Key::Stats => {
+            "Your statistics:\n* threads started: {}\n* messages sent: {}\n* messages received: \
+             {}\n* users banned: {}"
+        }
+        Key::BotStatus => {
+            "lovebot v{}\n* uptime: {}\n* registered users: {}\n* events written since startup: \
+             {}"
+        }
+        Key::ThreadRequested => {
+            "Someone wants to start an anonymous thread {0}. Use `/accept {0}` or `/decline {0}`."
+        }
+        Key::ThreadClosedByOther => "Thread {} has been closed by the other side.",
+        Key::ThreadReopenedByOther => "Thread {} has been reopened by the other side.",
+        Key::PollFrom => ">>> Poll from {}:",
+        Key::PollFromAnonymous => ">>> Poll from anonymous {}:",
+        Key::PollFromRandomChat => ">>> Poll from random chat {}:",
+        Key::DiceFrom => ">>> Dice from {}:",
+        Key::DiceFromAnonymous => ">>> Dice from anonymous {}:",
+        Key::DiceFromRandomChat => ">>> Dice from random chat {}:",
+        Key::MessageFromRoom => ">>> Message from {} in {}:\n{}",
+        Key::ThreadPausedByOther => "The other side paused thread {}.",
+        Key::ThreadResumedByOther => "The other side resumed thread {}.",
+        Key::Seen => "👀 seen by {}",
+        Key::PollVoteRetracted => "{} has retracted their vote in the poll \"{}\".",
+        Key::PollVoted => "{} has voted in the poll \"{}\": {}",
+        Key::DndOver => "Do-not-disturb is over. {} updates arrived while you were away.",
+        Key::DndDropped => " The oldest {} of them were dropped.",
+        Key::VideoNoteAttribution => "(video note)",
+        Key::StickerAttribution => "(sticker)",
+        Key::ContactAttribution => "(contact)",
+        Key::VenueAttribution => "(venue)",
+        Key::LocationAttribution => "(location)",
+        Key::InReplyTo => ">>> In reply to: '{}'\n{}",
+        Key::MessageFrom => ">>> Message from {}:\n{}",
+        Key::MessageFromAnonymous => ">>> Message from anonymous {}:\n{}",
+        Key::MessageFromRandomChat => ">>> Message from random chat {}:\n{}",
+        Key::BanExpiresIn => "expires in {}",
+        Key::BanPermanent => "permanent",
+    }
+}
+
+fn russian(key: Key) -> &'static str {
+    match key {
+        Key::Start => RU_START,
+        Key::Stop => "Бот остановлен. Используйте `/start`, чтобы запустить его снова.",
+        Key::Help => help(Language::Ru),
+        Key::BotStopped => "вы остановили бота. Используйте `/start`, чтобы запустить его снова",
+        Key::NoThreads => "У вас нет активных тредов.",
+        Key::NoArchivedThreads => "У вас нет архивных тредов.",
+        Key::NoBans => "Вы никого не забанили.",
+        Key::BroadcastFinished => {
+            "Рассылка завершена. Доставлено: {}, не доставлено: {}, пропущено: {}."
+        }
+        Key::LanguageChanged => "Язык изменён на русский.",
+        Key::MalformedCommand => "неверный формат команды, ожидается",
+        Key::On => "вкл",
+        Key::ThreadPinned => "Тред {} закреплён.",
+        Key::ThreadUnpinned => "Тред {} откреплён.",
+        Key::Reminder => "⏰ Напоминание: {}",
+        Key::BanExpired => "Бан {} истёк.",
+        Key::ConfirmationExpired => "Истекло.",
+        Key::RandomRequestExpired => {
+            "Никто не появился вовремя, поэтому ваш запрос `/random` истёк."
+        }
+        Key::ThreadRequestExpired => "Запрос треда {} истёк.",
+        Key::ThreadExpired => "Тред {} истёк и был закрыт.",
+        Key::ThreadIdExpanded => "{} используется вместо {}.",
+        Key::ThreadClosed => "Тред {} закрыт.",
+        Key::ThreadBanned => "Тред {} забанен.",
+        Key::ConfirmCloseThread => "Закрыть тред {}?",
+        Key::ConfirmCloseOnlyThread => "Закрыть ваш единственный тред?",
+        Key::ConfirmCloseAll => "Закрыть все треды?",
+        Key::ConfirmBan => "Забанить {}?",
+        Key::ConfirmBanOnlyPartner => "Забанить вашего единственного анонимного собеседника?",
+        Key::ConfirmBroadcastAll => "Отправить рассылку всем пользователям?",
+        Key::ConfirmBroadcastActive => "Отправить рассылку активным пользователям?",
+        Key::ConfirmBroadcastUsers => "Отправить рассылку {}?",
+        Key::ConfirmLocation => {
+            "Отправить это местоположение {}? Для этого треда я больше не буду спрашивать."
+        }
+        Key::ConfirmVoice => {
+            "Голосовые сообщения выдают собеседнику ваш настоящий голос. Всё равно отправить? Я \
+             больше не буду спрашивать."
+        }
+        Key::ConfirmButton => "Подтвердить",
+        Key::CancelButton => "Отмена",
+        Key::ConfirmationCancelled => "Отменено.",
+        Key::ConfirmationConfirmed => "Подтверждено.",
+        Key::AvailableUsers => "Доступные пользователи:\n* {}",
+        Key::NobodyToWriteTo => "Пока что некому написать.",
+        Key::PickUserTruncated => {
+            "Выберите, кому написать (показаны первые {} из {}, чтобы сузить список, используйте \
+             `/find`):"
+        }
+        Key::PickUser => "Выберите, кому написать:",
+        Key::ReplyToWrite => "Ответьте на это сообщение, чтобы написать {}.",
+        Key::NoUsersFound => "Никого не найдено.",
+        Key::FoundUsersTruncated => "Найдено пользователей: {}, показаны первые {}:\n* {}",
+        Key::FoundUsers => "Найдено пользователей: {}\n* {}",
+        Key::NobodyActive => "За последние 24 часа никто не был активен.",
+        Key::ActiveUsers => "Активны за последние 24 часа:\n* {}",
+        Key::ThreadSlowModeMark => " (медленный режим: {})",
+        Key::ThreadSecretMark => " (исчезающие сообщения: {})",
+        Key::ThreadExpiryMark => " (закроется через {})",
+        Key::NoThreadsLabeled => "Нет тредов с меткой {}.",
+        Key::ActiveThreads => "Активные треды:\n* {}",
+        Key::ThreadLabeled => "Тред {} помечен меткой {}.",
+        Key::LabelRemoved => "Метка {} снята с {}.",
+        Key::NoLabels => "Вы не пометили ни одного треда.",
+        Key::Labels => "Метки:\n* {}",
+        Key::AnonymityMe => "вы анонимны",
+        Key::AnonymityThem => "собеседник анонимен",
+        Key::AnonymityBoth => "оба анонимны",
+        Key::Unknown => "неизвестно",
+        Key::ThreadInfoStarted => "начат: {}",
+        Key::ThreadInfoAnonymity => "анонимность: {}",
+        Key::ThreadInfoMessagesSent => "отправлено сообщений: {}",
+        Key::ThreadInfoMessagesReceived => "получено сообщений: {}",
+        Key::ThreadInfoPinned => "закреплён: {}",
+        Key::ThreadInfoArchived => "в архиве: {}",
+        Key::ThreadInfoReceipts => "отметки о прочтении: {}",
+        Key::ThreadInfoLinkPreviews => "превью ссылок: {}",
+        Key::ThreadInfoPriority => "приоритетный: {}",
+        Key::ThreadInfoSlowMode => "медленный режим: {}",
+        Key::ThreadInfoSecret => "исчезающие сообщения: {}",
+        Key::ThreadInfoExpires => "закроется: {}",
+        Key::ThreadInfoNote => "заметка: {}",
+        Key::ThreadInfo => "Тред {}:\n* {}",
+        Key::ArchivedThreads => "Архивные треды:\n* {}",
+        Key::MatchedByTopic => "Собеседник найден по теме «{}».",
+        Key::TopicNotMatched => {
+            "Никого с темой «{}» сейчас нет, поэтому ваше сообщение ушло случайному пользователю."
+        }
+        Key::AnonymousThreadStarted => "Начат новый анонимный тред {}.",
+        Key::RandomQueued => {
+            "Сейчас не с кем поговорить. Вы в очереди ожидания на {} и будете соединены со \
+             следующим пользователем, который использует `/random`. Чтобы выйти из очереди, \
+             используйте `/cancel`."
+        }
+        Key::RandomQueueLeft => "Вы вышли из очереди `/random`.",
+        Key::SendManySent => "{}: отправлено",
+        Key::SendManyReport => "Отправлено {} из {} получателей:\n* {}",
+        Key::MessageScheduled => "Сообщение для {} будет отправлено через {}.",
+        Key::NoScheduledMessages => "У вас нет запланированных сообщений.",
+        Key::ScheduledMessages => "Запланированные сообщения:\n{}",
+        Key::ScheduledMessageLine => "{}. {} в {}: {}",
+        Key::ScheduledMessageCancelled => "Запланированное сообщение для {} отменено.",
+        Key::ScheduledMessageFailed => {
+            "Не удалось отправить запланированное сообщение для {}: {}\n\n{}"
+        }
+        Key::RoomCreated => {
+            "Комната {} создана. Другие могут присоединиться к ней с помощью `/join {}`."
+        }
+        Key::RoomJoined => "Вы присоединились к {} как participant-{}.",
+        Key::RoomLeft => "Вы покинули {}.",
+        Key::SentToRoom => "✓ отправлено участникам {1}: {0}",
+        Key::PollSent => "Опрос отправлен в {}.",
+        Key::DiceRolled => "{} Вам выпало {} в {}.",
+        Key::ReminderSet => "Я напомню вам через {}.",
+        Key::NoReminders => "У вас нет напоминаний.",
+        Key::Reminders => "Напоминания:\n{}",
+        Key::ReminderLine => "{}. в {}: {}",
+        Key::ReminderCancelled => "Напоминание отменено.",
+        Key::Error => "Ошибка: {}.",
+        Key::DefaultThreadSet => "Сообщения без команды будут отправляться в {}.",
+        Key::DefaultThreadCleared => {
+            "Сообщения без команды будут отправляться в последний использованный тред."
+        }
+        Key::LastMessageEdited => "Ваше последнее сообщение в {} отредактировано.",
+        Key::LastMessageDeleted => "Ваше последнее сообщение в {}{} удалено.",
+        Key::Note => "Заметка для {}:\n{}",
+        Key::NoNote => "Для {} нет заметки.",
+        Key::NoteCleared => "Заметка для {} удалена.",
+        Key::NoteSaved => "Заметка для {} сохранена.",
+        Key::ReceiptsEnabled => {
+            "Отметки о прочтении включены для {}. Они работают, только если собеседник тоже их \
+             включит."
+        }
+        Key::ReceiptsDisabled => "Отметки о прочтении выключены для {}.",
+        Key::LinkPreviewsShown => "Превью ссылок показываются для {}.",
+        Key::LinkPreviewsHidden => "Превью ссылок скрыты для {}.",
+        Key::SecretDisabled => "Исчезающие сообщения выключены для {}.",
+        Key::SecretEnabled => "Ваши сообщения в {} исчезнут через {}.",
+        Key::ThreadReopened => "Тред {} снова открыт.",
+        Key::BanLifted => "Бан с {} снят.",
+        Key::ThreadExpirySet => "Тред {} будет закрыт через {}.",
+        Key::ThreadExpiryCleared => "Тред {} больше не закроется сам.",
+        Key::ThreadsClosed => "Закрыто тредов: {}.",
+        Key::ThreadsClosedWithFailures => "Закрыто тредов: {}, не удалось закрыть: {}:\n* {}",
+        Key::ThreadAccepted => "Тред {} принят.",
+        Key::ThreadDeclined => "Тред {} отклонён.",
+        Key::BanInfo => "Бан {}:\n* забанен: {}\n* срок: {}\n* причина: {}",
+        Key::ThreadPrioritized => {
+            "Тред {} теперь приоритетный. Его сообщения будут приходить даже в режиме «не \
+             беспокоить»."
+        }
+        Key::ThreadDeprioritized => "Тред {} больше не приоритетный.",
+        Key::ThreadPaused => "Тред {} приостановлен. Чтобы продолжить, используйте `/resume {}`.",
+        Key::ThreadResumed => "Тред {} возобновлён.",
+        Key::SlowModeDisabled => "Медленный режим выключен для {}.",
+        Key::SlowModeSet => "Медленный режим для {}: {}.",
+        Key::ThreadArchived => {
+            "Тред {} перенесён в архив. Чтобы вернуть его, используйте `/unarchive {}`."
+        }
+        Key::ThreadRestored => "Тред {} восстановлен.",
+        Key::BannedThreads => "Забаненные треды:\n* {}",
+        Key::BlockedUsers => "Заблокированные пользователи:\n* {}",
+        Key::UserBlocked => "Пользователь @{} заблокирован.",
+        Key::UserUnblocked => "Пользователь @{} разблокирован.",
+        Key::BroadcastStarted => "Начинаю рассылку, получателей: {}...",
+        Key::BroadcastFailed => "Не удалось отправить рассылку пользователю @{}: {}",
+        Key::Settings => {
+            "Ваши настройки:\n{}\n\nЧтобы изменить настройку, используйте `/settings [key] \
+             [value]`."
+        }
+        Key::SettingUpdated => "Настройка {} обновлена.",
+        Key::MediaPolicy => "Правила для медиа:\n{}",
+        Key::MediaPolicyUpdated => "Правило для медиа {} обновлено.",
+        Key::CurrentTopic => "Ваша текущая тема: {}",
+        Key::NoTopic => "У вас не задана тема. Чтобы задать её, используйте `/topic [topic]`.",
+        Key::TopicSet => {
+            "Ваша тема теперь «{}». Другие могут найти вас с помощью `/random {} [message]`."
+        }
+        Key::TopicCleared => "Ваша тема удалена.",
+        Key::NoInterests => {
+            "У вас не заданы интересы. Чтобы задать их, используйте `/interests [tags]`."
+        }
+        Key::Interests => "Ваши интересы: #{}",
+        Key::InterestsCleared => "Ваши интересы удалены.",
+        Key::InterestsUpdated => "Ваши интересы обновлены: #{}",
+        Key::MaxIncomingSet => "Вы будете принимать не больше {} входящих анонимных тредов.",
+        Key::MaxIncomingCleared => "Входящие анонимные треды больше не ограничены.",
+        Key::IncomingOfMax => "{} из {}",
+        Key::IncomingUnlimited => "{} (без ограничения)",
+        Key::Off => "выкл",
+        Key::Status => {
+            "Статус:\n* входящие треды: {}\n* не беспокоить: {}\n* тред по умолчанию: {}\n* в \
+             очереди `/random`: {}"
+        }
+        Key::DndSet => {
+            "Режим «не беспокоить»: {}. В это время входящие сообщения будут придержаны."
+        }
+        Key::DndDisabled => "Режим «не беспокоить» выключен.",
+        Key::GreetingSet => {
+            "Приветствие задано. Оно будет отправлено каждому, кто начнёт с вами новый тред."
+        }
+        Key::GreetingCleared => "Приветствие удалено.",
+        Key::Profile => {
+            "Ваш профиль:\n* логин: @{}\n* имя: {}\n* фамилия: {}\n* chat id: {}\n* первое \
+             подключение: {}\n* в списке /users: {}"
+        }
+        Key::Missing => "нет",
+        Key::ProfileNameChanged => {
+            "\n\nВаше имя в Telegram теперь «{}». Чтобы обновить его, используйте `/whoami \
+             refresh`."
+        }
+        Key::ProfileUpdated => "Ваш профиль обновлён.",
+        Key::ForgetWarning => {
+            "Все ваши треды будут закрыты, а вы будете удалены из бота. Чтобы продолжить, \
+             отправьте `/forget confirm`."
+        }
+        Key::Forgotten => "Вы забыты. Чтобы начать заново, отправьте любое сообщение.",
+        Key::Stats => {
+            "Ваша статистика:\n* начато тредов: {}\n* отправлено сообщений: {}\n* получено \
+             сообщений: {}\n* забанено пользователей: {}"
+        }
+        Key::BotStatus => {
+            "lovebot v{}\n* время работы: {}\n* зарегистрировано пользователей: {}\n* событий \
+             записано с запуска: {}"
+        }
+        Key::ThreadRequested => {
+            "Кто-то хочет начать анонимный тред {0}. Используйте `/accept {0}` или `/decline \
+             {0}`."
+        }
+        Key::ThreadClosedByOther => "Тред {} закрыт собеседником.",
+        Key::ThreadReopenedByOther => "Тред {} снова открыт собеседником.",
+        Key::PollFrom => ">>> Опрос от {}:",
+        Key::PollFromAnonymous => ">>> Опрос от анонима {}:",
+        Key::PollFromRandomChat => ">>> Опрос из случайного чата {}:",
+        Key::DiceFrom => ">>> Кубик от {}:",
+        Key::DiceFromAnonymous => ">>> Кубик от анонима {}:",
+        Key::DiceFromRandomChat => ">>> Кубик из случайного чата {}:",
+        Key::MessageFromRoom => ">>> Сообщение от {} в {}:\n{}",
+        Key::ThreadPausedByOther => "Собеседник приостановил тред {}.",
+        Key::ThreadResumedByOther => "Собеседник возобновил тред {}.",
+        Key::Seen => "👀 прочитано в {}",
+        Key::PollVoteRetracted => "{} отозвал(а) свой голос в опросе «{}».",
+        Key::PollVoted => "{} проголосовал(а) в опросе «{}»: {}",
+        Key::DndOver => {
+            "Режим «не беспокоить» закончился. Пока вас не было, пришло обновлений: {}."
+        }
+        Key::DndDropped => " Самые старые из них ({}) были отброшены.",
+        Key::VideoNoteAttribution => "(видеосообщение)",
+        Key::StickerAttribution => "(стикер)",
+        Key::ContactAttribution => "(контакт)",
+        Key::VenueAttribution => "(место)",
+        Key::LocationAttribution => "(геопозиция)",
+        Key::InReplyTo => ">>> В ответ на: '{}'\n{}",
+        Key::MessageFrom => ">>> Сообщение от {}:\n{}",
+        Key::MessageFromAnonymous => ">>> Сообщение от анонима {}:\n{}",
+        Key::MessageFromRandomChat => ">>> Сообщение из случайного чата {}:\n{}",
+        Key::BanExpiresIn => "истекает через {}",
+        Key::BanPermanent => "навсегда",
+    }
+}
+
+fn help(language: Language) -> &'static str {
+    lazy_static! {
+        static ref EN_HELP: String = format_help(Language::En);
+        static ref RU_HELP: String = format_help(Language::Ru);
+    }
+    match language {
+        Language::En => &EN_HELP,
+        Language::Ru => &RU_HELP,
+    }
+}
+
+fn format_help(language: Language) -> String {
+    let (header, hints) = match language {
+        Language::En => (EN_HELP_HEADER, EN_HELP_HINTS),
+        Language::Ru => (RU_HELP_HEADER, RU_HELP_HINTS),
+    };
+    let mut lines = vec![header.to_string()];
+    let mut described = vec![];
+    for info in COMMANDS {
+        let (args, description) = match language {
+            Language::En => (info.args, info.description),
+            Language::Ru => (info.ru_args, info.ru_description),
+        };
+        let mut line = if args.is_empty() {
+            format!("* `/{}` - {}", info.name, description)
+        } else {
+            format!("* `/{} {}` - {}", info.name, args, description)
+        };
+        if !described.contains(&info.name) {
+            described.push(info.name);
+            let aliases = ALIASES
+                .iter()
+                .filter(|&&(_, command)| command == info.name)
+                .map(|&(alias, _)| format!("`/{}`", alias))
+                .collect::<Vec<_>>();
+            if !aliases.is_empty() {
+                let label = match language {
+                    Language::En => "Short",
+                    Language::Ru => "Коротко",
+                };
+                line.push_str(&format!(" {}: {}.", label, aliases.join(", ")));
+            }
+        }
+        lines.push(line);
+    }
+    format!("{}\n\n{}", lines.join("\n"), hints)
+}
+
+/// Describes a single command in detail for `/help [command]`.
+pub fn command_help(language: Language, name: &str) -> String {
+    let (example_label, errors_label) = match language {
+        Language::En => ("Example", "Common errors"),
+        Language::Ru => ("Пример", "Частые ошибки"),
+    };
+    let mut usages = vec![];
+    let mut examples = vec![];
+    let mut errors = vec![];
+    for info in COMMANDS.iter().filter(|info| info.name == name) {
+        let (args, description) = match language {
+            Language::En => (info.args, info.description),
+            Language::Ru => (info.ru_args, info.ru_description),
+        };
+        if args.is_empty() {
+            usages.push(format!("`/{}` - {}", info.name, description));
+        } else {
+            usages.push(format!("`/{} {}` - {}", info.name, args, description));
+        }
+        examples.push(format!("`{}`", info.example));
+        errors.extend(info.errors.iter().map(|&(en, ru)| match language {
+            Language::En => en,
+            Language::Ru => ru,
+        }));
+    }
+
+    let mut sections = vec![
+        usages.join("\n"),
+        format!("{}: {}", example_label, examples.join(", ")),
+    ];
+    if !errors.is_empty() {
+        sections.push(format!("{}:\n* {}", errors_label, errors.join("\n* ")));
+    }
+    sections.join("\n\n")
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+const EN_START: &str = "\
+Hello! This is anonymous chatting bot. Quick start guide:
+
+* Use command `/send @sergio_4min Hello!` to send an anonymous message to a particular user;
+* Use command `/random Hello!` to send an anonymous message to a random user.
+* Use command `/users` to list all available users.
+
+For more commands, use `/help`.";
+
+const EN_HELP_HEADER: &str = "Available commands:";
+
+const EN_HELP_HINTS: &str = "\
+Hints:
+* You can reply to a message instead of using `/send` command.
+* A message without a command is sent to your default or last used thread.
+
+Should you need any further assistance, contact @sergio_4min.";
+
+const RU_START: &str = "\
+Привет! Это бот для анонимного общения. Краткое руководство:
+
+* Команда `/send @sergio_4min Привет!` отправит анонимное сообщение конкретному пользователю;
+* Команда `/random Привет!` отправит анонимное сообщение случайному пользователю.
+* Команда `/users` покажет список всех доступных пользователей.
+
+Остальные команды можно посмотреть с помощью `/help`.";
+
+const RU_HELP_HEADER: &str = "Доступные команды:";
+
+const RU_HELP_HINTS: &str = "\
+Подсказки:
+* Вместо команды `/send` можно просто ответить на сообщение.
+* Сообщение без команды отправляется в тред по умолчанию или в последний использованный.
+* Основные команды можно писать по-русски, например `/помощь` или `/закрыть [тред]`.
+
+Если вам нужна помощь, пишите @sergio_4min.";
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYS: &[Key] = &[
+        Key::Start,
+        Key::Stop,
+        Key::Help,
+        Key::BotStopped,
+        Key::NoThreads,
+        Key::NoArchivedThreads,
+        Key::NoBans,
+        Key::BroadcastFinished,
+        Key::LanguageChanged,
+        Key::MalformedCommand,
+        Key::On,
+        Key::ThreadPinned,
+        Key::ThreadUnpinned,
+        Key::Reminder,
+        Key::BanExpired,
+        Key::ConfirmationExpired,
+        Key::RandomRequestExpired,
+        Key::ThreadRequestExpired,
+        Key::ThreadExpired,
+        Key::ThreadIdExpanded,
+        Key::ThreadClosed,
+        Key::ThreadBanned,
+        Key::ConfirmCloseThread,
+        Key::ConfirmCloseOnlyThread,
+        Key::ConfirmCloseAll,
+        Key::ConfirmBan,
+        Key::ConfirmBanOnlyPartner,
+        Key::ConfirmBroadcastAll,
+        Key::ConfirmBroadcastActive,
+        Key::ConfirmBroadcastUsers,
+        Key::ConfirmLocation,
+        Key::ConfirmVoice,
+        Key::ConfirmButton,
+        Key::CancelButton,
+        Key::ConfirmationCancelled,
+        Key::ConfirmationConfirmed,
+        Key::AvailableUsers,
+        Key::NobodyToWriteTo,
+        Key::PickUserTruncated,
+        Key::PickUser,
+        Key::ReplyToWrite,
+        Key::NoUsersFound,
+        Key::FoundUsersTruncated,
+        Key::FoundUsers,
+        Key::NobodyActive,
+        Key::ActiveUsers,
+        Key::ThreadSlowModeMark,
+        Key::ThreadSecretMark,
+        Key::ThreadExpiryMark,
+        Key::NoThreadsLabeled,
+        Key::ActiveThreads,
+        Key::ThreadLabeled,
+        Key::LabelRemoved,
+        Key::NoLabels,
+        Key::Labels,
+        Key::AnonymityMe,
+        Key::AnonymityThem,
+        Key::AnonymityBoth,
+        Key::Unknown,
+        Key::ThreadInfoStarted,
+        Key::ThreadInfoAnonymity,
+        Key::ThreadInfoMessagesSent,
+        Key::ThreadInfoMessagesReceived,
+        Key::ThreadInfoPinned,
+        Key::ThreadInfoArchived,
+        Key::ThreadInfoReceipts,
+        Key::ThreadInfoLinkPreviews,
+        Key::ThreadInfoPriority,
+        Key::ThreadInfoSlowMode,
+        Key::ThreadInfoSecret,
+        Key::ThreadInfoExpires,
+        Key::ThreadInfoNote,
+        Key::ThreadInfo,
+        Key::ArchivedThreads,
+        Key::MatchedByTopic,
+        Key::TopicNotMatched,
+        Key::AnonymousThreadStarted,
+        Key::RandomQueued,
+        Key::RandomQueueLeft,
+        Key::SendManySent,
+        Key::SendManyReport,
+        Key::MessageScheduled,
+        Key::NoScheduledMessages,
+        Key::ScheduledMessages,
+        Key::ScheduledMessageLine,
+        Key::ScheduledMessageCancelled,
+        Key::ScheduledMessageFailed,
+        Key::RoomCreated,
+        Key::RoomJoined,
+        Key::RoomLeft,
+        Key::SentToRoom,
+        Key::PollSent,
+        Key::DiceRolled,
+        Key::ReminderSet,
+        Key::NoReminders,
+        Key::Reminders,
+        Key::ReminderLine,
+        Key::ReminderCancelled,
+        Key::Error,
+        Key::DefaultThreadSet,
+        Key::DefaultThreadCleared,
+        Key::LastMessageEdited,
+        Key::LastMessageDeleted,
+        Key::Note,
+        Key::NoNote,
+        Key::NoteCleared,
+        Key::NoteSaved,
+        Key::ReceiptsEnabled,
+        Key::ReceiptsDisabled,
+        Key::LinkPreviewsShown,
+        Key::LinkPreviewsHidden,
+        Key::SecretDisabled,
+        Key::SecretEnabled,
+        Key::ThreadReopened,
+        Key::BanLifted,
+        Key::ThreadExpirySet,
+        Key::ThreadExpiryCleared,
+        Key::ThreadsClosed,
+        Key::ThreadsClosedWithFailures,
+        Key::ThreadAccepted,
+        Key::ThreadDeclined,
+        Key::BanInfo,
+        Key::ThreadPrioritized,
+        Key::ThreadDeprioritized,
+        Key::ThreadPaused,
+        Key::ThreadResumed,
+        Key::SlowModeDisabled,
+        Key::SlowModeSet,
+        Key::ThreadArchived,
+        Key::ThreadRestored,
+        Key::BannedThreads,
+        Key::BlockedUsers,
+        Key::UserBlocked,
+        Key::UserUnblocked,
+        Key::BroadcastStarted,
+        Key::BroadcastFailed,
+        Key::Settings,
+        Key::SettingUpdated,
+        Key::MediaPolicy,
+        Key::MediaPolicyUpdated,
+        Key::CurrentTopic,
+        Key::NoTopic,
+        Key::TopicSet,
+        Key::TopicCleared,
+        Key::NoInterests,
+        Key::Interests,
+        Key::InterestsCleared,
+        Key::InterestsUpdated,
+        Key::MaxIncomingSet,
+        Key::MaxIncomingCleared,
+        Key::IncomingOfMax,
+        Key::IncomingUnlimited,
+        Key::Off,
+        Key::Status,
+        Key::DndSet,
+        Key::DndDisabled,
+        Key::GreetingSet,
+        Key::GreetingCleared,
+        Key::Profile,
+        Key::Missing,
+        Key::ProfileNameChanged,
+        Key::ProfileUpdated,
+        Key::ForgetWarning,
+        Key::Forgotten,
+        Key::Stats,
+        Key::BotStatus,
+        Key::ThreadRequested,
+        Key::ThreadClosedByOther,
+        Key::ThreadReopenedByOther,
+        Key::PollFrom,
+        Key::PollFromAnonymous,
+        Key::PollFromRandomChat,
+        Key::DiceFrom,
+        Key::DiceFromAnonymous,
+        Key::DiceFromRandomChat,
+        Key::MessageFromRoom,
+        Key::ThreadPausedByOther,
+        Key::ThreadResumedByOther,
+        Key::Seen,
+        Key::PollVoteRetracted,
+        Key::PollVoted,
+        Key::DndOver,
+        Key::DndDropped,
+        Key::VideoNoteAttribution,
+        Key::StickerAttribution,
+        Key::ContactAttribution,
+        Key::VenueAttribution,
+        Key::LocationAttribution,
+        Key::InReplyTo,
+        Key::MessageFrom,
+        Key::MessageFromAnonymous,
+        Key::MessageFromRandomChat,
+        Key::BanExpiresIn,
+        Key::BanPermanent,
+    ];
+
+    /// Counts the arguments a text takes.
+    fn arg_count(text: &str) -> usize {
+        let mut next_index = 0;
+        let mut count = 0;
+        for placeholder in text.split('{').skip(1) {
+            let index = &placeholder[..placeholder.find('}').unwrap()];
+            let index = if index.is_empty() {
+                next_index += 1;
+                next_index - 1
+            } else {
+                index.parse::<usize>().unwrap()
+            };
+            count = count.max(index + 1);
+        }
+        count
+    }
+
+    #[test]
+    fn translations_take_same_arguments() {
+        for &key in KEYS {
+            assert_eq!(
+                arg_count(get(Language::En, key)),
+                arg_count(get(Language::Ru, key)),
+                "{:?}",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn placeholders_are_filled_in_order() {
+        assert_eq!(
+            format(Language::En, Key::ThreadLabeled, &[&"#1", &"work"]),
+            "Thread #1 is labeled work."
+        );
+        assert_eq!(
+            format(Language::En, Key::BroadcastFinished, &[&3, &1, &0]),
+            "Broadcast is finished. Delivered: 3, failed: 1, skipped: 0."
+        );
+    }
+
+    #[test]
+    fn indexed_placeholders_repeat_and_reorder_arguments() {
+        assert_eq!(
+            format(Language::En, Key::ThreadRequested, &[&"#7"]),
+            "Someone wants to start an anonymous thread #7. \
+             Use `/accept #7` or `/decline #7`."
+        );
+        assert_eq!(
+            format(Language::Ru, Key::SentToRoom, &[&5, &"#room"]),
+            "✓ отправлено участникам #room: 5"
+        );
+    }
+}
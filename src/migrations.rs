@@ -0,0 +1,74 @@
+use serde_json::{json, Value};
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Every change to the shape of logged records bumps `CURRENT_VERSION` and adds
+// a step that upgrades records of the previous version. Steps work on JSON
+// values, so that they do not need the old structs; bincode logs have no such
+// representation and are only read at the version they have been written at.
+//
+// Version 0 is a log without a header: bare events or, once timestamps were
+// introduced, events along with them, with or without a checksum.
+// Version 1 is a log with a header, where every event has a timestamp field.
+// Version 2 adds the text of relayed messages and the `keep_sent_texts`
+// setting.
+
+/// The version of the records written by this build.
+pub const CURRENT_VERSION: u32 = 2;
+
+/// Upgrades a JSON record written at `version` to the current shape.
+pub fn migrate(version: u32, mut record: Value) -> Value {
+    for from in version..CURRENT_VERSION {
+        record = match from {
+            0 => migrate_v0(record),
+            // The new fields are optional and missing ones take defaults.
+            1 => record,
+            _ => unreachable!("no migration from version {}", from),
+        };
+    }
+    record
+}
+
+/// Wraps bare events into records with an unknown timestamp.
+fn migrate_v0(record: Value) -> Value {
+    let is_wrapped = record
+        .as_object()
+        .is_some_and(|object| object.contains_key("event") || object.contains_key("snapshot"));
+    if is_wrapped {
+        record
+    } else {
+        json!({ "ts": null, "event": record })
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bare_event_of_v0_is_wrapped() {
+        let event = json!({ "UserStopped": { "login": "alice" } });
+        assert_eq!(
+            migrate(0, event.clone()),
+            json!({ "ts": null, "event": event })
+        );
+    }
+
+    #[test]
+    fn wrapped_records_of_v0_are_kept() {
+        let logged = json!({ "ts": 5, "event": { "UserStopped": { "login": "alice" } } });
+        assert_eq!(migrate(0, logged.clone()), logged);
+        let snapshot = json!({ "snapshot": { "users": [] } });
+        assert_eq!(migrate(0, snapshot.clone()), snapshot);
+    }
+
+    #[test]
+    fn records_of_later_versions_are_kept() {
+        let logged = json!({ "ts": 5, "event": { "UserStopped": { "login": "alice" } } });
+        for version in 1..=CURRENT_VERSION {
+            assert_eq!(migrate(version, logged.clone()), logged);
+        }
+    }
+}
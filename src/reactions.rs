@@ -0,0 +1,226 @@
+use std::time::Duration;
+
+use futures::{
+    future::{ready, Either},
+    stream::{self, Stream, StreamExt},
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+use teloxide::{
+    adaptors::AutoSend,
+    dispatching::{
+        stop_token::{AsyncStopFlag, AsyncStopToken},
+        update_listeners::{StatefulListener, UpdateListener},
+    },
+    requests::{JsonRequest, Payload, Request},
+    types::{AllowedUpdate, Update, User},
+    Bot, RequestError,
+};
+use tokio::sync::mpsc;
+
+////////////////////////////////////////////////////////////////////////////////
+
+// The teloxide version in use knows neither reaction updates nor
+// `setMessageReaction`, so both are done with hand-written payloads, like
+// spoilers are. Reaction updates are only sent when asked for explicitly, hence
+// the custom polling below.
+
+const POLLING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A change of the reactions a user has put on a message.
+#[derive(Debug, Deserialize)]
+pub struct MessageReactionUpdated {
+    pub chat: ReactionChat,
+    pub message_id: i32,
+    /// Missing for anonymous reactions in groups.
+    pub user: Option<User>,
+    pub new_reaction: Vec<ReactionType>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReactionChat {
+    pub id: i64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ReactionType {
+    Emoji {
+        emoji: String,
+    },
+    /// Custom and paid reactions, which bots cannot put.
+    #[serde(other)]
+    Other,
+}
+
+impl MessageReactionUpdated {
+    /// Returns `Some(None)` if the reactions have been removed and `None` if
+    /// none of the new ones can be relayed.
+    pub fn emoji(&self) -> Option<Option<&str>> {
+        if self.new_reaction.is_empty() {
+            return Some(None);
+        }
+        self.new_reaction
+            .iter()
+            .find_map(|reaction| match reaction {
+                ReactionType::Emoji { emoji } => Some(Some(emoji.as_str())),
+                ReactionType::Other => None,
+            })
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Serialize)]
+struct GetRawUpdates {
+    offset: i32,
+    timeout: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    limit: Option<u8>,
+    allowed_updates: Vec<String>,
+}
+
+impl Payload for GetRawUpdates {
+    type Output = Vec<serde_json::Value>;
+
+    const NAME: &'static str = "GetUpdates";
+}
+
+#[derive(Serialize)]
+struct EmojiReaction {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    emoji: String,
+}
+
+#[derive(Serialize)]
+struct SetMessageReaction {
+    chat_id: i64,
+    message_id: i32,
+    reaction: Vec<EmojiReaction>,
+}
+
+impl Payload for SetMessageReaction {
+    type Output = bool;
+
+    const NAME: &'static str = "SetMessageReaction";
+}
+
+/// Puts an emoji reaction on a message, or removes the bot's reaction if
+/// `emoji` is `None`.
+pub async fn set_reaction(
+    bot: &AutoSend<Bot>,
+    chat_id: i64,
+    message_id: i32,
+    emoji: Option<String>,
+) -> Result<bool, RequestError> {
+    let payload = SetMessageReaction {
+        chat_id,
+        message_id,
+        reaction: emoji
+            .into_iter()
+            .map(|emoji| EmojiReaction {
+                kind: "emoji",
+                emoji,
+            })
+            .collect(),
+    };
+    JsonRequest::new(bot.inner().clone(), payload).send().await
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct PollingState {
+    bot: Bot,
+    allowed_updates: Vec<String>,
+    offset: i32,
+    reaction_sender: mpsc::UnboundedSender<MessageReactionUpdated>,
+    flag: AsyncStopFlag,
+    token: AsyncStopToken,
+}
+
+/// Long polling that works like teloxide's own, but also asks for reaction
+/// updates and hands them over to `reaction_sender`.
+pub fn polling_with_reactions(
+    bot: Bot,
+    reaction_sender: mpsc::UnboundedSender<MessageReactionUpdated>,
+) -> impl UpdateListener<RequestError> {
+    let (token, flag) = AsyncStopToken::new_pair();
+    let state = PollingState {
+        bot,
+        allowed_updates: vec!["message_reaction".to_string()],
+        offset: 0,
+        reaction_sender,
+        flag,
+        token,
+    };
+    let stop_token = |state: &mut PollingState| state.token.clone();
+    let hint_allowed_updates = Some(
+        |state: &mut PollingState, allowed: &mut dyn Iterator<Item = AllowedUpdate>| {
+            state.allowed_updates = allowed
+                .filter_map(|update| serde_json::to_value(update).ok())
+                .filter_map(|update| update.as_str().map(str::to_string))
+                .chain(Some("message_reaction".to_string()))
+                .collect();
+        },
+    );
+    let timeout_hint = Some(|_: &PollingState| Some(POLLING_TIMEOUT));
+    StatefulListener::new_with_hints(
+        state,
+        poll_updates,
+        stop_token,
+        hint_allowed_updates,
+        timeout_hint,
+    )
+}
+
+fn poll_updates(state: &mut PollingState) -> impl Stream<Item = Result<Update, RequestError>> + '_ {
+    stream::unfold(state, |state| async move {
+        if state.flag.is_stopped() {
+            // Confirms the updates that have been handled, so that they are
+            // not received again after a restart.
+            let payload = GetRawUpdates {
+                offset: state.offset,
+                timeout: 0,
+                limit: Some(1),
+                allowed_updates: state.allowed_updates.clone(),
+            };
+            return match JsonRequest::new(state.bot.clone(), payload).send().await {
+                Ok(_) => None,
+                Err(err) => Some((Either::Left(stream::once(ready(Err(err)))), state)),
+            };
+        }
+
+        let payload = GetRawUpdates {
+            offset: state.offset,
+            timeout: POLLING_TIMEOUT.as_secs() as u32,
+            limit: None,
+            allowed_updates: state.allowed_updates.clone(),
+        };
+        let values = match JsonRequest::new(state.bot.clone(), payload).send().await {
+            Ok(values) => values,
+            Err(err) => return Some((Either::Left(stream::once(ready(Err(err)))), state)),
+        };
+        let mut updates = vec![];
+        for value in values {
+            if let Some(update_id) = value["update_id"].as_i64() {
+                state.offset = update_id as i32 + 1;
+            }
+            if let Some(reaction) = value.get("message_reaction") {
+                match serde_json::from_value(reaction.clone()) {
+                    Ok(reaction) => {
+                        state.reaction_sender.send(reaction).ok();
+                    }
+                    Err(err) => error!("failed to parse reaction update {}: {}", reaction, err),
+                }
+                continue;
+            }
+            match Update::try_parse(&value) {
+                Ok(update) => updates.push(Ok(update)),
+                Err(err) => error!("failed to parse update {}: {}", value, err),
+            }
+        }
+        Some((Either::Right(stream::iter(updates)), state))
+    })
+    .flatten()
+}
@@ -0,0 +1,134 @@
+use serde::Serialize;
+use teloxide::{
+    prelude::*,
+    requests::{JsonRequest, Payload, Request},
+    types::{Message, ParseMode},
+    RequestError,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+// The teloxide version in use does not know about `has_spoiler`, so photos and
+// videos under a spoiler are sent with hand-written payloads. Media is always
+// sent by file id, which keeps them plain JSON requests.
+
+#[derive(Serialize)]
+struct SendSpoilerPhoto {
+    chat_id: i64,
+    photo: String,
+    caption: String,
+    parse_mode: ParseMode,
+    has_spoiler: bool,
+}
+
+impl SendSpoilerPhoto {
+    fn new(chat_id: i64, file_id: String, caption: String) -> Self {
+        Self {
+            chat_id,
+            photo: file_id,
+            caption,
+            parse_mode: ParseMode::Html,
+            has_spoiler: true,
+        }
+    }
+}
+
+impl Payload for SendSpoilerPhoto {
+    type Output = Message;
+
+    const NAME: &'static str = "SendPhoto";
+}
+
+#[derive(Serialize)]
+struct SendSpoilerVideo {
+    chat_id: i64,
+    video: String,
+    caption: String,
+    parse_mode: ParseMode,
+    duration: u32,
+    has_spoiler: bool,
+}
+
+impl SendSpoilerVideo {
+    fn new(chat_id: i64, file_id: String, caption: String, duration: u32) -> Self {
+        Self {
+            chat_id,
+            video: file_id,
+            caption,
+            parse_mode: ParseMode::Html,
+            duration,
+            has_spoiler: true,
+        }
+    }
+}
+
+impl Payload for SendSpoilerVideo {
+    type Output = Message;
+
+    const NAME: &'static str = "SendVideo";
+}
+
+/// Sends a photo that the recipient has to tap to reveal. The caption is HTML.
+pub async fn send_photo(
+    bot: &AutoSend<Bot>,
+    chat_id: i64,
+    file_id: String,
+    caption: String,
+) -> Result<Message, RequestError> {
+    let payload = SendSpoilerPhoto::new(chat_id, file_id, caption);
+    JsonRequest::new(bot.inner().clone(), payload).send().await
+}
+
+/// Sends a video that the recipient has to tap to reveal. The caption is HTML.
+pub async fn send_video(
+    bot: &AutoSend<Bot>,
+    chat_id: i64,
+    file_id: String,
+    caption: String,
+    duration: u32,
+) -> Result<Message, RequestError> {
+    let payload = SendSpoilerVideo::new(chat_id, file_id, caption, duration);
+    JsonRequest::new(bot.inner().clone(), payload).send().await
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn photo_payload_has_spoiler() {
+        let payload = SendSpoilerPhoto::new(42, "photo-id".into(), "<b>hi</b>".into());
+        assert_eq!(SendSpoilerPhoto::NAME, "SendPhoto");
+        assert_eq!(
+            serde_json::to_value(&payload).unwrap(),
+            json!({
+                "chat_id": 42,
+                "photo": "photo-id",
+                "caption": "<b>hi</b>",
+                "parse_mode": "HTML",
+                "has_spoiler": true,
+            })
+        );
+    }
+
+    #[test]
+    fn video_payload_has_spoiler() {
+        let payload = SendSpoilerVideo::new(-7, "video-id".into(), String::new(), 15);
+        assert_eq!(SendSpoilerVideo::NAME, "SendVideo");
+        assert_eq!(
+            serde_json::to_value(&payload).unwrap(),
+            json!({
+                "chat_id": -7,
+                "video": "video-id",
+                "caption": "",
+                "parse_mode": "HTML",
+                "duration": 15,
+                "has_spoiler": true,
+            })
+        );
+    }
+}
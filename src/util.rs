@@ -1,38 +1,11 @@
-use std::sync::{Arc, LockResult, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::{
+    sync::{Arc, LockResult, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use lazy_static::lazy_static;
 use rand::{prelude::SliceRandom, thread_rng};
-
-////////////////////////////////////////////////////////////////////////////////
-
-pub const START_MESSAGE: &str = "\
-Hello! This is anonymous chatting bot. Quick start guide:
-
-* Use command `/send @sergio_4min Hello!` to send an anonymous message to a particular user;
-* Use command `/random Hello!` to send an anonymous message to a random user.
-* Use command `/users` to list all available users.
-
-For more commands, use `/help`.";
-
-pub const STOP_MESSAGE: &str = "The bot is stopped. Use `/start` to restart it.";
-
-pub const HELP_MESSAGE: &str = "\
-Available commands:
-* `/send [receiver] [message]` - send a message. Receiver can either be a @username or a #thread.
-* `/random [message]` - send a message to a random user.
-* `/users` - list available users.
-* `/threads` - list active anonymous threads.
-* `/close [thread]` - close a thread.
-* `/ban [thread]` - close a thread and ban the user that started it.
-* `/unban [thread]` - unban a user that started this thread.
-* `/banlist` - show all the banned threads.
-* `/stop` - stop this bot.
-* `/help` - show this message.
-
-Hints:
-* You can reply to a message instead of using `/send` command.
-
-Should you need any further assistance, contact @sergio_4min.";
+use teloxide::types::{MessageEntity, MessageEntityKind};
 
 ////////////////////////////////////////////////////////////////////////////////
 
@@ -93,3 +66,288 @@ pub fn random_adjective() -> &'static str {
     }
     ADJECTIVES.choose(&mut thread_rng()).unwrap()
 }
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Escapes user-provided text so that it can be embedded into a bot message
+/// written in markup, see `markup_to_html`.
+pub fn escape_markup(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('`', "\\`")
+}
+
+/// Converts the markup of bot messages to Telegram HTML. The markup is plain
+/// text where `backticks` denote code and a backslash escapes the next character.
+pub fn markup_to_html(text: &str) -> String {
+    let mut html = String::with_capacity(text.len());
+    let mut in_code = false;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => chars.next().unwrap_or('\\'),
+            '`' => {
+                html.push_str(if in_code { "</code>" } else { "<code>" });
+                in_code = !in_code;
+                continue;
+            }
+            c => c,
+        };
+        match c {
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            c => html.push(c),
+        }
+    }
+    if in_code {
+        html.push_str("</code>");
+    }
+    html
+}
+
+/// Converts the markup of bot messages to plain text, for messages that are
+/// sent with explicit entities instead of HTML.
+pub fn markup_to_plain(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => plain.push(chars.next().unwrap_or('\\')),
+            '`' => {}
+            c => plain.push(c),
+        }
+    }
+    plain
+}
+
+/// Length of text in UTF-16 code units, the unit of entity offsets.
+pub fn utf16_len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+/// Takes the formatting entities (bold, links, code and so on) that fall into
+/// `length` UTF-16 code units starting at `start`, relative to that range.
+/// Mentions, hashtags and the like are dropped: Telegram detects them anew.
+pub fn slice_entities(
+    entities: &[MessageEntity],
+    start: usize,
+    length: usize,
+) -> Vec<MessageEntity> {
+    let end = start + length;
+    entities
+        .iter()
+        .filter(|entity| {
+            matches!(
+                entity.kind,
+                MessageEntityKind::Bold
+                    | MessageEntityKind::Italic
+                    | MessageEntityKind::Underline
+                    | MessageEntityKind::Strikethrough
+                    | MessageEntityKind::Code
+                    | MessageEntityKind::Pre { .. }
+                    | MessageEntityKind::TextLink { .. }
+            )
+        })
+        .filter_map(|entity| {
+            let entity_start = entity.offset.max(start);
+            let entity_end = (entity.offset + entity.length).min(end);
+            if entity_start >= entity_end {
+                return None;
+            }
+            Some(MessageEntity {
+                kind: entity.kind.clone(),
+                offset: entity_start - start,
+                length: entity_end - entity_start,
+            })
+        })
+        .collect()
+}
+
+/// Splits text into chunks of at most `max_len` characters. Chunks end on line
+/// boundaries unless a single line is longer than `max_len`.
+pub fn split_text(text: &str, max_len: usize) -> Vec<String> {
+    let mut chunks = vec![];
+    let mut chunk = String::new();
+    let mut chunk_len = 0;
+    for line in text.split('\n') {
+        let line_len = line.chars().count();
+        if chunk_len > 0 && chunk_len + 1 + line_len > max_len {
+            chunks.push(std::mem::take(&mut chunk));
+            chunk_len = 0;
+        } else if chunk_len > 0 {
+            chunk.push('\n');
+            chunk_len += 1;
+        }
+        let mut line = line.chars().peekable();
+        while line.peek().is_some() {
+            if chunk_len == max_len {
+                chunks.push(std::mem::take(&mut chunk));
+                chunk_len = 0;
+            }
+            chunk.extend(line.by_ref().take(max_len - chunk_len));
+            chunk_len = chunk.chars().count();
+        }
+    }
+    if chunk_len > 0 || chunks.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+/// Splits text like `split_text` does and gives each chunk the entities that
+/// fall into it, see `slice_entities`.
+pub fn split_text_with_entities(
+    text: &str,
+    entities: &[MessageEntity],
+    max_len: usize,
+) -> Vec<(String, Vec<MessageEntity>)> {
+    let mut position = 0;
+    split_text(text, max_len)
+        .into_iter()
+        .map(|chunk| {
+            // Chunks come in order and only lose the line breaks between them.
+            let start = position + text[position..].find(chunk.as_str()).unwrap_or(0);
+            position = start + chunk.len();
+            let chunk_entities =
+                slice_entities(entities, utf16_len(&text[..start]), utf16_len(&chunk));
+            (chunk, chunk_entities)
+        })
+        .collect()
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub fn now_timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time is before unix epoch")
+        .as_secs() as i64
+}
+
+pub fn format_timestamp(timestamp: i64) -> String {
+    let time = UNIX_EPOCH + Duration::from_secs(timestamp.max(0) as u64);
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
+pub fn format_age(timestamp: i64) -> String {
+    let secs = (now_timestamp() - timestamp).max(0);
+    let (value, unit) = if secs < 60 {
+        return "just now".to_string();
+    } else if secs < 3600 {
+        (secs / 60, "minute")
+    } else if secs < 86400 {
+        (secs / 3600, "hour")
+    } else if secs < 7 * 86400 {
+        (secs / 86400, "day")
+    } else if secs < 30 * 86400 {
+        (secs / (7 * 86400), "week")
+    } else if secs < 365 * 86400 {
+        (secs / (30 * 86400), "month")
+    } else {
+        (secs / (365 * 86400), "year")
+    };
+    format!(
+        "{} {}{} ago",
+        value,
+        unit,
+        if value == 1 { "" } else { "s" }
+    )
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (days, hours, minutes, seconds) = (
+        secs / 86400,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+    );
+    if days > 0 {
+        format!("{}d {}h {}m", days, hours, minutes)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markup_characters_of_user_text_are_kept() {
+        let text = r"snake_case *bold* [link](x) a\b `code` <b>&amp;</b>";
+        let markup = format!("Thread `#x`: {}", escape_markup(text));
+        assert_eq!(
+            markup_to_html(&markup),
+            r"Thread <code>#x</code>: snake_case *bold* [link](x) a\b `code` &lt;b&gt;&amp;amp;&lt;/b&gt;"
+        );
+        assert_eq!(markup_to_plain(&markup), format!("Thread #x: {}", text));
+    }
+
+    #[test]
+    fn escaped_text_stays_inside_code() {
+        let login = r"al`i\ce_";
+        let markup = format!("`@{}`", escape_markup(login));
+        assert_eq!(markup_to_html(&markup), r"<code>@al`i\ce_</code>");
+    }
+
+    #[test]
+    fn unbalanced_markup_is_closed() {
+        assert_eq!(markup_to_html("`/send"), "<code>/send</code>");
+        assert_eq!(markup_to_html(r"trailing \"), r"trailing \");
+        assert_eq!(markup_to_plain(r"trailing \"), r"trailing \");
+    }
+
+    #[test]
+    fn split_text_keeps_short_text_whole() {
+        assert_eq!(split_text("", 10), vec![""]);
+        assert_eq!(split_text("one\ntwo", 10), vec!["one\ntwo"]);
+        assert_eq!(split_text("0123456789", 10), vec!["0123456789"]);
+    }
+
+    #[test]
+    fn split_text_ends_chunks_on_line_breaks() {
+        let text = "first line\nsecond\nthird line\nfourth";
+        let chunks = split_text(text, 17);
+        assert_eq!(chunks, vec!["first line\nsecond", "third line\nfourth"]);
+        assert_eq!(chunks.join("\n"), text);
+    }
+
+    #[test]
+    fn split_text_cuts_lines_longer_than_the_limit() {
+        let text = format!("short\n{}\nend", "ж".repeat(25));
+        let chunks = split_text(&text, 10);
+        assert_eq!(
+            chunks,
+            vec![
+                "short".to_string(),
+                "ж".repeat(10),
+                "ж".repeat(10),
+                format!("{}\nend", "ж".repeat(5)),
+            ]
+        );
+        assert!(chunks.iter().all(|chunk| chunk.chars().count() <= 10));
+    }
+
+    #[test]
+    fn split_text_with_entities_moves_entities_into_chunks() {
+        let bold = MessageEntity {
+            kind: MessageEntityKind::Bold,
+            offset: 4,
+            length: 7,
+        };
+        let chunks = split_text_with_entities("abc defg\nhij", &[bold], 8);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].0, "abc defg");
+        assert_eq!(chunks[0].1[0].offset, 4);
+        assert_eq!(chunks[0].1[0].length, 4);
+        assert_eq!(chunks[1].0, "hij");
+        assert_eq!(chunks[1].1[0].offset, 0);
+        assert_eq!(chunks[1].1[0].length, 2);
+    }
+}